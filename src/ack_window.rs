@@ -1,33 +1,34 @@
 use crate::seq_number::{AckSeqNumber, SeqNumber};
-use std::collections::{BTreeMap, VecDeque};
+use std::collections::VecDeque;
 use tokio::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub(crate) struct AckWindow {
     size: usize,
-    acks: BTreeMap<AckSeqNumber, (SeqNumber, Instant)>,
-    keys: VecDeque<AckSeqNumber>,
+    acks: VecDeque<(AckSeqNumber, SeqNumber, Instant)>, // oldest first
 }
 
 impl AckWindow {
     pub fn new(size: usize) -> Self {
         Self {
             size,
-            acks: BTreeMap::new(),
-            keys: VecDeque::with_capacity(size),
+            acks: VecDeque::with_capacity(size),
         }
     }
 
     pub fn store(&mut self, seq: SeqNumber, ack: AckSeqNumber) {
-        if self.keys.len() >= self.size {
-            let oldest = self.keys.pop_front().unwrap();
-            self.acks.remove(&oldest);
+        if self.acks.len() >= self.size {
+            self.acks.pop_front();
         }
-        self.keys.push_back(ack);
-        self.acks.insert(ack, (seq, Instant::now()));
+        self.acks.push_back((ack, seq, Instant::now()));
     }
 
     pub fn get(&mut self, ack: AckSeqNumber) -> Option<(SeqNumber, Duration)> {
-        self.acks.get(&ack).map(|(seq, ts)| (*seq, ts.elapsed()))
+        // ACK2 packets answer recent ACKs
+        self.acks
+            .iter()
+            .rev()
+            .find(|(a, _, _)| *a == ack)
+            .map(|(_, seq, ts)| (*seq, ts.elapsed()))
     }
 }
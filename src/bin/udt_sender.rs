@@ -10,8 +10,7 @@ async fn main() {
 
     println!("Connected!");
 
-    let buffer: Vec<u8> = std::iter::repeat(b"Hello World!")
-        .take(100_000)
+    let buffer: Vec<u8> = std::iter::repeat_n(b"Hello World!", 100_000)
         .flat_map(|b| *b)
         .collect();
     println!("Message length: {}", buffer.len());
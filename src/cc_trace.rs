@@ -0,0 +1,101 @@
+//! Congestion control trace, to plot the behavior of the controller of a connection.
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Congestion control input which triggered a trace entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CcTraceEvent {
+    /// An ACK was received
+    Ack,
+    /// A loss report (NAK) was received
+    Nak,
+    /// The retransmission timer expired
+    Timeout,
+    /// The pacing interval applied to the connection changed
+    RateChange,
+}
+
+/// State of the congestion controller after processing an event
+#[derive(Debug, Clone)]
+pub struct CcTraceEntry {
+    pub socket_id: u32,
+    /// Time elapsed since the creation of the socket
+    pub timestamp: Duration,
+    pub event: CcTraceEvent,
+    /// Congestion window size (nb of packets)
+    pub congestion_window_size: f64,
+    /// Pacing interval between two data packets
+    pub pkt_send_period: Duration,
+    /// RTT estimate used by the controller
+    pub rtt: Duration,
+    pub slow_start: bool,
+}
+
+/// Destination of the congestion control trace of a connection.
+/// Entries are recorded from the packet processing path: sinks should be cheap,
+/// e.g. push to a channel or an in-memory buffer.
+pub trait CcTraceSink: Send + Sync {
+    fn record(&self, entry: &CcTraceEntry);
+}
+
+impl<F> CcTraceSink for F
+where
+    F: Fn(&CcTraceEntry) + Send + Sync,
+{
+    fn record(&self, entry: &CcTraceEntry) {
+        self(entry)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct CcTraceSinkRef(pub Arc<dyn CcTraceSink>);
+
+impl std::fmt::Debug for CcTraceSinkRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CcTraceSink")
+    }
+}
+
+#[tokio::test]
+async fn test_cc_trace() {
+    use crate::configuration::UdtConfiguration;
+    use crate::connection::UdtConnection;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A small flow window ends the slow start early, after which the pacing is adjusted
+    let config = UdtConfiguration {
+        flight_flag_size: 64,
+        ..Default::default()
+    };
+    let (mut client, mut server) = UdtConnection::pair(Some(config)).await.unwrap();
+    let entries = Arc::new(Mutex::new(Vec::new()));
+    let sink = entries.clone();
+    client.set_cc_trace(Some(Arc::new(move |entry: &CcTraceEntry| {
+        sink.lock().unwrap().push(entry.clone())
+    })));
+
+    let len = 1 << 20;
+    let sender = tokio::spawn(async move {
+        client.write_all(&vec![0; len]).await.unwrap();
+        client.flush().await.unwrap();
+        client
+    });
+    let mut received = vec![0; len];
+    server.read_exact(&mut received).await.unwrap();
+    let client = sender.await.unwrap();
+    client.set_cc_trace(None);
+
+    let entries = entries.lock().unwrap();
+    assert!(entries.iter().any(|e| e.event == CcTraceEvent::Ack));
+    assert!(entries.iter().all(|e| e.socket_id == client.socket_id()));
+    assert!(entries.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+    // Each rate change entry reports a new pacing interval
+    let rates: Vec<_> = entries
+        .iter()
+        .filter(|e| e.event == CcTraceEvent::RateChange)
+        .map(|e| e.pkt_send_period)
+        .collect();
+    assert!(!rates.is_empty());
+    assert!(rates.windows(2).all(|w| w[0] != w[1]));
+}
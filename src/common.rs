@@ -1,8 +1,9 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr};
 
-/// Converts an IP address bytes.
+/// Converts an IP address to bytes.
+/// IPv4 addresses, including IPv4-mapped IPv6 ones, use the first 4 bytes as in UDT4.
 pub fn ip_to_bytes(ip: IpAddr) -> [u8; 16] {
-    match ip {
+    match canonical_ip(ip) {
         IpAddr::V4(addr) => {
             let mut bytes = [0; 16];
             bytes[0..4].copy_from_slice(&addr.octets());
@@ -11,3 +12,29 @@ pub fn ip_to_bytes(ip: IpAddr) -> [u8; 16] {
         IpAddr::V6(addr) => addr.octets(),
     }
 }
+
+/// Converts bytes to an IP address, the reverse of `ip_to_bytes`.
+/// IPv4-mapped IPv6 addresses, sent by some implementations, are converted to IPv4.
+pub fn bytes_to_ip(bytes: [u8; 16]) -> IpAddr {
+    if bytes[4..].iter().all(|b| *b == 0) {
+        IpAddr::from([bytes[0], bytes[1], bytes[2], bytes[3]])
+    } else {
+        canonical_ip(bytes.into())
+    }
+}
+
+/// Whether the IPv6 address is link-local (fe80::/10): it needs a scope id to be reachable.
+pub fn is_ipv6_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// Converts IPv4-mapped IPv6 addresses (as seen on dual-stack sockets) to IPv4.
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(addr) => match addr.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::from([a, b, c, d]),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
@@ -1,7 +1,132 @@
+#[cfg(feature = "encryption")]
+use crate::crypto::UdtEncryptionKey;
+use crate::error::{UdtError, UdtErrorCode};
+use crate::handshake_extension::UdtHandshakeExtensions;
+use crate::metrics::MetricsSink;
+use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+
 const DEFAULT_MSS: u32 = 1500;
 const DEFAULT_UDT_BUF_SIZE: u32 = 81920;
 const DEFAULT_UDP_BUF_SIZE: usize = 8_000_000;
-const UDT_VERSION: u32 = 4;
+const DEFAULT_UDP_RCV_BATCH_SIZE: usize = 100;
+const DEFAULT_SND_BATCH_SIZE: usize = 100;
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_METRICS_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+// Version of the reference C++ implementation (UDT 4.11), the lowest one implemented
+pub(crate) const UDT4_VERSION: u32 = 4;
+// UDT4 with the handshake extensions of this crate
+const UDT_VERSION: u32 = 5;
+
+/// Flow label set on outgoing IPv6 packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ipv6FlowLabel {
+    /// Stable label derived from the socket id, distinct for each connection
+    FromSocketId,
+    /// Fixed 20-bit label
+    Fixed(u32),
+}
+
+/// Behavior of the receiver when its buffer is full because the application reads too slowly
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RcvOverloadPolicy {
+    /// Advertise a zero window: the sender pauses until the application catches up.
+    /// No data is lost, but latency grows unbounded.
+    #[default]
+    Backpressure,
+    /// Drop the oldest data waiting to be read to make room for new packets.
+    /// In message mode, whole messages are dropped.
+    DropOldest,
+}
+
+/// Thresholds of the alarms of a connection, reported as
+/// [`UdtConnectionEvent::AlarmRaised`](crate::UdtConnectionEvent::AlarmRaised) and
+/// [`UdtConnectionEvent::AlarmCleared`](crate::UdtConnectionEvent::AlarmCleared) events.
+/// Loss rate and goodput are measured over one second intervals.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UdtAlarmThresholds {
+    /// Smoothed round-trip time above which the RTT alarm is raised.
+    pub max_rtt: Option<Duration>,
+    /// Fraction of the data packets sent that the peer reported lost (0.0 to 1.0),
+    /// above which the loss alarm is raised.
+    pub max_loss_rate: Option<f64>,
+    /// Data rate acknowledged by the peer, in bytes per second, below which the goodput
+    /// alarm is raised. It is only watched while data is waiting to be sent.
+    pub min_goodput: Option<u64>,
+    /// How long a threshold must stay crossed before its alarm is raised.
+    /// Default: 5s
+    pub sustain: Duration,
+}
+
+impl Default for UdtAlarmThresholds {
+    fn default() -> Self {
+        Self {
+            max_rtt: None,
+            max_loss_rate: None,
+            min_goodput: None,
+            sustain: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Limits of the rate of the handshakes (connection requests) processed by a listener,
+/// so that a flood of connection attempts cannot starve the established connections.
+/// Handshakes beyond the limits are dropped before being parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdtHandshakeRateLimit {
+    /// Handshakes per second processed from each source IP address.
+    /// Default: 10
+    pub per_source_rate: u32,
+    /// Handshakes from a source IP address processed in a burst.
+    /// Default: 20
+    pub per_source_burst: u32,
+    /// Handshakes per second processed from all sources.
+    /// Default: 1000
+    pub global_rate: u32,
+    /// Handshakes from all sources processed in a burst.
+    /// Default: 2000
+    pub global_burst: u32,
+}
+
+impl Default for UdtHandshakeRateLimit {
+    fn default() -> Self {
+        Self {
+            per_source_rate: 10,
+            per_source_burst: 20,
+            global_rate: 1000,
+            global_burst: 2000,
+        }
+    }
+}
+
+/// Limit of the aggregated send rate of all the UDT connections of the process,
+/// see `process::set_egress_rate_limit`, or of the payloads of a distribution session,
+/// see `UdtDistributionSession::set_pacing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdtEgressRateLimit {
+    /// Bytes per second sent by all connections, UDP and IP headers excluded.
+    pub bytes_per_sec: NonZeroU64,
+    /// Bytes sent in a burst, after the connections have been idle.
+    pub burst_bytes: u64,
+}
+
+/// Parameters agreed on by both sides of a connection during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdtNegotiatedParams {
+    /// Smaller `UdtConfiguration::mss` of both sides (bytes)
+    pub mss: u32,
+    /// Maximum number of unacknowledged packets in flight: the smaller
+    /// `UdtConfiguration::flight_flag_size` of both sides, bounded by the receive buffer
+    /// of the peer
+    pub max_window_size: u32,
+    /// Highest UDT protocol version implemented by both sides
+    pub udt_version: u32,
+}
 
 /// Options for UDT protocol
 #[derive(Debug, Clone)]
@@ -9,64 +134,469 @@ pub struct UdtConfiguration {
     /// Packet size: the optimal size is the network MTU size. The default value is 1500 bytes.
     /// A UDT connection will choose the smaller value of the MSS between the two peer sides.
     pub mss: u32,
+    /// Path MTU discovery: data packets are first sent with a size of 1280 bytes
+    /// (or `mss` if smaller), then larger sizes up to the negotiated `mss` are probed,
+    /// and the packet size grows to the largest probe acknowledged by the peer.
+    /// On Linux, the packets are sent with the Don't Fragment flag, so that oversized
+    /// probes are dropped instead of fragmented. Raise `mss` to probe jumbo frames.
+    /// Default: false
+    pub path_mtu_discovery: bool,
     /// Maximum window size (nb of packets).  
     /// Internal parameter: you should set it to not less than `rcv_buf_size`.
+    /// A UDT connection will choose the smaller value between the two peer sides.
     /// Default: 256000
     pub flight_flag_size: u32,
-    /// Size of temporary storage for packets to send (nb of packets)
+    /// Size of temporary storage for packets to send (nb of packets).
+    /// It can be changed per connection with `UdtConnection::set_snd_buf_size`.
     pub snd_buf_size: u32,
-    /// Size of temporary storage for packets to receive (nb of packets)
+    /// Blocking sending, like `UDT_SNDSYN`: `send`, `send_vectored`, `send_msg` and
+    /// `send_with_deadline` wait for room in a full send buffer (until the deadline
+    /// for the latter). Otherwise they fail at once with `ErrorKind::OutOfMemory`
+    /// (`UdtErrorCode::AsyncSnd`), and the data can be sent again once `writable`.
+    /// It can be changed per connection with `UdtConnection::set_snd_syn`.
+    /// Default: false
+    pub snd_syn: bool,
+    /// Size of temporary storage for packets to receive (nb of packets).
+    /// Its free space is advertised to the peer as flow window: when the application
+    /// stops reading and the buffer fills, the peer stops sending until data is read.
+    /// It can be changed per connection with `UdtConnection::set_rcv_buf_size`.
     pub rcv_buf_size: u32,
     /// UDT uses UDP as the data channel, so the UDP buffer size may affect the performance.
     /// The sending buffer size is applied on the UDP socket. The actual value used
     /// by the kernel is bounded by "net.core.wmem_max".
+    /// The effective value can be read with `udp_snd_buf_size()` on the listener or connection.
     pub udp_snd_buf_size: usize,
     /// UDT uses UDP as the data channel, so the UDP buffer size may affect the performance.
     /// The receiving buffer size is applied on the UDP socket. The actual value used
     /// by the kernel is bounded by "net.core.rmem_max".
+    /// The effective value can be read with `udp_rcv_buf_size()` on the listener or connection.
     pub udp_rcv_buf_size: usize,
     /// Whether SO_REUSEPORT option should be set on the UDP socket.
     /// On Linux, this option can be useful to load-balance packets
     /// from multiple clients to distinct threads and distinct UDT multiplexers.
     /// Default: false.
     pub udp_reuse_port: bool,
+    /// Whether a UDP socket bound to an IPv6 address only exchanges IPv6 packets
+    /// (`IPV6_V6ONLY`). When false, a listener bound to `[::]` is dual-stack:
+    /// it also accepts IPv4 peers, seen as IPv4-mapped IPv6 addresses.
+    /// Default: false
+    pub ipv6_only: bool,
+    /// TTL (IPv4) or hop limit (IPv6) of the packets sent by the UDP socket.
+    /// It applies to the whole multiplexer, including the connections reusing it.
+    /// Default: None (OS default)
+    pub udp_ttl: Option<u32>,
+    /// Type of service (IPv4) or traffic class (IPv6) byte of the packets sent by the UDP socket,
+    /// e.g. `46 << 2` to mark them with the DSCP "expedited forwarding" class.
+    /// It applies to the whole multiplexer, including the connections reusing it.
+    /// The traffic class is only supported on Unix: elsewhere, binding an IPv6 socket fails.
+    /// Default: None (OS default)
+    pub udp_tos: Option<u32>,
+    /// Network interface (e.g. "eth0") the UDP socket is pinned to, for multi-homed hosts.
+    /// On Linux, `SO_BINDTODEVICE` is used (it may require `CAP_NET_RAW`).
+    /// Elsewhere, a wildcard bind address is replaced by the first address of the interface.
+    /// Default: None
+    pub bind_device: Option<String>,
+    /// Flow label set on outgoing IPv6 packets, so that ECMP-hashed networks keep all
+    /// the packets of a connection on a single path.
+    /// On Linux, the label is leased on the UDP socket (`IPV6_FLOWLABEL_MGR`);
+    /// if the lease is refused, packets are sent without label.
+    /// Default: None (the label is chosen by the OS)
+    pub ipv6_flow_label: Option<Ipv6FlowLabel>,
+    /// Size of the buffer used to receive each UDP datagram on the multiplexer.
+    /// Larger datagrams are truncated. It can be raised for jumbo frames or GRO,
+    /// or lowered on constrained devices.
+    /// Default: None (uses `mss`)
+    pub udp_rcv_datagram_size: Option<usize>,
+    /// Maximum number of datagrams received by the multiplexer in a single batch.
+    /// The receive scratch buffer holds `udp_rcv_batch_size` datagrams.
+    /// Default: 100
+    pub udp_rcv_batch_size: usize,
+    /// When no datagram is pending, the receive worker keeps polling the UDP socket
+    /// for up to this duration before waiting for readiness. It saves the scheduler
+    /// wakeup latency, but keeps a runtime thread spinning, i.e. close to a full CPU
+    /// core per multiplexer even without traffic. Linux only: ignored elsewhere.
+    /// Default: None
+    pub udp_rcv_busy_poll: Option<Duration>,
+    /// Whether consecutive data packets of a batch are handed to the kernel as a single
+    /// buffer segmented into datagrams (`UDP_SEGMENT`), which saves CPU on bulk transfers.
+    /// Linux only: ignored elsewhere, or if unsupported by the kernel or the network device.
+    /// Default: false
+    pub udp_gso: bool,
+    /// Whether the kernel may coalesce received datagrams of a connection (`UDP_GRO`).
+    /// The receive buffer of each datagram is then 64KB, unless `udp_rcv_datagram_size` is set.
+    /// Linux only: ignored elsewhere.
+    /// Default: false
+    pub udp_gro: bool,
+    /// Maximum number of packet buffers kept for reuse by each multiplexer,
+    /// for received packets and for the payloads of sent packets respectively.
+    /// Buffers are recycled once read by the application or acknowledged by the peer,
+    /// so that steady-state transfers do not allocate per packet. 0 disables the reuse.
+    /// In fixed-capacity mode, both pools are preallocated with this many buffers.
+    /// Default: 1024
+    pub packet_pool_size: usize,
+    /// Fixed-capacity mode, for embedded gateways and latency-critical deployments
+    /// that forbid allocator jitter: packet buffers, send and receive buffers,
+    /// loss lists and queues are preallocated when the multiplexer and the connections
+    /// are created, and steady-state transfers do not allocate.
+    /// Where they would grow, operations fail instead: sending fails with
+    /// `ErrorKind::OutOfMemory` (`UdtErrorCode::AsyncSnd`) while every buffer of the
+    /// packet pool is in use, and datagrams are left to the UDP socket while no receive
+    /// buffer is free. Send and receive buffers can not be enlarged past their initial size.
+    /// See `UdtConfiguration::fixed_capacity_profile` for consistent sizes.
+    /// The guarantee covers connections over UDP on Linux. Neither `udp_gro` nor
+    /// encryption can be enabled in this mode.
+    /// Default: false
+    pub fixed_capacity: bool,
+    /// Maximum number of new data packets a connection hands to the send worker
+    /// per scheduling round. Small batches keep pacing smooth on LAN links,
+    /// large ones make the most of batched system calls on fast WAN links.
+    /// Default: 100
+    pub snd_batch_size: usize,
+    /// Whether the send batch is scaled with the current pacing rate: each round covers
+    /// about 500µs of sending, from a single packet when pacing is slow up to
+    /// `snd_batch_size` packets when inter-packet intervals are tiny.
+    /// When disabled, every round sends up to `snd_batch_size` packets.
+    /// Default: true
+    pub snd_batch_adaptive: bool,
     /// Whether a potential existing UDT multiplexer (and associated UDP socket)
-    /// should be reused when binding the same port. The preexisting listener
+    /// should be reused when binding the same port and address family. The preexisting listener
     /// must have been created with this option set to true.
     /// For optimal throughput from multiple clients, using
     /// `udp_reuse_port` may be preferable.
     /// Default: true
     pub reuse_mux: bool,
-    /// UDT rendez-vous mode. (NOT IMPLEMENTED)
+    /// Label of the connection, reported with its background errors, debug logs, metrics
+    /// and statistics, and in the `udt_socket` span of the `tracing` feature,
+    /// to correlate transport issues with application-level jobs.
+    /// Connections accepted by a listener inherit the label of the listener.
+    /// Default: None
+    pub label: Option<String>,
+    /// Runtime on which the multiplexer workers are spawned and its UDP socket is
+    /// registered, to isolate transport work from the application tasks.
+    /// A reused multiplexer keeps the runtime it was created with.
+    /// Default: None (the runtime of the caller)
+    pub runtime: Option<Handle>,
+    /// UDT rendez-vous mode: both peers connect to each other at about the same time,
+    /// from bound sockets, instead of one of them listening. This gets through NATs
+    /// whose mappings are opened by the outgoing handshakes (UDP hole punching).
+    /// Handshake extensions are not exchanged in this mode.
+    /// See `UdtConnection::rendezvous`.
+    /// Default: false
     pub rendezvous: bool,
-    /// Maximum number of pending UDT connections to accept.
+    /// Whether a listener rejects connection requests whose handshake carries a server
+    /// address different from the local address the request was received on.
+    /// This is a cheap anti-spoofing and misconfiguration check, but it rejects clients
+    /// reaching the server through NAT. Mismatches are counted in the multiplexer stats
+    /// even when this option is disabled.
+    /// Default: false
+    pub verify_handshake_addr: bool,
+    /// Backlog of a listener: maximum number of connections set up but not returned by
+    /// `accept()` yet. Beyond it, connection requests are rejected, and the connection
+    /// of the peer fails with a "connection rejected" error instead of timing out.
+    /// Default: 1000
     pub accept_queue_size: usize,
-    /// Linger time on close()
+    /// Maximum number of live connections accepted by a listener, including the ones
+    /// not returned by `accept()` yet. Beyond it, connection requests are rejected
+    /// with a "server busy" error. See also `process::set_max_connections`.
+    /// Default: None (unlimited)
+    pub max_connections: Option<usize>,
+    /// Linger time on close(), in seconds: how long to wait for the data in the send
+    /// buffer to be acknowledged before shutting down the connection.
+    /// `None` discards the pending data. It can be overridden with
+    /// `UdtConnection::close_with_deadline`.
+    /// Default: 10 seconds
     pub linger_timeout: Option<u32>,
+    /// Period of the keep-alive packets sent to the peer, which keep the connection
+    /// (and NAT mappings) alive while no data flows. `None` disables keep-alives.
+    /// Default: 1 second
+    pub keep_alive_interval: Option<Duration>,
+    /// Whether keep-alives are only sent while the send buffer is empty.
+    /// When disabled, they are also sent during transfers, e.g. to refresh NAT mappings
+    /// on one-way links where the peer hardly sends anything back.
+    /// Default: true
+    pub keep_alive_idle_only: bool,
+    /// Number of consecutive keep-alives sent without receiving anything from the peer
+    /// after which the connection is considered broken.
+    /// Default: None (only the expiration timer breaks silent connections)
+    pub keep_alive_max_unanswered: Option<u32>,
+    /// The connection is considered broken when nothing was received from the peer
+    /// for this period, and the expiration timer fired at least `max_exp_count` times.
+    /// Pending and later reads and writes then fail with a "connection lost" error.
+    /// Default: 5 seconds
+    pub peer_idle_timeout: Duration,
+    /// Whether the connection follows the peer when its address changes, e.g. after a NAT
+    /// rebinding. Packets from a new address are dropped until the address is validated:
+    /// it is sent an ACK, whose ACK2 must come back from it.
+    /// Without this option, packets from any other address than the peer one are ignored.
+    /// Default: false
+    pub peer_migration: bool,
+    /// Number of consecutive expiration events required to break a silent connection.
+    /// The expiration period grows with each event, from the RTT-based retransmission
+    /// timeout (at least 300ms).
+    /// Default: 16
+    pub max_exp_count: u32,
+    /// How long a connection broken on the client side can be resumed with
+    /// `UdtConnection::resume`, going on from where the server stopped receiving.
+    /// Meanwhile, the server waits this long for its silent peer instead of `peer_idle_timeout`,
+    /// and both sides keep the data which was not acknowledged. Both sides must set it,
+    /// and the peers must settle on UDT version 5. Not available in rendezvous mode.
+    /// Default: None (connections cannot be resumed)
+    pub resumption_timeout: Option<Duration>,
+    /// Time allowed for the handshake when connecting: past it, the connection attempt
+    /// is aborted with a `TimedOut` error. `None` waits for the peer indefinitely.
+    /// Default: 3 seconds, as the reference implementation
+    pub connect_timeout: Option<Duration>,
+    /// Lowest UDT protocol version accepted from the peer: the handshake settles on the
+    /// highest version implemented by both sides, and peers below this one are rejected.
+    /// Version 4 is spoken by the reference C++ implementation (UDT 4.11), version 5 adds
+    /// the handshake extensions of this crate, such as the reason of rejections.
+    /// Default: 4 (any UDT4 peer is accepted)
+    pub min_udt_version: u32,
+    /// Handlers of the application extensions exchanged in the handshake, e.g. to check
+    /// an authentication token. They are only sent to peers of version 5 or above.
+    /// Default: none
+    pub handshake_extensions: UdtHandshakeExtensions,
+    /// In message mode, incomplete messages are discarded by the receiver when
+    /// their missing fragments did not arrive within this delay after the first one.
+    /// They are counted in `UdtStatistics::rcv_msg_reassembly_timeouts`.
+    /// Default: None (incomplete messages are kept until the connection is closed).
+    pub msg_reassembly_timeout: Option<Duration>,
+    /// Receiver latency budget for live streaming.
+    /// Lost packets that could not be recovered within this delay after their loss was
+    /// detected are given up on: the receiver acknowledges past them, so the sender stops
+    /// retransmitting them and the data received after the gap is delivered without waiting.
+    /// Retransmissions arriving later are discarded.
+    /// Live mode (`live_latency`) has its own drop policy: both can not be set.
+    /// Default: None (the receiver waits for every lost packet).
+    pub rcv_latency: Option<Duration>,
+    /// Enables live mode, for media streaming: the receiver delivers each packet this
+    /// long after it was handed to the sending socket, so that the stream is read at the
+    /// pace it was written. A lost packet is given up once the packet following it is due,
+    /// retransmissions arriving later are discarded.
+    /// The latency should cover a few RTTs, and the receive buffer the data sent during it.
+    /// Both sides must enable it: data packets then carry the time their data was handed
+    /// to the socket, instead of the time they were sent.
+    /// Default: None (packets are delivered as soon as they are received in order)
+    pub live_latency: Option<Duration>,
+    /// Fraction of the pacing interval applied after a retransmitted packet:
+    /// 0.0 sends retransmissions back to back, 1.0 paces them like new data.
+    /// Lowering it shortens loss recovery on fast links, at the cost of burstier traffic.
+    /// Values are clamped to 0.0..=1.0, NaN is rejected.
+    /// Default: 1.0
+    pub retransmit_pacing: f64,
+    /// Maximum sending rate of the connection in bits per second, retransmissions included,
+    /// e.g. to keep a bulk transfer from starving other traffic on the link.
+    /// Congestion control may send slower, never faster. The UDP and IP headers are not counted.
+    /// It can be changed on a live connection with `UdtConnection::set_max_bandwidth`.
+    /// Default: None (no limit)
+    pub max_bandwidth_bps: Option<u64>,
+    /// What the receiver does when its buffer is full.
+    /// Default: `RcvOverloadPolicy::Backpressure`
+    pub rcv_overload_policy: RcvOverloadPolicy,
+    /// Sink the connection statistics are exported to, e.g. to bridge them to Prometheus.
+    /// Connections accepted by a listener report to the sink of the listener.
+    /// Default: None
+    pub metrics_sink: Option<Arc<dyn MetricsSink>>,
+    /// Period at which the statistics are reported to `metrics_sink`.
+    /// Default: 1 second
+    pub metrics_interval: Duration,
+    /// Alarms raised when RTT, loss rate or goodput cross their thresholds,
+    /// so that applications can fail over or alert without sampling stats.
+    /// Default: None
+    pub alarms: Option<UdtAlarmThresholds>,
+    /// Rate limits of the handshakes received by a listener, counted in
+    /// `UdtMultiplexerStatistics::handshakes_dropped` when exceeded.
+    /// Default: None (unlimited)
+    pub handshake_rate_limit: Option<UdtHandshakeRateLimit>,
+    /// Pre-shared key encrypting the data packets and authenticating the control packets
+    /// with AES-256-GCM. Both peers must use the same key: the handshake proves it,
+    /// and a peer without the key or with another one is rejected.
+    /// Default: None (packets are sent in clear)
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<UdtEncryptionKey>,
+    /// Seed of the random choices of the protocol (initial sequence number,
+    /// congestion control backoff), to replay a simulation exactly.
+    /// Default: None (seeded from the system)
+    #[cfg(feature = "test-utils")]
+    pub rng_seed: Option<u64>,
 }
 
 impl UdtConfiguration {
-    /// Gets the implemented UDT protocol version.
+    /// Gets the highest implemented UDT protocol version.
     #[must_use]
     pub fn udt_version() -> u32 {
         UDT_VERSION
     }
+
+    /// Version used with a peer implementing up to `peer_version`,
+    /// or `None` if it is below `min_udt_version`.
+    pub(crate) fn negotiate_udt_version(&self, peer_version: u32) -> Option<u32> {
+        let version = peer_version.min(UDT_VERSION);
+        (version >= self.min_udt_version.max(UDT4_VERSION)).then_some(version)
+    }
+
+    /// Configuration of the fixed-capacity mode (see `fixed_capacity`) for connections
+    /// buffering up to `buf_size` packets in each direction: the packet pools of the
+    /// multiplexer hold the packets of a single such connection, plus a receive batch.
+    /// Raise `packet_pool_size` for multiplexers carrying several connections.
+    #[must_use]
+    pub fn fixed_capacity_profile(buf_size: u32) -> Self {
+        Self {
+            fixed_capacity: true,
+            snd_buf_size: buf_size,
+            rcv_buf_size: buf_size,
+            flight_flag_size: buf_size,
+            packet_pool_size: buf_size as usize + DEFAULT_UDP_RCV_BATCH_SIZE,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn rcv_datagram_size(&self) -> usize {
+        match self.udp_rcv_datagram_size {
+            Some(size) => size,
+            None if self.udp_gro && cfg!(target_os = "linux") => usize::from(u16::MAX),
+            None => self.mss as usize,
+        }
+    }
+
+    /// Time it takes to send `nbytes` at `max_bandwidth_bps`, zero without limit.
+    pub(crate) fn max_bandwidth_interval(&self, nbytes: usize) -> Duration {
+        match self.max_bandwidth_bps {
+            Some(bps) if bps > 0 => Duration::from_secs_f64(nbytes as f64 * 8.0 / bps as f64),
+            _ => Duration::ZERO,
+        }
+    }
+
+    pub(crate) fn runtime(&self) -> Handle {
+        self.runtime.clone().unwrap_or_else(Handle::current)
+    }
+
+    /// Rejects the combinations of options that can not be applied together.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let invalid = |msg| {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                UdtError::new(UdtErrorCode::InvParam, msg),
+            ))
+        };
+        if self.live_latency.is_some() && self.rcv_latency.is_some() {
+            // Live mode gives up lost packets on its own schedule
+            return invalid("rcv_latency can not be set in live mode");
+        }
+        if self.retransmit_pacing.is_nan() {
+            return invalid("retransmit_pacing is not a number");
+        }
+        if self.fixed_capacity && self.udp_gro {
+            // Coalesced datagrams are copied out of their buffer
+            return invalid("udp_gro can not be set in fixed-capacity mode");
+        }
+        #[cfg(feature = "encryption")]
+        if self.fixed_capacity && self.encryption_key.is_some() {
+            return invalid("encryption is not available in fixed-capacity mode");
+        }
+        Ok(())
+    }
 }
 
 impl Default for UdtConfiguration {
     fn default() -> Self {
         Self {
             mss: DEFAULT_MSS,
+            path_mtu_discovery: false,
             flight_flag_size: 256_000,
             snd_buf_size: DEFAULT_UDT_BUF_SIZE,
+            snd_syn: false,
             rcv_buf_size: DEFAULT_UDT_BUF_SIZE * 2,
             udp_snd_buf_size: DEFAULT_UDP_BUF_SIZE,
             udp_rcv_buf_size: DEFAULT_UDP_BUF_SIZE,
             udp_reuse_port: false,
+            ipv6_only: false,
+            udp_ttl: None,
+            udp_tos: None,
+            bind_device: None,
+            ipv6_flow_label: None,
+            udp_rcv_datagram_size: None,
+            udp_rcv_batch_size: DEFAULT_UDP_RCV_BATCH_SIZE,
+            udp_rcv_busy_poll: None,
+            udp_gso: false,
+            udp_gro: false,
+            packet_pool_size: 1024,
+            fixed_capacity: false,
+            snd_batch_size: DEFAULT_SND_BATCH_SIZE,
+            snd_batch_adaptive: true,
             linger_timeout: Some(10),
+            keep_alive_interval: Some(DEFAULT_KEEP_ALIVE_INTERVAL),
+            keep_alive_idle_only: true,
+            keep_alive_max_unanswered: None,
+            peer_idle_timeout: DEFAULT_PEER_IDLE_TIMEOUT,
+            peer_migration: false,
+            max_exp_count: 16,
+            resumption_timeout: None,
+            connect_timeout: Some(DEFAULT_CONNECT_TIMEOUT),
+            min_udt_version: UDT4_VERSION,
+            handshake_extensions: UdtHandshakeExtensions::new(),
             reuse_mux: true,
+            label: None,
+            runtime: None,
             rendezvous: false,
+            verify_handshake_addr: false,
             accept_queue_size: 1000,
+            max_connections: None,
+            msg_reassembly_timeout: None,
+            rcv_latency: None,
+            live_latency: None,
+            retransmit_pacing: 1.0,
+            max_bandwidth_bps: None,
+            rcv_overload_policy: RcvOverloadPolicy::default(),
+            metrics_sink: None,
+            metrics_interval: DEFAULT_METRICS_INTERVAL,
+            alarms: None,
+            handshake_rate_limit: None,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            #[cfg(feature = "test-utils")]
+            rng_seed: None,
         }
     }
 }
+
+#[test]
+fn test_negotiate_udt_version() {
+    let mut configuration = UdtConfiguration::default();
+    assert_eq!(configuration.negotiate_udt_version(4), Some(4));
+    assert_eq!(configuration.negotiate_udt_version(9), Some(UDT_VERSION));
+    assert_eq!(configuration.negotiate_udt_version(3), None);
+    configuration.min_udt_version = 5;
+    assert_eq!(configuration.negotiate_udt_version(4), None);
+    assert_eq!(configuration.negotiate_udt_version(5), Some(5));
+}
+
+#[test]
+fn test_validate() {
+    assert!(UdtConfiguration::default().validate().is_ok());
+    let live = UdtConfiguration {
+        live_latency: Some(Duration::from_millis(120)),
+        rcv_latency: Some(Duration::from_millis(120)),
+        ..Default::default()
+    };
+    assert_eq!(live.validate().unwrap_err().kind(), ErrorKind::InvalidInput);
+    let nan_pacing = UdtConfiguration {
+        retransmit_pacing: f64::NAN,
+        ..Default::default()
+    };
+    assert_eq!(
+        nan_pacing.validate().unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    let fixed_gro = UdtConfiguration {
+        udp_gro: true,
+        ..UdtConfiguration::fixed_capacity_profile(1024)
+    };
+    assert_eq!(
+        fixed_gro.validate().unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert!(UdtConfiguration::fixed_capacity_profile(1024)
+        .validate()
+        .is_ok());
+}
@@ -1,53 +1,272 @@
-use crate::configuration::UdtConfiguration;
+#[cfg(feature = "cc-trace")]
+use crate::cc_trace::CcTraceSink;
+use crate::configuration::{UdtConfiguration, UdtNegotiatedParams};
+use crate::error::{UdtError, UdtErrorCode};
+use crate::event::{UdtConnectionEvent, UdtUserControlHandler};
+use crate::listener::UdtListener;
+use crate::message::UdtMessageInfo;
+use crate::multiplexer::UdtMultiplexer;
 use crate::socket::{SocketType, UdtStatus};
+use crate::split::{self, OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf};
+use crate::stats::{UdtMultiplexerStatistics, UdtStatistics};
 use crate::udt::{SocketRef, Udt};
+use bytes::{BufMut, Bytes};
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::io::{IoSlice, SeekFrom};
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncWrite, Error, ErrorKind, ReadBuf, Result};
-use tokio::net::{lookup_host, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::fs::File;
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind, Interest,
+    ReadBuf, Ready, Result,
+};
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tokio::runtime::Handle;
+use tokio::sync::broadcast;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+
+/// Size of the chunks read from or written to a file by `send_file` and `recv_file`,
+/// which bounds the memory they use besides the UDT buffers.
+const FILE_CHUNK_SIZE: usize = 256 * 1024;
+/// Interval between the handshakes of a rendezvous connection setup
+const RENDEZVOUS_RETRY_INTERVAL: Duration = Duration::from_millis(250);
 
 pub struct UdtConnection {
     socket: SocketRef,
+    sink_item: Option<Bytes>, // message accepted by `Sink::start_send`, not buffered yet
+    // Runtime of the multiplexer, closing the connection when it is dropped,
+    // even outside of any runtime
+    runtime: Option<Handle>,
+    close_on_drop: bool,
+    shutdown_started: AtomicBool,
 }
 
 impl UdtConnection {
     pub(crate) fn new(socket: SocketRef) -> Self {
-        Self { socket }
+        let runtime = socket
+            .multiplexer()
+            .map(|mux| mux.runtime().clone())
+            .or_else(|| Handle::try_current().ok());
+        Self {
+            socket,
+            sink_item: None,
+            runtime,
+            close_on_drop: true,
+            shutdown_started: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn socket(&self) -> &SocketRef {
+        &self.socket
     }
 
     pub async fn connect(
         addr: impl ToSocketAddrs,
         config: Option<UdtConfiguration>,
     ) -> Result<Self> {
-        Self::_bind_and_connect(None, addr, config).await
+        Self::_bind_and_connect(None, addr, config, SocketType::Stream).await
+    }
+
+    /// Opens a datagram (message-oriented) connection to a listener created with
+    /// [`UdtListener::bind_datagram`](crate::UdtListener::bind_datagram).
+    /// Data is exchanged with `send_msg` and `recv_msg`, which preserve message boundaries.
+    pub async fn connect_datagram(
+        addr: impl ToSocketAddrs,
+        config: Option<UdtConfiguration>,
+    ) -> Result<Self> {
+        Self::_bind_and_connect(None, addr, config, SocketType::Datagram).await
     }
 
+    /// Connects from `bind_addr`. When a [`UdtListener`](crate::UdtListener) is already bound
+    /// to this address with `reuse_mux` enabled, its UDP port is shared, so that a peer can both
+    /// accept and dial connections from a single port. Both configurations must enable
+    /// `reuse_mux` and agree on `mss` and `bind_device`, otherwise binding fails as the port
+    /// is in use. With the listener at hand, [`UdtListener::connect`](crate::UdtListener::connect)
+    /// shares its port without these conditions.
     pub async fn bind_and_connect(
         bind_addr: SocketAddr,
         connect_addr: impl ToSocketAddrs,
         config: Option<UdtConfiguration>,
     ) -> Result<Self> {
-        Self::_bind_and_connect(Some(bind_addr), connect_addr, config).await
+        Self::_bind_and_connect(Some(bind_addr), connect_addr, config, SocketType::Stream).await
+    }
+
+    /// Connects from a UDP socket already bound by the caller, e.g. to set options
+    /// not covered by [`UdtConfiguration`] or to run a STUN exchange first.
+    /// The socket must be created within the Tokio runtime used by UDT.
+    pub async fn connect_from_udp_socket(
+        udp_socket: UdpSocket,
+        connect_addr: impl ToSocketAddrs,
+        config: Option<UdtConfiguration>,
+    ) -> Result<Self> {
+        Self::_connect_from_udp_socket(udp_socket, connect_addr, config, SocketType::Stream).await
+    }
+
+    /// Opens a datagram connection from a UDP socket already bound by the caller,
+    /// as with [`UdtConnection::connect_from_udp_socket`].
+    pub async fn connect_datagram_from_udp_socket(
+        udp_socket: UdpSocket,
+        connect_addr: impl ToSocketAddrs,
+        config: Option<UdtConfiguration>,
+    ) -> Result<Self> {
+        Self::_connect_from_udp_socket(udp_socket, connect_addr, config, SocketType::Datagram).await
+    }
+
+    async fn _connect_from_udp_socket(
+        udp_socket: UdpSocket,
+        connect_addr: impl ToSocketAddrs,
+        config: Option<UdtConfiguration>,
+        socket_type: SocketType,
+    ) -> Result<Self> {
+        let socket = {
+            let mut udt = Udt::get().write().await;
+            let socket = udt.new_socket(socket_type, config)?.clone();
+            udt.attach_udp_socket(&socket, udp_socket)?;
+            socket
+        };
+        Self::connect_socket(socket, None, connect_addr).await
+    }
+
+    /// Creates two connected endpoints exchanging their packets in memory, without any
+    /// UDP socket. The whole protocol runs as over a network (handshake, acknowledgements,
+    /// congestion control...), which makes it handy to test code using UDT connections.
+    pub async fn pair(config: Option<UdtConfiguration>) -> Result<(Self, Self)> {
+        let (listen_socket, socket, listen_addr) = {
+            let mut udt = Udt::get().write().await;
+            let listen_socket = udt.new_socket(SocketType::Stream, config.clone())?.clone();
+            let socket = udt.new_socket(SocketType::Stream, config)?.clone();
+            let listen_addr = udt.attach_memory_pair(&listen_socket, &socket)?;
+            (listen_socket, socket, listen_addr)
+        };
+        listen_socket.open();
+        let listener = UdtListener::listen(listen_socket).await?;
+        let accepted = async { listener.accept().await.map(|(_, connection)| connection) };
+        let result = tokio::try_join!(Self::connect_socket(socket, None, listen_addr), accepted);
+        listener.close().await;
+        result
+    }
+
+    /// Connects from `local` to `remote` in rendezvous mode: instead of a listener,
+    /// the peer connects from `remote` to `local` at about the same time. Both sides
+    /// send handshakes until they get through, which opens the mappings of NATs
+    /// between them (UDP hole punching).
+    pub async fn rendezvous(
+        local: SocketAddr,
+        remote: SocketAddr,
+        config: Option<UdtConfiguration>,
+    ) -> Result<Self> {
+        let mut config = config.unwrap_or_default();
+        config.rendezvous = true;
+        Self::_bind_and_connect(Some(local), remote, Some(config), SocketType::Stream).await
+    }
+
+    /// Attempts rendezvous connections over candidate `(local, remote)` address pairs
+    /// at once, e.g. gathered with STUN, and returns the connection of the first pair
+    /// in the list that succeeds, once all the pairs before it failed.
+    /// The other attempts are aborted. Fails with the error of the last pair if all fail.
+    ///
+    /// This is not the first connection established: each side could see a different
+    /// one first, and they would keep different connections. Ranking the pairs agrees
+    /// on one without an extra exchange, provided the peer lists the same pairs
+    /// (mirrored) in the same order, e.g. the most direct first.
+    /// A pair never failing holds back the ones after it: `connect_timeout` should be set.
+    pub async fn rendezvous_race(
+        candidates: &[(SocketAddr, SocketAddr)],
+        config: Option<UdtConfiguration>,
+    ) -> Result<Self> {
+        let mut config = config.unwrap_or_default();
+        config.rendezvous = true;
+        let sockets = {
+            let mut udt = Udt::get().write().await;
+            candidates
+                .iter()
+                .map(|_| {
+                    Ok(udt
+                        .new_socket(SocketType::Stream, Some(config.clone()))?
+                        .clone())
+                })
+                .collect::<Result<Vec<_>>>()?
+        };
+
+        let mut attempts = JoinSet::new();
+        for (index, (socket, &(local, remote))) in sockets.iter().zip(candidates).enumerate() {
+            let socket = socket.clone();
+            attempts.spawn(async move {
+                let result = Self::connect_socket(socket, Some(local), remote).await;
+                (index, result)
+            });
+        }
+        let mut results: Vec<Option<Result<Self>>> = candidates.iter().map(|_| None).collect();
+        let winner = loop {
+            // Failed pairs are skipped, the next one decides once its attempt ends
+            match results
+                .iter()
+                .position(|result| !matches!(result, Some(Err(_))))
+            {
+                Some(index) if results[index].is_some() => break results[index].take(),
+                Some(_) => (),
+                // All pairs failed
+                None => break results.pop().flatten(),
+            }
+            match attempts.join_next().await {
+                Some(Ok((index, result))) => results[index] = Some(result),
+                Some(Err(err)) => break Some(Err(Error::other(err))),
+                None => break None,
+            }
+        };
+        attempts.shutdown().await;
+
+        let winner = winner.unwrap_or_else(|| {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                UdtError::new(UdtErrorCode::InvParam, "no candidate address pair"),
+            ))
+        });
+        for socket in &sockets {
+            if !winner
+                .as_ref()
+                .is_ok_and(|connection| Arc::ptr_eq(&connection.socket, socket))
+            {
+                Udt::close_socket(socket).await;
+            }
+        }
+        winner
     }
 
     async fn _bind_and_connect(
         bind_addr: Option<SocketAddr>,
         addrs: impl ToSocketAddrs,
         config: Option<UdtConfiguration>,
+        socket_type: SocketType,
     ) -> Result<Self> {
         let socket = {
             let mut udt = Udt::get().write().await;
-            udt.new_socket(SocketType::Stream, config)?.clone()
+            udt.new_socket(socket_type, config)?.clone()
         };
+        Self::connect_socket(socket, bind_addr, addrs).await
+    }
 
+    /// Connects a new socket, which may already be attached to a multiplexer.
+    pub(crate) async fn connect_socket(
+        socket: SocketRef,
+        bind_addr: Option<SocketAddr>,
+        addrs: impl ToSocketAddrs,
+    ) -> Result<Self> {
         let mut last_err = None;
-        let mut connected = false;
+        let mut connected = None;
 
         for addr in lookup_host(addrs).await? {
-            match socket.connect(addr, bind_addr).await {
+            match socket
+                .in_handshake_span(addr, socket.connect(addr, bind_addr))
+                .await
+            {
                 Ok(()) => {
-                    connected = true;
+                    connected = Some(addr);
                     break;
                 }
                 Err(e) => {
@@ -56,44 +275,634 @@ impl UdtConnection {
             }
         }
 
-        if !connected {
-            return Err(last_err.unwrap_or_else(|| {
-                Error::new(ErrorKind::InvalidInput, "could not resolve address")
-            }));
-        }
+        let peer = match connected {
+            Some(peer) => peer,
+            None => {
+                return Err(last_err.unwrap_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        UdtError::new(UdtErrorCode::NoServer, "could not resolve address"),
+                    )
+                }));
+            }
+        };
 
-        loop {
-            let status = socket.wait_for_connection().await;
-            if status != UdtStatus::Connecting {
-                break;
+        let rendezvous = socket.configuration.read().unwrap().rendezvous;
+        let handshake = socket.in_handshake_span(peer, async {
+            loop {
+                let status = if rendezvous {
+                    match timeout(RENDEZVOUS_RETRY_INTERVAL, socket.wait_for_connection()).await {
+                        Ok(status) => status,
+                        Err(_) => {
+                            // The first handshakes may be dropped by the NAT of the peer
+                            if let Err(err) = socket.send_rendezvous_handshake().await {
+                                socket.break_with(err);
+                            }
+                            continue;
+                        }
+                    }
+                } else {
+                    socket.wait_for_connection().await
+                };
+                if status != UdtStatus::Connecting {
+                    break;
+                }
+            }
+        });
+        let connect_timeout = socket.configuration.read().unwrap().connect_timeout;
+        if let Some(connect_timeout) = connect_timeout {
+            if timeout(connect_timeout, handshake).await.is_err() {
+                // Release the socket and its UDP port
+                Udt::close_socket(&socket).await;
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    UdtError::new(UdtErrorCode::NoServer, "connection setup timed out"),
+                ));
             }
+        } else {
+            handshake.await;
+        }
+        if socket.status() == UdtStatus::Broken {
+            let err = socket.connect_error();
+            Udt::close_socket(&socket).await;
+            return Err(err);
         }
         Ok(Self::new(socket))
     }
 
+    /// Splits the connection into a read half and a write half, which can be used
+    /// concurrently (e.g. from two tasks). The connection is closed once both are dropped.
+    #[must_use]
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        split::split_owned(self)
+    }
+
+    /// Splits a borrowed connection into a read half and a write half,
+    /// which can be used concurrently within the lifetime of the borrow.
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        split::split(self)
+    }
+
+    /// Sends `msg`. When the send buffer is full, waits for room if `snd_syn` is set,
+    /// or fails with `ErrorKind::OutOfMemory`.
     pub async fn send(&self, msg: &[u8]) -> Result<()> {
-        self.socket.send(msg)
+        self.send_when_ready(msg.len(), None, || self.socket.send(msg))
+            .await
+    }
+
+    /// Waits until the connection is ready for `interest`, and returns the ready set.
+    /// The connection is also ready once it is broken, so that the next read or write
+    /// reports the error. Readiness may be spurious: a following `try_*` call may
+    /// still fail with `WouldBlock`.
+    pub async fn ready(&self, interest: Interest) -> Result<Ready> {
+        std::future::poll_fn(|cx| {
+            let mut ready = Ready::EMPTY;
+            if interest.is_readable() && self.socket.poll_read_ready(cx).is_ready() {
+                ready |= Ready::READABLE;
+            }
+            if interest.is_writable() && self.socket.poll_write_ready(cx).is_ready() {
+                ready |= Ready::WRITABLE;
+            }
+            if ready.is_empty() {
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(ready))
+            }
+        })
+        .await
+    }
+
+    /// Waits until there is data to read, to be read with `try_recv`.
+    pub async fn readable(&self) -> Result<()> {
+        self.ready(Interest::READABLE).await.map(|_| ())
+    }
+
+    /// Waits until the send buffer has room, to be filled with `try_send`.
+    pub async fn writable(&self) -> Result<()> {
+        self.ready(Interest::WRITABLE).await.map(|_| ())
+    }
+
+    /// Sends `buf` if the send buffer has room for it, without waiting.
+    /// Fails with `WouldBlock` when the send buffer is full.
+    pub fn try_send(&self, buf: &[u8]) -> Result<usize> {
+        match self.socket.send(buf) {
+            Ok(()) => Ok(buf.len()),
+            Err(err) if err.kind() == ErrorKind::OutOfMemory => Err(Error::new(
+                ErrorKind::WouldBlock,
+                UdtError::new(UdtErrorCode::AsyncSnd, "send buffer is full"),
+            )),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads the data already received into `buf`, without waiting.
+    /// Fails with `WouldBlock` when there is no data to read.
+    pub fn try_recv(&self, buf: &mut [u8]) -> Result<usize> {
+        match self.socket.poll_recv(&mut ReadBuf::new(buf)) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(Error::new(
+                ErrorKind::WouldBlock,
+                UdtError::new(UdtErrorCode::AsyncRcv, "no data available to read"),
+            )),
+        }
+    }
+
+    /// Sends the concatenation of `bufs`, e.g. a header and a body,
+    /// without the caller having to concatenate them first.
+    /// When the send buffer is full, waits for room if `snd_syn` is set,
+    /// or fails with `ErrorKind::OutOfMemory`.
+    pub async fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        self.send_when_ready(len, None, || self.socket.send_vectored(bufs))
+            .await
+    }
+
+    /// Sends `msg`, unless none of it could be sent before `deadline`:
+    /// in that case the message is discarded locally without ever reaching the network.
+    /// Unlike a TTL, the deadline does not apply once the first packet was sent.
+    /// When the send buffer is full, waits for room until `deadline` if `snd_syn` is set,
+    /// or fails with `ErrorKind::OutOfMemory`.
+    pub async fn send_with_deadline(&self, msg: &[u8], deadline: Instant) -> Result<()> {
+        self.send_when_ready(msg.len(), Some(deadline), || {
+            self.socket.send_with_deadline(msg, Some(deadline))
+        })
+        .await
+    }
+
+    /// Records the decisions of the congestion controller of the connection to `sink`,
+    /// one entry per ACK, NAK, retransmission timeout or change of the pacing interval.
+    /// `None` stops the trace.
+    #[cfg(feature = "cc-trace")]
+    pub fn set_cc_trace(&self, sink: Option<Arc<dyn CcTraceSink>>) {
+        self.socket.set_cc_trace(sink);
+    }
+
+    /// Sends `msg` as a single message on a datagram connection.
+    /// The receiver gets it whole from `recv_msg`, even when it spans several packets.
+    /// If it could not be delivered within `ttl`, the message is dropped: it is not sent
+    /// or retransmitted anymore, and the receiver is told to skip it.
+    /// With `in_order` false, the message may be delivered before older incomplete ones.
+    /// When the send buffer is full, waits for room if `snd_syn` is set,
+    /// or fails with `ErrorKind::OutOfMemory`.
+    pub async fn send_msg(&self, msg: &[u8], ttl: Option<Duration>, in_order: bool) -> Result<()> {
+        self.send_when_ready(msg.len(), None, || self.socket.send_msg(msg, ttl, in_order))
+            .await
+    }
+
+    /// Receives the next message of a datagram connection into `buf`, returning its size,
+    /// waiting until a complete message is available.
+    /// The message is left in place if `buf` is too small.
+    pub async fn recv_msg(&self, buf: &mut [u8]) -> Result<usize> {
+        self.socket.recv_msg(buf).await.map(|info| info.len)
     }
 
+    /// Receives the next message, i.e. the data passed to a single `send` call by the peer,
+    /// along with its metadata. The message is left in place if `buf` is too small.
+    /// Mixing it with `recv` may return the end of a message partially read by `recv`.
+    pub async fn recv_msg_full(&self, buf: &mut [u8]) -> Result<UdtMessageInfo> {
+        self.socket.recv_msg(buf).await
+    }
+
+    /// Subscribes to the events of the connection, such as acknowledgements, losses or
+    /// the peer stalling because its application does not read fast enough.
+    /// The first subscriber also gets the events emitted since the socket was created,
+    /// starting with `Connected`; later ones only get the events emitted after subscribing.
+    /// A receiver falling too far behind skips the oldest events (`RecvError::Lagged`).
+    pub fn subscribe_events(&self) -> broadcast::Receiver<UdtConnectionEvent> {
+        self.socket.subscribe_events()
+    }
+
+    /// Calls `handler` for each user-defined control packet received from the peer.
+    /// It runs on the receive worker: it should return quickly. `None` removes the handler.
+    pub fn set_user_control_handler(&self, handler: Option<UdtUserControlHandler>) {
+        self.socket.set_user_control_handler(handler);
+    }
+
+    /// Sends a user-defined control packet to the peer, to piggyback custom signaling
+    /// on the UDT control channel. Like other control packets, it is not retransmitted
+    /// and may be lost. `payload` must fit in a single packet.
+    pub fn send_user_control(&self, reserved: u16, payload: &[u8]) -> Result<()> {
+        self.socket.send_user_control(reserved, payload)
+    }
+
+    /// Receives data into `buf`. Returns 0 once the peer shut down its sending side
+    /// (see `AsyncWrite::poll_shutdown`) and all its data was read.
     pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
         let nbytes = self.socket.recv(buf).await?;
         Ok(nbytes)
     }
 
+    /// Receives data into `buf`, e.g. a `BytesMut`, until it is full or no data is left to read.
+    pub async fn recv_buf(&self, buf: &mut impl BufMut) -> Result<usize> {
+        self.socket.recv_buf(buf).await
+    }
+
+    /// Receives the payload of the next packet. It is handed out as is,
+    /// without being copied out of the receive buffer. It is empty at the end of the stream.
+    pub async fn recv_bytes(&self) -> Result<Bytes> {
+        self.socket.recv_bytes().await
+    }
+
+    /// Sends `len` bytes of `file` starting at `offset`, streamed chunk by chunk into
+    /// the send buffer. `progress` is called with the number of bytes sent so far
+    /// after each chunk. Returns once all the data is in the send buffer,
+    /// like `write_all`: call `flush` to wait for its acknowledgement.
+    pub async fn send_file(
+        &mut self,
+        file: &mut File,
+        offset: u64,
+        len: u64,
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64> {
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|err| file_error(err, UdtErrorCode::InvRdOff))?;
+        let mut buf = vec![0; FILE_CHUNK_SIZE.min(len as usize)];
+        let mut sent = 0;
+        while sent < len {
+            let chunk = (len - sent).min(buf.len() as u64) as usize;
+            let nbytes = file
+                .read(&mut buf[..chunk])
+                .await
+                .map_err(|err| file_error(err, UdtErrorCode::RdPerm))?;
+            if nbytes == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    UdtError::new(
+                        UdtErrorCode::File,
+                        format!("end of file reached after sending {} bytes", sent),
+                    ),
+                ));
+            }
+            self.write_all(&buf[..nbytes]).await?;
+            sent += nbytes as u64;
+            progress(sent);
+        }
+        Ok(sent)
+    }
+
+    /// Receives `len` bytes into `file` starting at `offset`, streamed chunk by chunk from
+    /// the receive buffer. `progress` is called with the number of bytes received so far
+    /// after each chunk. The data following the `len` bytes is left in the receive buffer.
+    pub async fn recv_file(
+        &mut self,
+        file: &mut File,
+        offset: u64,
+        len: u64,
+        mut progress: impl FnMut(u64),
+    ) -> Result<u64> {
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|err| file_error(err, UdtErrorCode::InvWrOff))?;
+        let mut buf = vec![0; FILE_CHUNK_SIZE.min(len as usize)];
+        let mut received = 0;
+        while received < len {
+            let chunk = (len - received).min(buf.len() as u64) as usize;
+            let nbytes = self.recv(&mut buf[..chunk]).await?;
+            if nbytes == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    UdtError::new(
+                        UdtErrorCode::ConnLost,
+                        format!("connection closed after receiving {} bytes", received),
+                    ),
+                ));
+            }
+            file.write_all(&buf[..nbytes])
+                .await
+                .map_err(|err| file_error(err, UdtErrorCode::WrPerm))?;
+            received += nbytes as u64;
+            progress(received);
+        }
+        file.flush()
+            .await
+            .map_err(|err| file_error(err, UdtErrorCode::WrPerm))?;
+        Ok(received)
+    }
+
     pub fn rate_control(
         &self,
     ) -> std::sync::RwLockWriteGuard<'_, crate::rate_control::RateControl> {
         self.socket.rate_control.write().unwrap()
     }
 
+    /// Closes the connection, flushing pending data within the linger timeout.
+    /// The UDP socket is released once no other connection uses it.
+    /// Dropping the connection does the same in a background task,
+    /// unless it is released with `forget`.
     pub async fn close(&self) {
-        self.socket.close().await;
+        Udt::close_socket(&self.socket).await;
+    }
+
+    /// Resumes a broken connection with the listener which accepted it, without losing
+    /// any data: the sides restore their positions, and the data the listener has not
+    /// received yet is sent again. Both sides must set `resumption_timeout`, and the
+    /// connection must be resumed within it. Pending operations fail when the connection
+    /// breaks; the ones started after `resume` returns go on with the restored session.
+    pub async fn resume(&self) -> Result<()> {
+        self.socket.resume().await
+    }
+
+    /// Releases the handle without closing the connection, which stays open and keeps
+    /// delivering the data of its send buffer until the peer closes it, it breaks
+    /// or `process::close_all` is called.
+    pub fn forget(mut self) {
+        self.close_on_drop = false;
+    }
+
+    /// Closes the connection, waiting up to `linger` (instead of the configured
+    /// linger timeout) for the pending data to be acknowledged before shutting down.
+    /// A zero `linger` discards the pending data.
+    pub async fn close_with_deadline(&self, linger: Duration) {
+        Udt::close_socket_with_linger(&self.socket, linger).await;
     }
 
     #[must_use]
     pub fn socket_id(&self) -> u32 {
         self.socket.socket_id
     }
+
+    /// Gets the label of the connection, set with `UdtConfiguration::label`.
+    #[must_use]
+    pub fn label(&self) -> Option<String> {
+        self.socket.label()
+    }
+
+    /// Sets the label of the connection, e.g. to tag a connection accepted by a listener
+    /// with the job it serves.
+    pub fn set_label(&self, label: Option<String>) {
+        self.socket.set_label(label);
+    }
+
+    /// Gets the sending rate cap of the connection in bits per second,
+    /// see `UdtConfiguration::max_bandwidth_bps`.
+    #[must_use]
+    pub fn max_bandwidth(&self) -> Option<u64> {
+        self.socket.max_bandwidth()
+    }
+
+    /// Caps the sending rate of the connection to `bps` bits per second,
+    /// from the next packets on. `None` removes the cap.
+    pub fn set_max_bandwidth(&self, bps: Option<u64>) {
+        self.socket.set_max_bandwidth(bps);
+    }
+
+    /// Gets the capacity of the send buffer in packets, see `UdtConfiguration::snd_buf_size`.
+    #[must_use]
+    pub fn snd_buf_size(&self) -> u32 {
+        self.socket.snd_buf_size()
+    }
+
+    /// Resizes the send buffer to `packets`. Shrinking it below the data waiting
+    /// to be acknowledged keeps the data: sending waits until enough is acknowledged.
+    pub fn set_snd_buf_size(&self, packets: u32) {
+        self.socket.set_snd_buf_size(packets);
+    }
+
+    /// Gets whether sending waits for room in a full send buffer,
+    /// see `UdtConfiguration::snd_syn`.
+    #[must_use]
+    pub fn snd_syn(&self) -> bool {
+        self.socket.snd_syn()
+    }
+
+    /// Makes `send` and the like wait for room in a full send buffer,
+    /// or fail at once with `ErrorKind::OutOfMemory`.
+    pub fn set_snd_syn(&self, snd_syn: bool) {
+        self.socket.set_snd_syn(snd_syn);
+    }
+
+    /// Gets the capacity of the receive buffer in packets, see `UdtConfiguration::rcv_buf_size`.
+    #[must_use]
+    pub fn rcv_buf_size(&self) -> u32 {
+        self.socket.rcv_buf_size()
+    }
+
+    /// Resizes the receive buffer to `packets`. Shrinking it below the data waiting
+    /// to be read keeps the data: the peer stalls until enough is read.
+    pub fn set_rcv_buf_size(&self, packets: u32) {
+        self.socket.set_rcv_buf_size(packets);
+    }
+
+    /// Gets the size of the data packets sent, including the IP and UDP headers:
+    /// the negotiated `UdtConfiguration::mss`, or the path MTU found so far
+    /// with `UdtConfiguration::path_mtu_discovery`.
+    #[must_use]
+    pub fn packet_size(&self) -> u32 {
+        self.socket.packet_size()
+    }
+
+    /// Gets the MSS and maximum flow window agreed on with the peer during the handshake.
+    #[must_use]
+    pub fn negotiated_params(&self) -> UdtNegotiatedParams {
+        self.socket.negotiated_params()
+    }
+
+    /// Gets the value of the application extension block of type `ext_type` received
+    /// from the peer in the handshake, see `UdtConfiguration::handshake_extensions`.
+    #[must_use]
+    pub fn handshake_extension(&self, ext_type: u16) -> Option<Bytes> {
+        self.socket.peer_handshake_extension(ext_type)
+    }
+
+    /// Returns a snapshot of the connection statistics.
+    #[must_use]
+    pub fn stats(&self) -> UdtStatistics {
+        self.socket.stats()
+    }
+
+    /// Returns the smoothed round-trip time, refreshed by each ACK/ACK2 exchange.
+    /// It is 100ms until the first exchange.
+    #[must_use]
+    pub fn rtt(&self) -> Duration {
+        self.socket.rtt().0
+    }
+
+    /// Returns the variance of the round-trip time, e.g. to derive a timeout as
+    /// `rtt() + 4 * rtt_variance()`.
+    #[must_use]
+    pub fn rtt_variance(&self) -> Duration {
+        self.socket.rtt().1
+    }
+
+    /// Returns the local address of the UDP socket used by the connection.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.multiplexer()?.channel.local_addr()
+    }
+
+    /// Returns the address of the peer of the connection.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.socket.peer_addr().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotConnected,
+                UdtError::new(UdtErrorCode::NoConn, "socket is not connected"),
+            )
+        })
+    }
+
+    /// Returns the effective receive buffer size of the UDP socket used by the connection.
+    /// It may differ from the configured `udp_rcv_buf_size` (e.g. Linux doubles the value
+    /// and caps it to "net.core.rmem_max").
+    pub fn udp_rcv_buf_size(&self) -> Result<usize> {
+        self.multiplexer()?.udp_rcv_buf_size()
+    }
+
+    /// Returns the effective send buffer size of the UDP socket used by the connection.
+    pub fn udp_snd_buf_size(&self) -> Result<usize> {
+        self.multiplexer()?.udp_snd_buf_size()
+    }
+
+    fn multiplexer(&self) -> Result<Arc<UdtMultiplexer>> {
+        self.socket.multiplexer().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotConnected,
+                UdtError::new(UdtErrorCode::UnboundSock, "socket has no multiplexer"),
+            )
+        })
+    }
+
+    /// Returns a snapshot of the statistics of the multiplexer (UDP socket) used by the connection.
+    #[must_use]
+    pub fn multiplexer_stats(&self) -> Option<UdtMultiplexerStatistics> {
+        self.socket.multiplexer().map(|mux| mux.stats())
+    }
+
+    /// Returns the time at which the last packet (data or control) was received from the peer.
+    #[must_use]
+    pub fn last_activity(&self) -> Instant {
+        self.socket.last_activity()
+    }
+
+    /// Returns whether the connection is still up and the peer was heard from
+    /// within the last `threshold`.
+    #[must_use]
+    pub fn is_alive(&self, threshold: Duration) -> bool {
+        self.socket.status() == UdtStatus::Connected && self.last_activity().elapsed() <= threshold
+    }
+}
+
+impl Drop for UdtConnection {
+    fn drop(&mut self) {
+        if !self.close_on_drop
+            || matches!(self.socket.status(), UdtStatus::Closing | UdtStatus::Closed)
+        {
+            return;
+        }
+        let runtime = self.runtime.take().or_else(|| Handle::try_current().ok());
+        if let Some(runtime) = runtime {
+            let socket = self.socket.clone();
+            runtime.spawn(async move { Udt::close_socket(&socket).await });
+        }
+    }
+}
+
+impl UdtConnection {
+    pub(crate) fn poll_read_priv(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        loop {
+            if let Poll::Ready(res) = self.socket.poll_recv(buf) {
+                return Poll::Ready(res.map(|_| ()));
+            }
+            ready!(self.socket.poll_read_ready(cx));
+        }
+    }
+
+    /// Writes as much of `buf` as the send buffer can hold, waiting for the peer
+    /// to acknowledge some data if it is full.
+    pub(crate) fn poll_write_priv(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        loop {
+            let room = self.socket.snd_buffer_room();
+            if room == 0 {
+                // Waits without building the error of a full buffer
+                ready!(self.socket.poll_write_ready(cx));
+            }
+            // With a full buffer, trying to send one byte reports a broken connection
+            let len = buf.len().min(room.max(1));
+            match self.socket.send(&buf[..len]) {
+                Ok(()) => return Poll::Ready(Ok(len)),
+                Err(err) if err.kind() == ErrorKind::OutOfMemory => {
+                    ready!(self.socket.poll_write_ready(cx));
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    pub(crate) fn poll_write_vectored_priv(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        let len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if len > self.socket.snd_buffer_room() {
+            // Only part of the first buffer fits
+            let buf = bufs.iter().find(|buf| !buf.is_empty()).unwrap();
+            return self.poll_write_priv(cx, buf);
+        }
+        loop {
+            match self.socket.send_vectored(bufs) {
+                Err(err) if err.kind() == ErrorKind::OutOfMemory => {
+                    ready!(self.socket.poll_write_ready(cx));
+                }
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    /// Runs `send`. If the send buffer is full and sending is blocking,
+    /// waits until it has room for `len` bytes, at most until `deadline`, and tries again.
+    async fn send_when_ready<T>(
+        &self,
+        len: usize,
+        deadline: Option<Instant>,
+        send: impl Fn() -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            match send() {
+                Err(err) if err.kind() == ErrorKind::OutOfMemory && self.socket.snd_syn() => {
+                    let room = std::future::poll_fn(|cx| self.socket.poll_snd_room(cx, len));
+                    match deadline {
+                        Some(deadline) => {
+                            if timeout_at(deadline, room).await.is_err() {
+                                return Err(err);
+                            }
+                        }
+                        None => room.await,
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    pub(crate) fn poll_flush_priv(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.socket.poll_snd_buffer_empty(cx)
+    }
+
+    /// Shuts down the sending side when the owned write half is dropped. Connections
+    /// which can not half-close stay open until the read half is dropped too.
+    pub(crate) fn shutdown_snd_on_drop(&self) {
+        if !self.shutdown_started.swap(true, Ordering::Relaxed) {
+            self.socket.shutdown_snd();
+        }
+    }
+
+    /// Shuts down the sending side of a stream connection once the data sent is
+    /// acknowledged, the peer can still send. Other connections, and those to UDT4
+    /// peers which do not implement it, are closed.
+    pub(crate) fn poll_shutdown_priv(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if !self.shutdown_started.swap(true, Ordering::Relaxed) && !self.socket.shutdown_snd() {
+            let socket = self.socket.clone();
+            tokio::spawn(async move { Udt::close_socket(&socket).await });
+        }
+        if self.socket.is_snd_shutdown() {
+            return self.poll_flush_priv(cx);
+        }
+        self.socket.poll_closed(cx).map(Ok)
+    }
 }
 
 impl AsyncRead for UdtConnection {
@@ -102,65 +911,391 @@ impl AsyncRead for UdtConnection {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<Result<()>> {
-        match self.socket.poll_recv(buf) {
-            Poll::Ready(res) => Poll::Ready(res.map(|_| ())),
-            Poll::Pending => {
-                let waker = cx.waker().clone();
-                let socket = self.socket.clone();
-                tokio::spawn(async move {
-                    socket.wait_for_data_to_read().await;
-                    waker.wake();
-                });
-                Poll::Pending
-            }
-        }
+        self.poll_read_priv(cx, buf)
     }
 }
 
 impl AsyncWrite for UdtConnection {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
-        let buf_len = buf.len();
-        match self.socket.send(buf) {
-            Ok(_) => Poll::Ready(Ok(buf_len)),
-            Err(err) => match err.kind() {
-                ErrorKind::OutOfMemory => {
-                    let waker = cx.waker().clone();
-                    let socket = self.socket.clone();
-                    tokio::spawn(async move {
-                        socket.wait_for_next_ack_or_empty_snd_buffer().await;
-                        waker.wake();
-                    });
-                    Poll::Pending
-                }
-                _ => Poll::Ready(Err(err)),
-            },
-        }
+        self.poll_write_priv(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        self.poll_write_vectored_priv(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        if self.socket.snd_buffer_is_empty() {
-            Poll::Ready(Ok(()))
-        } else {
-            let waker = cx.waker().clone();
-            let socket = self.socket.clone();
-            tokio::spawn(async move {
-                socket.wait_for_next_ack_or_empty_snd_buffer().await;
-                waker.wake();
-            });
-            Poll::Pending
-        }
+        self.poll_flush_priv(cx)
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        if self.socket.status() == UdtStatus::Closed {
-            return Poll::Ready(Ok(()));
-        }
-        let socket = self.socket.clone();
-        let waker = cx.waker().clone();
-        tokio::spawn(async move {
-            socket.close().await;
-            waker.wake();
-        });
-        Poll::Pending
+        self.poll_shutdown_priv(cx)
+    }
+}
+
+/// Tags an I/O error of a file transfer with the matching UDT error code.
+fn file_error(err: Error, code: UdtErrorCode) -> Error {
+    Error::new(err.kind(), UdtError::new(code, err.to_string()))
+}
+
+/// Yields the messages received on the connection, as with `recv_msg`.
+/// The stream ends once the connection is closed or broken.
+impl Stream for UdtConnection {
+    type Item = Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.socket.poll_recv_msg_bytes(cx)
+    }
+}
+
+/// Sends each item as an in-order message of a datagram connection, as with `send_msg`.
+impl Sink<Bytes> for UdtConnection {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while let Some(item) = self.sink_item.take() {
+            match self.socket.send_msg_bytes(item.clone()) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::OutOfMemory => {
+                    let len = item.len();
+                    self.sink_item = Some(item);
+                    ready!(self.socket.poll_snd_room(cx, len));
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> Result<()> {
+        self.sink_item = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_ready(cx))?;
+        self.poll_flush_priv(cx)
     }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        ready!(self.as_mut().poll_ready(cx))?;
+        self.poll_shutdown_priv(cx)
+    }
+}
+
+#[cfg(test)]
+fn unused_local_addr() -> SocketAddr {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn test_rendezvous() {
+    let (a, b) = (unused_local_addr(), unused_local_addr());
+    let (client, peer) = tokio::join!(
+        UdtConnection::rendezvous(a, b, None),
+        UdtConnection::rendezvous(b, a, None)
+    );
+    let (mut client, mut peer) = (client.unwrap(), peer.unwrap());
+    client.write_all(b"punched").await.unwrap();
+    let mut buf = [0; 7];
+    peer.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"punched");
+    peer.write_all(b"back").await.unwrap();
+    client.read_exact(&mut buf[..4]).await.unwrap();
+    assert_eq!(&buf[..4], b"back");
+}
+
+#[tokio::test]
+async fn test_rendezvous_race() {
+    let config = UdtConfiguration {
+        connect_timeout: Some(Duration::from_secs(1)),
+        ..Default::default()
+    };
+    // The first pair leads nowhere: both sides settle on the second one
+    let (a1, a2, b1, b2) = (
+        unused_local_addr(),
+        unused_local_addr(),
+        unused_local_addr(),
+        unused_local_addr(),
+    );
+    let (unreachable_a, unreachable_b) = (unused_local_addr(), unused_local_addr());
+    let client_candidates = [(a1, unreachable_b), (a2, b2)];
+    let peer_candidates = [(b1, unreachable_a), (b2, a2)];
+    let (client, peer) = tokio::join!(
+        UdtConnection::rendezvous_race(&client_candidates, Some(config.clone())),
+        UdtConnection::rendezvous_race(&peer_candidates, Some(config))
+    );
+    let (mut client, mut peer) = (client.unwrap(), peer.unwrap());
+    assert_eq!(client.peer_addr().unwrap(), b2);
+    assert_eq!(peer.peer_addr().unwrap(), a2);
+    client.write_all(b"raced").await.unwrap();
+    let mut buf = [0; 5];
+    peer.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"raced");
+}
+
+/// Counts the wake-ups of a task.
+#[cfg(test)]
+#[derive(Default)]
+struct WakeCounter(std::sync::atomic::AtomicUsize);
+
+#[cfg(test)]
+impl std::task::Wake for WakeCounter {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Polls `poll` until it is pending with a full send buffer, then checks that the task
+/// is not woken up again while the peer does not read, i.e. that it does not spin.
+#[cfg(test)]
+async fn assert_parked(mut poll: impl FnMut(&mut Context<'_>) -> Poll<()>) {
+    let wakes = Arc::new(WakeCounter::default());
+    let waker = std::task::Waker::from(wakes.clone());
+    let mut cx = Context::from_waker(&waker);
+    for _ in 0..200 {
+        wakes.0.store(0, Ordering::Relaxed);
+        if poll(&mut cx).is_ready() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            continue;
+        }
+        // Woken up only when the peer acknowledges data, which stops once it is full
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if wakes.0.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+    }
+    panic!("woken up repeatedly while the send buffer is full");
+}
+
+#[tokio::test]
+async fn test_write_waits_for_room() {
+    let config = UdtConfiguration {
+        snd_buf_size: 16,
+        rcv_buf_size: 16,
+        ..Default::default()
+    };
+    let (mut client, mut server) = UdtConnection::pair(Some(config)).await.unwrap();
+    let buf = vec![0; 1 << 16];
+    let mut written = 0;
+    assert_parked(|cx| {
+        Pin::new(&mut client).poll_write(cx, &buf).map(|res| {
+            written += res.unwrap();
+        })
+    })
+    .await;
+
+    // Reading wakes the writer up
+    let len = written + buf.len();
+    let reader = tokio::spawn(async move {
+        let mut received = vec![0; len];
+        server.read_exact(&mut received).await.map(|_| ())
+    });
+    client.write_all(&buf).await.unwrap();
+    timeout(Duration::from_secs(5), reader)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_sink_waits_for_room() {
+    let config = UdtConfiguration {
+        snd_buf_size: 16,
+        rcv_buf_size: 16,
+        ..Default::default()
+    };
+    let listener = UdtListener::bind_datagram("127.0.0.1:0".parse().unwrap(), Some(config.clone()))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, server) = tokio::join!(
+        UdtConnection::connect_datagram(addr, Some(config)),
+        listener.accept()
+    );
+    let mut client = client.unwrap();
+    let (_, server) = server.unwrap();
+    let msg = Bytes::from(vec![0; 4096]);
+    let mut sent = 0;
+    assert_parked(|cx| {
+        let mut sink = Pin::new(&mut client);
+        sink.as_mut().poll_ready(cx).map(|res| {
+            res.unwrap();
+            sink.start_send(msg.clone()).unwrap();
+            sent += 1;
+        })
+    })
+    .await;
+
+    // Receiving wakes the sink up, which then buffers the last message
+    let ready = std::future::poll_fn(|cx| Pin::new(&mut client).poll_ready(cx));
+    let receive = async {
+        let mut buf = vec![0; msg.len()];
+        for _ in 0..sent {
+            assert_eq!(server.recv_msg(&mut buf).await.unwrap(), msg.len());
+        }
+    };
+    let (ready, ()) = timeout(Duration::from_secs(5), async {
+        tokio::join!(ready, receive)
+    })
+    .await
+    .unwrap();
+    ready.unwrap();
+}
+
+#[tokio::test]
+async fn test_file_transfer() {
+    let dir = std::env::temp_dir();
+    let src_path = dir.join(format!("udt-send-file-{}", std::process::id()));
+    let dst_path = dir.join(format!("udt-recv-file-{}", std::process::id()));
+    // Not a multiple of the chunk size nor of the packet size
+    let data: Vec<u8> = (0..FILE_CHUNK_SIZE * 2 + 1234)
+        .map(|idx| idx as u8)
+        .collect();
+    tokio::fs::write(&src_path, &data).await.unwrap();
+
+    let (mut client, mut server) = UdtConnection::pair(None).await.unwrap();
+    let mut src = File::open(&src_path).await.unwrap();
+    let mut dst = File::create(&dst_path).await.unwrap();
+    let len = data.len() as u64;
+    let send = async {
+        client.send_file(&mut src, 0, len, |_| ()).await.unwrap();
+        // Data following the file, left to the next reads
+        client.write_all(b"trailer").await.unwrap();
+    };
+    let mut chunks = 0;
+    let recv = server.recv_file(&mut dst, 0, len, |_| chunks += 1);
+    let ((), received) = tokio::join!(send, recv);
+    assert_eq!(received.unwrap(), len);
+    assert!(chunks >= 3);
+    assert!(tokio::fs::read(&dst_path).await.unwrap() == data);
+
+    // Reads smaller than a packet get its data in parts
+    let mut buf = [0; 3];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"tra");
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"iler");
+    let _ = std::fs::remove_file(src_path);
+    let _ = std::fs::remove_file(dst_path);
+}
+
+#[tokio::test]
+async fn test_datagram_from_udp_socket() {
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let listener = UdtListener::datagram_from_udp_socket(udp_socket, None)
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let udp_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (client, accepted) = tokio::join!(
+        UdtConnection::connect_datagram_from_udp_socket(udp_socket, addr, None),
+        listener.accept()
+    );
+    let client = client.unwrap();
+    let (_, server) = accepted.unwrap();
+    client.send_msg(b"message", None, true).await.unwrap();
+    let mut buf = [0; 16];
+    let len = timeout(Duration::from_secs(5), server.recv_msg(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"message");
+}
+
+#[tokio::test]
+async fn test_resume() {
+    let config = UdtConfiguration {
+        resumption_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    };
+    let listener = UdtListener::bind("127.0.0.1:0".parse().unwrap(), Some(config.clone()))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, accepted) = tokio::join!(
+        UdtConnection::connect(addr, Some(config)),
+        listener.accept()
+    );
+    let mut client = client.unwrap();
+    let (_, mut server) = accepted.unwrap();
+
+    let data: Vec<u8> = (0..400_000).map(|idx| (idx % 251) as u8).collect();
+    let (first, rest) = data.split_at(250_000);
+    client.write_all(first).await.unwrap();
+    // Broken with data still in flight
+    client.socket().break_with(Error::new(
+        ErrorKind::ConnectionReset,
+        UdtError::new(UdtErrorCode::ConnLost, "link down"),
+    ));
+    assert!(client.write_all(rest).await.is_err());
+    timeout(Duration::from_secs(5), client.resume())
+        .await
+        .unwrap()
+        .unwrap();
+    client.write_all(rest).await.unwrap();
+
+    let mut received = vec![0; data.len()];
+    timeout(Duration::from_secs(10), server.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(received == data);
+
+    // Without resumption, a broken connection stays broken
+    let (client, _server) = UdtConnection::pair(None).await.unwrap();
+    client.socket().break_with(Error::new(
+        ErrorKind::ConnectionReset,
+        UdtError::new(UdtErrorCode::ConnLost, "link down"),
+    ));
+    assert!(client.resume().await.is_err());
+}
+
+#[tokio::test]
+async fn test_bind_and_connect_shares_listener_port() {
+    let node = UdtListener::bind("127.0.0.1:0".parse().unwrap(), None)
+        .await
+        .unwrap();
+    let remote = UdtListener::bind("127.0.0.1:0".parse().unwrap(), None)
+        .await
+        .unwrap();
+    let node_addr = node.local_addr().unwrap();
+    let (outgoing, accepted) = tokio::join!(
+        UdtConnection::bind_and_connect(node_addr, remote.local_addr().unwrap(), None),
+        remote.accept()
+    );
+    let (mut outgoing, (dialed_from, mut remote_side)) = (outgoing.unwrap(), accepted.unwrap());
+    assert_eq!(dialed_from, node_addr);
+    assert_eq!(outgoing.local_addr().unwrap(), node_addr);
+    outgoing.write_all(b"shared").await.unwrap();
+    let mut buf = [0; 6];
+    remote_side.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"shared");
+
+    // Without reuse_mux, the port of the listener is not shared
+    let config = UdtConfiguration {
+        reuse_mux: false,
+        ..Default::default()
+    };
+    let private = UdtListener::bind("127.0.0.1:0".parse().unwrap(), Some(config))
+        .await
+        .unwrap();
+    let connection = UdtConnection::bind_and_connect(
+        private.local_addr().unwrap(),
+        remote.local_addr().unwrap(),
+        None,
+    )
+    .await;
+    assert_eq!(connection.err().unwrap().kind(), ErrorKind::AddrInUse);
 }
@@ -1,7 +1,14 @@
 use super::socket::{SocketId, SocketType};
-use crate::common::ip_to_bytes;
+use crate::common::{bytes_to_ip, ip_to_bytes};
+use crate::configuration::UDT4_VERSION;
+use crate::handshake_extension::{
+    self, ENCRYPTION_PROOF_EXTENSION, ENCRYPTION_SALT_EXTENSION, LEGACY_PROOF_SIZE,
+    RESUMPTION_EXTENSION, UDT_USER_EXTENSION_MIN,
+};
 use crate::seq_number::{AckSeqNumber, MsgNumber, SeqNumber};
+use bytes::{BufMut, Bytes};
 use std::net::IpAddr;
+use std::ops::RangeInclusive;
 use tokio::io::{Error, ErrorKind, Result};
 
 #[derive(Debug)]
@@ -25,11 +32,9 @@ impl UdtControlPacket {
         }
     }
 
-    pub fn new_nak(loss_list: Vec<u32>, dest_socket_id: SocketId) -> Self {
+    pub fn new_nak(losses: RangeInclusive<SeqNumber>, dest_socket_id: SocketId) -> Self {
         Self {
-            packet_type: ControlPacketType::Nak(NakInfo {
-                loss_info: loss_list,
-            }),
+            packet_type: ControlPacketType::Nak(NakInfo::Range(losses)),
             reserved: 0,
             additional_info: 0,
             timestamp: 0,
@@ -65,6 +70,16 @@ impl UdtControlPacket {
         }
     }
 
+    pub fn new_user_defined(reserved: u16, payload: Vec<u8>, dest_socket_id: SocketId) -> Self {
+        Self {
+            packet_type: ControlPacketType::UserDefined(payload),
+            dest_socket_id,
+            additional_info: 0,
+            reserved,
+            timestamp: 0,
+        }
+    }
+
     pub fn new_keep_alive(dest_socket_id: SocketId) -> Self {
         Self {
             packet_type: ControlPacketType::KeepAlive,
@@ -95,6 +110,7 @@ impl UdtControlPacket {
             packet_type: ControlPacketType::Ack(AckInfo {
                 next_seq_number,
                 info,
+                padding: 0,
             }),
             dest_socket_id,
             additional_info: ack_number.number(),
@@ -103,9 +119,19 @@ impl UdtControlPacket {
         }
     }
 
+    /// Pads an ACK with `len` zero bytes, making it a probe of path MTU discovery.
+    pub fn with_padding(mut self, len: usize) -> Self {
+        if let ControlPacketType::Ack(ack) = &mut self.packet_type {
+            ack.padding = len;
+        }
+        self
+    }
+
     pub fn ack_seq_number(&self) -> Option<AckSeqNumber> {
         match self.packet_type {
-            ControlPacketType::Ack(_) | ControlPacketType::Ack2 => Some(self.additional_info.into()),
+            ControlPacketType::Ack(_) | ControlPacketType::Ack2 => {
+                Some(self.additional_info.into())
+            }
             _ => None,
         }
     }
@@ -119,18 +145,17 @@ impl UdtControlPacket {
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer: Vec<u8> = Vec::with_capacity(8);
-        buffer.extend_from_slice(&(0x8000 + self.packet_type.type_as_u15()).to_be_bytes());
-        buffer.extend_from_slice(&self.reserved.to_be_bytes());
-        buffer.extend_from_slice(&self.additional_info.to_be_bytes());
-        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
-        buffer.extend_from_slice(&self.dest_socket_id.to_be_bytes());
-        buffer.extend_from_slice(&self.packet_type.control_info_field());
-        buffer
+    /// Appends the serialized packet to `buffer`.
+    pub fn serialize_into(&self, buffer: &mut impl BufMut) {
+        buffer.put_u16(0x8000 + self.packet_type.type_as_u15());
+        buffer.put_u16(self.reserved);
+        buffer.put_u32(self.additional_info);
+        buffer.put_u32(self.timestamp);
+        buffer.put_u32(self.dest_socket_id);
+        self.packet_type.serialize_control_info_into(buffer);
     }
 
-    pub fn deserialize(raw: &[u8]) -> Result<Self> {
+    pub fn deserialize(raw: &Bytes) -> Result<Self> {
         if raw.len() < 16 {
             return Err(Error::new(
                 ErrorKind::InvalidData,
@@ -162,7 +187,7 @@ pub(crate) enum ControlPacketType {
     Shutdown,
     Ack2,
     MsgDropRequest(DropRequestInfo),
-    UserDefined,
+    UserDefined(Vec<u8>),
 }
 
 impl ControlPacketType {
@@ -175,33 +200,33 @@ impl ControlPacketType {
             Self::Shutdown => 0x0005,
             Self::Ack2 => 0x0006,
             Self::MsgDropRequest(_) => 0x0007,
-            Self::UserDefined => 0x7fff,
+            Self::UserDefined(_) => 0x7fff,
         }
     }
 
-    pub fn control_info_field(&self) -> Vec<u8> {
+    /// Appends the Control Information Field to `buffer`.
+    pub fn serialize_control_info_into(&self, buffer: &mut impl BufMut) {
         match self {
-            Self::Handshake(hs) => hs.serialize(),
-            Self::Ack(ack) => ack.serialize(),
-            Self::Nak(nak) => nak.serialize(),
-            Self::MsgDropRequest(drop) => drop.serialize(),
-            _ => vec![],
+            Self::Handshake(hs) => hs.serialize_into(buffer),
+            Self::Ack(ack) => ack.serialize_into(buffer),
+            Self::Nak(nak) => nak.serialize_into(buffer),
+            Self::MsgDropRequest(drop) => drop.serialize_into(buffer),
+            Self::UserDefined(payload) => buffer.put_slice(payload),
+            _ => {}
         }
     }
 
-    pub fn deserialize(raw_control_packet: &[u8]) -> Result<Self> {
+    pub fn deserialize(raw_control_packet: &Bytes) -> Result<Self> {
         let type_id = u16::from_be_bytes(raw_control_packet[0..2].try_into().unwrap()) & 0x7FFF;
         let packet = match type_id {
             0x0000 => Self::Handshake(HandShakeInfo::deserialize(&raw_control_packet[16..])?),
             0x0001 => Self::KeepAlive,
             0x0002 => Self::Ack(AckInfo::deserialize(&raw_control_packet[16..])),
-            0x0003 => Self::Nak(NakInfo::deserialize(&raw_control_packet[16..])),
+            0x0003 => Self::Nak(NakInfo::deserialize(raw_control_packet.slice(16..))?),
             0x0005 => Self::Shutdown,
             0x0006 => Self::Ack2,
-            0x0007 => {
-                Self::MsgDropRequest(DropRequestInfo::deserialize(&raw_control_packet[16..]))
-            }
-            0x7fff => Self::UserDefined,
+            0x0007 => Self::MsgDropRequest(DropRequestInfo::deserialize(&raw_control_packet[16..])),
+            0x7fff => Self::UserDefined(raw_control_packet[16..].to_vec()),
             _ => {
                 return Err(Error::new(
                     ErrorKind::InvalidData,
@@ -213,6 +238,41 @@ impl ControlPacketType {
     }
 }
 
+/// Reason of the rejection of a connection request, sent as the connection type of the
+/// handshake. The C++ implementation only uses 1002, extended to 1000 + reason as in SRT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HandshakeRejection {
+    Peer = 1002,
+    Busy = 1003,
+    Backlog = 1005,
+}
+
+impl HandshakeRejection {
+    pub fn from_connection_type(connection_type: i32) -> Option<Self> {
+        match connection_type {
+            1003 => Some(Self::Busy),
+            1005 => Some(Self::Backlog),
+            ..=1000 => None,
+            _ => Some(Self::Peer),
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::Peer => "connection rejected by the peer",
+            Self::Busy => "server is busy: too many connections",
+            Self::Backlog => "accept backlog of the listener is full",
+        }
+    }
+}
+
+/// Side of the connection sending a handshake, which proves the encryption key differently
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum HandshakeRole {
+    Initiator,
+    Responder,
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct HandShakeInfo {
     pub udt_version: u32,
@@ -224,40 +284,79 @@ pub(crate) struct HandShakeInfo {
     pub socket_id: SocketId,
     pub syn_cookie: u32,
     pub ip_address: IpAddr,
+    /// Extension to the UDT4 handshake, appended when the connection is encrypted
+    pub encryption_proof: Option<[u8; 16]>,
+    /// Random value of the sender mixed into the session key, sent with the proof
+    pub encryption_salt: Option<[u8; 16]>,
+    /// Application extensions (type and value)
+    pub extensions: Vec<(u16, Bytes)>,
 }
 
 impl HandShakeInfo {
-    pub fn serialize(&self) -> Vec<u8> {
-        [
-            self.udt_version,
-            self.socket_type as u32,
-            self.initial_seq_number.number(),
-            self.max_packet_size,
-            self.max_window_size,
-        ]
-        .iter()
-        .flat_map(|v| v.to_be_bytes())
-        .chain(self.connection_type.to_be_bytes().into_iter())
-        .chain(self.socket_id.to_be_bytes().into_iter())
-        .chain(self.syn_cookie.to_be_bytes().into_iter())
-        .chain(ip_to_bytes(self.ip_address))
-        .collect()
+    pub fn serialize_into(&self, buffer: &mut impl BufMut) {
+        buffer.put_u32(self.udt_version);
+        buffer.put_u32(self.socket_type as u32);
+        buffer.put_u32(self.initial_seq_number.number());
+        buffer.put_u32(self.max_packet_size);
+        buffer.put_u32(self.max_window_size);
+        buffer.put_i32(self.connection_type);
+        buffer.put_u32(self.socket_id);
+        buffer.put_u32(self.syn_cookie);
+        buffer.put_slice(&ip_to_bytes(self.ip_address));
+        // UDT4 peers do not expect anything after the handshake information
+        if self.udt_version <= UDT4_VERSION {
+            return;
+        }
+        if let Some(proof) = self.encryption_proof {
+            handshake_extension::serialize_block(buffer, ENCRYPTION_PROOF_EXTENSION, &proof);
+        }
+        if let Some(salt) = self.encryption_salt {
+            handshake_extension::serialize_block(buffer, ENCRYPTION_SALT_EXTENSION, &salt);
+        }
+        for (ext_type, value) in &self.extensions {
+            handshake_extension::serialize_block(buffer, *ext_type, value);
+        }
     }
 
     pub fn deserialize(raw: &[u8]) -> Result<Self> {
+        if raw.len() < 48 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "handshake information is too short",
+            ));
+        }
         let get_u32 =
             |idx: usize| u32::from_be_bytes(raw[(idx * 4)..(idx + 1) * 4].try_into().unwrap());
-        let addr: IpAddr = {
-            if raw[36..48].iter().all(|b| *b == 0) {
-                // IPv4
-                let octets: [u8; 4] = raw[32..36].try_into().unwrap();
-                octets.into()
-            } else {
-                // IPv6
-                let octets: [u8; 16] = raw[32..48].try_into().unwrap();
-                octets.into()
-            }
+        let addr = bytes_to_ip(raw[32..48].try_into().unwrap());
+        let mut encryption_proof = None;
+        let mut encryption_salt = None;
+        // Peers predating the extension blocks append the 16 bytes of the proof as is.
+        // It can only be confused with a single application block of 12 bytes.
+        let legacy_proof = raw.len() == 48 + LEGACY_PROOF_SIZE
+            && !matches!(
+                handshake_extension::deserialize_blocks(&raw[48..]).as_deref(),
+                Ok([(ext_type, _)]) if *ext_type >= UDT_USER_EXTENSION_MIN
+            );
+        if legacy_proof {
+            encryption_proof = raw[48..].try_into().ok();
+        }
+        let mut extensions = if legacy_proof {
+            vec![]
+        } else {
+            handshake_extension::deserialize_blocks(&raw[48..])?
         };
+        extensions.retain(|(ext_type, value)| match *ext_type {
+            ENCRYPTION_PROOF_EXTENSION => {
+                encryption_proof = value[..].try_into().ok();
+                false
+            }
+            ENCRYPTION_SALT_EXTENSION => {
+                encryption_salt = value[..].try_into().ok();
+                false
+            }
+            RESUMPTION_EXTENSION => true,
+            ext_type => ext_type >= UDT_USER_EXTENSION_MIN,
+        });
 
         Ok(Self {
             udt_version: get_u32(0),
@@ -269,16 +368,26 @@ impl HandShakeInfo {
             socket_id: get_u32(6),
             syn_cookie: get_u32(7),
             ip_address: addr,
+            encryption_proof,
+            encryption_salt,
+            extensions,
         })
     }
 }
 
+pub(crate) const UDT_CONTROL_HEADER_SIZE: usize = 16;
+
+/// Size of the control information of a full ACK
+pub(crate) const ACK_INFO_SIZE: usize = 24;
+
 #[derive(Debug)]
 pub(crate) struct AckInfo {
     /// The packet sequence number to which all the
     /// previous packets have been received (excluding)
     pub next_seq_number: SeqNumber,
     pub info: Option<AckOptionalInfo>,
+    /// Zero bytes following the optional information, in path MTU probes
+    pub padding: usize,
 }
 
 impl AckInfo {
@@ -292,6 +401,7 @@ impl AckInfo {
             return Self {
                 next_seq_number,
                 info: None,
+                padding: 0,
             };
         }
         let info = AckOptionalInfo {
@@ -304,24 +414,20 @@ impl AckInfo {
         Self {
             next_seq_number,
             info: Some(info),
+            padding: raw.len().saturating_sub(ACK_INFO_SIZE),
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        match &self.info {
-            None => self.next_seq_number.number().to_be_bytes().to_vec(),
-            Some(extra) => [
-                self.next_seq_number.number(),
-                extra.rtt,
-                extra.rtt_variance,
-                extra.available_buf_size,
-                extra.pack_recv_rate,
-                extra.link_capacity,
-            ]
-            .iter()
-            .flat_map(|v| v.to_be_bytes())
-            .collect(),
+    pub fn serialize_into(&self, buffer: &mut impl BufMut) {
+        buffer.put_u32(self.next_seq_number.number());
+        if let Some(extra) = &self.info {
+            buffer.put_u32(extra.rtt);
+            buffer.put_u32(extra.rtt_variance);
+            buffer.put_u32(extra.available_buf_size);
+            buffer.put_u32(extra.pack_recv_rate);
+            buffer.put_u32(extra.link_capacity);
         }
+        buffer.put_bytes(0, self.padding);
     }
 }
 
@@ -335,30 +441,97 @@ pub(crate) struct AckOptionalInfo {
     pub link_capacity: u32,
 }
 
+/// Flag of a loss entry starting a range, followed by the last sequence number of the range
+const NAK_RANGE_FLAG: u32 = 0x8000_0000;
+
 #[derive(Debug)]
-pub(crate) struct NakInfo {
-    pub loss_info: Vec<u32>,
+pub(crate) enum NakInfo {
+    /// Lost sequence numbers, reported when a loss is detected: the range may wrap around
+    /// the maximum sequence number
+    Range(RangeInclusive<SeqNumber>),
+    /// Loss entries of a received NAK, referencing the packet without copy: a lost
+    /// sequence number, or the first one of a range with `NAK_RANGE_FLAG` followed by the last
+    Entries(Bytes),
 }
 
 impl NakInfo {
-    pub fn deserialize(raw: &[u8]) -> Self {
-        let losses: Vec<u32> = raw
-            .chunks(4)
-            .filter_map(|chunk| {
-                if chunk.len() < 4 {
-                    return None;
+    pub fn deserialize(raw: Bytes) -> Result<Self> {
+        let raw = raw.slice(..raw.len() - raw.len() % 4);
+        let mut entries = raw
+            .chunks_exact(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()));
+        while let Some(entry) = entries.next() {
+            if entry & NAK_RANGE_FLAG == 0 {
+                continue;
+            }
+            match entries.next() {
+                Some(end) if end & NAK_RANGE_FLAG == 0 => (),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "NAK range without last sequence number",
+                    ));
                 }
-                Some(u32::from_be_bytes(chunk.try_into().unwrap()))
-            })
-            .collect();
-        Self { loss_info: losses }
+            }
+        }
+        Ok(Self::Entries(raw))
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        self.loss_info
-            .iter()
-            .flat_map(|x| x.to_be_bytes())
-            .collect()
+    /// Ranges of lost sequence numbers: a range may wrap around the maximum sequence number.
+    pub fn losses(&self) -> NakLosses<'_> {
+        match self {
+            Self::Range(range) => NakLosses {
+                range: Some(range.clone()),
+                entries: &[],
+            },
+            Self::Entries(entries) => NakLosses {
+                range: None,
+                entries,
+            },
+        }
+    }
+
+    pub fn serialize_into(&self, buffer: &mut impl BufMut) {
+        match self {
+            Self::Range(range) if range.start() == range.end() => {
+                buffer.put_u32(range.start().number());
+            }
+            Self::Range(range) => {
+                buffer.put_u32(range.start().number() | NAK_RANGE_FLAG);
+                buffer.put_u32(range.end().number());
+            }
+            Self::Entries(entries) => buffer.put_slice(entries),
+        }
+    }
+}
+
+/// Iterator over the losses of a NAK, see `NakInfo::losses`
+pub(crate) struct NakLosses<'a> {
+    range: Option<RangeInclusive<SeqNumber>>,
+    entries: &'a [u8], // checked by `NakInfo::deserialize`
+}
+
+impl NakLosses<'_> {
+    fn next_entry(&mut self) -> Option<u32> {
+        let (entry, rest) = self.entries.split_first_chunk::<4>()?;
+        self.entries = rest;
+        Some(u32::from_be_bytes(*entry))
+    }
+}
+
+impl Iterator for NakLosses<'_> {
+    type Item = RangeInclusive<SeqNumber>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(range) = self.range.take() {
+            return Some(range);
+        }
+        let entry = self.next_entry()?;
+        let start: SeqNumber = (entry & !NAK_RANGE_FLAG).into();
+        if entry & NAK_RANGE_FLAG == 0 {
+            return Some(start..=start);
+        }
+        Some(start..=self.next_entry()?.into())
     }
 }
 
@@ -379,13 +552,121 @@ impl DropRequestInfo {
         }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        [
-            self.first_seq_number.number(),
-            self.last_seq_number.number(),
-        ]
-        .iter()
-        .flat_map(|x| x.to_be_bytes())
-        .collect()
+    pub fn serialize_into(&self, buffer: &mut impl BufMut) {
+        buffer.put_u32(self.first_seq_number.number());
+        buffer.put_u32(self.last_seq_number.number());
     }
 }
+
+#[test]
+fn test_nak_range_compression() {
+    let losses: Vec<RangeInclusive<SeqNumber>> = vec![
+        10.into()..=12.into(),
+        20.into()..=20.into(),
+        SeqNumber::max()..=1.into(),
+    ];
+    let mut raw = vec![];
+    for range in &losses {
+        NakInfo::Range(range.clone()).serialize_into(&mut raw);
+    }
+    assert_eq!(raw.len(), 5 * 4);
+    let raw = Bytes::from(raw);
+    let nak = NakInfo::deserialize(raw.clone()).unwrap();
+    assert_eq!(nak.losses().collect::<Vec<_>>(), losses);
+    let mut serialized = vec![];
+    nak.serialize_into(&mut serialized);
+    assert_eq!(serialized, raw);
+
+    // A range start must be followed by the end of the range
+    assert!(NakInfo::deserialize(raw.slice(..4)).is_err());
+}
+
+#[test]
+fn test_handshake_ipv4_mapped_address() {
+    let hs = HandShakeInfo {
+        udt_version: 4,
+        socket_type: SocketType::Stream,
+        initial_seq_number: 1.into(),
+        max_packet_size: 1500,
+        max_window_size: 8192,
+        connection_type: 1,
+        socket_id: 1,
+        syn_cookie: 0,
+        ip_address: "::ffff:10.0.0.1".parse().unwrap(),
+        encryption_proof: None,
+        encryption_salt: None,
+        extensions: vec![],
+    };
+    let mut raw = vec![];
+    hs.serialize_into(&mut raw);
+    // Encoded as an IPv4 address, as UDT4 does
+    assert_eq!(
+        raw[32..48],
+        [10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+    );
+
+    raw[32..48].copy_from_slice(
+        &"::ffff:10.0.0.1"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets(),
+    );
+    let ip = HandShakeInfo::deserialize(&raw).unwrap().ip_address;
+    assert_eq!(ip, IpAddr::from([10, 0, 0, 1]));
+}
+
+#[test]
+fn test_handshake_extensions() {
+    let mut hs = HandShakeInfo {
+        udt_version: 5,
+        socket_type: SocketType::Stream,
+        initial_seq_number: 1.into(),
+        max_packet_size: 1500,
+        max_window_size: 8192,
+        connection_type: -1,
+        socket_id: 1,
+        syn_cookie: 0,
+        ip_address: "10.0.0.1".parse().unwrap(),
+        encryption_proof: Some([7; 16]),
+        encryption_salt: Some([9; 16]),
+        extensions: vec![(0x8001, Bytes::from_static(b"token"))],
+    };
+    let mut raw = vec![];
+    hs.serialize_into(&mut raw);
+    assert_eq!(raw.len(), 48 + 2 * (4 + 16) + (4 + 5));
+    let parsed = HandShakeInfo::deserialize(&raw).unwrap();
+    assert_eq!(parsed.encryption_proof, hs.encryption_proof);
+    assert_eq!(parsed.encryption_salt, hs.encryption_salt);
+    assert_eq!(parsed.extensions, hs.extensions);
+    assert!(HandShakeInfo::deserialize(&raw[..raw.len() - 1]).is_err());
+
+    // Nothing is appended for UDT4 peers
+    hs.udt_version = 4;
+    raw.clear();
+    hs.serialize_into(&mut raw);
+    assert_eq!(raw.len(), 48);
+}
+
+#[test]
+fn test_handshake_legacy_proof() {
+    let hs = HandShakeInfo {
+        udt_version: 4,
+        socket_type: SocketType::Stream,
+        initial_seq_number: 0.into(),
+        max_packet_size: 1500,
+        max_window_size: 8192,
+        connection_type: 1,
+        socket_id: 7,
+        syn_cookie: 0,
+        ip_address: IpAddr::from([10, 0, 0, 1]),
+        encryption_proof: None,
+        encryption_salt: None,
+        extensions: vec![],
+    };
+    let mut raw = vec![];
+    hs.serialize_into(&mut raw);
+    raw.extend_from_slice(&[0xab; 16]);
+    let parsed = HandShakeInfo::deserialize(&raw).unwrap();
+    assert_eq!(parsed.encryption_proof, Some([0xab; 16]));
+    assert!(parsed.extensions.is_empty());
+}
@@ -0,0 +1,331 @@
+use crate::control_packet::{HandShakeInfo, HandshakeRole};
+use crate::packet::UdtPacket;
+use crate::socket::SocketId;
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::AeadInPlace;
+use aes_gcm::{Aes256Gcm, KeyInit};
+use bytes::{BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Size of the packet header, authenticated but not encrypted
+const HEADER_SIZE: usize = 16;
+const COUNTER_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+/// Bytes added to each packet by encryption: the nonce counter and the authentication tag
+pub(crate) const SEAL_OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE;
+
+/// Pre-shared AES-256 key, see `UdtConfiguration::encryption_key`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct UdtEncryptionKey([u8; 32]);
+
+impl From<[u8; 32]> for UdtEncryptionKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+impl std::fmt::Debug for UdtEncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UdtEncryptionKey(..)")
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn handshake_mac(key: &UdtEncryptionKey, hs: &HandShakeInfo, role: HandshakeRole) -> HmacSha256 {
+    let mut body = Vec::with_capacity(96);
+    HandShakeInfo {
+        encryption_proof: None,
+        ..hs.clone()
+    }
+    .serialize_into(&mut body);
+    let label: &[u8] = match role {
+        HandshakeRole::Initiator => b"udt handshake initiator",
+        HandshakeRole::Responder => b"udt handshake responder",
+    };
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&key.0).expect("HMAC accepts any key size");
+    mac.update(label);
+    mac.update(&body);
+    mac
+}
+
+/// Proves the knowledge of `key` to the peer, bound to the content of the handshake.
+pub(crate) fn handshake_proof(
+    key: &UdtEncryptionKey,
+    hs: &HandShakeInfo,
+    role: HandshakeRole,
+) -> [u8; 16] {
+    let tag = handshake_mac(key, hs, role).finalize().into_bytes();
+    tag[..16].try_into().unwrap()
+}
+
+/// Whether the handshake sent by `role` carries the proof of `key`, compared in constant time.
+pub(crate) fn verify_handshake_proof(
+    key: &UdtEncryptionKey,
+    hs: &HandShakeInfo,
+    role: HandshakeRole,
+) -> bool {
+    match hs.encryption_proof {
+        Some(proof) => handshake_mac(key, hs, role)
+            .verify_truncated_left(&proof)
+            .is_ok(),
+        None => false,
+    }
+}
+
+/// Random value sent by each side in the handshake, so that the key of a connection
+/// is never reused, even if a pair of socket ids is.
+pub(crate) fn new_session_salt() -> [u8; 16] {
+    let mut salt = [0; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+// Counters of the packets received, within this distance of the highest one
+const REPLAY_WINDOW_SIZE: u64 = 1024;
+
+/// Sliding window of the counters received from the peer, rejecting replayed packets.
+#[derive(Debug)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    seen: [u64; (REPLAY_WINDOW_SIZE / 64) as usize], // bit `counter % REPLAY_WINDOW_SIZE`
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            highest: None,
+            seen: [0; (REPLAY_WINDOW_SIZE / 64) as usize],
+        }
+    }
+
+    fn bit(counter: u64) -> (usize, u64) {
+        let index = counter % REPLAY_WINDOW_SIZE;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    /// Whether a packet with `counter` may be accepted: not seen yet, nor too old.
+    fn check(&self, counter: u64) -> bool {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => return true,
+        };
+        if counter > highest {
+            return true;
+        }
+        if highest - counter >= REPLAY_WINDOW_SIZE {
+            return false;
+        }
+        let (word, mask) = Self::bit(counter);
+        self.seen[word] & mask == 0
+    }
+
+    /// Records an authenticated packet. Returns false if it was already seen.
+    fn accept(&mut self, counter: u64) -> bool {
+        if !self.check(counter) {
+            return false;
+        }
+        match self.highest {
+            Some(highest) if counter <= highest => (),
+            Some(highest) if counter - highest < REPLAY_WINDOW_SIZE => {
+                for skipped in highest + 1..counter {
+                    let (word, mask) = Self::bit(skipped);
+                    self.seen[word] &= !mask;
+                }
+                self.highest = Some(counter);
+            }
+            _ => {
+                self.seen = [0; (REPLAY_WINDOW_SIZE / 64) as usize];
+                self.highest = Some(counter);
+            }
+        }
+        let (word, mask) = Self::bit(counter);
+        self.seen[word] |= mask;
+        true
+    }
+}
+
+/// Encrypts and authenticates the packets of a connection.
+/// The packet header is authenticated, the rest is encrypted and followed by a
+/// counter, which makes the nonce unique, and the authentication tag.
+pub(crate) struct PacketCipher {
+    cipher: Aes256Gcm,
+    local_socket_id: SocketId,
+    peer_socket_id: SocketId,
+    counter: AtomicU64,
+    replay_window: Mutex<ReplayWindow>,
+    buffer: Mutex<BytesMut>, // sealed packets are split off it, reusing its allocation
+}
+
+impl PacketCipher {
+    /// Derives the key of the connection from the pre-shared key, the two socket ids
+    /// and the salts sent by both sides in the handshake.
+    pub fn new(
+        key: &UdtEncryptionKey,
+        (local_socket_id, local_salt): (SocketId, [u8; 16]),
+        (peer_socket_id, peer_salt): (SocketId, [u8; 16]),
+    ) -> Self {
+        let ((low, low_salt), (high, high_salt)) = if local_socket_id < peer_socket_id {
+            ((local_socket_id, local_salt), (peer_socket_id, peer_salt))
+        } else {
+            ((peer_socket_id, peer_salt), (local_socket_id, local_salt))
+        };
+        let mut kdf =
+            <HmacSha256 as Mac>::new_from_slice(&key.0).expect("HMAC accepts any key size");
+        kdf.update(b"udt session");
+        kdf.update(&low.to_be_bytes());
+        kdf.update(&low_salt);
+        kdf.update(&high.to_be_bytes());
+        kdf.update(&high_salt);
+        let session_key = kdf.finalize().into_bytes();
+        Self {
+            cipher: Aes256Gcm::new(&session_key),
+            local_socket_id,
+            peer_socket_id,
+            counter: AtomicU64::new(0),
+            replay_window: Mutex::new(ReplayWindow::new()),
+            buffer: Mutex::new(BytesMut::new()),
+        }
+    }
+
+    /// The nonce is made of the sender socket id and of a counter never reused by the sender.
+    fn nonce(sender: SocketId, counter: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..4].copy_from_slice(&sender.to_be_bytes());
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Returns the sealed packet, or None once the counters are exhausted:
+    /// a nonce is never used twice with the key of the connection.
+    pub fn seal(&self, packet: &UdtPacket) -> Option<Bytes> {
+        let counter = self
+            .counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |counter| {
+                counter.checked_add(1)
+            })
+            .ok()?;
+        let nonce = Self::nonce(self.local_socket_id, counter);
+        let mut raw = self.buffer.lock().unwrap();
+        raw.reserve(1500);
+        packet.serialize_into(&mut *raw);
+        // The counter is inserted between the header and the body
+        raw.put_u64(counter);
+        raw[HEADER_SIZE..].rotate_right(COUNTER_SIZE);
+        let (header, body) = raw.split_at_mut(HEADER_SIZE);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                header,
+                &mut body[COUNTER_SIZE..],
+            )
+            .expect("packet is too large to be encrypted");
+        raw.put_slice(&tag);
+        Some(raw.split().freeze())
+    }
+
+    /// Returns the decrypted packet, or None if it was not sealed by the peer
+    /// or was already received.
+    pub fn open(&self, raw: &[u8]) -> Option<Bytes> {
+        if raw.len() < HEADER_SIZE + SEAL_OVERHEAD {
+            return None;
+        }
+        let (header, rest) = raw.split_at(HEADER_SIZE);
+        let (counter, rest) = rest.split_at(COUNTER_SIZE);
+        let (body, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let counter = u64::from_be_bytes(counter.try_into().unwrap());
+        if !self.replay_window.lock().unwrap().check(counter) {
+            return None;
+        }
+        let nonce = Self::nonce(self.peer_socket_id, counter);
+
+        let mut packet = BytesMut::with_capacity(HEADER_SIZE + body.len());
+        packet.extend_from_slice(header);
+        packet.extend_from_slice(body);
+        self.cipher
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                header,
+                &mut packet[HEADER_SIZE..],
+                GenericArray::from_slice(tag),
+            )
+            .ok()?;
+        // Only authenticated packets move the window
+        if !self.replay_window.lock().unwrap().accept(counter) {
+            return None;
+        }
+        Some(packet.freeze())
+    }
+}
+
+impl std::fmt::Debug for PacketCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PacketCipher")
+            .field("counter", &self.counter)
+            .finish_non_exhaustive()
+    }
+}
+
+#[test]
+fn test_seal_and_open() {
+    use crate::control_packet::UdtControlPacket;
+
+    let key = UdtEncryptionKey::from([7; 32]);
+    let (alice_salt, bob_salt) = (new_session_salt(), new_session_salt());
+    let alice = PacketCipher::new(&key, (1, alice_salt), (2, bob_salt));
+    let bob = PacketCipher::new(&key, (2, bob_salt), (1, alice_salt));
+    let packet: UdtPacket = UdtControlPacket::new_keep_alive(2).into();
+    let mut plain = vec![];
+    packet.serialize_into(&mut plain);
+
+    let sealed = alice.seal(&packet).unwrap();
+    assert_eq!(sealed.len(), plain.len() + SEAL_OVERHEAD);
+    assert_eq!(bob.open(&sealed).unwrap(), plain);
+    // Only the peer can open the packet, and any change is detected
+    assert!(alice.open(&sealed).is_none());
+    let mut tampered = alice.seal(&packet).unwrap().to_vec();
+    tampered[15] ^= 1;
+    assert!(bob.open(&tampered).is_none());
+
+    // The same socket ids with other salts make another key
+    let other = PacketCipher::new(&key, (2, new_session_salt()), (1, alice_salt));
+    assert!(other.open(&alice.seal(&packet).unwrap()).is_none());
+
+    // The counters are never reused
+    alice.counter.store(u64::MAX, Ordering::Relaxed);
+    assert!(alice.seal(&packet).is_none());
+}
+
+#[test]
+fn test_replay_window() {
+    use crate::control_packet::UdtControlPacket;
+
+    let key = UdtEncryptionKey::from([7; 32]);
+    let alice = PacketCipher::new(&key, (1, [1; 16]), (2, [2; 16]));
+    let bob = PacketCipher::new(&key, (2, [2; 16]), (1, [1; 16]));
+    let packet: UdtPacket = UdtControlPacket::new_keep_alive(2).into();
+    let sealed: Vec<_> = (0..REPLAY_WINDOW_SIZE + 10)
+        .map(|_| alice.seal(&packet).unwrap())
+        .collect();
+
+    // Reordered packets are accepted once
+    assert!(bob.open(&sealed[1]).is_some());
+    assert!(bob.open(&sealed[0]).is_some());
+    assert!(bob.open(&sealed[0]).is_none());
+    assert!(bob.open(&sealed[1]).is_none());
+    assert!(bob.open(&sealed[5]).is_some());
+    assert!(bob.open(&sealed[3]).is_some());
+    assert!(bob.open(&sealed[5]).is_none());
+
+    // Packets older than the window are dropped
+    let last = sealed.len() - 1;
+    assert!(bob.open(&sealed[last]).is_some());
+    assert!(bob.open(&sealed[4]).is_none());
+    assert!(bob.open(&sealed[last - 1]).is_some());
+    assert!(bob.open(&sealed[last]).is_none());
+}
@@ -1,5 +1,5 @@
 use crate::seq_number::{MsgNumber, SeqNumber};
-use bytes::Bytes;
+use bytes::{BufMut, Bytes};
 use tokio::io::{Error, ErrorKind, Result};
 
 pub const UDT_DATA_HEADER_SIZE: usize = 16;
@@ -11,9 +11,10 @@ pub(crate) struct UdtDataPacket {
 }
 
 impl UdtDataPacket {
-    pub fn deserialize(raw: &[u8]) -> Result<Self> {
-        let header = UdtDataPacketHeader::deserialize(&raw[..UDT_DATA_HEADER_SIZE])?;
-        let data = Bytes::copy_from_slice(&raw[UDT_DATA_HEADER_SIZE..]);
+    /// The payload is a slice of `raw`, without copy.
+    pub fn deserialize(raw: Bytes) -> Result<Self> {
+        let header = UdtDataPacketHeader::deserialize(&raw)?;
+        let data = raw.slice(UDT_DATA_HEADER_SIZE..);
         Ok(Self { header, data })
     }
 
@@ -21,11 +22,10 @@ impl UdtDataPacket {
         self.data.len()
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer = Vec::with_capacity(1500);
-        buffer.extend_from_slice(&self.header.serialize());
-        buffer.extend_from_slice(&self.data);
-        buffer
+    /// Appends the serialized packet to `buffer`.
+    pub fn serialize_into(&self, buffer: &mut impl BufMut) {
+        self.header.serialize_into(buffer);
+        buffer.put_slice(&self.data);
     }
 }
 
@@ -42,7 +42,7 @@ pub(crate) struct UdtDataPacketHeader {
 
 impl UdtDataPacketHeader {
     pub fn deserialize(raw: &[u8]) -> Result<Self> {
-        if raw.len() < 16 {
+        if raw.len() < UDT_DATA_HEADER_SIZE {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 "data packet header is too short",
@@ -64,18 +64,16 @@ impl UdtDataPacketHeader {
         })
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buffer: Vec<u8> = Vec::with_capacity(UDT_DATA_HEADER_SIZE);
-        buffer.extend_from_slice(&self.seq_number.number().to_be_bytes());
+    pub fn serialize_into(&self, buffer: &mut impl BufMut) {
+        buffer.put_u32(self.seq_number.number());
 
         let block: u32 = ((self.position as u32) << 30)
             + (u32::from(self.in_order) << 29)
             + self.msg_number.number();
 
-        buffer.extend_from_slice(&block.to_be_bytes());
-        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
-        buffer.extend_from_slice(&self.dest_socket_id.to_be_bytes());
-        buffer
+        buffer.put_u32(block);
+        buffer.put_u32(self.timestamp);
+        buffer.put_u32(self.dest_socket_id);
     }
 }
 
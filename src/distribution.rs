@@ -0,0 +1,222 @@
+use crate::configuration::UdtEgressRateLimit;
+use crate::connection::UdtConnection;
+use crate::error::{UdtError, UdtErrorCode};
+use crate::group::UdtConnectionGroup;
+use crate::rate_limiter::TokenBucket;
+use bytes::Bytes;
+use std::task::Poll;
+use tokio::io::{Error, ErrorKind};
+use tokio::time::{Duration, Instant};
+
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// **Experimental**: one-to-many distribution of identical payloads.
+///
+/// The session keeps one UDT connection per receiver, in a [`UdtConnectionGroup`],
+/// each with its own ACK/NAK processing and loss recovery. Payloads are stored only
+/// once: the send buffers of all receivers share the same memory instead of holding
+/// N copies.
+///
+/// A payload is admitted once every receiver has room for it, so the receivers
+/// progress at the pace of the slowest one, unless it stalls for the stall timeout:
+/// it is then dropped from the session. With a pacing budget, payloads are admitted
+/// at its rate whatever the number of receivers, on top of the congestion control
+/// of each connection.
+pub struct UdtDistributionSession {
+    receivers: UdtConnectionGroup,
+    pacing: Option<TokenBucket>,
+    stall_timeout: Duration,
+}
+
+impl Default for UdtDistributionSession {
+    fn default() -> Self {
+        Self {
+            receivers: UdtConnectionGroup::default(),
+            pacing: None,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+        }
+    }
+}
+
+impl UdtDistributionSession {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the budget shared by all receivers: each payload is charged once,
+    /// as it is sent the same to every receiver.
+    /// Default: None (admitted as soon as the receivers have room)
+    pub fn set_pacing(&mut self, budget: Option<UdtEgressRateLimit>) {
+        self.pacing =
+            budget.map(|budget| TokenBucket::new(budget.bytes_per_sec.get(), budget.burst_bytes));
+    }
+
+    /// Sets how long a receiver without room for a payload holds back the others,
+    /// before it is dropped from the session.
+    /// Default: 10s
+    pub fn set_stall_timeout(&mut self, timeout: Duration) {
+        self.stall_timeout = timeout;
+    }
+
+    /// Adds an established stream connection to the set of receivers.
+    pub fn add_receiver(&mut self, connection: UdtConnection) {
+        self.receivers.add(connection);
+    }
+
+    /// Removes a receiver from the session, returning its connection.
+    pub fn remove_receiver(&mut self, socket_id: u32) -> Option<UdtConnection> {
+        self.receivers.remove(socket_id)
+    }
+
+    pub fn receivers(&self) -> &UdtConnectionGroup {
+        &self.receivers
+    }
+
+    /// Sends the same payload to all receivers, once all of them have room for it.
+    ///
+    /// Receivers that fail, or still have no room for the payload after the stall
+    /// timeout (e.g. as it is larger than their send buffer), are removed from the
+    /// session and returned along with the corresponding error.
+    pub async fn send(&mut self, payload: impl Into<Bytes>) -> Vec<(UdtConnection, Error)> {
+        let payload = payload.into();
+        if let Some(pacing) = &mut self.pacing {
+            tokio::time::sleep(pacing.consume(payload.len())).await;
+        }
+
+        // The receivers are waited for at once: the stall timeout applies to each of them
+        let deadline = Instant::now() + self.stall_timeout;
+        let receivers = &self.receivers;
+        let wait_for_room = std::future::poll_fn(|cx| {
+            let mut ready = true;
+            for connection in receivers.iter() {
+                ready &= connection
+                    .socket()
+                    .poll_snd_room(cx, payload.len())
+                    .is_ready();
+            }
+            if ready {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        });
+        let _ = tokio::time::timeout_at(deadline, wait_for_room).await;
+        let stalled: Vec<_> = self
+            .receivers
+            .iter()
+            .filter(|connection| connection.socket().snd_buffer_room() < payload.len())
+            .filter(|connection| connection.socket().status().is_alive())
+            .map(|connection| connection.socket_id())
+            .collect();
+        let mut failed: Vec<_> = stalled
+            .into_iter()
+            .filter_map(|socket_id| self.receivers.remove(socket_id))
+            .map(|connection| {
+                let err = Error::new(
+                    ErrorKind::TimedOut,
+                    UdtError::new(UdtErrorCode::AsyncSnd, "receiver stalled"),
+                );
+                (connection, err)
+            })
+            .collect();
+
+        let results = self.receivers.broadcast_send_bytes(payload);
+        failed.extend(results.into_iter().filter_map(|(socket_id, res)| {
+            let err = res.err()?;
+            Some((self.receivers.remove(socket_id).unwrap(), err))
+        }));
+        failed
+    }
+
+    /// Waits until all sent payloads have been acknowledged by every receiver.
+    pub async fn flush(&self) {
+        for connection in self.receivers.iter() {
+            let socket = connection.socket();
+            while socket.status().is_alive() && !socket.snd_buffer_is_empty() {
+                socket.wait_for_next_ack_or_empty_snd_buffer().await;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_distribution_session() {
+    use tokio::io::AsyncReadExt;
+
+    let mut session = UdtDistributionSession::new();
+    let mut servers = vec![];
+    for _ in 0..2 {
+        let (client, server) = UdtConnection::pair(None).await.unwrap();
+        session.add_receiver(client);
+        servers.push(server);
+    }
+    let payload = Bytes::from(vec![7; 100_000]);
+    for _ in 0..4 {
+        assert!(session.send(payload.clone()).await.is_empty());
+    }
+    session.flush().await;
+    for mut server in servers {
+        let mut received = vec![0; 4 * payload.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert!(received.iter().all(|&byte| byte == 7));
+    }
+}
+
+#[tokio::test]
+async fn test_distribution_stalled_receiver() {
+    use crate::configuration::UdtConfiguration;
+    use tokio::io::AsyncReadExt;
+
+    let mut session = UdtDistributionSession::new();
+    session.set_stall_timeout(Duration::from_millis(200));
+    let (client, mut server) = UdtConnection::pair(None).await.unwrap();
+    session.add_receiver(client);
+    // This receiver does not read, and its buffers are small: it stalls the session
+    let config = UdtConfiguration {
+        snd_buf_size: 64,
+        rcv_buf_size: 32,
+        ..Default::default()
+    };
+    let (stalling, _stalled_server) = UdtConnection::pair(Some(config)).await.unwrap();
+    let stalling_id = stalling.socket_id();
+    session.add_receiver(stalling);
+
+    let payload = Bytes::from(vec![7; 50_000]);
+    let mut dropped = vec![];
+    for _ in 0..6 {
+        dropped.extend(session.send(payload.clone()).await);
+    }
+    assert_eq!(dropped.len(), 1);
+    assert_eq!(dropped[0].0.socket_id(), stalling_id);
+    assert_eq!(dropped[0].1.kind(), ErrorKind::TimedOut);
+
+    // The other receiver got every payload
+    session.flush().await;
+    let mut received = vec![0; 6 * payload.len()];
+    server.read_exact(&mut received).await.unwrap();
+    assert!(received.iter().all(|&byte| byte == 7));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_distribution_pacing() {
+    let mut session = UdtDistributionSession::new();
+    session.set_pacing(Some(UdtEgressRateLimit {
+        bytes_per_sec: 1_000_000.try_into().unwrap(),
+        burst_bytes: 0,
+    }));
+    let mut servers = vec![];
+    for _ in 0..3 {
+        let (client, server) = UdtConnection::pair(None).await.unwrap();
+        session.add_receiver(client);
+        servers.push(server);
+    }
+    // The budget is charged once per payload, whatever the number of receivers
+    let start = Instant::now();
+    for _ in 0..4 {
+        assert!(session.send(vec![7; 100_000]).await.is_empty());
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+}
@@ -0,0 +1,259 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+/// Error codes defined by the reference UDT4 implementation (`CUDTException`).
+///
+/// Every error returned by this crate is a [`std::io::Error`]. Use
+/// [`UdtErrorExt::udt_error_code`] to retrieve the matching UDT4 code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum UdtErrorCode {
+    /// `SUCCESS`: no error.
+    Success = 0,
+    /// `ECONNSETUP`: connection setup failure.
+    ConnSetup = 1000,
+    /// `ENOSERVER`: server does not exist.
+    NoServer = 1001,
+    /// `ECONNREJ`: connection request was rejected by server.
+    ConnRej = 1002,
+    /// `ESOCKFAIL`: could not create/configure UDP socket.
+    SockFail = 1003,
+    /// `ESECFAIL`: connection request was aborted due to security reasons.
+    SecFail = 1004,
+    /// `ECONNFAIL`: connection failure.
+    ConnFail = 2000,
+    /// `ECONNLOST`: connection was broken.
+    ConnLost = 2001,
+    /// `ENOCONN`: connection does not exist.
+    NoConn = 2002,
+    /// `ERESOURCE`: system resource failure.
+    Resource = 3000,
+    /// `ETHREAD`: could not create new thread (or task).
+    Thread = 3001,
+    /// `ENOBUF`: no memory space.
+    NoBuf = 3002,
+    /// `EFILE`: file access error.
+    File = 4000,
+    /// `EINVRDOFF`: invalid read offset.
+    InvRdOff = 4001,
+    /// `ERDPERM`: no read permission.
+    RdPerm = 4002,
+    /// `EINVWROFF`: invalid write offset.
+    InvWrOff = 4003,
+    /// `EWRPERM`: no write permission.
+    WrPerm = 4004,
+    /// `EINVOP`: operation not supported.
+    InvOp = 5000,
+    /// `EBOUNDSOCK`: cannot execute the operation on a bound socket.
+    BoundSock = 5001,
+    /// `ECONNSOCK`: cannot execute the operation on a connected socket.
+    ConnSock = 5002,
+    /// `EINVPARAM`: bad parameters.
+    InvParam = 5003,
+    /// `EINVSOCK`: invalid UDT socket.
+    InvSock = 5004,
+    /// `EUNBOUNDSOCK`: cannot listen on unbound socket.
+    UnboundSock = 5005,
+    /// `ENOLISTEN`: (accept) socket is not in listening state.
+    NoListen = 5006,
+    /// `ERDVNOSERV`: rendezvous connection process does not allow listen and accept.
+    RdvNoServ = 5007,
+    /// `ERDVUNBOUND`: rendezvous connection setup is enabled but the socket is not bound.
+    RdvUnbound = 5008,
+    /// `ESTREAMILL`: operation not supported in `SOCK_STREAM` mode.
+    StreamIll = 5009,
+    /// `EDGRAMILL`: operation not supported in `SOCK_DGRAM` mode.
+    DgramIll = 5010,
+    /// `EDUPLISTEN`: another socket is already listening on the same UDP port.
+    DupListen = 5011,
+    /// `ELARGEMSG`: message is too large to be held in the sending buffer.
+    LargeMsg = 5012,
+    /// `EINVPOLLID`: epoll ID is invalid.
+    InvPollId = 5013,
+    /// `EASYNCFAIL`: non-blocking call failure.
+    AsyncFail = 6000,
+    /// `EASYNCSND`: no buffer available for non-blocking sending.
+    AsyncSnd = 6001,
+    /// `EASYNCRCV`: no data available for non-blocking reading.
+    AsyncRcv = 6002,
+    /// `ETIMEOUT`: timeout before operation completes.
+    Timeout = 6003,
+    /// `EPEERERR`: error has happened at the peer side.
+    PeerErr = 7000,
+    /// `EUNKNOWN`: unknown error.
+    Unknown = -1,
+}
+
+impl UdtErrorCode {
+    /// Gets the numeric value used by the C++ implementation.
+    #[must_use]
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// Gets the symbolic name used by the C++ implementation (e.g. `"ECONNLOST"`).
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Success => "SUCCESS",
+            Self::ConnSetup => "ECONNSETUP",
+            Self::NoServer => "ENOSERVER",
+            Self::ConnRej => "ECONNREJ",
+            Self::SockFail => "ESOCKFAIL",
+            Self::SecFail => "ESECFAIL",
+            Self::ConnFail => "ECONNFAIL",
+            Self::ConnLost => "ECONNLOST",
+            Self::NoConn => "ENOCONN",
+            Self::Resource => "ERESOURCE",
+            Self::Thread => "ETHREAD",
+            Self::NoBuf => "ENOBUF",
+            Self::File => "EFILE",
+            Self::InvRdOff => "EINVRDOFF",
+            Self::RdPerm => "ERDPERM",
+            Self::InvWrOff => "EINVWROFF",
+            Self::WrPerm => "EWRPERM",
+            Self::InvOp => "EINVOP",
+            Self::BoundSock => "EBOUNDSOCK",
+            Self::ConnSock => "ECONNSOCK",
+            Self::InvParam => "EINVPARAM",
+            Self::InvSock => "EINVSOCK",
+            Self::UnboundSock => "EUNBOUNDSOCK",
+            Self::NoListen => "ENOLISTEN",
+            Self::RdvNoServ => "ERDVNOSERV",
+            Self::RdvUnbound => "ERDVUNBOUND",
+            Self::StreamIll => "ESTREAMILL",
+            Self::DgramIll => "EDGRAMILL",
+            Self::DupListen => "EDUPLISTEN",
+            Self::LargeMsg => "ELARGEMSG",
+            Self::InvPollId => "EINVPOLLID",
+            Self::AsyncFail => "EASYNCFAIL",
+            Self::AsyncSnd => "EASYNCSND",
+            Self::AsyncRcv => "EASYNCRCV",
+            Self::Timeout => "ETIMEOUT",
+            Self::PeerErr => "EPEERERR",
+            Self::Unknown => "EUNKNOWN",
+        }
+    }
+}
+
+impl fmt::Display for UdtErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name(), self.code())
+    }
+}
+
+/// Best-effort mapping for errors that were not created with an explicit UDT code
+/// (e.g. errors reported by the OS for the underlying UDP socket).
+impl From<ErrorKind> for UdtErrorCode {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::ConnectionRefused => Self::ConnRej,
+            ErrorKind::NotConnected => Self::NoConn,
+            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+                Self::ConnLost
+            }
+            ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable => Self::SockFail,
+            ErrorKind::PermissionDenied => Self::SecFail,
+            ErrorKind::OutOfMemory => Self::NoBuf,
+            ErrorKind::WouldBlock => Self::AsyncFail,
+            ErrorKind::TimedOut => Self::Timeout,
+            ErrorKind::InvalidInput => Self::InvParam,
+            ErrorKind::Unsupported => Self::InvOp,
+            ErrorKind::InvalidData => Self::PeerErr,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Error payload carrying a UDT4 error code, wrapped in a [`std::io::Error`].
+#[derive(Debug)]
+pub struct UdtError {
+    code: UdtErrorCode,
+    message: String,
+}
+
+impl UdtError {
+    pub fn new(code: UdtErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    #[must_use]
+    pub fn code(&self) -> UdtErrorCode {
+        self.code
+    }
+}
+
+impl fmt::Display for UdtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for UdtError {}
+
+/// Error raised by a background task of the crate (multiplexer workers, packet processing),
+/// as received from [`process::subscribe_errors`](crate::process::subscribe_errors).
+#[derive(Debug, Clone)]
+pub struct UdtBackgroundError {
+    /// Socket broken by the error, if any.
+    pub socket_id: Option<u32>,
+    /// Multiplexer (UDP socket) the failing task belongs to, if any.
+    pub multiplexer_id: Option<u32>,
+    /// Label of the broken socket, if any.
+    pub label: Option<String>,
+    pub error: Arc<Error>,
+}
+
+impl fmt::Display for UdtBackgroundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(socket_id) = self.socket_id {
+            write!(f, "socket {}", socket_id)?;
+            if let Some(label) = &self.label {
+                write!(f, " ({})", label)?;
+            }
+            write!(f, ": ")?;
+        } else if let Some(mux_id) = self.multiplexer_id {
+            write!(f, "multiplexer {}: ", mux_id)?;
+        }
+        write!(f, "{}", self.error)
+    }
+}
+
+/// Extension trait to retrieve the UDT4 error code of an I/O error.
+pub trait UdtErrorExt {
+    /// Gets the UDT4 error code matching this error.
+    fn udt_error_code(&self) -> UdtErrorCode;
+}
+
+impl UdtErrorExt for Error {
+    fn udt_error_code(&self) -> UdtErrorCode {
+        self.get_ref()
+            .and_then(|inner| inner.downcast_ref::<UdtError>())
+            .map_or_else(|| self.kind().into(), UdtError::code)
+    }
+}
+
+#[test]
+fn test_explicit_error_code() {
+    let err = Error::new(
+        ErrorKind::NotConnected,
+        UdtError::new(UdtErrorCode::NoListen, "socket is not in listening state"),
+    );
+    assert_eq!(err.udt_error_code(), UdtErrorCode::NoListen);
+    assert_eq!(err.udt_error_code().code(), 5006);
+    assert_eq!(err.to_string(), "socket is not in listening state");
+}
+
+#[test]
+fn test_error_code_from_kind() {
+    let err = Error::new(ErrorKind::BrokenPipe, "connection was closed or broken");
+    assert_eq!(err.udt_error_code(), UdtErrorCode::ConnLost);
+    assert_eq!(
+        Error::from(ErrorKind::TimedOut).udt_error_code().name(),
+        "ETIMEOUT"
+    );
+}
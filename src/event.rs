@@ -0,0 +1,123 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Notable change in the state of a UDT connection,
+/// as received from [`UdtConnection::subscribe_events`](crate::UdtConnection::subscribe_events).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UdtConnectionEvent {
+    /// The handshake completed, before `connect` or `accept` return the connection:
+    /// only the first subscriber of the connection receives it.
+    Connected,
+    /// The peer acknowledged this number of new data packets.
+    AckReceived(u32),
+    /// This number of data packets were lost: reported missing by the peer for the
+    /// packets sent, or found missing by this side among the packets received.
+    LossDetected(u32),
+    /// Nothing was received from the peer for `UdtConfiguration::peer_idle_timeout`,
+    /// the time since the last packet. The connection breaks if the silence lasts
+    /// `max_exp_count` expirations. Sent once per silence.
+    PeerIdle(Duration),
+    /// The connection is broken: reads and writes fail with the cause, which is also
+    /// reported to `process::subscribe_errors`.
+    Broken,
+    /// The connection was closed, by the application or the peer, or released after
+    /// breaking. It is the last event of the connection.
+    Closed,
+    /// The peer advertised a (nearly) full receive buffer: sending is paused
+    /// until its application reads the pending data.
+    PeerStalled,
+    /// The peer receive buffer has room again and sending resumed.
+    PeerResumed,
+    /// A threshold of `UdtConfiguration::alarms` has been crossed for the sustained period.
+    AlarmRaised(UdtAlarm),
+    /// The metric of a raised alarm is back within its threshold.
+    AlarmCleared(UdtAlarm),
+    /// The peer moved to a new address (see `UdtConfiguration::peer_migration`),
+    /// which the connection now sends to.
+    PeerMigrated(SocketAddr),
+    /// Path MTU discovery found that the path carries larger packets:
+    /// data packets are now sent with this size, including the IP and UDP headers.
+    PathMtuChanged(u32),
+    /// The session was resumed (see `UdtConnection::resume`): the data the peer
+    /// did not receive is sent again.
+    SessionResumed,
+}
+
+/// Connection metric watched by an alarm, see [`UdtAlarmThresholds`](crate::UdtAlarmThresholds).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UdtAlarm {
+    /// Smoothed round-trip time above `max_rtt`.
+    Rtt,
+    /// Loss rate reported by the peer above `max_loss_rate`.
+    LossRate,
+    /// Acknowledged data rate below `min_goodput`.
+    Goodput,
+}
+
+/// Handler of the user-defined control packets received on a connection,
+/// called with the `reserved` field of the packet and its payload.
+/// See [`UdtConnection::set_user_control_handler`](crate::UdtConnection::set_user_control_handler).
+pub type UdtUserControlHandler = Arc<dyn Fn(u16, &[u8]) + Send + Sync>;
+
+#[tokio::test]
+async fn test_connection_events() {
+    use crate::{UdtConfiguration, UdtConnection};
+    use tokio::sync::broadcast::{error::RecvError, Receiver};
+    use tokio::time::timeout;
+
+    async fn wait_for(
+        events: &mut Receiver<UdtConnectionEvent>,
+        expected: fn(&UdtConnectionEvent) -> bool,
+    ) {
+        let found = async {
+            loop {
+                match events.recv().await {
+                    Ok(event) if expected(&event) => break,
+                    Err(RecvError::Closed) => panic!("event not received"),
+                    _ => {}
+                }
+            }
+        };
+        timeout(Duration::from_secs(10), found).await.unwrap();
+    }
+
+    // A small receive queue drops datagrams of the burst
+    let config = UdtConfiguration {
+        udp_rcv_buf_size: 64 * 1024,
+        ..Default::default()
+    };
+    let (client, server) = UdtConnection::pair(Some(config)).await.unwrap();
+    let mut client_events = client.subscribe_events();
+    let mut server_events = server.subscribe_events();
+    assert_eq!(client_events.try_recv(), Ok(UdtConnectionEvent::Connected));
+    assert_eq!(server_events.try_recv(), Ok(UdtConnectionEvent::Connected));
+
+    let data = vec![0u8; 1024 * 1024];
+    let sender = async {
+        client.send(&data).await.unwrap();
+        wait_for(&mut client_events, |event| {
+            matches!(event, UdtConnectionEvent::AckReceived(_))
+        })
+        .await;
+    };
+    let receiver = async {
+        let mut buf = vec![0u8; data.len()];
+        let mut received = 0;
+        while received < data.len() {
+            received += server.recv(&mut buf[received..]).await.unwrap();
+        }
+    };
+    tokio::join!(sender, receiver);
+    wait_for(&mut server_events, |event| {
+        matches!(event, UdtConnectionEvent::LossDetected(_))
+    })
+    .await;
+
+    client.close().await;
+    wait_for(&mut server_events, |event| {
+        *event == UdtConnectionEvent::Closed
+    })
+    .await;
+}
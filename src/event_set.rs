@@ -0,0 +1,198 @@
+use crate::connection::UdtConnection;
+use crate::socket::SocketId;
+use crate::udt::SocketRef;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Wake, Waker};
+use tokio::io::Interest;
+use tokio::sync::Notify;
+
+/// Readiness of a connection of a [`UdtEventSet`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdtReadiness {
+    pub socket_id: u32,
+    pub readable: bool,
+    pub writable: bool,
+    /// The connection is broken or closed: the next read or write reports the error
+    pub broken: bool,
+}
+
+/// Sockets to check by the next wait, because they were notified or still ready.
+#[derive(Debug, Default)]
+struct Candidates {
+    sockets: Mutex<HashSet<SocketId>>,
+    notify: Notify,
+}
+
+/// Waker registered in a socket, making it a candidate of its event set.
+#[derive(Debug)]
+struct CandidateWaker {
+    socket_id: SocketId,
+    candidates: Weak<Candidates>,
+}
+
+impl Wake for CandidateWaker {
+    fn wake(self: Arc<Self>) {
+        if let Some(candidates) = self.candidates.upgrade() {
+            candidates.sockets.lock().unwrap().insert(self.socket_id);
+            candidates.notify.notify_one();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Registration {
+    socket: SocketRef,
+    interest: Interest,
+    waker: Waker,
+}
+
+/// Set of connections waited on together, like the epoll facility of UDT4.
+/// A single task can serve many connections: it waits for any of them to be
+/// readable, writable or broken, without a task or a future per connection.
+///
+/// Readiness is level-triggered: a connection is reported by every wait as long
+/// as it stays ready. Broken or closed connections are reported until removed.
+#[derive(Debug, Default)]
+pub struct UdtEventSet {
+    candidates: Arc<Candidates>,
+    sockets: HashMap<SocketId, Registration>,
+}
+
+impl UdtEventSet {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `connection` for `interest`, or replaces its interest if already registered.
+    /// The set does not keep the connection open: dropping it reports it as broken.
+    pub fn add(&mut self, connection: &UdtConnection, interest: Interest) {
+        let socket_id = connection.socket_id();
+        let waker = Waker::from(Arc::new(CandidateWaker {
+            socket_id,
+            candidates: Arc::downgrade(&self.candidates),
+        }));
+        self.sockets.insert(
+            socket_id,
+            Registration {
+                socket: connection.socket().clone(),
+                interest,
+                waker,
+            },
+        );
+        self.candidates.sockets.lock().unwrap().insert(socket_id);
+        self.candidates.notify.notify_one();
+    }
+
+    /// Unregisters a connection. Returns whether it was registered.
+    pub fn remove(&mut self, socket_id: u32) -> bool {
+        self.candidates.sockets.lock().unwrap().remove(&socket_id);
+        self.sockets.remove(&socket_id).is_some()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.sockets.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.sockets.is_empty()
+    }
+
+    /// Waits until at least one connection is ready for its interest,
+    /// and returns the ready ones. Waiting on an empty set never completes.
+    /// Cancel safe: no readiness is lost if the future is dropped.
+    pub async fn wait(&self) -> Vec<UdtReadiness> {
+        loop {
+            let ready = self.try_wait();
+            if !ready.is_empty() {
+                return ready;
+            }
+            self.candidates.notify.notified().await;
+        }
+    }
+
+    /// Returns the connections ready for their interest, without waiting.
+    #[must_use]
+    pub fn try_wait(&self) -> Vec<UdtReadiness> {
+        let candidates = std::mem::take(&mut *self.candidates.sockets.lock().unwrap());
+        let ready: Vec<_> = candidates
+            .into_iter()
+            .filter_map(|socket_id| {
+                let registration = self.sockets.get(&socket_id)?;
+                // Pending polls register the waker, which makes the socket
+                // a candidate again once it may be ready
+                let mut cx = Context::from_waker(&registration.waker);
+                let socket = &registration.socket;
+                let readable = registration.interest.is_readable()
+                    && socket.poll_read_ready(&mut cx).is_ready();
+                let writable = registration.interest.is_writable()
+                    && socket.poll_write_ready(&mut cx).is_ready();
+                (readable || writable).then(|| UdtReadiness {
+                    socket_id,
+                    readable,
+                    writable,
+                    broken: !socket.status().is_alive(),
+                })
+            })
+            .collect();
+        // Still ready sockets are checked again by the next wait
+        self.candidates
+            .sockets
+            .lock()
+            .unwrap()
+            .extend(ready.iter().map(|readiness| readiness.socket_id));
+        ready
+    }
+}
+
+#[tokio::test]
+async fn test_event_set() {
+    use tokio::time::{timeout, Duration};
+
+    async fn wait(events: &UdtEventSet) -> Vec<UdtReadiness> {
+        timeout(Duration::from_secs(5), events.wait())
+            .await
+            .unwrap()
+    }
+
+    let (client1, server1) = UdtConnection::pair(None).await.unwrap();
+    let (client2, server2) = UdtConnection::pair(None).await.unwrap();
+    let mut events = UdtEventSet::new();
+    events.add(&server1, Interest::READABLE);
+    events.add(&server2, Interest::READABLE);
+    assert!(events.try_wait().is_empty());
+
+    client1.send(b"data").await.unwrap();
+    let ready = wait(&events).await;
+    let expected = UdtReadiness {
+        socket_id: server1.socket_id(),
+        readable: true,
+        writable: false,
+        broken: false,
+    };
+    assert_eq!(ready, [expected]);
+    // Level-triggered: reported until the data is read
+    assert_eq!(wait(&events).await, [expected]);
+    let mut buf = [0; 16];
+    assert_eq!(server1.recv(&mut buf).await.unwrap(), 4);
+    assert!(events.try_wait().is_empty());
+
+    events.add(&client2, Interest::WRITABLE);
+    let ready = wait(&events).await;
+    assert_eq!(ready.len(), 1);
+    assert!(ready[0].socket_id == client2.socket_id() && ready[0].writable);
+    assert!(events.remove(client2.socket_id()));
+
+    // The end of the connection is reported as readable and broken
+    client1.close().await;
+    let ready = wait(&events).await;
+    assert_eq!(ready.len(), 1);
+    assert!(ready[0].socket_id == server1.socket_id() && ready[0].readable && ready[0].broken);
+    // Until removed
+    assert_eq!(wait(&events).await, ready);
+    assert!(events.remove(server1.socket_id()));
+    assert!(events.try_wait().is_empty());
+}
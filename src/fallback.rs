@@ -0,0 +1,135 @@
+use crate::configuration::UdtConfiguration;
+use crate::connection::UdtConnection;
+use crate::socket::{SocketType, UdtStatus};
+use crate::udt::Udt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, Result};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::{timeout, Duration};
+
+/// Connection over UDT, or over TCP when UDT could not be established
+/// (e.g. UDP blocked by a firewall). The variant tells which transport is used.
+pub enum UdtFallbackConnection {
+    Udt(UdtConnection),
+    Tcp(TcpStream),
+}
+
+impl UdtFallbackConnection {
+    /// Connects to `udt_addr` with UDT. If the handshake fails or does not complete
+    /// within `handshake_timeout`, connects to `tcp_addr` with TCP instead.
+    pub async fn connect(
+        udt_addr: impl ToSocketAddrs,
+        tcp_addr: impl ToSocketAddrs,
+        config: Option<UdtConfiguration>,
+        handshake_timeout: Duration,
+    ) -> Result<Self> {
+        let socket = {
+            let mut udt = Udt::get().write().await;
+            udt.new_socket(SocketType::Stream, config)?.clone()
+        };
+        let connect = UdtConnection::connect_socket(socket.clone(), None, udt_addr);
+        if let Ok(Ok(connection)) = timeout(handshake_timeout, connect).await {
+            if socket.status() == UdtStatus::Connected {
+                return Ok(Self::Udt(connection));
+            }
+        }
+        // The handshake may still be pending: release the socket and its UDP port
+        Udt::close_socket(&socket).await;
+
+        Ok(Self::Tcp(TcpStream::connect(tcp_addr).await?))
+    }
+
+    #[must_use]
+    pub fn is_udt(&self) -> bool {
+        matches!(self, Self::Udt(_))
+    }
+}
+
+impl AsyncRead for UdtFallbackConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Self::Udt(connection) => Pin::new(connection).poll_read(cx, buf),
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UdtFallbackConnection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.get_mut() {
+            Self::Udt(connection) => Pin::new(connection).poll_write(cx, buf),
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Self::Udt(connection) => Pin::new(connection).poll_flush(cx),
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.get_mut() {
+            Self::Udt(connection) => Pin::new(connection).poll_shutdown(cx),
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_fallback_to_tcp() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    // A UDP socket which never answers the handshake, as behind a firewall
+    let silent = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (connection, accepted) = tokio::join!(
+        UdtFallbackConnection::connect(
+            silent.local_addr().unwrap(),
+            tcp_listener.local_addr().unwrap(),
+            None,
+            Duration::from_millis(300),
+        ),
+        tcp_listener.accept()
+    );
+    let mut connection = connection.unwrap();
+    let (mut stream, _) = accepted.unwrap();
+    assert!(!connection.is_udt());
+    connection.write_all(b"over tcp").await.unwrap();
+    let mut buf = [0; 8];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"over tcp");
+    stream.write_all(b"back").await.unwrap();
+    connection.read_exact(&mut buf[..4]).await.unwrap();
+    assert_eq!(&buf[..4], b"back");
+}
+
+#[tokio::test]
+async fn test_no_fallback() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = crate::UdtListener::bind("127.0.0.1:0".parse().unwrap(), None)
+        .await
+        .unwrap();
+    let tcp_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let (connection, accepted) = tokio::join!(
+        UdtFallbackConnection::connect(
+            listener.local_addr().unwrap(),
+            tcp_listener.local_addr().unwrap(),
+            None,
+            Duration::from_secs(3),
+        ),
+        listener.accept()
+    );
+    let mut connection = connection.unwrap();
+    let (_, mut peer) = accepted.unwrap();
+    assert!(connection.is_udt());
+    connection.write_all(b"over udt").await.unwrap();
+    let mut buf = [0; 8];
+    peer.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"over udt");
+}
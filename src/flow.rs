@@ -25,9 +25,9 @@ impl Default for UdtFlow {
         Self {
             flow_window_size: 0,
             last_arrival_time: now,
-            arrival_window: VecDeque::new(),
+            arrival_window: VecDeque::with_capacity(ARRIVAL_WINDOW_SIZE + 1),
             probe_time: now,
-            probe_window: VecDeque::new(),
+            probe_window: VecDeque::with_capacity(PROBE_WINDOW_SIZE + 1),
             rtt: Duration::from_millis(100),
             rtt_var: Duration::from_millis(50),
             peer_bandwidth: 1,
@@ -59,38 +59,25 @@ impl UdtFlow {
 
     /// Returns a number of packets per second
     pub fn get_pkt_rcv_speed(&self) -> u32 {
-        let length = self.arrival_window.len();
-        let mut values = self.arrival_window.clone();
-        let (_, median, _) = values.make_contiguous().select_nth_unstable(length / 2);
-        let median = *median;
-        let values: Vec<_> = values
-            .into_iter()
-            .filter(|x| *x > median / 8 && *x < median * 8)
-            .collect();
-        if values.len() < ARRIVAL_WINDOW_SIZE / 2 {
+        if self.arrival_window.is_empty() {
             return 0;
         }
-        let total_duration: Duration = values.iter().sum();
-        (values.len() as f64 / total_duration.as_secs_f64()).ceil() as u32
+        let (count, total_duration) = filter_outliers::<ARRIVAL_WINDOW_SIZE>(&self.arrival_window);
+        if count < ARRIVAL_WINDOW_SIZE / 2 {
+            return 0;
+        }
+        (count as f64 / total_duration.as_secs_f64()).ceil() as u32
     }
 
     pub fn get_bandwidth(&self) -> u32 {
         if self.probe_window.is_empty() {
             return 0;
         }
-        let length = self.probe_window.len();
-        let mut values = self.probe_window.clone();
-        let (_, median, _) = values.make_contiguous().select_nth_unstable(length / 2);
-        let median = *median;
-        let values: Vec<_> = values
-            .into_iter()
-            .filter(|x| *x > median / 8 && *x < median * 8)
-            .collect();
-        let total_duration: Duration = values.iter().sum();
+        let (count, total_duration) = filter_outliers::<PROBE_WINDOW_SIZE>(&self.probe_window);
         if total_duration.is_zero() {
             return 0;
         }
-        (values.len() as f64 / total_duration.as_secs_f64()).ceil() as u32
+        (count as f64 / total_duration.as_secs_f64()).ceil() as u32
     }
 
     pub fn update_rtt(&mut self, new_val: Duration) {
@@ -109,3 +96,21 @@ impl UdtFlow {
         self.peer_delivery_rate = (7 * self.peer_delivery_rate + new_val) / 8;
     }
 }
+
+/// Number and sum of the durations of a non-empty `window` of at most `N` values,
+/// within a factor 8 of their median. The values are sorted on the stack.
+fn filter_outliers<const N: usize>(window: &VecDeque<Duration>) -> (usize, Duration) {
+    let mut values = [Duration::ZERO; N];
+    let values = &mut values[..window.len()];
+    for (value, duration) in values.iter_mut().zip(window) {
+        *value = *duration;
+    }
+    let (_, median, _) = values.select_nth_unstable(values.len() / 2);
+    let median = *median;
+    values
+        .iter()
+        .filter(|x| **x > median / 8 && **x < median * 8)
+        .fold((0, Duration::ZERO), |(count, total), x| {
+            (count + 1, total + *x)
+        })
+}
@@ -0,0 +1,116 @@
+use crate::connection::UdtConnection;
+use crate::stats::UdtStatistics;
+use bytes::Bytes;
+use tokio::io::Result;
+
+/// A set of established connections that can be sent the same messages.
+#[derive(Default)]
+pub struct UdtConnectionGroup {
+    connections: Vec<UdtConnection>,
+}
+
+impl UdtConnectionGroup {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, connection: UdtConnection) {
+        self.connections.push(connection);
+    }
+
+    /// Removes a connection from the group, returning it.
+    pub fn remove(&mut self, socket_id: u32) -> Option<UdtConnection> {
+        let idx = self
+            .connections
+            .iter()
+            .position(|conn| conn.socket_id() == socket_id)?;
+        Some(self.connections.remove(idx))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &UdtConnection> {
+        self.connections.iter()
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Sends `msg` on every connection of the group.
+    ///
+    /// The message is copied once and shared by all connections.
+    /// Returns the result of the send for each connection, identified by its socket id.
+    /// It does not wait for room: a connection whose send buffer is full reports
+    /// `ErrorKind::OutOfMemory` without delaying the others.
+    pub fn broadcast_send(&self, msg: &[u8]) -> Vec<(u32, Result<()>)> {
+        self.broadcast_send_bytes(Bytes::copy_from_slice(msg))
+    }
+
+    /// Sends `msg` on every connection of the group, without copying it.
+    pub(crate) fn broadcast_send_bytes(&self, msg: Bytes) -> Vec<(u32, Result<()>)> {
+        self.connections
+            .iter()
+            .map(|conn| (conn.socket_id(), conn.socket().send_bytes(msg.clone())))
+            .collect()
+    }
+
+    /// Returns the statistics of all connections of the group, combined.
+    #[must_use]
+    pub fn stats(&self) -> UdtStatistics {
+        let mut stats = UdtStatistics::default();
+        for conn in &self.connections {
+            stats += &conn.stats();
+        }
+        stats
+    }
+}
+
+#[tokio::test]
+async fn test_broadcast_send() {
+    use tokio::io::AsyncReadExt;
+    let mut group = UdtConnectionGroup::new();
+    let mut receivers = vec![];
+    for _ in 0..3 {
+        let (sender, receiver) = UdtConnection::pair(None).await.unwrap();
+        group.add(sender);
+        receivers.push(receiver);
+    }
+    let results = group.broadcast_send(b"to all");
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|(_, res)| res.is_ok()));
+    for receiver in &mut receivers {
+        let mut buf = [0; 6];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"to all");
+    }
+}
+
+#[tokio::test]
+async fn test_broadcast_send_member_failing() {
+    use tokio::io::AsyncReadExt;
+    let mut group = UdtConnectionGroup::new();
+    let (healthy, mut healthy_receiver) = UdtConnection::pair(None).await.unwrap();
+    let (failing, _failing_receiver) = UdtConnection::pair(None).await.unwrap();
+    let failing_id = failing.socket_id();
+    failing.close().await;
+    group.add(failing);
+    group.add(healthy);
+
+    // The failure is reported for its member only
+    let results = group.broadcast_send(b"still sent");
+    for (socket_id, res) in &results {
+        assert_eq!(res.is_err(), *socket_id == failing_id);
+    }
+    let mut buf = [0; 10];
+    healthy_receiver.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"still sent");
+
+    assert!(group.remove(failing_id).is_some());
+    assert_eq!(group.len(), 1);
+}
@@ -0,0 +1,201 @@
+//! Extension blocks appended to the UDT4 handshake, e.g. to exchange capability flags
+//! or authentication tokens. Each block is a 16-bit type, a 16-bit length and the value.
+//! They are only sent to peers settling on UDT version 5 or above.
+use crate::error::{UdtError, UdtErrorCode};
+use bytes::{BufMut, Bytes};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{Error, ErrorKind, Result};
+
+/// Extension block holding the proof of the encryption key
+pub(crate) const ENCRYPTION_PROOF_EXTENSION: u16 = 1;
+/// Extension block holding the random salt of the session key
+pub(crate) const ENCRYPTION_SALT_EXTENSION: u16 = 2;
+/// Extension block asking for a resumption token, or presenting it to resume a session
+pub(crate) const RESUMPTION_EXTENSION: u16 = 3;
+/// First extension type available to applications, lower ones are reserved to this crate
+pub const UDT_USER_EXTENSION_MIN: u16 = 0x8000;
+
+/// Handler of an application extension of the handshake,
+/// registered in [`UdtHandshakeExtensions`].
+///
+/// The methods are called synchronously by the workers processing the packets of the
+/// multiplexer, while it holds the state of the connection: they must not block nor
+/// take long (no blocking I/O nor waiting on other connections of the same multiplexer).
+/// Slow checks, e.g. validating a token with a remote service, should be done after
+/// the connection is established, with `UdtConnection::handshake_extension`.
+pub trait UdtHandshakeExtension: Send + Sync {
+    /// Value of the block appended by the initiator to its connection request,
+    /// `None` to leave it out. Called again if the request is repeated.
+    fn request(&self, peer: SocketAddr) -> Option<Bytes>;
+
+    /// Called by the responder with the block of the request, `None` if the initiator
+    /// did not send it. Returns the value of the block of the response, or an error
+    /// rejecting the connection.
+    fn respond(&self, peer: SocketAddr, request: Option<&[u8]>) -> Result<Option<Bytes>>;
+
+    /// Called by the initiator with the block of the response, `None` if the responder
+    /// did not send it. An error fails the connection.
+    fn on_response(&self, peer: SocketAddr, response: Option<&[u8]>) -> Result<()>;
+}
+
+/// Registry of the handlers of the application extensions of the handshake,
+/// set with `UdtConfiguration::handshake_extensions`.
+/// The blocks received from the peer can also be read once connected with
+/// `UdtConnection::handshake_extension`.
+#[derive(Clone, Default)]
+pub struct UdtHandshakeExtensions {
+    handlers: BTreeMap<u16, Arc<dyn UdtHandshakeExtension>>,
+}
+
+impl fmt::Debug for UdtHandshakeExtensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+impl UdtHandshakeExtensions {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler of the blocks of type `ext_type`, replacing the previous one.
+    ///
+    /// # Panics
+    ///
+    /// If `ext_type` is below [`UDT_USER_EXTENSION_MIN`], reserved to this crate.
+    pub fn register(&mut self, ext_type: u16, handler: impl UdtHandshakeExtension + 'static) {
+        assert!(
+            ext_type >= UDT_USER_EXTENSION_MIN,
+            "handshake extension type {ext_type:#06x} is reserved"
+        );
+        self.handlers.insert(ext_type, Arc::new(handler));
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Blocks of a connection request.
+    pub(crate) fn request(&self, peer: SocketAddr) -> Vec<(u16, Bytes)> {
+        self.handlers
+            .iter()
+            .filter_map(|(&ext_type, handler)| Some((ext_type, handler.request(peer)?)))
+            .collect()
+    }
+
+    /// Blocks of the response to a connection request, or the error rejecting it.
+    pub(crate) fn respond(
+        &self,
+        peer: SocketAddr,
+        request: &[(u16, Bytes)],
+    ) -> Result<Vec<(u16, Bytes)>> {
+        let mut response = vec![];
+        for (&ext_type, handler) in &self.handlers {
+            match handler.respond(peer, find(request, ext_type)) {
+                Ok(Some(value)) => response.push((ext_type, value)),
+                Ok(None) => (),
+                Err(err) => return Err(rejection(ext_type, &err)),
+            }
+        }
+        Ok(response)
+    }
+
+    /// Checks the blocks of the response to a connection request.
+    pub(crate) fn on_response(&self, peer: SocketAddr, response: &[(u16, Bytes)]) -> Result<()> {
+        for (&ext_type, handler) in &self.handlers {
+            handler
+                .on_response(peer, find(response, ext_type))
+                .map_err(|err| rejection(ext_type, &err))?;
+        }
+        Ok(())
+    }
+}
+
+fn rejection(ext_type: u16, err: &Error) -> Error {
+    Error::new(
+        ErrorKind::ConnectionRefused,
+        UdtError::new(
+            UdtErrorCode::ConnRej,
+            format!("handshake extension {ext_type:#06x} rejected: {err}"),
+        ),
+    )
+}
+
+/// Value of the first block of type `ext_type`.
+pub(crate) fn find(blocks: &[(u16, Bytes)], ext_type: u16) -> Option<&[u8]> {
+    blocks
+        .iter()
+        .find(|(block_type, _)| *block_type == ext_type)
+        .map(|(_, value)| &value[..])
+}
+
+pub(crate) fn serialize_block(buffer: &mut impl BufMut, ext_type: u16, value: &[u8]) {
+    buffer.put_u16(ext_type);
+    buffer.put_u16(value.len() as u16);
+    buffer.put_slice(value);
+}
+
+/// Size of the encryption proof appended as is, before extension blocks were introduced
+pub(crate) const LEGACY_PROOF_SIZE: usize = 16;
+
+pub(crate) fn deserialize_blocks(mut raw: &[u8]) -> Result<Vec<(u16, Bytes)>> {
+    let mut blocks = vec![];
+    while !raw.is_empty() {
+        let header = raw
+            .get(..4)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated handshake extension"))?;
+        let ext_type = u16::from_be_bytes([header[0], header[1]]);
+        let len = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        let value = raw
+            .get(4..4 + len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated handshake extension"))?;
+        blocks.push((ext_type, Bytes::copy_from_slice(value)));
+        raw = &raw[4 + len..];
+    }
+    Ok(blocks)
+}
+
+#[test]
+fn test_extension_blocks() {
+    struct Token;
+    impl UdtHandshakeExtension for Token {
+        fn request(&self, _peer: SocketAddr) -> Option<Bytes> {
+            Some(Bytes::from_static(b"secret"))
+        }
+        fn respond(&self, _peer: SocketAddr, request: Option<&[u8]>) -> Result<Option<Bytes>> {
+            match request {
+                Some(b"secret") => Ok(Some(Bytes::from_static(b"welcome"))),
+                _ => Err(Error::new(ErrorKind::PermissionDenied, "invalid token")),
+            }
+        }
+        fn on_response(&self, _peer: SocketAddr, response: Option<&[u8]>) -> Result<()> {
+            match response {
+                Some(_) => Ok(()),
+                None => Err(Error::new(ErrorKind::PermissionDenied, "no token")),
+            }
+        }
+    }
+    let peer: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let mut extensions = UdtHandshakeExtensions::new();
+    extensions.register(0x8001, Token);
+
+    let request = extensions.request(peer);
+    let mut raw = vec![];
+    for (ext_type, value) in &request {
+        serialize_block(&mut raw, *ext_type, value);
+    }
+    assert_eq!(raw.len(), 4 + 6);
+    assert_eq!(deserialize_blocks(&raw).unwrap(), request);
+    assert!(deserialize_blocks(&raw[..7]).is_err());
+
+    let response = extensions.respond(peer, &request).unwrap();
+    assert_eq!(find(&response, 0x8001), Some(&b"welcome"[..]));
+    assert!(extensions.on_response(peer, &response).is_ok());
+    assert!(extensions.respond(peer, &[]).is_err());
+    assert!(extensions.on_response(peer, &[]).is_err());
+}
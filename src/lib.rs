@@ -65,26 +65,87 @@ async fn main() -> Result<()> {
 }
 ```
 */
+
+/// Emits a `tracing` event when the "tracing" feature is enabled.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+)
+    };
+}
+
 mod ack_window;
+#[cfg(feature = "cc-trace")]
+mod cc_trace;
 mod common;
 mod configuration;
 mod connection;
 mod control_packet;
+#[cfg(feature = "encryption")]
+mod crypto;
 mod data_packet;
+mod distribution;
+mod error;
+mod event;
+mod event_set;
+mod fallback;
 mod flow;
+mod group;
+mod handshake_extension;
 mod listener;
 mod loss_list;
+mod memory;
+mod message;
+mod metrics;
+#[cfg(target_os = "linux")]
+mod mmsg;
 mod multiplexer;
 mod packet;
+mod path_mtu;
+pub mod process;
 mod queue;
 mod rate_control;
+mod rate_limiter;
+mod reconnect;
 mod seq_number;
+#[cfg(feature = "test-utils")]
+pub mod sim;
 mod socket;
+mod split;
 mod state;
+mod stats;
+mod syn_cookie;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+#[cfg(feature = "test-vectors")]
+pub mod test_vectors;
+mod tsbpd;
 mod udt;
+mod watchdog;
 
-pub use configuration::UdtConfiguration;
+#[cfg(feature = "cc-trace")]
+pub use cc_trace::{CcTraceEntry, CcTraceEvent, CcTraceSink};
+pub use configuration::{
+    Ipv6FlowLabel, RcvOverloadPolicy, UdtAlarmThresholds, UdtConfiguration, UdtEgressRateLimit,
+    UdtHandshakeRateLimit, UdtNegotiatedParams,
+};
 pub use connection::UdtConnection;
-pub use listener::UdtListener;
+#[cfg(feature = "encryption")]
+pub use crypto::UdtEncryptionKey;
+pub use distribution::UdtDistributionSession;
+pub use error::{UdtBackgroundError, UdtError, UdtErrorCode, UdtErrorExt};
+pub use event::{UdtAlarm, UdtConnectionEvent, UdtUserControlHandler};
+pub use event_set::{UdtEventSet, UdtReadiness};
+pub use fallback::UdtFallbackConnection;
+pub use group::UdtConnectionGroup;
+pub use handshake_extension::{
+    UdtHandshakeExtension, UdtHandshakeExtensions, UDT_USER_EXTENSION_MIN,
+};
+pub use listener::{UdtIncoming, UdtListener};
+pub use message::UdtMessageInfo;
+pub use metrics::MetricsSink;
 pub use rate_control::RateControl;
+pub use reconnect::{UdtReconnectPolicy, UdtReconnectingConnection};
 pub use seq_number::SeqNumber;
+pub use split::{OwnedReadHalf, OwnedWriteHalf, ReadHalf, WriteHalf};
+pub use stats::{UdtMultiplexerStatistics, UdtStatistics};
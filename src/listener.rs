@@ -1,49 +1,129 @@
 use crate::configuration::UdtConfiguration;
 use crate::connection::UdtConnection;
+use crate::error::{UdtError, UdtErrorCode};
+use crate::multiplexer::UdtMultiplexer;
 use crate::socket::{SocketType, UdtStatus};
+use crate::stats::UdtMultiplexerStatistics;
 use crate::udt::{SocketRef, Udt};
+use futures_core::Stream;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use tokio::io::{Error, ErrorKind, Result};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::runtime::Handle;
 
 /// An I/O object representing a UTP protocol overlaying UDP
 pub struct UdtListener {
     socket: SocketRef,
+    // Runtime of the multiplexer, closing the listener when it is dropped,
+    // even outside of any runtime
+    runtime: Option<Handle>,
 }
 
 impl UdtListener {
     /// Creates a new UTP over UDP socket and attempts to bind it to `bind_addr`
     pub async fn bind(bind_addr: SocketAddr, config: Option<UdtConfiguration>) -> Result<Self> {
+        Self::_bind(bind_addr, config, SocketType::Stream).await
+    }
+
+    /// Creates a listener accepting datagram (message-oriented) connections,
+    /// opened by peers with [`UdtConnection::connect_datagram`].
+    pub async fn bind_datagram(
+        bind_addr: SocketAddr,
+        config: Option<UdtConfiguration>,
+    ) -> Result<Self> {
+        Self::_bind(bind_addr, config, SocketType::Datagram).await
+    }
+
+    /// Creates a listener on a UDP socket already bound by the caller, e.g. to set
+    /// options not covered by [`UdtConfiguration`] or to run a STUN exchange first.
+    /// The socket must be created within the Tokio runtime used by UDT.
+    pub async fn from_udp_socket(
+        udp_socket: UdpSocket,
+        config: Option<UdtConfiguration>,
+    ) -> Result<Self> {
+        Self::_from_udp_socket(udp_socket, config, SocketType::Stream).await
+    }
+
+    /// Creates a listener accepting datagram connections on a UDP socket already bound
+    /// by the caller, as with [`UdtListener::from_udp_socket`].
+    pub async fn datagram_from_udp_socket(
+        udp_socket: UdpSocket,
+        config: Option<UdtConfiguration>,
+    ) -> Result<Self> {
+        Self::_from_udp_socket(udp_socket, config, SocketType::Datagram).await
+    }
+
+    async fn _from_udp_socket(
+        udp_socket: UdpSocket,
+        config: Option<UdtConfiguration>,
+        socket_type: SocketType,
+    ) -> Result<Self> {
+        let socket = Self::new_socket(config, socket_type).await?;
+        {
+            let mut udt = Udt::get().write().await;
+            udt.attach_udp_socket(&socket, udp_socket)?;
+        }
+        socket.open();
+        Self::listen(socket).await
+    }
+
+    async fn _bind(
+        bind_addr: SocketAddr,
+        config: Option<UdtConfiguration>,
+        socket_type: SocketType,
+    ) -> Result<Self> {
+        let socket = Self::new_socket(config, socket_type).await?;
+        {
+            let mut udt = Udt::get().write().await;
+            udt.bind(socket.socket_id, bind_addr).await?;
+        }
+        Self::listen(socket).await
+    }
+
+    async fn new_socket(
+        config: Option<UdtConfiguration>,
+        socket_type: SocketType,
+    ) -> Result<SocketRef> {
         let socket = {
             let mut udt = Udt::get().write().await;
-            udt.new_socket(SocketType::Stream, config)?.clone()
+            udt.new_socket(socket_type, config)?.clone()
         };
 
         if socket.configuration.read().unwrap().rendezvous {
             return Err(Error::new(
                 ErrorKind::Unsupported,
-                "listen is not supported in rendezvous connection setup",
+                UdtError::new(
+                    UdtErrorCode::RdvNoServ,
+                    "listen is not supported in rendezvous connection setup",
+                ),
             ));
         }
+        Ok(socket)
+    }
 
-        let socket_id = socket.socket_id;
-
-        {
-            let mut udt = Udt::get().write().await;
-            udt.bind(socket_id, bind_addr).await?;
-        }
-
+    pub(crate) async fn listen(socket: SocketRef) -> Result<Self> {
         {
             let socket_ref = socket.clone();
             let mux = socket
                 .multiplexer()
                 .expect("multiplexer is not initialized");
             *mux.listener.write().await = Some(socket_ref);
+            mux.handshake_limiter
+                .set(socket.configuration.read().unwrap().handshake_rate_limit);
             *socket.status.lock().unwrap() = UdtStatus::Listening;
-
-            println!("Now listening on {:?}", bind_addr);
+            trace_event!(info, addr = ?mux.channel.local_addr().ok(), "listening");
         }
 
-        Ok(Self { socket })
+        let runtime = socket
+            .multiplexer()
+            .map(|mux| mux.runtime().clone())
+            .or_else(|| Handle::try_current().ok());
+        Ok(Self { socket, runtime })
     }
 
     pub async fn accept(&self) -> Result<(SocketAddr, UdtConnection)> {
@@ -51,7 +131,10 @@ impl UdtListener {
             if self.socket.configuration.read().unwrap().rendezvous {
                 return Err(Error::new(
                     ErrorKind::Unsupported,
-                    "no 'accept' in rendezvous connection setup",
+                    UdtError::new(
+                        UdtErrorCode::RdvNoServ,
+                        "no 'accept' in rendezvous connection setup",
+                    ),
                 ));
             }
         }
@@ -59,10 +142,10 @@ impl UdtListener {
         let accepted_socket_id = loop {
             let notified = {
                 if self.socket.status() != UdtStatus::Listening {
-                    return Err(Error::new(
-                        ErrorKind::Other,
+                    return Err(Error::other(UdtError::new(
+                        UdtErrorCode::NoListen,
                         "socket is not in listening state",
-                    ));
+                    )));
                 }
 
                 let mut queue = self.socket.queued_sockets.write().await;
@@ -78,25 +161,109 @@ impl UdtListener {
 
         let udt = Udt::get().read().await;
         let accepted_socket = udt.get_socket(accepted_socket_id).ok_or_else(|| {
-            Error::new(
-                ErrorKind::Other,
+            Error::other(UdtError::new(
+                UdtErrorCode::InvSock,
                 "invalid socket id when accepting connection",
-            )
+            ))
         })?;
 
         let peer_addr = accepted_socket.peer_addr().ok_or_else(|| {
-            Error::new(
-                ErrorKind::Other,
+            Error::other(UdtError::new(
+                UdtErrorCode::NoConn,
                 "unknown peer address for accepted connection",
-            )
+            ))
         })?;
 
         Ok((peer_addr, UdtConnection::new(accepted_socket)))
     }
 
+    /// Returns a stream of the accepted connections.
+    /// The stream ends once the listener is closed.
+    #[must_use]
+    pub fn incoming(&self) -> UdtIncoming<'_> {
+        UdtIncoming {
+            listener: self,
+            accept: None,
+        }
+    }
+
+    /// Opens an outgoing connection from the UDP port of this listener,
+    /// so that peers can be both accepted and dialed from a single well-known port.
+    /// The connection uses the configuration of the listener.
+    /// Unlike [`UdtConnection::bind_and_connect`] from the listener address, it does not
+    /// require `reuse_mux`, nor a configuration compatible with the one of the listener.
+    pub async fn connect(&self, addr: impl ToSocketAddrs) -> Result<UdtConnection> {
+        let mux = self.multiplexer()?;
+        let socket = {
+            let config = self.socket.configuration.read().unwrap().clone();
+            let mut udt = Udt::get().write().await;
+            udt.new_socket(self.socket.socket_type, Some(config))?
+                .clone()
+        };
+        socket.set_multiplexer(&mux);
+        UdtConnection::connect_socket(socket, None, addr).await
+    }
+
+    /// Stops listening and closes the connections not accepted yet.
+    /// The UDP socket is released once no connection uses it anymore.
+    /// Dropping the listener does the same in a background task.
+    pub async fn close(&self) {
+        Self::close_socket(&self.socket).await;
+    }
+
+    async fn close_socket(socket: &SocketRef) {
+        let queued = std::mem::take(&mut *socket.queued_sockets.write().await);
+        let pending: Vec<_> = {
+            let udt = Udt::get().read().await;
+            queued
+                .into_iter()
+                .filter_map(|socket_id| udt.get_socket(socket_id))
+                .collect()
+        };
+        for pending_socket in pending {
+            pending_socket.close().await;
+        }
+        Udt::close_socket(socket).await;
+    }
+
     /// Returns the local address this socket is bound to.
+    /// Useful to learn the port picked by the OS when binding to port 0.
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.socket.multiplexer().unwrap().channel.local_addr()
+        self.multiplexer()?.channel.local_addr()
+    }
+
+    /// Returns the effective receive buffer size of the UDP socket used by the listener.
+    /// It may differ from the configured `udp_rcv_buf_size` (e.g. Linux doubles the value
+    /// and caps it to "net.core.rmem_max").
+    pub fn udp_rcv_buf_size(&self) -> Result<usize> {
+        self.multiplexer()?.udp_rcv_buf_size()
+    }
+
+    /// Returns the effective send buffer size of the UDP socket used by the listener.
+    pub fn udp_snd_buf_size(&self) -> Result<usize> {
+        self.multiplexer()?.udp_snd_buf_size()
+    }
+
+    fn multiplexer(&self) -> Result<Arc<UdtMultiplexer>> {
+        self.socket.multiplexer().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotConnected,
+                UdtError::new(UdtErrorCode::UnboundSock, "socket has no multiplexer"),
+            )
+        })
+    }
+
+    /// Returns a snapshot of the statistics of the multiplexer (UDP socket) of the listener.
+    #[must_use]
+    pub fn multiplexer_stats(&self) -> Option<UdtMultiplexerStatistics> {
+        self.socket.multiplexer().map(|mux| mux.stats())
+    }
+
+    /// Number of connection requests refused by the listener, e.g. because of
+    /// `max_connections`, a full backlog or a configuration mismatch.
+    #[must_use]
+    pub fn refused_connections(&self) -> u64 {
+        self.socket.refused_connections.load(Ordering::Relaxed)
     }
 
     /// Returns the ID of the socket (unique to others)
@@ -105,3 +272,101 @@ impl UdtListener {
         self.socket.socket_id
     }
 }
+
+type AcceptFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(SocketAddr, UdtConnection)>> + Send + 'a>>;
+
+/// Stream of connections accepted by a [`UdtListener`], created with [`UdtListener::incoming`].
+pub struct UdtIncoming<'a> {
+    listener: &'a UdtListener,
+    accept: Option<AcceptFuture<'a>>,
+}
+
+impl<'a> Stream for UdtIncoming<'a> {
+    type Item = Result<(SocketAddr, UdtConnection)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.accept.is_none() {
+            if self.listener.socket.status() != UdtStatus::Listening {
+                return Poll::Ready(None);
+            }
+            let listener = self.listener;
+            self.accept = Some(Box::pin(listener.accept()));
+        }
+        let accept = self.accept.as_mut().unwrap();
+        let result = futures_core::ready!(accept.as_mut().poll(cx));
+        self.accept = None;
+        Poll::Ready(Some(result))
+    }
+}
+
+impl Drop for UdtListener {
+    fn drop(&mut self) {
+        if matches!(self.socket.status(), UdtStatus::Closing | UdtStatus::Closed) {
+            return;
+        }
+        let runtime = self.runtime.take().or_else(|| Handle::try_current().ok());
+        if let Some(runtime) = runtime {
+            let socket = self.socket.clone();
+            runtime.spawn(async move { Self::close_socket(&socket).await });
+        }
+    }
+}
+
+#[test]
+fn test_drop_outside_runtime() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let listener = runtime
+        .block_on(UdtListener::bind("127.0.0.1:0".parse().unwrap(), None))
+        .unwrap();
+    let socket = listener.socket.clone();
+    // Dropped from a thread which is not running the runtime
+    drop(listener);
+    runtime.block_on(async {
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !matches!(socket.status(), UdtStatus::Closing | UdtStatus::Closed) {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    });
+}
+
+#[tokio::test]
+async fn test_connect_from_listener_port() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let node = UdtListener::bind("127.0.0.1:0".parse().unwrap(), None)
+        .await
+        .unwrap();
+    let remote = UdtListener::bind("127.0.0.1:0".parse().unwrap(), None)
+        .await
+        .unwrap();
+    let node_addr = node.local_addr().unwrap();
+
+    // The node dials a remote peer, and accepts an inbound peer, on the same UDP port
+    let (outgoing, remote_accepted) =
+        tokio::join!(node.connect(remote.local_addr().unwrap()), remote.accept());
+    let (mut outgoing, (dialed_from, mut remote_side)) =
+        (outgoing.unwrap(), remote_accepted.unwrap());
+    assert_eq!(dialed_from, node_addr);
+    assert_eq!(outgoing.local_addr().unwrap(), node_addr);
+    let (inbound_peer, node_accepted) =
+        tokio::join!(UdtConnection::connect(node_addr, None), node.accept());
+    let (mut inbound_peer, (_, mut inbound)) = (inbound_peer.unwrap(), node_accepted.unwrap());
+
+    // The packets arriving on the shared port reach the connection they belong to
+    outgoing.write_all(b"outgoing").await.unwrap();
+    inbound_peer.write_all(b"inbound").await.unwrap();
+    let mut buf = [0; 8];
+    remote_side.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"outgoing");
+    inbound.read_exact(&mut buf[..7]).await.unwrap();
+    assert_eq!(&buf[..7], b"inbound");
+    remote_side.write_all(b"back").await.unwrap();
+    outgoing.read_exact(&mut buf[..4]).await.unwrap();
+    assert_eq!(&buf[..4], b"back");
+}
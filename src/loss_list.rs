@@ -1,160 +1,288 @@
 use crate::seq_number::SeqNumber;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, VecDeque};
 
+/// Receiver loss list: disjoint ranges of lost sequence numbers, keyed by their first number.
+/// A range wrapping around the maximum sequence number is stored as two ranges, so that
+/// large burst losses cost a single entry and every operation is logarithmic.
 #[derive(Debug)]
 pub(crate) struct LossList {
-    sequences: BTreeMap<SeqNumber, (SeqNumber, SeqNumber)>,
+    ranges: Ranges,
+}
+
+/// Storage of the loss ranges, ordered by their first number.
+///
+/// In fixed-capacity mode the ranges are kept in a sorted, preallocated deque instead of a
+/// tree, which allocates its nodes as it changes. Lost packets lie in the receive window
+/// and two disjoint ranges are separated by at least one received packet, so the deque
+/// never needs more than half the receive buffer size.
+#[derive(Debug)]
+enum Ranges {
+    Tree(BTreeMap<SeqNumber, SeqNumber>),
+    Sorted(VecDeque<(SeqNumber, SeqNumber)>),
+}
+
+impl Ranges {
+    /// Last range starting before `num`, or at `num` if `inclusive`.
+    fn last_before(&self, num: SeqNumber, inclusive: bool) -> Option<(SeqNumber, SeqNumber)> {
+        match self {
+            Self::Tree(tree) => {
+                let mut range = if inclusive {
+                    tree.range(..=num)
+                } else {
+                    tree.range(..num)
+                };
+                range.next_back().map(|(&s, &e)| (s, e))
+            }
+            Self::Sorted(sorted) => {
+                let index = sorted.partition_point(|&(s, _)| s < num || (inclusive && s == num));
+                index.checked_sub(1).map(|index| sorted[index])
+            }
+        }
+    }
+
+    /// First range starting at or after `num`.
+    fn first_from(&self, num: SeqNumber) -> Option<(SeqNumber, SeqNumber)> {
+        match self {
+            Self::Tree(tree) => tree.range(num..).next().map(|(&s, &e)| (s, e)),
+            Self::Sorted(sorted) => sorted
+                .get(sorted.partition_point(|&(s, _)| s < num))
+                .copied(),
+        }
+    }
+
+    fn first(&self) -> Option<(SeqNumber, SeqNumber)> {
+        match self {
+            Self::Tree(tree) => tree.first_key_value().map(|(&s, &e)| (s, e)),
+            Self::Sorted(sorted) => sorted.front().copied(),
+        }
+    }
+
+    /// Inserts the range starting at `start`, or updates its end.
+    fn set(&mut self, start: SeqNumber, end: SeqNumber) {
+        match self {
+            Self::Tree(tree) => {
+                tree.insert(start, end);
+            }
+            Self::Sorted(sorted) => match sorted.binary_search_by_key(&start, |&(s, _)| s) {
+                Ok(index) => sorted[index].1 = end,
+                Err(index) => sorted.insert(index, (start, end)),
+            },
+        }
+    }
+
+    fn remove(&mut self, start: SeqNumber) {
+        match self {
+            Self::Tree(tree) => {
+                tree.remove(&start);
+            }
+            Self::Sorted(sorted) => {
+                if let Ok(index) = sorted.binary_search_by_key(&start, |&(s, _)| s) {
+                    sorted.remove(index);
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn to_vec(&self) -> Vec<(SeqNumber, SeqNumber)> {
+        match self {
+            Self::Tree(tree) => tree.iter().map(|(&s, &e)| (s, e)).collect(),
+            Self::Sorted(sorted) => sorted.iter().copied().collect(),
+        }
+    }
 }
 
 impl LossList {
     pub fn new() -> Self {
         Self {
-            sequences: BTreeMap::new(),
+            ranges: Ranges::Tree(BTreeMap::new()),
+        }
+    }
+
+    /// Loss list for fixed-capacity mode, with room for `capacity` disjoint ranges.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            ranges: Ranges::Sorted(VecDeque::with_capacity(capacity)),
         }
     }
 
     pub fn insert(&mut self, n1: SeqNumber, n2: SeqNumber) {
-        if n1.number() > n2.number() {
+        if n1 > n2 {
             self.insert(n1, SeqNumber::max());
             self.insert(SeqNumber::zero(), n2);
             return;
         }
 
-        let mut n2 = n2;
-        if n2.number() > n1.number() {
-            let mut keys_to_remove = vec![];
-            for (key, (_start, end)) in self.sequences.range((n1 + 1)..=n2) {
-                keys_to_remove.push(*key);
-                if *end > n2 {
-                    n2 = *end;
-                }
-            }
-            for key in keys_to_remove {
-                self.sequences.remove(&key);
+        let (mut start, mut end) = (n1, n2);
+        if let Some((s, e)) = self.ranges.last_before(start, false) {
+            if e.number() + 1 >= start.number() {
+                start = s;
+                end = end.max(e);
             }
         }
-
-        if let Some((_, (_start, end))) = self.sequences.range_mut(..=n1).next_back() {
-            if *end >= n1 - 1 {
-                *end = std::cmp::max(*end, n2);
-                return;
-            }
+        // Ranges starting inside or right after the new one are merged into it
+        while let Some((s, e)) = self
+            .ranges
+            .first_from(start)
+            .filter(|&(s, _)| s.number() <= end.number() + 1)
+        {
+            self.ranges.remove(s);
+            end = end.max(e);
         }
-        self.sequences.insert(n1, (n1, n2));
+        self.ranges.set(start, end);
     }
 
     pub fn remove(&mut self, num: SeqNumber) {
-        if let Some((key, (start, end))) = self.sequences.range_mut(..=num).next_back() {
-            if *start == num {
-                let key = *key;
-                let end = *end;
-                self.sequences.remove(&key);
-                if end > num {
-                    self.sequences.insert(num + 1, (num + 1, end));
-                }
-            } else if *end >= num {
-                let current_end = *end;
-                *end = num - 1;
-                if current_end > num {
-                    self.sequences.insert(num + 1, (num + 1, current_end));
+        self.remove_all(num, num);
+    }
+
+    pub fn remove_all(&mut self, n1: SeqNumber, n2: SeqNumber) {
+        if n1 > n2 {
+            self.remove_all(n1, SeqNumber::max());
+            self.remove_all(SeqNumber::zero(), n2);
+            return;
+        }
+
+        if let Some((s, e)) = self.ranges.last_before(n1, false) {
+            if e >= n1 {
+                self.ranges.set(s, n1 - 1);
+                if e > n2 {
+                    self.ranges.set(n2 + 1, e);
+                    return;
                 }
             }
         }
+        while let Some((s, e)) = self.ranges.first_from(n1).filter(|&(s, _)| s <= n2) {
+            self.ranges.remove(s);
+            if e > n2 {
+                self.ranges.set(n2 + 1, e);
+            }
+        }
     }
 
-    pub fn remove_all(&mut self, n1: SeqNumber, n2: SeqNumber) {
-        if n1 <= n2 {
-            for i in (n1.number()..=n2.number()).rev() {
-                self.remove(i.into());
+    pub fn contains(&self, num: SeqNumber) -> bool {
+        self.ranges
+            .last_before(num, true)
+            .is_some_and(|(_, end)| end >= num)
+    }
+
+    /// Returns the first lost sequence number from `after`, wrapping around.
+    pub fn peek_after(&self, after: SeqNumber) -> Option<SeqNumber> {
+        if self.contains(after) {
+            return Some(after);
+        }
+        self.ranges
+            .first_from(after)
+            .or_else(|| self.ranges.first())
+            .map(|(start, _)| start)
+    }
+
+    /// Number of lost sequence numbers between `n1` and `n2` (included).
+    pub fn count(&self, n1: SeqNumber, n2: SeqNumber) -> usize {
+        if n1 > n2 {
+            return self.count(n1, SeqNumber::max()) + self.count(SeqNumber::zero(), n2);
+        }
+        let overlapping = self.ranges.last_before(n1, false).filter(|&(_, e)| e >= n1);
+        let mut count = overlapping
+            .map(|(_, e)| (e.min(n2).number() - n1.number()) as usize + 1)
+            .unwrap_or(0);
+        let mut from = n1;
+        while let Some((s, e)) = self.ranges.first_from(from).filter(|&(s, _)| s <= n2) {
+            count += (e.min(n2).number() - s.number()) as usize + 1;
+            if e >= n2 {
+                break;
             }
-        } else {
-            self.remove_all(n1, SeqNumber::max());
-            self.remove_all(SeqNumber::zero(), n2);
+            from = e + 1;
         }
+        count
     }
+}
 
-    // fn find(&self, n1: SeqNumber, n2: SeqNumber) -> bool {
-    //     if n1 > n2 {
-    //         return self.find(n1, SeqNumber::max()) || self.find(SeqNumber::zero(), n2);
-    //     }
+/// Sender loss list: coalesced ranges of sequence numbers waiting for retransmission,
+/// ordered from the oldest. Sequence numbers are compared with wrap-around.
+#[derive(Debug, Default)]
+pub(crate) struct SndLossList {
+    ranges: VecDeque<(SeqNumber, SeqNumber)>,
+}
 
-    //     if let Some((_, (_start, end))) = self.sequences.range(..n1).next_back() {
-    //         if *end >= n1 {
-    //             return true;
-    //         }
-    //     } else if let Some((_, (start, _end))) = self.sequences.range(n1..).next() {
-    //         if *start <= n2 {
-    //             return true;
-    //         }
-    //     }
-    //     false
-    // }
+impl SndLossList {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-    pub fn is_empty(&self) -> bool {
-        self.sequences.is_empty()
-    }
-
-    // pub fn get_loss_array(&self, limit: usize) -> Vec<u32> {
-    //     let mut array: Vec<_> = self
-    //         .sequences
-    //         .values()
-    //         .flat_map(|(start, end)| {
-    //             if start == end {
-    //                 vec![start.number()]
-    //             } else {
-    //                 vec![start.number() | 0x8000000, end.number()]
-    //             }
-    //         })
-    //         .take(limit)
-    //         .collect();
-
-    //     if let Some(v) = array.last() {
-    //         if *v >= 0x8000000 {
-    //             array.pop();
-    //         }
-    //     }
-    //     array
-    // }
-
-    pub fn pop_after(&mut self, after: SeqNumber) -> Option<SeqNumber> {
-        if self.sequences.is_empty() {
-            return None;
-        }
-        if let Some((_, (_start, end))) = self.sequences.range(..=after).next_back() {
-            if *end >= after {
-                self.remove(after);
-                return Some(after);
-            }
-        }
-        if let Some((_, (start, _end))) = self.sequences.range(after..).next() {
-            let start = *start;
-            self.remove(start);
-            return Some(start);
-        }
-        if let Some((_, (start, _end))) = self.sequences.iter().next() {
-            let start = *start;
-            self.remove(start);
-            return Some(start);
-        }
-        None
+    /// Loss list for fixed-capacity mode, with room for `capacity` disjoint ranges.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            ranges: VecDeque::with_capacity(capacity),
+        }
     }
 
-    pub fn peek_after(&self, after: SeqNumber) -> Option<SeqNumber> {
-        if self.sequences.is_empty() {
-            return None;
+    pub fn insert(&mut self, start: SeqNumber, end: SeqNumber) {
+        // NAK ranges usually extend the list: merge or append in constant time
+        match self.ranges.back_mut() {
+            None => {
+                self.ranges.push_back((start, end));
+                return;
+            }
+            Some(last) if start - last.1 > 1 => {
+                self.ranges.push_back((start, end));
+                return;
+            }
+            Some(last) if start - last.0 >= 0 => {
+                if end - last.1 > 0 {
+                    last.1 = end;
+                }
+                return;
+            }
+            Some(_) => {}
         }
-        if let Some((_, (_start, end))) = self.sequences.range(..=after).next_back() {
-            if *end >= after {
-                return Some(after);
+
+        let first = self.ranges.partition_point(|&(_, e)| start - e > 1);
+        let (mut start, mut end) = (start, end);
+        let mut last = first;
+        while let Some(&(s, e)) = self.ranges.get(last) {
+            if s - end > 1 {
+                break;
             }
+            if s - start < 0 {
+                start = s;
+            }
+            if e - end > 0 {
+                end = e;
+            }
+            last += 1;
         }
-        if let Some((_, (start, _end))) = self.sequences.range(after..).next() {
-            return Some(*start);
+        self.ranges.drain(first..last);
+        self.ranges.insert(first, (start, end));
+    }
+
+    /// Removes every sequence number up to `seq` (included).
+    pub fn remove_up_to(&mut self, seq: SeqNumber) {
+        while let Some(first) = self.ranges.front_mut() {
+            if first.1 - seq <= 0 {
+                self.ranges.pop_front();
+            } else {
+                if first.0 - seq <= 0 {
+                    first.0 = seq + 1;
+                }
+                break;
+            }
         }
-        if let Some((_, (start, _end))) = self.sequences.iter().next() {
-            return Some(*start);
+    }
+
+    pub fn pop_first(&mut self) -> Option<SeqNumber> {
+        let first = self.ranges.front_mut()?;
+        let seq = first.0;
+        if first.0 == first.1 {
+            self.ranges.pop_front();
+        } else {
+            first.0 = seq + 1;
         }
-        None
+        Some(seq)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
     }
 }
 
@@ -163,21 +291,17 @@ fn test_insert_sequences() {
     let mut loss_list = crate::loss_list::LossList::new();
     loss_list.insert(5.into(), 10.into());
     loss_list.insert(1.into(), 2.into());
-    assert_eq!(loss_list.sequences.len(), 2);
+    assert_eq!(loss_list.ranges.to_vec().len(), 2);
 
-    let items: Vec<_> = loss_list.sequences.clone().into_iter().collect();
-    assert_eq!(
-        items,
-        [
-            (1.into(), (1.into(), 2.into())),
-            (5.into(), (5.into(), 10.into())),
-        ]
-    );
+    let items = loss_list.ranges.to_vec();
+    assert_eq!(items, [(1.into(), 2.into()), (5.into(), 10.into())]);
 
     assert_eq!(loss_list.peek_after(1.into()), Some(1.into()));
     assert_eq!(loss_list.peek_after(4.into()), Some(5.into()));
     assert_eq!(loss_list.peek_after(10.into()), Some(10.into()));
     assert_eq!(loss_list.peek_after(11.into()), Some(1.into()));
+    assert!(loss_list.contains(1.into()));
+    assert!(!loss_list.contains(4.into()));
 }
 
 #[test]
@@ -185,20 +309,21 @@ fn test_insert_overlapping_sequence() {
     let mut loss_list = crate::loss_list::LossList::new();
     loss_list.insert(1.into(), 10.into());
     loss_list.insert(5.into(), 20.into());
-    assert_eq!(loss_list.sequences.len(), 1);
-    let items: Vec<_> = loss_list.sequences.into_iter().collect();
-    assert_eq!(items, [(1.into(), (1.into(), 20.into())),]);
+    assert_eq!(loss_list.ranges.to_vec().len(), 1);
+    let items = loss_list.ranges.to_vec();
+    assert_eq!(items, [(1.into(), 20.into())]);
 }
 
 #[test]
 fn test_insert_with_multiple_overlapping_sequences() {
-    let mut loss_list = crate::loss_list::LossList::new();
-    loss_list.insert(6.into(), 10.into());
-    loss_list.insert(12.into(), 25.into());
-    loss_list.insert(1.into(), 22.into());
-    assert_eq!(loss_list.sequences.len(), 1);
-    let items: Vec<_> = loss_list.sequences.into_iter().collect();
-    assert_eq!(items, [(1.into(), (1.into(), 25.into())),]);
+    for mut loss_list in [LossList::new(), LossList::with_capacity(4)] {
+        loss_list.insert(6.into(), 10.into());
+        loss_list.insert(12.into(), 25.into());
+        loss_list.insert(1.into(), 22.into());
+        assert_eq!(loss_list.ranges.to_vec().len(), 1);
+        let items = loss_list.ranges.to_vec();
+        assert_eq!(items, [(1.into(), 25.into())]);
+    }
 }
 
 #[test]
@@ -206,9 +331,9 @@ fn test_insert_with_bigger_existing_sequence() {
     let mut loss_list = crate::loss_list::LossList::new();
     loss_list.insert(10.into(), 30.into());
     loss_list.insert(10.into(), 20.into());
-    assert_eq!(loss_list.sequences.len(), 1);
-    let items: Vec<_> = loss_list.sequences.into_iter().collect();
-    assert_eq!(items, [(10.into(), (10.into(), 30.into())),]);
+    assert_eq!(loss_list.ranges.to_vec().len(), 1);
+    let items = loss_list.ranges.to_vec();
+    assert_eq!(items, [(10.into(), 30.into())]);
 }
 
 #[test]
@@ -217,15 +342,9 @@ fn test_remove_seq_inside_sequence() {
     loss_list.insert(1.into(), 10.into());
     loss_list.remove(5.into());
 
-    assert_eq!(loss_list.sequences.len(), 2);
-    let items: Vec<_> = loss_list.sequences.into_iter().collect();
-    assert_eq!(
-        items,
-        [
-            (1.into(), (1.into(), 4.into())),
-            (6.into(), (6.into(), 10.into())),
-        ]
-    );
+    assert_eq!(loss_list.ranges.to_vec().len(), 2);
+    let items = loss_list.ranges.to_vec();
+    assert_eq!(items, [(1.into(), 4.into()), (6.into(), 10.into())]);
 }
 
 #[test]
@@ -234,7 +353,42 @@ fn test_remove_first_item() {
     loss_list.insert(1.into(), 10.into());
     loss_list.remove(1.into());
 
-    assert_eq!(loss_list.sequences.len(), 1);
-    let items: Vec<_> = loss_list.sequences.into_iter().collect();
-    assert_eq!(items, [(2.into(), (2.into(), 10.into())),]);
+    assert_eq!(loss_list.ranges.to_vec().len(), 1);
+    let items = loss_list.ranges.to_vec();
+    assert_eq!(items, [(2.into(), 10.into())]);
+}
+
+#[test]
+fn test_remove_and_count_wrapping_burst() {
+    for mut loss_list in [LossList::new(), LossList::with_capacity(4)] {
+        let start = SeqNumber::max() - 999_999;
+        loss_list.insert(start, 1_000_000.into());
+        loss_list.insert(2_000_000.into(), 2_000_000.into());
+        assert_eq!(loss_list.count(start, 1_999_999.into()), 2_000_001);
+
+        loss_list.remove_all(SeqNumber::max() - 9, 9.into());
+        assert_eq!(loss_list.count(start, 2_000_000.into()), 1_999_982);
+        assert!(!loss_list.contains(SeqNumber::zero()));
+        assert!(loss_list.contains(10.into()));
+        assert!(loss_list.contains(SeqNumber::max() - 10));
+        assert_eq!(loss_list.ranges.to_vec().len(), 3);
+    }
+}
+
+#[test]
+fn test_snd_loss_list() {
+    let mut loss_list = crate::loss_list::SndLossList::new();
+    loss_list.insert(10.into(), 20.into());
+    loss_list.insert(21.into(), 25.into());
+    loss_list.insert(30.into(), 30.into());
+    loss_list.insert(2.into(), 4.into());
+    loss_list.insert(5.into(), 12.into());
+    let items: Vec<_> = loss_list.ranges.iter().copied().collect();
+    assert_eq!(items, [(2.into(), 25.into()), (30.into(), 30.into())],);
+
+    loss_list.remove_up_to(24.into());
+    assert_eq!(loss_list.pop_first(), Some(25.into()));
+    assert_eq!(loss_list.pop_first(), Some(30.into()));
+    assert_eq!(loss_list.pop_first(), None);
+    assert!(loss_list.is_empty());
 }
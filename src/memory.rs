@@ -0,0 +1,153 @@
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::{mpsc, Notify};
+
+// Ports of the addresses given to in-memory endpoints, which are never bound
+static NEXT_MEMORY_PORT: AtomicU16 = AtomicU16::new(1);
+
+#[derive(Debug, Default)]
+struct Datagrams {
+    queue: VecDeque<(Bytes, SocketAddr)>,
+    size: usize, // bytes
+}
+
+/// In-memory datagram endpoint, standing for the UDP socket of a multiplexer:
+/// datagrams sent to the address of the peer endpoint are queued to it.
+/// Like a UDP receive buffer, the queue holds up to `capacity` bytes,
+/// datagrams are dropped beyond.
+#[derive(Debug)]
+pub(crate) struct MemoryChannel {
+    local_port: AtomicU16, // on the loopback address
+    peer: OnceCell<Weak<MemoryChannel>>,
+    // Datagrams to the peer go through it when set, e.g. to apply impairments
+    relay: OnceCell<mpsc::UnboundedSender<Bytes>>,
+    datagrams: Mutex<Datagrams>,
+    capacity: usize,
+    drops: AtomicU32,
+    readable: Notify,
+}
+
+impl MemoryChannel {
+    /// Creates two endpoints sending to each other.
+    pub fn pair(capacity: usize) -> (Arc<Self>, Arc<Self>) {
+        let new_endpoint = || {
+            Arc::new(Self {
+                local_port: AtomicU16::new(NEXT_MEMORY_PORT.fetch_add(1, Ordering::Relaxed)),
+                peer: OnceCell::new(),
+                relay: OnceCell::new(),
+                datagrams: Mutex::default(),
+                capacity,
+                drops: AtomicU32::new(0),
+                readable: Notify::new(),
+            })
+        };
+        let (a, b) = (new_endpoint(), new_endpoint());
+        a.peer.set(Arc::downgrade(&b)).unwrap();
+        b.peer.set(Arc::downgrade(&a)).unwrap();
+        (a, b)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, self.local_port.load(Ordering::Relaxed)).into()
+    }
+
+    /// Moves the endpoint to a new address, like a NAT rebinding.
+    #[cfg(test)]
+    pub fn rebind(&self) -> SocketAddr {
+        let port = NEXT_MEMORY_PORT.fetch_add(1, Ordering::Relaxed);
+        self.local_port.store(port, Ordering::Relaxed);
+        self.local_addr()
+    }
+
+    /// Sends the datagrams to the peer through `relay`, which hands them back
+    /// to `deliver`. They are lost once the relay is dropped.
+    #[cfg(feature = "test-utils")]
+    pub fn set_relay(&self, relay: mpsc::UnboundedSender<Bytes>) {
+        self.relay.set(relay).expect("relay already set");
+    }
+
+    /// Queues `data` to the peer endpoint. Like UDP, the datagram is silently lost
+    /// if `addr` is not the peer address or if the peer queue is full.
+    pub fn send_to(&self, data: &[u8], addr: &SocketAddr) -> usize {
+        let is_peer = self
+            .peer
+            .get()
+            .and_then(Weak::upgrade)
+            .is_some_and(|peer| peer.local_addr() == *addr);
+        if is_peer {
+            let data = Bytes::copy_from_slice(data);
+            match self.relay.get() {
+                Some(relay) => {
+                    let _ = relay.send(data);
+                }
+                None => self.deliver(data),
+            }
+        }
+        data.len()
+    }
+
+    /// Queues `data` to the peer endpoint, from the current address of this one.
+    pub fn deliver(&self, data: Bytes) {
+        if let Some(peer) = self.peer.get().and_then(Weak::upgrade) {
+            peer.push(data, self.local_addr());
+        }
+    }
+
+    fn push(&self, data: Bytes, from: SocketAddr) {
+        {
+            let mut datagrams = self.datagrams.lock().unwrap();
+            if datagrams.size + data.len() > self.capacity {
+                self.drops.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            datagrams.size += data.len();
+            datagrams.queue.push_back((data, from));
+        }
+        self.readable.notify_one();
+    }
+
+    /// Takes up to `max` queued datagrams and their source address.
+    pub fn try_recv(&self, max: usize) -> Vec<(Bytes, SocketAddr)> {
+        let mut datagrams = self.datagrams.lock().unwrap();
+        let count = max.min(datagrams.queue.len());
+        let received: Vec<_> = datagrams.queue.drain(..count).collect();
+        datagrams.size -= received.iter().map(|(data, _)| data.len()).sum::<usize>();
+        received
+    }
+
+    /// Waits until a datagram is queued.
+    pub async fn readable(&self) {
+        if self.datagrams.lock().unwrap().queue.is_empty() {
+            self.readable.notified().await;
+        }
+    }
+
+    /// Number of datagrams dropped because the queue was full.
+    pub fn drops(&self) -> u32 {
+        self.drops.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn test_memory_channel() {
+    let (a, b) = MemoryChannel::pair(10);
+    let stranger: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    assert_eq!(a.send_to(b"hello", &b.local_addr()), 5);
+    a.send_to(b"lost", &stranger);
+    // Dropped beyond the capacity of the queue
+    a.send_to(b"world!", &b.local_addr());
+    a.send_to(b"!", &b.local_addr());
+    assert_eq!(b.drops(), 1);
+    assert_eq!(
+        b.try_recv(10),
+        [
+            (Bytes::from_static(b"hello"), a.local_addr()),
+            (Bytes::from_static(b"!"), a.local_addr())
+        ]
+    );
+    assert!(b.try_recv(10).is_empty() && a.try_recv(10).is_empty());
+}
@@ -0,0 +1,16 @@
+use std::time::Duration;
+
+/// Metadata of a message received with
+/// [`UdtConnection::recv_msg_full`](crate::UdtConnection::recv_msg_full)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdtMessageInfo {
+    /// Size of the message payload
+    pub len: usize,
+    /// Message number assigned by the sender
+    pub msg_number: u32,
+    /// Sender timestamp of the first packet of the message, relative to the creation
+    /// of the sending socket. In live mode, the time the message was handed to the socket.
+    pub timestamp: Duration,
+    /// Whether the sender required the message to be delivered in order
+    pub in_order: bool,
+}
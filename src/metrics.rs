@@ -0,0 +1,61 @@
+//! Hook to export connection metrics to a monitoring system (e.g. Prometheus or StatsD),
+//! without depending on a specific metrics library.
+use crate::stats::UdtStatistics;
+use std::fmt;
+
+/// Destination of the metrics of the connections configured with
+/// `UdtConfiguration::metrics_sink`. Metrics are reported from the timers of each
+/// connection every `metrics_interval`: sinks should be cheap, e.g. update atomics.
+///
+/// `socket_id` and `label` identify the connection, and can be used as metric labels.
+pub trait MetricsSink: Send + Sync {
+    /// Reports the total of a counter since the connection was opened.
+    fn counter(&self, name: &'static str, socket_id: u32, label: Option<&str>, value: u64);
+
+    /// Reports the current value of a gauge.
+    fn gauge(&self, name: &'static str, socket_id: u32, label: Option<&str>, value: f64);
+}
+
+impl fmt::Debug for dyn MetricsSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MetricsSink")
+    }
+}
+
+/// Reports a snapshot of the statistics of a connection.
+pub(crate) fn report(
+    sink: &dyn MetricsSink,
+    socket_id: u32,
+    label: Option<&str>,
+    stats: &UdtStatistics,
+) {
+    let counters = [
+        ("udt_packets_sent", stats.pkt_sent),
+        ("udt_bytes_sent", stats.bytes_sent),
+        ("udt_packets_retransmitted", stats.pkt_retrans),
+        ("udt_packets_received", stats.pkt_recv),
+        ("udt_bytes_received", stats.bytes_recv),
+        ("udt_snd_losses", stats.pkt_snd_loss),
+        ("udt_rcv_losses", stats.pkt_rcv_loss),
+        ("udt_acks_sent", stats.ack_sent),
+        ("udt_acks_received", stats.ack_recv),
+        ("udt_naks_sent", stats.nak_sent),
+        ("udt_naks_received", stats.nak_recv),
+    ];
+    for (name, value) in counters {
+        sink.counter(name, socket_id, label, value);
+    }
+
+    let gauges = [
+        ("udt_rtt_seconds", stats.rtt.as_secs_f64()),
+        ("udt_rtt_var_seconds", stats.rtt_var.as_secs_f64()),
+        ("udt_bandwidth_packets", stats.bandwidth.into()),
+        ("udt_snd_rate_packets", stats.snd_rate as f64),
+        ("udt_rcv_rate_packets", stats.rcv_rate.into()),
+        ("udt_snd_buffer_packets", stats.snd_buffer_packets as f64),
+        ("udt_rcv_buffer_packets", stats.rcv_buffer_packets as f64),
+    ];
+    for (name, value) in gauges {
+        sink.gauge(name, socket_id, label, value);
+    }
+}
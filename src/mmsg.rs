@@ -0,0 +1,347 @@
+//! Batched `sendmmsg` and `recvmmsg` calls on message headers kept from one call to the
+//! next: unlike the `nix` wrappers, they do not allocate once batches reached their size.
+
+use crate::queue::{Datagram, PacketBuf};
+use nix::libc::{self, c_int, c_uint, c_void, cmsghdr, in6_pktinfo, in_pktinfo, mmsghdr};
+use std::io::{Error, Result};
+use std::mem::{self, size_of};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+// Room for the control messages of a datagram: packet info, GSO or GRO segment size
+// and the kernel drop counter, with the alignment of their headers
+type CmsgSpace = [u64; 16];
+
+/// Datagram to send: range of the serialized packets and GSO segment size
+pub(crate) type Outgoing = (usize, usize, Option<u16>);
+
+/// Source address of an outgoing datagram (`IP_PKTINFO` / `IPV6_PKTINFO`)
+pub(crate) enum PacketInfo {
+    V4(in_pktinfo),
+    V6(in6_pktinfo),
+}
+
+impl PacketInfo {
+    pub fn new(src_ip: IpAddr, dest: &SocketAddr) -> Self {
+        use nix::libc::{in6_addr, in_addr};
+        match src_ip {
+            IpAddr::V4(ip) => Self::V4(in_pktinfo {
+                ipi_ifindex: 0,
+                ipi_spec_dst: in_addr {
+                    s_addr: u32::from(ip).to_be(),
+                },
+                ipi_addr: in_addr { s_addr: 0 },
+            }),
+            IpAddr::V6(ip) => Self::V6(in6_pktinfo {
+                ipi6_addr: in6_addr {
+                    s6_addr: ip.octets(),
+                },
+                // Link-local source addresses are only valid on the interface of the peer
+                ipi6_ifindex: match dest {
+                    SocketAddr::V6(dest) => dest.scope_id(),
+                    SocketAddr::V4(_) => 0,
+                },
+            }),
+        }
+    }
+}
+
+/// Headers of the datagrams of a `sendmmsg` or `recvmmsg` call.
+#[derive(Default)]
+pub(crate) struct MmsgHeaders {
+    msgs: Vec<mmsghdr>,
+    iovs: Vec<libc::iovec>,
+    addrs: Vec<libc::sockaddr_storage>,
+    cmsgs: Vec<CmsgSpace>,
+}
+
+// SAFETY: the pointers of the headers are set before each call, and only dereferenced
+// by the kernel during the call
+unsafe impl Send for MmsgHeaders {}
+
+impl std::fmt::Debug for MmsgHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MmsgHeaders")
+            .field("capacity", &self.msgs.capacity())
+            .finish()
+    }
+}
+
+impl MmsgHeaders {
+    /// Headers for batches of up to `len` datagrams, allocated up front.
+    pub fn with_capacity(len: usize) -> Self {
+        let mut headers = Self {
+            msgs: Vec::with_capacity(len),
+            iovs: Vec::with_capacity(len),
+            addrs: Vec::with_capacity(len),
+            cmsgs: Vec::with_capacity(len),
+        };
+        headers.reset(len);
+        headers
+    }
+
+    /// Clears `len` headers, the lengths and pointers are set by the caller.
+    fn reset(&mut self, len: usize) {
+        // SAFETY: all-zero headers, buffers and addresses are valid values
+        self.msgs.resize_with(len, || unsafe { mem::zeroed() });
+        self.iovs.resize_with(len, || unsafe { mem::zeroed() });
+        self.addrs.resize_with(len, || unsafe { mem::zeroed() });
+        self.cmsgs.resize(len, [0; 16]);
+        for idx in 0..len {
+            self.msgs[idx] = unsafe { mem::zeroed() };
+            let hdr = &mut self.msgs[idx].msg_hdr;
+            hdr.msg_iov = &mut self.iovs[idx];
+            hdr.msg_iovlen = 1;
+            hdr.msg_name = ptr::addr_of_mut!(self.addrs[idx]).cast();
+            hdr.msg_control = self.cmsgs[idx].as_mut_ptr().cast();
+        }
+    }
+
+    /// Sends the `datagrams` ranges of `data` to `dest` with a single `sendmmsg` call,
+    /// from the source address of `src` if set. A datagram with a segment size is
+    /// split by the kernel (GSO).
+    /// Returns the number of datagrams sent: the kernel may accept only the first ones.
+    pub fn sendmmsg(
+        &mut self,
+        fd: RawFd,
+        data: &[u8],
+        datagrams: &[Outgoing],
+        dest: &SocketAddr,
+        src: Option<&PacketInfo>,
+    ) -> Result<usize> {
+        if datagrams.is_empty() {
+            return Ok(0);
+        }
+        self.reset(datagrams.len());
+        let addr_len = write_sockaddr(dest, &mut self.addrs[0]);
+        let dest_addr = self.addrs[0];
+        for (idx, &(start, end, segment_size)) in datagrams.iter().enumerate() {
+            self.iovs[idx] = libc::iovec {
+                iov_base: data[start..end].as_ptr() as *mut c_void,
+                iov_len: end - start,
+            };
+            self.addrs[idx] = dest_addr;
+            let hdr = &mut self.msgs[idx].msg_hdr;
+            hdr.msg_namelen = addr_len;
+            hdr.msg_controllen = mem::size_of::<CmsgSpace>() as _;
+            // SAFETY: the control buffer has room for both messages
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+                let mut len = 0;
+                match src {
+                    Some(PacketInfo::V4(info)) => {
+                        len += put_cmsg(cmsg, libc::IPPROTO_IP, libc::IP_PKTINFO, info);
+                        cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+                    }
+                    Some(PacketInfo::V6(info)) => {
+                        len += put_cmsg(cmsg, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info);
+                        cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+                    }
+                    None => (),
+                }
+                if let Some(segment_size) = segment_size {
+                    len += put_cmsg(cmsg, libc::SOL_UDP, libc::UDP_SEGMENT, &segment_size);
+                }
+                hdr.msg_controllen = len as _;
+                if len == 0 {
+                    hdr.msg_control = ptr::null_mut();
+                }
+            }
+        }
+        // SAFETY: the headers point to buffers living during the call
+        let sent = unsafe {
+            libc::sendmmsg(
+                fd,
+                self.msgs.as_mut_ptr(),
+                datagrams.len() as c_uint,
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if sent < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// Receives datagrams into `bufs` with a single `recvmmsg` call, and appends
+    /// their description to `datagrams`.
+    /// Returns the kernel drop counter, if reported with the datagrams (`SO_RXQ_OVFL`).
+    pub fn recvmmsg(
+        &mut self,
+        fd: RawFd,
+        bufs: &mut [PacketBuf],
+        datagrams: &mut Vec<Datagram>,
+    ) -> Result<Option<u32>> {
+        self.reset(bufs.len());
+        for (idx, buf) in bufs.iter_mut().enumerate() {
+            self.iovs[idx] = libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            };
+            let hdr = &mut self.msgs[idx].msg_hdr;
+            hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as _;
+            hdr.msg_controllen = size_of::<CmsgSpace>() as _;
+        }
+        // SAFETY: the headers point to buffers living during the call
+        let received = unsafe {
+            libc::recvmmsg(
+                fd,
+                self.msgs.as_mut_ptr(),
+                bufs.len() as c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut drops = None;
+        for msg in &self.msgs[..received as usize] {
+            let hdr = &msg.msg_hdr;
+            let mut local_ip = None;
+            let mut segment_size = None;
+            // SAFETY: the kernel wrote `msg_controllen` bytes of control messages
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+                while let Some(header) = cmsg.as_ref() {
+                    let data = libc::CMSG_DATA(cmsg);
+                    match (header.cmsg_level, header.cmsg_type) {
+                        (libc::SOL_SOCKET, libc::SO_RXQ_OVFL) => {
+                            drops = Some(ptr::read_unaligned(data.cast::<u32>()));
+                        }
+                        (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                            let info = ptr::read_unaligned(data.cast::<in_pktinfo>());
+                            let ip = Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr));
+                            local_ip = Some(ip.into());
+                        }
+                        (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                            let info = ptr::read_unaligned(data.cast::<in6_pktinfo>());
+                            local_ip = Some(Ipv6Addr::from(info.ipi6_addr.s6_addr).into());
+                        }
+                        (libc::SOL_UDP, libc::UDP_GRO) => {
+                            let size = ptr::read_unaligned(data.cast::<c_int>());
+                            segment_size = usize::try_from(size).ok();
+                        }
+                        _ => (),
+                    }
+                    cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+                }
+            }
+            // SAFETY: the kernel wrote the source address in the storage
+            let addr = unsafe { read_sockaddr(&*hdr.msg_name.cast::<libc::sockaddr_storage>()) };
+            if let Some(addr) = addr {
+                datagrams.push((msg.msg_len as usize, addr, local_ip, segment_size));
+            }
+        }
+        Ok(drops)
+    }
+}
+
+/// Sends `data` to `dest` from the source address of `src`, with headers on the stack.
+pub(crate) fn sendmsg_from(
+    fd: RawFd,
+    data: &[u8],
+    dest: &SocketAddr,
+    src: &PacketInfo,
+) -> Result<usize> {
+    // SAFETY: all-zero values are valid, the pointers live during the call
+    unsafe {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let addr_len = write_sockaddr(dest, &mut addr);
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut c_void,
+            iov_len: data.len(),
+        };
+        let mut control: CmsgSpace = [0; 16];
+        let mut hdr: libc::msghdr = mem::zeroed();
+        hdr.msg_name = ptr::addr_of_mut!(addr).cast();
+        hdr.msg_namelen = addr_len;
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = control.as_mut_ptr().cast();
+        hdr.msg_controllen = size_of::<CmsgSpace>() as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        hdr.msg_controllen = match src {
+            PacketInfo::V4(info) => put_cmsg(cmsg, libc::IPPROTO_IP, libc::IP_PKTINFO, info),
+            PacketInfo::V6(info) => put_cmsg(cmsg, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info),
+        } as _;
+        let sent = libc::sendmsg(fd, &hdr, libc::MSG_DONTWAIT);
+        if sent < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+}
+
+/// Writes a control message holding `value`, returns the room it takes.
+///
+/// # Safety
+/// `cmsg` must point to a control buffer with room for the message.
+unsafe fn put_cmsg<T>(cmsg: *mut cmsghdr, level: c_int, ty: c_int, value: &T) -> usize {
+    (*cmsg).cmsg_level = level;
+    (*cmsg).cmsg_type = ty;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<T>() as c_uint) as _;
+    ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<T>(), ptr::read(value));
+    libc::CMSG_SPACE(size_of::<T>() as c_uint) as usize
+}
+
+/// Writes `addr` in `storage`, returns its length.
+fn write_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*addr.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: the storage is large enough and aligned for any address
+            unsafe { ptr::write(ptr::addr_of_mut!(*storage).cast(), sin) };
+            size_of::<libc::sockaddr_in>() as _
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: the storage is large enough and aligned for any address
+            unsafe { ptr::write(ptr::addr_of_mut!(*storage).cast(), sin6) };
+            size_of::<libc::sockaddr_in6>() as _
+        }
+    }
+}
+
+/// Reads an IPv4 or IPv6 address written by the kernel.
+fn read_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            // SAFETY: the storage holds an IPv4 address
+            let sin = unsafe { &*ptr::addr_of!(*storage).cast::<libc::sockaddr_in>() };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            Some(SocketAddrV4::new(ip, u16::from_be(sin.sin_port)).into())
+        }
+        libc::AF_INET6 => {
+            // SAFETY: the storage holds an IPv6 address
+            let sin6 = unsafe { &*ptr::addr_of!(*storage).cast::<libc::sockaddr_in6>() };
+            Some(
+                SocketAddrV6::new(
+                    Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                    u16::from_be(sin6.sin6_port),
+                    sin6.sin6_flowinfo,
+                    sin6.sin6_scope_id, // interface of link-local peers
+                )
+                .into(),
+            )
+        }
+        _ => None,
+    }
+}
@@ -1,27 +1,136 @@
 use super::configuration::UdtConfiguration;
 use super::packet::UdtPacket;
-use crate::queue::{UdtRcvQueue, UdtSndQueue};
-use crate::udt::SocketRef;
-use socket2::{Domain, Socket, Type};
+use crate::error::{UdtError, UdtErrorCode};
+use crate::memory::MemoryChannel;
+#[cfg(target_os = "linux")]
+use crate::mmsg::{MmsgHeaders, Outgoing, PacketInfo};
+use crate::queue::{PacketPool, UdtRcvQueue, UdtSndQueue};
+use crate::rate_limiter::{EgressLimiter, HandshakeLimiter};
+use crate::socket::SocketId;
+use crate::stats::UdtMultiplexerStatistics;
+use crate::udt::{SocketRef, Udt};
+use bytes::BytesMut;
+use socket2::{Domain, SockRef, Socket, Type};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
 use std::io::Result;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::sync::Arc;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
 use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration, Instant};
 
 pub type MultiplexerId = u32;
 
+const MAX_WORKER_RESTARTS: usize = 3;
+const WORKER_RESTART_WINDOW: Duration = Duration::from_secs(10);
+const WORKER_RESTART_DELAY: Duration = Duration::from_millis(100);
+#[cfg(target_os = "linux")]
+const GSO_MAX_SEGMENTS: usize = 64; // UDP_MAX_SEGMENTS in the kernel
+#[cfg(target_os = "linux")]
+const GSO_MAX_SIZE: usize = 65_000; // below the maximum UDP payload over IPv4 and IPv6
+
+// Buffers kept to serialize the packets sent outside of data batches
+const MAX_SND_BUFFERS: usize = 4;
+
+/// Buffers reused by `send_mmsg_to`, to send batches without allocating
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+struct MmsgScratch {
+    data: BytesMut,   // packets serialized back to back
+    ends: Vec<usize>, // end offset of each packet
+    datagrams: Vec<Outgoing>,
+    headers: MmsgHeaders,
+}
+
+#[cfg(target_os = "linux")]
+impl MmsgScratch {
+    fn with_capacity(packets: usize, mss: usize) -> Self {
+        Self {
+            data: BytesMut::with_capacity(packets * mss),
+            ends: Vec::with_capacity(packets),
+            datagrams: Vec::with_capacity(packets),
+            headers: MmsgHeaders::with_capacity(packets),
+        }
+    }
+}
+
+/// Transport of the datagrams of a multiplexer
+#[derive(Debug, Clone)]
+pub(crate) enum Channel {
+    Udp(Arc<UdpSocket>),
+    Memory(Arc<MemoryChannel>),
+}
+
+impl From<UdpSocket> for Channel {
+    fn from(udp_socket: UdpSocket) -> Self {
+        Self::Udp(Arc::new(udp_socket))
+    }
+}
+
+impl Channel {
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Self::Udp(udp_socket) => udp_socket.local_addr(),
+            Self::Memory(memory) => Ok(memory.local_addr()),
+        }
+    }
+
+    /// The UDP socket of the channel, for the operations an in-memory channel does not support.
+    pub fn udp_socket(&self) -> Result<&UdpSocket> {
+        match self {
+            Self::Udp(udp_socket) => Ok(udp_socket),
+            Self::Memory(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                UdtError::new(UdtErrorCode::InvOp, "not supported by an in-memory channel"),
+            )),
+        }
+    }
+
+    pub async fn readable(&self) -> Result<()> {
+        match self {
+            Self::Udp(udp_socket) => udp_socket.readable().await,
+            Self::Memory(memory) => {
+                memory.readable().await;
+                Ok(())
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct UdtMultiplexer {
     pub id: MultiplexerId,
     pub port: u16,
-    pub channel: Arc<UdpSocket>,
+    pub(crate) channel: Channel,
     pub reusable: bool,
     pub mss: u32,
+    pub(crate) bind_device: Option<String>,
+    path_mtu_discovery: bool, // packets are sent with the Don't Fragment flag
+    runtime: Handle,
+    #[cfg(target_os = "linux")]
+    flow_labels: Mutex<BTreeMap<u32, bool>>, // IPv6 flow label -> leased
+    #[cfg(target_os = "linux")]
+    gso: AtomicBool, // UDP segmentation offload enabled and supported
 
     pub(crate) snd_queue: UdtSndQueue,
     pub(crate) rcv_queue: UdtRcvQueue,
+    pub(crate) packet_pool: Arc<PacketPool>, // payloads of the messages to send
     pub listener: RwLock<Option<SocketRef>>,
+    // Sockets setting up a rendezvous connection, by peer address: their handshakes
+    // are sent to socket id 0, like connection requests
+    pub(crate) rendezvous: Mutex<HashMap<SocketAddr, SocketId>>,
+    pub(crate) handshake_addr_mismatches: AtomicU64,
+    pub(crate) handshakes_rejected: AtomicU64,
+    pub(crate) handshake_limiter: HandshakeLimiter,
+    worker_failures: AtomicU64,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    snd_buffers: Mutex<Vec<BytesMut>>, // reused to serialize outgoing packets
+    #[cfg(target_os = "linux")]
+    mmsg: Mutex<MmsgScratch>,
 }
 
 impl UdtMultiplexer {
@@ -35,72 +144,439 @@ impl UdtMultiplexer {
         } else {
             Domain::IPV4
         };
-        tokio::task::spawn_blocking({
-            let config = config.clone();
-            move || {
-                let socket = Socket::new(domain, Type::DGRAM, None)?;
-                socket.set_recv_buffer_size(config.udp_rcv_buf_size)?;
-                socket.set_send_buffer_size(config.udp_snd_buf_size)?;
-                socket.set_reuse_port(config.udp_reuse_port)?;
-                socket.set_nonblocking(true)?;
-                socket.bind(&bind_addr.into())?;
-                UdpSocket::from_std(socket.into())
+        // The socket is registered with the reactor of the runtime running the closure
+        config
+            .runtime()
+            .spawn_blocking({
+                let config = config.clone();
+                move || {
+                    let socket = Socket::new(domain, Type::DGRAM, None)?;
+                    socket.set_recv_buffer_size(config.udp_rcv_buf_size)?;
+                    socket.set_send_buffer_size(config.udp_snd_buf_size)?;
+                    socket.set_reuse_port(config.udp_reuse_port)?;
+                    if bind_addr.is_ipv6() {
+                        // The OS default varies (e.g. dual-stack on Linux, not on BSDs)
+                        socket.set_only_v6(config.ipv6_only)?;
+                    }
+                    if let Some(ttl) = config.udp_ttl {
+                        if bind_addr.is_ipv4() {
+                            socket.set_ttl(ttl)?;
+                        } else {
+                            socket.set_unicast_hops_v6(ttl)?;
+                        }
+                    }
+                    if let Some(tos) = config.udp_tos {
+                        if bind_addr.is_ipv4() {
+                            socket.set_tos(tos)?;
+                        } else {
+                            set_traffic_class(&socket, tos)?;
+                        }
+                    }
+                    #[cfg(target_os = "linux")]
+                    {
+                        use std::os::unix::io::AsRawFd;
+                        Self::set_receive_options(socket.as_raw_fd(), bind_addr, &config)?;
+                    }
+                    #[cfg(target_os = "linux")]
+                    if config.path_mtu_discovery {
+                        use std::os::unix::io::AsRawFd;
+                        set_dont_fragment(socket.as_raw_fd(), bind_addr.is_ipv6())?;
+                    }
+                    #[cfg(target_os = "linux")]
+                    if let Some(device) = &config.bind_device {
+                        use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
+                        use std::os::unix::io::AsRawFd;
+                        setsockopt(socket.as_raw_fd(), BindToDevice, &device.into())?;
+                    }
+                    #[cfg(not(target_os = "linux"))]
+                    let bind_addr = match &config.bind_device {
+                        Some(device) if bind_addr.ip().is_unspecified() => {
+                            Self::interface_addr(device, bind_addr)?
+                        }
+                        _ => bind_addr,
+                    };
+                    socket.set_nonblocking(true)?;
+                    socket.bind(&bind_addr.into())?;
+                    UdpSocket::from_std(socket.into())
+                }
+            })
+            .await?
+    }
+
+    /// Sets the options of the UDP socket needed by the receive queue.
+    #[cfg(target_os = "linux")]
+    fn set_receive_options(
+        fd: std::os::unix::io::RawFd,
+        bind_addr: SocketAddr,
+        config: &UdtConfiguration,
+    ) -> Result<()> {
+        use nix::sys::socket::setsockopt;
+        use nix::sys::socket::sockopt::{
+            Ipv4PacketInfo, Ipv6RecvPacketInfo, RxqOvfl, UdpGroSegment,
+        };
+
+        // Report the kernel drop counter with received datagrams
+        setsockopt(fd, RxqOvfl, &1)?;
+        if bind_addr.ip().is_unspecified() {
+            // Report the destination address of received datagrams,
+            // so that replies leave from the address the peer contacted
+            if bind_addr.is_ipv4() {
+                setsockopt(fd, Ipv4PacketInfo, &true)?;
+            } else {
+                setsockopt(fd, Ipv6RecvPacketInfo, &true)?;
             }
-        })
-        .await?
+        }
+        if config.udp_gro {
+            // Optional: datagrams are received one by one without it
+            static GRO_UNSUPPORTED: std::sync::Once = std::sync::Once::new();
+            if let Err(_err) = setsockopt(fd, UdpGroSegment, &true) {
+                GRO_UNSUPPORTED.call_once(|| {
+                    trace_event!(warn, error = %_err, "failed to enable UDP GRO");
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the kernel supports UDP segmentation offload (Linux 4.18+).
+    #[cfg(target_os = "linux")]
+    fn gso_supported(channel: &UdpSocket) -> bool {
+        use nix::sys::socket::{getsockopt, sockopt::UdpGsoSegment};
+        use std::os::unix::io::AsRawFd;
+        getsockopt(channel.as_raw_fd(), UdpGsoSegment).is_ok()
+    }
+
+    /// Finds the address of the interface named `device`, in the family of `bind_addr`.
+    #[cfg(not(target_os = "linux"))]
+    fn interface_addr(device: &str, bind_addr: SocketAddr) -> Result<SocketAddr> {
+        use crate::error::{UdtError, UdtErrorCode};
+        use nix::ifaddrs::getifaddrs;
+        use std::io::{Error, ErrorKind};
+        use std::net::IpAddr;
+
+        let ip = getifaddrs()?
+            .filter(|ifaddr| ifaddr.interface_name == device)
+            .filter_map(|ifaddr| ifaddr.address)
+            .find_map(|addr| -> Option<IpAddr> {
+                if bind_addr.is_ipv4() {
+                    addr.as_sockaddr_in()
+                        .map(|addr| Ipv4Addr::from(addr.ip()).into())
+                } else {
+                    addr.as_sockaddr_in6().map(|addr| addr.ip().into())
+                }
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    UdtError::new(
+                        UdtErrorCode::SockFail,
+                        format!("no address found for interface {}", device),
+                    ),
+                )
+            })?;
+        Ok(SocketAddr::new(ip, bind_addr.port()))
     }
 
     pub(crate) async fn new(
         id: MultiplexerId,
         config: &UdtConfiguration,
-    ) -> Result<(MultiplexerId, Arc<UdtMultiplexer>)> {
+        egress_limiter: Arc<EgressLimiter>,
+    ) -> Result<Arc<UdtMultiplexer>> {
         let udp_socket = Self::new_udp_socket(config, None).await?;
-        let channel = Arc::new(udp_socket);
-        let port = channel.local_addr()?.port();
-
-        let mux = Self {
-            id,
-            port,
-            reusable: config.reuse_mux,
-            mss: config.mss,
-            channel: channel.clone(),
-            snd_queue: UdtSndQueue::new(),
-            rcv_queue: UdtRcvQueue::new(channel, config.mss),
-            listener: RwLock::new(None),
-        };
-
-        let mux = Arc::new(mux);
-        mux.rcv_queue.set_multiplexer(&mux);
-        Ok((id, mux))
+        Self::with_channel(id, udp_socket, config, config.reuse_mux, egress_limiter)
     }
 
     pub(crate) async fn bind(
         id: MultiplexerId,
         bind_addr: SocketAddr,
         config: &UdtConfiguration,
-    ) -> Result<(MultiplexerId, Arc<UdtMultiplexer>)> {
+        egress_limiter: Arc<EgressLimiter>,
+    ) -> Result<Arc<UdtMultiplexer>> {
         let udp_socket = Self::new_udp_socket(config, Some(bind_addr)).await?;
-        let port = udp_socket.local_addr()?.port();
+        Self::with_channel(id, udp_socket, config, config.reuse_mux, egress_limiter)
+    }
+
+    /// Creates a multiplexer on a UDP socket bound by the user.
+    /// Only the buffer sizes of the configuration are applied: other options set by the user
+    /// (SO_REUSEPORT, TTL, bound device...) are kept as is.
+    /// The multiplexer is never shared with sockets binding the same port.
+    pub(crate) fn from_udp_socket(
+        id: MultiplexerId,
+        udp_socket: UdpSocket,
+        config: &UdtConfiguration,
+        egress_limiter: Arc<EgressLimiter>,
+    ) -> Result<Arc<UdtMultiplexer>> {
+        let socket = SockRef::from(&udp_socket);
+        socket.set_recv_buffer_size(config.udp_rcv_buf_size)?;
+        socket.set_send_buffer_size(config.udp_snd_buf_size)?;
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+            let local_addr = udp_socket.local_addr()?;
+            Self::set_receive_options(udp_socket.as_raw_fd(), local_addr, config)?;
+        }
+        Self::with_channel(id, udp_socket, config, false, egress_limiter)
+    }
+
+    /// Creates a multiplexer exchanging datagrams with the peer of an in-memory `channel`.
+    pub(crate) fn in_memory(
+        id: MultiplexerId,
+        channel: Arc<MemoryChannel>,
+        config: &UdtConfiguration,
+        egress_limiter: Arc<EgressLimiter>,
+    ) -> Result<Arc<UdtMultiplexer>> {
+        Self::with_channel(id, Channel::Memory(channel), config, false, egress_limiter)
+    }
+
+    /// Runtime running the workers of the multiplexer.
+    pub(crate) fn runtime(&self) -> &Handle {
+        &self.runtime
+    }
+
+    fn with_channel(
+        id: MultiplexerId,
+        channel: impl Into<Channel>,
+        config: &UdtConfiguration,
+        reusable: bool,
+        egress_limiter: Arc<EgressLimiter>,
+    ) -> Result<Arc<UdtMultiplexer>> {
+        let channel = channel.into();
+        let port = channel.local_addr()?.port();
+        let in_memory = matches!(channel, Channel::Memory(_));
 
-        let channel = Arc::new(udp_socket);
         let mux = Self {
             id,
             port,
-            reusable: config.reuse_mux,
+            reusable,
             mss: config.mss,
+            bind_device: config.bind_device.clone(),
+            path_mtu_discovery: config.path_mtu_discovery,
+            runtime: config.runtime(),
+            #[cfg(target_os = "linux")]
+            gso: AtomicBool::new(
+                config.udp_gso
+                    && matches!(&channel, Channel::Udp(udp_socket) if Self::gso_supported(udp_socket)),
+            ),
             channel: channel.clone(),
-            snd_queue: UdtSndQueue::new(),
-            rcv_queue: UdtRcvQueue::new(channel, config.mss),
+            snd_queue: UdtSndQueue::new(
+                egress_limiter,
+                in_memory,
+                config.fixed_capacity.then_some(config.snd_batch_size),
+            ),
+            rcv_queue: UdtRcvQueue::new(
+                channel,
+                config.rcv_datagram_size(),
+                config.udp_rcv_batch_size,
+                config.udp_rcv_busy_poll,
+                config.packet_pool_size,
+                config.fixed_capacity,
+            ),
+            packet_pool: PacketPool::new(
+                config.mss as usize,
+                config.packet_pool_size,
+                config.fixed_capacity,
+            ),
             listener: RwLock::new(None),
+            rendezvous: Mutex::new(HashMap::new()),
+            handshake_addr_mismatches: AtomicU64::new(0),
+            handshakes_rejected: AtomicU64::new(0),
+            handshake_limiter: HandshakeLimiter::default(),
+            worker_failures: AtomicU64::new(0),
+            workers: Mutex::new(vec![]),
+            snd_buffers: Mutex::new(if config.fixed_capacity {
+                (0..MAX_SND_BUFFERS)
+                    .map(|_| BytesMut::with_capacity(config.mss as usize))
+                    .collect()
+            } else {
+                vec![]
+            }),
+            #[cfg(target_os = "linux")]
+            mmsg: Mutex::new(if config.fixed_capacity {
+                MmsgScratch::with_capacity(config.snd_batch_size, config.mss as usize)
+            } else {
+                MmsgScratch::default()
+            }),
+            #[cfg(target_os = "linux")]
+            flow_labels: Mutex::new(BTreeMap::new()),
         };
 
         let mux = Arc::new(mux);
         mux.rcv_queue.set_multiplexer(&mux);
-        Ok((id, mux))
+        Ok(mux)
+    }
+
+    /// Whether a socket binding `bind_addr` with `config` can use this multiplexer,
+    /// e.g. to dial peers from the UDP port of a listener.
+    /// The addresses must be in the same family, unless the socket is a dual-stack IPv6 one.
+    pub(crate) fn can_share(&self, bind_addr: SocketAddr, config: &UdtConfiguration) -> bool {
+        let Channel::Udp(udp_socket) = &self.channel else {
+            return false;
+        };
+        let Ok(local_addr) = udp_socket.local_addr() else {
+            return false;
+        };
+        let dual_stack = local_addr.ip().is_unspecified()
+            && SockRef::from(&**udp_socket)
+                .only_v6()
+                .is_ok_and(|only_v6| !only_v6);
+        self.reusable
+            && self.port == bind_addr.port()
+            && (local_addr.is_ipv4() == bind_addr.is_ipv4() || dual_stack)
+            && (local_addr.ip() == bind_addr.ip()
+                || local_addr.ip().is_unspecified()
+                || bind_addr.ip().is_unspecified())
+            && self.mss == config.mss
+            && self.bind_device == config.bind_device
+            && self.path_mtu_discovery == config.path_mtu_discovery
+            && self.packet_pool.is_fixed() == config.fixed_capacity
     }
 
-    pub(crate) async fn send_to(&self, addr: &SocketAddr, packet: UdtPacket) -> Result<usize> {
-        self.channel.send_to(&packet.serialize(), addr).await
+    /// Gets the receive buffer size of the UDP socket, as applied by the kernel.
+    pub(crate) fn udp_rcv_buf_size(&self) -> Result<usize> {
+        SockRef::from(self.channel.udp_socket()?).recv_buffer_size()
+    }
+
+    /// Gets the send buffer size of the UDP socket, as applied by the kernel.
+    pub(crate) fn udp_snd_buf_size(&self) -> Result<usize> {
+        SockRef::from(self.channel.udp_socket()?).send_buffer_size()
+    }
+
+    /// Returns a snapshot of the statistics of the multiplexer.
+    pub(crate) fn stats(&self) -> UdtMultiplexerStatistics {
+        UdtMultiplexerStatistics {
+            udp_rcv_drops: self.rcv_queue.udp_rcv_drops(),
+            handshake_addr_mismatches: self.handshake_addr_mismatches.load(Ordering::Relaxed),
+            handshakes_dropped: self.handshake_limiter.dropped(),
+            handshakes_rejected: self.handshakes_rejected.load(Ordering::Relaxed),
+            stray_packets: self.rcv_queue.stray_packets(),
+            worker_failures: self.worker_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub(crate) async fn send_to(
+        &self,
+        addr: &SocketAddr,
+        packet: UdtPacket,
+        src_ip: Option<IpAddr>,
+    ) -> Result<usize> {
+        let udp_socket = match &self.channel {
+            Channel::Udp(udp_socket) => udp_socket,
+            Channel::Memory(memory) => return Ok(self.send_in_memory(memory, addr, [packet])),
+        };
+        let addr = &self.lease_flow_label(udp_socket, addr);
+        let mut data = self.take_snd_buffer();
+        packet.serialize_into(&mut data);
+        let result = match src_ip {
+            Some(src_ip) => Self::send_msg_from(udp_socket, &data, addr, src_ip).await,
+            None => udp_socket.send_to(&data, addr).await,
+        };
+        self.release_snd_buffer(data);
+        result
+    }
+
+    /// Sends `data` from the local address `src_ip`.
+    #[cfg(target_os = "linux")]
+    async fn send_msg_from(
+        udp_socket: &UdpSocket,
+        data: &[u8],
+        addr: &SocketAddr,
+        src_ip: IpAddr,
+    ) -> Result<usize> {
+        use std::io::ErrorKind;
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::Interest;
+
+        let info = PacketInfo::new(src_ip, addr);
+        loop {
+            udp_socket.writable().await?;
+            match udp_socket.try_io(Interest::WRITABLE, || {
+                crate::mmsg::sendmsg_from(udp_socket.as_raw_fd(), data, addr, &info)
+            }) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                res => return res,
+            }
+        }
+    }
+
+    /// Linux only sends the IPv6 flow labels leased by the socket:
+    /// leases the label of `addr`, or removes it if the lease is refused.
+    #[cfg(target_os = "linux")]
+    fn lease_flow_label(&self, udp_socket: &UdpSocket, addr: &SocketAddr) -> SocketAddr {
+        use std::os::unix::io::AsRawFd;
+        let mut addr = match addr {
+            SocketAddr::V6(addr) if addr.flowinfo() != 0 => *addr,
+            _ => return *addr,
+        };
+        let label = addr.flowinfo();
+        let leased = *self
+            .flow_labels
+            .lock()
+            .unwrap()
+            .entry(label)
+            .or_insert_with(|| {
+                lease_flow_label(udp_socket.as_raw_fd(), label, &addr).map_or_else(
+                    |_err| {
+                        trace_event!(debug, label, error = %_err, "failed to lease IPv6 flow label");
+                        false
+                    },
+                    |_| true,
+                )
+            });
+        if !leased {
+            addr.set_flowinfo(0);
+        }
+        addr.into()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) async fn send_to(
+        &self,
+        addr: &SocketAddr,
+        packet: UdtPacket,
+        _src_ip: Option<IpAddr>,
+    ) -> Result<usize> {
+        let udp_socket = match &self.channel {
+            Channel::Udp(udp_socket) => udp_socket,
+            Channel::Memory(memory) => return Ok(self.send_in_memory(memory, addr, [packet])),
+        };
+        let mut data = self.take_snd_buffer();
+        packet.serialize_into(&mut data);
+        let result = udp_socket.send_to(&data, addr).await;
+        self.release_snd_buffer(data);
+        result
+    }
+
+    /// Queues `packets` to the peer of an in-memory channel.
+    fn send_in_memory(
+        &self,
+        memory: &MemoryChannel,
+        addr: &SocketAddr,
+        packets: impl IntoIterator<Item = UdtPacket>,
+    ) -> usize {
+        let mut data = self.take_snd_buffer();
+        let mut sent = 0;
+        for packet in packets {
+            data.clear();
+            packet.serialize_into(&mut data);
+            sent += memory.send_to(&data, addr);
+        }
+        self.release_snd_buffer(data);
+        sent
+    }
+
+    /// Takes a buffer to serialize outgoing packets, to avoid an allocation per packet.
+    /// Senders beyond `MAX_SND_BUFFERS` concurrent ones get an empty buffer.
+    fn take_snd_buffer(&self) -> BytesMut {
+        self.snd_buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    fn release_snd_buffer(&self, mut buffer: BytesMut) {
+        buffer.clear();
+        let mut buffers = self.snd_buffers.lock().unwrap();
+        if buffers.len() < MAX_SND_BUFFERS {
+            buffers.push(buffer);
+        }
     }
 
     #[cfg(target_os = "linux")]
@@ -108,39 +584,119 @@ impl UdtMultiplexer {
         &self,
         addr: &SocketAddr,
         packets: impl Iterator<Item = UdtPacket>,
+        src_ip: Option<IpAddr>,
     ) -> Result<usize> {
-        use nix::sys::socket::{sendmmsg, MsgFlags, SendMmsgData, SockaddrStorage};
-        use std::io::IoSlice;
-        use std::os::unix::io::AsRawFd;
-        use tokio::io::{Error, ErrorKind, Interest};
-        let data: Vec<_> = packets.map(|p| p.serialize()).collect();
-        let dest: SockaddrStorage = (*addr).into();
-        let buffers: Vec<SendMmsgData<_, _, _>> = data
-            .iter()
-            .map(|packet| SendMmsgData {
-                iov: [IoSlice::new(packet)],
-                cmsgs: &[],
-                addr: Some(dest),
-                _lt: Default::default(),
-            })
-            .collect();
-        self.channel.writable().await?;
+        let udp_socket = match &self.channel {
+            Channel::Udp(udp_socket) => udp_socket,
+            Channel::Memory(memory) => return Ok(self.send_in_memory(memory, addr, packets)),
+        };
+        // Concurrent senders get empty buffers
+        let mut scratch = std::mem::take(&mut *self.mmsg.lock().unwrap());
+        let MmsgScratch {
+            data,
+            ends,
+            datagrams,
+            headers,
+        } = &mut scratch;
+        for packet in packets {
+            packet.serialize_into(data);
+            ends.push(data.len());
+        }
+        let dest = self.lease_flow_label(udp_socket, addr);
+        let info = src_ip.map(|src_ip| PacketInfo::new(src_ip, addr));
+        Self::gso_segments(ends, self.gso.load(Ordering::Relaxed), datagrams);
         let sent = self
-            .channel
-            .try_io(Interest::WRITABLE, || {
-                let sock_fd = self.channel.as_raw_fd();
-                let sent: usize = sendmmsg(sock_fd, &buffers, MsgFlags::MSG_DONTWAIT)
-                    .map_err(|err| {
-                        if err == nix::errno::Errno::EWOULDBLOCK {
-                            return Error::new(ErrorKind::WouldBlock, "sendmmsg would block");
-                        }
-                        Error::new(ErrorKind::Other, err)
-                    })?
-                    .into_iter()
-                    .sum();
-                Ok(sent)
-            })
-            .unwrap_or(0);
+            .send_mmsg_batch(udp_socket, headers, data, datagrams, &dest, info.as_ref())
+            .await;
+        data.clear();
+        ends.clear();
+        datagrams.clear();
+        *self.mmsg.lock().unwrap() = scratch;
+        sent
+    }
+
+    /// Splits the serialized packets ending at `ends` into the `datagrams` to send:
+    /// with GSO, consecutive packets of the same size (the last one may be shorter)
+    /// are sent as a single buffer segmented by the kernel.
+    /// Appends the range and the segment size (if segmented) of each datagram.
+    #[cfg(target_os = "linux")]
+    fn gso_segments(ends: &[usize], gso: bool, datagrams: &mut Vec<Outgoing>) {
+        let mut start = 0;
+        // Segment size and number of segments of the last datagram, while it can grow
+        let mut open: Option<(usize, usize)> = None;
+        for &end in ends {
+            let len = end - start;
+            match (datagrams.last_mut(), open) {
+                (Some((first, last, segment_size)), Some((size, count)))
+                    if len <= size && count < GSO_MAX_SEGMENTS && end - *first <= GSO_MAX_SIZE =>
+                {
+                    *last = end;
+                    *segment_size = u16::try_from(size).ok();
+                    open = (len == size).then_some((size, count + 1));
+                }
+                _ => {
+                    datagrams.push((start, end, None));
+                    open = gso.then_some((len, 1));
+                }
+            }
+            start = end;
+        }
+    }
+
+    /// Sends the `datagrams` ranges of `data`, with as few `sendmmsg` calls as possible.
+    /// The kernel may accept only part of the batch when the socket buffer fills up:
+    /// the rest is sent once the socket is writable again.
+    #[cfg(target_os = "linux")]
+    async fn send_mmsg_batch(
+        &self,
+        udp_socket: &UdpSocket,
+        headers: &mut MmsgHeaders,
+        data: &[u8],
+        datagrams: &[Outgoing],
+        dest: &SocketAddr,
+        src: Option<&PacketInfo>,
+    ) -> Result<usize> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::{ErrorKind, Interest};
+        let mut offset = 0;
+        let mut sent = 0;
+        while offset < datagrams.len() {
+            udp_socket.writable().await?;
+            let res = udp_socket.try_io(Interest::WRITABLE, || {
+                headers.sendmmsg(
+                    udp_socket.as_raw_fd(),
+                    data,
+                    &datagrams[offset..],
+                    dest,
+                    src,
+                )
+            });
+            match res {
+                Ok(count) if count > 0 => {
+                    sent += datagrams[offset..offset + count]
+                        .iter()
+                        .map(|(start, end, _)| end - start)
+                        .sum::<usize>();
+                    offset += count;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                Err(err)
+                    if err.raw_os_error() == Some(nix::libc::EIO)
+                        && self.gso.swap(false, Ordering::Relaxed) =>
+                {
+                    // The network device does not support segmentation offload,
+                    // reported once as GSO stays disabled on this multiplexer
+                    trace_event!(
+                        warn,
+                        mux = self.id,
+                        "UDP GSO failed, sending datagrams one by one"
+                    );
+                    break;
+                }
+                // Unsent packets are retransmitted after a loss report
+                _ => break,
+            }
+        }
         Ok(sent)
     }
 
@@ -149,12 +705,27 @@ impl UdtMultiplexer {
         &self,
         addr: &SocketAddr,
         packets: impl Iterator<Item = UdtPacket>,
+        _src_ip: Option<IpAddr>,
     ) -> Result<usize> {
-        self.channel.writable().await?;
+        let udp_socket = match &self.channel {
+            Channel::Udp(udp_socket) => udp_socket,
+            Channel::Memory(memory) => return Ok(self.send_in_memory(memory, addr, packets)),
+        };
+        udp_socket.writable().await?;
+        let mut data = self.take_snd_buffer();
         let mut sent = 0;
-        for data in packets.map(|p| p.serialize()) {
-            sent += self.channel.send_to(&data, addr).await?;
+        for packet in packets {
+            data.clear();
+            packet.serialize_into(&mut data);
+            match udp_socket.send_to(&data, addr).await {
+                Ok(len) => sent += len,
+                Err(err) => {
+                    self.release_snd_buffer(data);
+                    return Err(err);
+                }
+            }
         }
+        self.release_snd_buffer(data);
         Ok(sent)
     }
 
@@ -165,10 +736,253 @@ impl UdtMultiplexer {
     // }
 
     pub fn run(mux: Arc<Self>) {
-        tokio::spawn({
-            let mux = mux.clone();
-            async move { mux.rcv_queue.worker().await.unwrap() }
+        let rcv_worker = Self::supervise(&mux, "receive", |mux| async move {
+            mux.rcv_queue.worker().await
+        });
+        let snd_worker = Self::supervise(&mux, "send", |mux| async move {
+            mux.snd_queue.worker(Arc::downgrade(&mux)).await
         });
-        tokio::spawn(async move { mux.snd_queue.worker().await.unwrap() });
+        mux.workers.lock().unwrap().extend([rcv_worker, snd_worker]);
+    }
+
+    /// Runs a queue worker, restarting it when it fails or panics.
+    /// If it keeps failing, the sockets of the multiplexer are broken
+    /// instead of waiting forever for traffic that will never flow.
+    fn supervise<F, Fut>(mux: &Arc<Self>, name: &'static str, worker: F) -> JoinHandle<()>
+    where
+        F: Fn(Arc<Self>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let mux = mux.clone();
+        mux.runtime.clone().spawn(async move {
+            let mut failures: VecDeque<Instant> = VecDeque::new();
+            loop {
+                // Aborting the supervisor aborts the running worker as well
+                let worker = worker(mux.clone());
+                #[cfg(feature = "tracing")]
+                let worker = tracing::Instrument::instrument(
+                    worker,
+                    tracing::debug_span!("udt_worker", worker = name, mux = mux.id),
+                );
+                let mut handle = AbortOnDrop(mux.runtime.spawn(worker));
+                let cause = match (&mut handle.0).await {
+                    Ok(Ok(())) => return,
+                    Ok(Err(err)) => err.to_string(),
+                    Err(err) if err.is_panic() => {
+                        let panic = err.into_panic();
+                        let msg = panic
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        format!("panicked: {}", msg)
+                    }
+                    Err(_) => return,
+                };
+                mux.worker_failures.fetch_add(1, Ordering::Relaxed);
+                Udt::report_error(
+                    None,
+                    Some(mux.id),
+                    None,
+                    std::io::Error::other(UdtError::new(
+                        UdtErrorCode::Thread,
+                        format!("{} worker failed: {}", name, cause),
+                    )),
+                );
+
+                let now = Instant::now();
+                failures.retain(|ts| now.duration_since(*ts) < WORKER_RESTART_WINDOW);
+                failures.push_back(now);
+                if failures.len() > MAX_WORKER_RESTARTS {
+                    Udt::get().read().await.break_multiplexer_sockets(
+                        mux.id,
+                        &format!("{} worker of the multiplexer keeps failing", name),
+                    );
+                    return;
+                }
+                sleep(WORKER_RESTART_DELAY).await;
+            }
+        })
+    }
+
+    /// Stops the queue workers. The UDP socket is released once the multiplexer is dropped.
+    pub(crate) async fn shutdown(&self) {
+        for worker in self.workers.lock().unwrap().drain(..) {
+            worker.abort();
+        }
+        *self.listener.write().await = None;
+    }
+}
+
+struct AbortOnDrop<T>(JoinHandle<T>);
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
     }
 }
+
+/// `struct in6_flowlabel_req` from `linux/in6.h`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct In6FlowlabelReq {
+    flr_dst: nix::libc::in6_addr,
+    flr_label: u32,
+    flr_action: u8,
+    flr_share: u8,
+    flr_flags: u16,
+    flr_expires: u16,
+    flr_linger: u16,
+    flr_pad: u32,
+}
+
+/// Sets the traffic class of the packets sent by an IPv6 socket.
+#[cfg(unix)]
+fn set_traffic_class(socket: &Socket, tclass: u32) -> Result<()> {
+    use nix::libc::{self, c_int, c_void, socklen_t};
+    use std::io::Error;
+    use std::os::unix::io::AsRawFd;
+
+    let tclass = tclass as c_int;
+    // SAFETY: the option value is valid for the given length during the call
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &tclass as *const _ as *const c_void,
+            std::mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_traffic_class(_socket: &Socket, _tclass: u32) -> Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        UdtError::new(
+            UdtErrorCode::InvOp,
+            "the traffic class of IPv6 packets is not supported on this platform",
+        ),
+    ))
+}
+
+/// Sets the Don't Fragment flag on the packets sent by the socket, ignoring the path MTU
+/// known by the kernel: datagrams larger than the MTU of the interface fail to be sent
+/// with `EMSGSIZE`, and larger than the path MTU are dropped on the way.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(fd: std::os::unix::io::RawFd, ipv6: bool) -> Result<()> {
+    use nix::libc::{self, c_int, c_void, socklen_t};
+    use std::io::Error;
+
+    let set_option = |level, name, value: c_int| {
+        // SAFETY: the option value is valid for the given length during the call
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const _ as *const c_void,
+                std::mem::size_of::<c_int>() as socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    };
+    if ipv6 {
+        set_option(
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        )?;
+    }
+    // Also applies to the IPv4 peers of a dual-stack socket
+    let ipv4 = set_option(
+        libc::IPPROTO_IP,
+        libc::IP_MTU_DISCOVER,
+        libc::IP_PMTUDISC_PROBE,
+    );
+    if !ipv6 {
+        ipv4?;
+    }
+    Ok(())
+}
+
+/// Leases an IPv6 flow label (in network byte order) on the socket,
+/// and enables sending flow labels.
+#[cfg(target_os = "linux")]
+fn lease_flow_label(
+    fd: std::os::unix::io::RawFd,
+    label: u32,
+    dest: &std::net::SocketAddrV6,
+) -> Result<()> {
+    use nix::libc::{self, c_int, c_void, socklen_t};
+    use std::io::Error;
+    const IPV6_FL_A_GET: u8 = 0;
+    const IPV6_FL_S_ANY: u8 = 255;
+    const IPV6_FL_F_CREATE: u16 = 1;
+
+    let request = In6FlowlabelReq {
+        flr_dst: libc::in6_addr {
+            s6_addr: dest.ip().octets(),
+        },
+        flr_label: label,
+        flr_action: IPV6_FL_A_GET,
+        flr_share: IPV6_FL_S_ANY,
+        flr_flags: IPV6_FL_F_CREATE,
+        flr_expires: 0,
+        flr_linger: 0,
+        flr_pad: 0,
+    };
+    let enable: c_int = 1;
+    // SAFETY: the option values are valid for the given lengths during the calls
+    unsafe {
+        if libc::setsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_FLOWLABEL_MGR,
+            &request as *const _ as *const c_void,
+            std::mem::size_of::<In6FlowlabelReq>() as socklen_t,
+        ) != 0
+            || libc::setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                libc::IPV6_FLOWINFO_SEND,
+                &enable as *const _ as *const c_void,
+                std::mem::size_of::<c_int>() as socklen_t,
+            ) != 0
+        {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_gso_segments() {
+    let segments = |ends: &[usize], gso| {
+        let mut datagrams = vec![];
+        UdtMultiplexer::gso_segments(ends, gso, &mut datagrams);
+        datagrams
+    };
+    // Two messages of 3 and 2 packets: full-size packets, then a shorter last one
+    let ends = [100, 200, 250, 350, 400];
+    assert_eq!(
+        segments(&ends, true),
+        [(0, 250, Some(100)), (250, 400, Some(100))]
+    );
+    assert_eq!(segments(&ends, false).len(), 5);
+
+    let ends: Vec<_> = (1..=100).map(|idx| idx * 1000).collect();
+    assert_eq!(
+        segments(&ends, true),
+        [(0, 64_000, Some(1000)), (64_000, 100_000, Some(1000))]
+    );
+}
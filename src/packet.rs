@@ -1,34 +1,42 @@
 use super::control_packet::{ControlPacketType, HandShakeInfo, UdtControlPacket};
 use super::data_packet::UdtDataPacket;
+use bytes::{BufMut, Bytes};
 use tokio::io::{Error, ErrorKind, Result};
 
 #[derive(Debug)]
 pub(crate) enum UdtPacket {
     Control(UdtControlPacket),
     Data(UdtDataPacket),
+    /// Packet encrypted by the cipher of the connection, ready to be sent
+    #[cfg(feature = "encryption")]
+    Sealed(Bytes),
 }
 
 /// Defines a packet that cna be sent in a UDT protocol
 impl UdtPacket {
-    pub fn get_dest_socket_id(&self) -> u32 {
-        match self {
-            Self::Control(p) => p.dest_socket_id,
-            Self::Data(p) => p.header.dest_socket_id,
-        }
+    /// Serializes this packet into a new buffer
+    #[cfg(feature = "test-vectors")]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(1500);
+        self.serialize_into(&mut buffer);
+        buffer
     }
 
-    /// Serializes this packet into a buffer
-    pub fn serialize(&self) -> Vec<u8> {
+    /// Appends the serialized packet to `buffer`, e.g. a send buffer reused across packets
+    pub fn serialize_into(&self, buffer: &mut impl BufMut) {
         match self {
-            Self::Control(p) => p.serialize(),
-            Self::Data(p) => p.serialize(),
+            Self::Control(p) => p.serialize_into(buffer),
+            Self::Data(p) => p.serialize_into(buffer),
+            #[cfg(feature = "encryption")]
+            Self::Sealed(raw) => buffer.put_slice(raw),
         }
     }
 
     /// Deserializes this buffer into a packet.
+    /// The payload of a data packet references `raw`.
     ///
     /// This cannot deserialize empty packets.
-    pub fn deserialize(raw: &[u8]) -> Result<Self> {
+    pub fn deserialize(raw: Bytes) -> Result<Self> {
         if raw.is_empty() {
             return Err(Error::new(
                 ErrorKind::InvalidData,
@@ -37,7 +45,7 @@ impl UdtPacket {
         }
         let first_bit = (raw[0] >> 7) != 0;
         let packet = if first_bit {
-          Self::Control(UdtControlPacket::deserialize(raw)?)
+            Self::Control(UdtControlPacket::deserialize(&raw)?)
         } else {
             Self::Data(UdtDataPacket::deserialize(raw)?)
         };
@@ -50,7 +58,7 @@ impl UdtPacket {
                 ControlPacketType::Handshake(info) => Some(info),
                 _ => None,
             },
-            Self::Data(_) => None,
+            _ => None,
         }
     }
 }
@@ -0,0 +1,138 @@
+use crate::seq_number::AckSeqNumber;
+use tokio::time::{Duration, Instant};
+
+/// Packet size used until a larger one is acknowledged, carried by nearly all paths
+/// (minimum MTU of IPv6).
+pub(crate) const PMTUD_BASE_SIZE: u32 = 1280;
+// Unanswered probes of a size after which the path is deemed not to carry it
+const PROBE_ATTEMPTS: u32 = 3;
+// The search stops once the packet size is within this distance of the smallest failed size
+const SEARCH_PRECISION: u32 = 16;
+// Delay after which larger sizes are probed again, in case the path changed
+const SEARCH_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+struct Probe {
+    size: u32,
+    challenge: AckSeqNumber,
+    attempts: u32,
+    last_sent: Instant,
+}
+
+/// Path MTU discovery: binary search of the largest packet size carried by the path,
+/// between the base size and the negotiated maximum packet size.
+/// Sizes include the IP and UDP headers, like `UdtConfiguration::mss`.
+#[derive(Debug)]
+pub(crate) struct PathMtu {
+    size: u32,     // largest acknowledged size
+    max_size: u32, // negotiated maximum packet size
+    ceiling: u32,  // smallest size the path does not carry
+    probe: Option<Probe>,
+    searched_at: Option<Instant>,
+}
+
+impl PathMtu {
+    pub fn new(max_size: u32) -> Self {
+        Self {
+            size: max_size.min(PMTUD_BASE_SIZE),
+            max_size,
+            ceiling: max_size + 1,
+            probe: None,
+            searched_at: None,
+        }
+    }
+
+    /// Current packet size.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Returns the size and challenge of the probe to send at `now`, if any.
+    /// A probe is repeated after `timeout` without answer, then the next size is probed.
+    pub fn next_probe(&mut self, now: Instant, timeout: Duration) -> Option<(u32, AckSeqNumber)> {
+        if let Some(probe) = &mut self.probe {
+            if now < probe.last_sent + timeout {
+                return None;
+            }
+            if probe.attempts < PROBE_ATTEMPTS {
+                probe.attempts += 1;
+                probe.last_sent = now;
+                return Some((probe.size, probe.challenge));
+            }
+            self.ceiling = probe.size;
+            self.probe = None;
+        }
+        if self.size == self.max_size {
+            return None;
+        }
+        if self.ceiling - self.size <= SEARCH_PRECISION {
+            let searched_at = *self.searched_at.get_or_insert(now);
+            if now < searched_at + SEARCH_INTERVAL {
+                return None;
+            }
+            self.ceiling = self.max_size + 1;
+            self.searched_at = None;
+        }
+        // The maximum size is tried first, as most paths carry it
+        let size = if self.ceiling > self.max_size {
+            self.max_size
+        } else {
+            (self.size + self.ceiling) / 2
+        };
+        let challenge = AckSeqNumber::random();
+        self.probe = Some(Probe {
+            size,
+            challenge,
+            attempts: 1,
+            last_sent: now,
+        });
+        Some((size, challenge))
+    }
+
+    /// The probe could not be sent, e.g. it exceeds the MTU of the local interface.
+    pub fn on_probe_failed(&mut self) {
+        if let Some(probe) = self.probe.take() {
+            self.ceiling = probe.size;
+        }
+    }
+
+    /// Returns the new packet size if `echo` answers the pending probe.
+    pub fn on_echo(&mut self, echo: AckSeqNumber) -> Option<u32> {
+        let probe = self.probe.take_if(|probe| probe.challenge == echo)?;
+        self.size = probe.size;
+        Some(self.size)
+    }
+}
+
+#[test]
+fn test_path_mtu_search() {
+    let mut path_mtu = PathMtu::new(9000);
+    assert_eq!(path_mtu.size(), PMTUD_BASE_SIZE);
+    let timeout = Duration::from_millis(100);
+    let mut now = Instant::now();
+
+    // The path carries up to 1500 bytes
+    let mut probes = vec![];
+    while let Some((size, challenge)) = path_mtu.next_probe(now, timeout) {
+        probes.push(size);
+        if size <= 1500 {
+            assert_eq!(path_mtu.on_echo(AckSeqNumber::random()), None);
+            assert_eq!(path_mtu.on_echo(challenge), Some(size));
+        }
+        now += timeout;
+    }
+    assert!(path_mtu.size() <= 1500 && path_mtu.size() > 1500 - SEARCH_PRECISION);
+    assert_eq!(probes[..3], [9000, 9000, 9000]);
+    assert_eq!(probes[3], (PMTUD_BASE_SIZE + 9000) / 2);
+
+    // Larger sizes are probed again later
+    assert!(path_mtu
+        .next_probe(now + SEARCH_INTERVAL, timeout)
+        .is_some());
+
+    let mut path_mtu = PathMtu::new(1400);
+    assert_eq!(path_mtu.next_probe(now, timeout).unwrap().0, 1400);
+    path_mtu.on_probe_failed();
+    assert_eq!(path_mtu.next_probe(now, timeout).unwrap().0, 1340);
+    assert!(PathMtu::new(1000).next_probe(now, timeout).is_none());
+}
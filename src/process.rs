@@ -0,0 +1,56 @@
+//! Settings and control shared by all the UDT sockets of the process.
+use crate::configuration::UdtEgressRateLimit;
+use crate::error::UdtBackgroundError;
+use crate::udt::Udt;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Sets the delay before closed or broken sockets are purged from the socket table.
+/// Default: 1s
+pub async fn set_purge_delay(delay: Duration) {
+    Udt::set_purge_delay(delay).await
+}
+
+/// Sets how long the id of a purged socket cannot be reused by a new socket,
+/// so that late packets targeting the old socket are not misdelivered.
+/// Default: 60s
+pub async fn set_socket_id_reuse_guard(guard: Duration) {
+    Udt::set_socket_id_reuse_guard(guard).await
+}
+
+/// Gets the number of closed or broken sockets purged from the socket table so far.
+pub async fn purged_sockets() -> u64 {
+    Udt::purged_sockets().await
+}
+
+/// Limits the aggregated send rate of all UDT connections of the process.
+/// The limit applies in addition to the congestion control of each connection.
+/// While the budget is exhausted, it is shared evenly between the connections sending,
+/// the share left unused by the slower ones going to the others.
+/// Default: None (unlimited)
+pub async fn set_egress_rate_limit(limit: Option<UdtEgressRateLimit>) {
+    Udt::set_egress_rate_limit(limit).await
+}
+
+/// Limits the number of live connections accepted by all the listeners of the process.
+/// Beyond it, connection requests are rejected with a "server busy" error,
+/// like with `UdtConfiguration::max_connections` for a single listener.
+/// Default: None (unlimited)
+pub async fn set_max_connections(limit: Option<usize>) {
+    Udt::set_max_connections(limit).await
+}
+
+/// Gracefully closes all UDT sockets of the process, then stops the multiplexers
+/// and releases their UDP sockets.
+/// Pending data is flushed within the linger timeout of each socket, but no longer
+/// than `timeout` overall: remaining sockets are then closed abruptly.
+pub async fn close_all(timeout: Duration) {
+    Udt::close_all(timeout).await
+}
+
+/// Subscribes to the errors raised by background tasks, such as a failing
+/// multiplexer worker or a connection broken while processing packets.
+/// Errors raised while nobody is subscribed are only traced, with the `tracing` feature.
+pub fn subscribe_errors() -> broadcast::Receiver<UdtBackgroundError> {
+    Udt::subscribe_errors()
+}
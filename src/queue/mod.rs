@@ -1,9 +1,11 @@
+mod packet_pool;
 mod rcv_buffer;
 mod rcv_queue;
 mod snd_buffer;
 mod snd_queue;
 
+pub(crate) use packet_pool::{PacketBuf, PacketPool};
 pub(crate) use rcv_buffer::RcvBuffer;
-pub(crate) use rcv_queue::UdtRcvQueue;
+pub(crate) use rcv_queue::{Datagram, UdtRcvQueue};
 pub(crate) use snd_buffer::SndBuffer;
 pub(crate) use snd_queue::UdtSndQueue;
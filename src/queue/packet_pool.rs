@@ -0,0 +1,301 @@
+use bytes::{Bytes, BytesMut};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, Weak};
+
+// Slots checked for a free buffer by `take` before allocating a new one,
+// in a pool that can grow
+const MAX_SCAN: usize = 16;
+
+/// Pool of packet-sized buffers: a buffer is reused once the last `Bytes` referencing it
+/// is dropped, so that steady-state operation does not allocate per packet.
+/// In fixed-capacity mode, the buffers are allocated up front and the pool never grows.
+#[derive(Debug)]
+pub(crate) struct PacketPool {
+    buf_size: usize,
+    max_size: usize,
+    fixed: bool,
+    slots: Mutex<Slots>,
+}
+
+#[derive(Debug)]
+struct Slots {
+    // Every buffer of the pool, free once no one else references it.
+    // A slot is empty while its buffer is being written.
+    bufs: Vec<Option<Bytes>>,
+    next: usize, // buffers are mostly released in the order they were taken
+}
+
+impl PacketPool {
+    pub fn new(buf_size: usize, max_size: usize, fixed: bool) -> Arc<Self> {
+        let mut bufs = Vec::with_capacity(max_size);
+        if fixed {
+            bufs.resize_with(max_size, || Some(new_buffer(buf_size).freeze()));
+        }
+        Arc::new(Self {
+            buf_size,
+            max_size,
+            fixed,
+            slots: Mutex::new(Slots { bufs, next: 0 }),
+        })
+    }
+
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+
+    pub fn is_fixed(&self) -> bool {
+        self.fixed
+    }
+
+    /// Takes a free buffer, or allocates one of `buf_size` capacity.
+    /// Returns `None` in fixed-capacity mode when every buffer is in use.
+    pub fn take(self: &Arc<Self>) -> Option<PacketBuf> {
+        let mut slots = self.slots.lock().unwrap();
+        let len = slots.bufs.len();
+        let scan = if self.fixed { len } else { len.min(MAX_SCAN) };
+        for _ in 0..scan {
+            let slot = slots.next;
+            slots.next = (slot + 1) % len;
+            if !slots.bufs[slot].as_ref().is_some_and(Bytes::is_unique) {
+                continue;
+            }
+            if let Ok(mut data) = slots.bufs[slot].take().unwrap().try_into_mut() {
+                // Reclaims the whole buffer, it may have been sliced
+                data.clear();
+                data.reserve(self.buf_size);
+                return Some(self.checkout(data, slot));
+            }
+        }
+        if self.fixed {
+            return None;
+        }
+        if len < self.max_size {
+            slots.bufs.push(None);
+            return Some(self.checkout(new_buffer(self.buf_size), len));
+        }
+        Some(PacketBuf::detached(self.buf_size))
+    }
+
+    fn checkout(self: &Arc<Self>, data: BytesMut, slot: usize) -> PacketBuf {
+        PacketBuf {
+            data,
+            slot: Some((Arc::downgrade(self), slot)),
+        }
+    }
+
+    fn put(&self, slot: usize, data: Bytes) {
+        self.slots.lock().unwrap().bufs[slot] = Some(data);
+    }
+}
+
+/// Buffer whose storage is shared from the start, so that freezing it and reclaiming it
+/// once unique do not allocate.
+fn new_buffer(size: usize) -> BytesMut {
+    let mut data = BytesMut::with_capacity(size);
+    drop(data.split());
+    data
+}
+
+/// Buffer of a [`PacketPool`], back in the pool once dropped with every `Bytes` frozen from it.
+#[derive(Debug)]
+pub(crate) struct PacketBuf {
+    data: BytesMut,
+    slot: Option<(Weak<PacketPool>, usize)>,
+}
+
+impl PacketBuf {
+    /// Buffer that does not belong to any pool.
+    pub fn detached(capacity: usize) -> Self {
+        Self {
+            data: BytesMut::with_capacity(capacity),
+            slot: None,
+        }
+    }
+
+    /// Converts the buffer into `Bytes` without copy.
+    pub fn freeze(mut self) -> Bytes {
+        let data = std::mem::take(&mut self.data).freeze();
+        if let Some((pool, slot)) = self.slot.take() {
+            if let Some(pool) = pool.upgrade() {
+                pool.put(slot, data.clone());
+            }
+        }
+        data
+    }
+
+    /// Splits the first `len` bytes of the buffer into datagrams of `segment_size` bytes,
+    /// the last one possibly shorter (GRO). A single datagram filling most of the buffer
+    /// references it without copy. Otherwise the datagrams are copied and the buffer goes
+    /// back to the pool at once: a small packet held in a receive buffer must not pin
+    /// a whole buffer sized for GRO. In fixed-capacity mode, buffers are packet-sized
+    /// and a single datagram is never copied.
+    pub fn into_datagrams(self, len: usize, segment_size: usize) -> Datagrams {
+        let segment_size = segment_size.max(1);
+        let fixed = self
+            .slot
+            .as_ref()
+            .and_then(|(pool, _)| pool.upgrade())
+            .is_some_and(|pool| pool.fixed);
+        if segment_size >= len && (len > self.data.capacity() / 2 || fixed) {
+            return Datagrams::Shared(Some(self.freeze().slice(..len)));
+        }
+        Datagrams::Copied {
+            buf: self,
+            len,
+            segment_size,
+            start: 0,
+        }
+    }
+}
+
+/// Datagrams received into a [`PacketBuf`], see [`PacketBuf::into_datagrams`].
+pub(crate) enum Datagrams {
+    Shared(Option<Bytes>),
+    Copied {
+        buf: PacketBuf,
+        len: usize,
+        segment_size: usize,
+        start: usize,
+    },
+}
+
+impl Iterator for Datagrams {
+    type Item = Bytes;
+
+    fn next(&mut self) -> Option<Bytes> {
+        match self {
+            Self::Shared(datagram) => datagram.take(),
+            Self::Copied {
+                buf,
+                len,
+                segment_size,
+                start,
+            } => {
+                if *start >= *len {
+                    return None;
+                }
+                let end = (*len).min(*start + *segment_size);
+                let datagram = Bytes::copy_from_slice(&buf[*start..end]);
+                *start = end;
+                Some(datagram)
+            }
+        }
+    }
+}
+
+impl Deref for PacketBuf {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.data
+    }
+}
+
+impl DerefMut for PacketBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.data
+    }
+}
+
+impl AsRef<[u8]> for PacketBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Drop for PacketBuf {
+    fn drop(&mut self) {
+        if let Some((pool, slot)) = self.slot.take() {
+            if let Some(pool) = pool.upgrade() {
+                pool.put(slot, std::mem::take(&mut self.data).freeze());
+            }
+        }
+    }
+}
+
+impl PacketPool {
+    /// Number of buffers free for reuse.
+    #[cfg(test)]
+    fn free(&self) -> usize {
+        let slots = self.slots.lock().unwrap();
+        slots
+            .bufs
+            .iter()
+            .filter(|buf| buf.as_ref().is_some_and(Bytes::is_unique))
+            .count()
+    }
+}
+
+#[test]
+fn test_packet_pool_reuse() {
+    let pool = PacketPool::new(16, 1, false);
+    let mut buf = pool.take().unwrap();
+    buf.extend_from_slice(b"payload");
+    let ptr = buf.as_ptr();
+    let bytes = buf.freeze();
+    let slice = bytes.slice(3..);
+    drop(bytes);
+    assert_eq!(pool.free(), 0);
+    drop(slice);
+
+    // The buffer is back once every reference is dropped
+    assert_eq!(pool.take().unwrap().as_ptr(), ptr);
+    let (first, second) = (pool.take(), pool.take());
+    drop((first, second));
+    assert_eq!(pool.free(), 1);
+}
+
+#[test]
+fn test_packet_pool_reuse_with_gro() {
+    // Buffers sized for GRO, receiving three coalesced datagrams
+    let pool = PacketPool::new(65535, 1, false);
+    let mut buf = pool.take().unwrap();
+    buf.resize(65535, 0);
+    let ptr = buf.as_ptr();
+    for (idx, segment) in buf.chunks_mut(1400).take(3).enumerate() {
+        segment.fill(idx as u8);
+    }
+    let datagrams: Vec<_> = buf.into_datagrams(3 * 1400 - 100, 1400).collect();
+    assert_eq!(datagrams.len(), 3);
+    assert!(datagrams[0].iter().all(|&b| b == 0));
+    assert!(datagrams[2].len() == 1300 && datagrams[2].iter().all(|&b| b == 2));
+
+    // The buffer is reused while the datagrams are still held
+    assert_eq!(pool.take().unwrap().as_ptr(), ptr);
+
+    // A small datagram is copied as well, a full one is not
+    let mut buf = pool.take().unwrap();
+    buf.resize(65535, 0);
+    let small: Vec<_> = buf.into_datagrams(1400, 1400).collect();
+    assert_eq!(pool.free(), 1);
+    let mut buf = pool.take().unwrap();
+    buf.resize(65535, 0);
+    let full: Vec<_> = buf.into_datagrams(65000, 65000).collect();
+    assert_eq!(pool.free(), 0);
+    drop((datagrams, small, full));
+    assert_eq!(pool.free(), 1);
+}
+
+#[test]
+fn test_fixed_packet_pool() {
+    let pool = PacketPool::new(1500, 2, true);
+    assert_eq!(pool.free(), 2);
+    let mut first = pool.take().unwrap();
+    first.resize(1500, 0);
+    let ptr = first.as_ptr();
+    let datagram: Vec<_> = first.into_datagrams(40, 40).collect();
+    let second = pool.take().unwrap();
+
+    // Exhausted: small datagrams are not copied, they keep their buffer
+    assert!(pool.take().is_none());
+    drop(second);
+    assert!(pool.take().is_some());
+    drop(datagram);
+    let mut buf = pool.take().unwrap();
+    assert_eq!(buf.as_ptr(), ptr);
+    assert!(buf.is_empty() && buf.capacity() >= 1500);
+    buf.extend_from_slice(b"payload");
+    let payload = buf.freeze().slice(1..);
+    drop(payload);
+    assert_eq!(pool.free(), 2);
+}
@@ -1,38 +1,215 @@
-use crate::data_packet::UdtDataPacket;
+use crate::data_packet::{PacketPosition, UdtDataPacket};
+use crate::error::{UdtError, UdtErrorCode};
+use crate::message::UdtMessageInfo;
 use crate::seq_number::{MsgNumber, SeqNumber};
-use std::collections::BTreeMap;
-use tokio::io::ReadBuf;
+use crate::tsbpd::Tsbpd;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+use tokio::io::{Error, ErrorKind, Result};
+use tokio::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub(crate) struct RcvBuffer {
-    packets: BTreeMap<SeqNumber, UdtDataPacket>, // map: seq_number -> packet
+    // Packets from `next_to_read` on, by offset: `None` until received
+    packets: VecDeque<Option<UdtDataPacket>>,
+    count: usize, // packets received and not read yet
     max_size: u32,
+    fixed_size: Option<u32>, // capacity allocated up front, in fixed-capacity mode
     next_to_read: SeqNumber,
     next_to_ack: SeqNumber,
+    message_mode: bool,
+    msg_arrivals: VecDeque<(MsgNumber, Instant)>, // first fragment arrival of the messages
+    tsbpd: Option<Tsbpd>,                         // live mode: packets are read once due
+    eof: bool, // the end of the stream was read, see `SndBuffer::add_eof`
 }
 
 impl RcvBuffer {
-    pub fn new(max_size: u32, initial_seq_number: SeqNumber) -> Self {
+    pub fn new(
+        max_size: u32,
+        initial_seq_number: SeqNumber,
+        message_mode: bool,
+        live_latency: Option<Duration>,
+        fixed_capacity: bool,
+    ) -> Self {
+        let capacity = if fixed_capacity { max_size as usize } else { 0 };
         Self {
             max_size,
-            packets: BTreeMap::new(),
+            fixed_size: fixed_capacity.then_some(max_size),
+            packets: VecDeque::with_capacity(capacity),
+            count: 0,
             next_to_read: initial_seq_number,
             next_to_ack: initial_seq_number,
+            message_mode,
+            msg_arrivals: VecDeque::with_capacity(capacity),
+            tsbpd: live_latency.map(Tsbpd::new),
+            eof: false,
         }
     }
 
+    /// Sets the sequence number of the first packet expected, before any is received.
+    pub fn reset(&mut self, initial_seq_number: SeqNumber) {
+        self.packets.clear();
+        self.count = 0;
+        self.next_to_read = initial_seq_number;
+        self.next_to_ack = initial_seq_number;
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
     pub fn get_available_buf_size(&self) -> u32 {
-        self.max_size - self.packets.len() as u32
+        self.max_size.saturating_sub(self.count as u32)
+    }
+
+    /// Number of packets to read before the packet `seq_number` fits in the buffer,
+    /// zero if it fits.
+    pub fn overflow(&self, seq_number: SeqNumber) -> u32 {
+        let offset = i64::from(seq_number - self.next_to_read);
+        u32::try_from(offset + 1 - i64::from(self.max_size)).unwrap_or(0)
+    }
+
+    /// Changes the capacity. Packets beyond a smaller capacity are kept,
+    /// no packet is accepted until they are read.
+    /// A fixed-capacity buffer can not grow past its initial size.
+    pub fn set_max_size(&mut self, max_size: u32) {
+        self.max_size = match self.fixed_size {
+            Some(fixed_size) => max_size.min(fixed_size),
+            None => max_size,
+        };
+    }
+
+    /// Received packet `seq_number`, if it was not read yet.
+    fn get(&self, seq_number: SeqNumber) -> Option<&UdtDataPacket> {
+        let offset = usize::try_from(seq_number - self.next_to_read).ok()?;
+        self.packets.get(offset)?.as_ref()
+    }
+
+    /// Received packets, in sequence order.
+    fn iter(&self) -> impl Iterator<Item = (SeqNumber, &UdtDataPacket)> {
+        let first = self.next_to_read;
+        self.packets
+            .iter()
+            .enumerate()
+            .filter_map(move |(offset, packet)| Some((first + offset as i32, packet.as_ref()?)))
+    }
+
+    /// Moves past the next packet to read, and returns it if it was received.
+    fn advance(&mut self) -> Option<UdtDataPacket> {
+        let packet = self.packets.pop_front().flatten();
+        if packet.is_some() {
+            self.count -= 1;
+        }
+        self.next_to_read = self.next_to_read + 1;
+        packet
+    }
+
+    fn remove_arrival(&mut self, msg: MsgNumber) {
+        if let Some(idx) = self.msg_arrivals.iter().position(|(m, _)| *m == msg) {
+            self.msg_arrivals.remove(idx);
+        }
     }
 
     pub fn insert(&mut self, packet: UdtDataPacket) {
         let seq_number = packet.header.seq_number;
-        self.packets.entry(seq_number).or_insert(packet);
+        // Packets already read are not kept again
+        let Ok(offset) = usize::try_from(seq_number - self.next_to_read) else {
+            return;
+        };
+        if let Some(tsbpd) = &mut self.tsbpd {
+            tsbpd.on_arrival(packet.header.timestamp, Instant::now());
+        }
+        let msg = packet.header.msg_number;
+        if self.message_mode
+            && !matches!(packet.header.position, PacketPosition::Only)
+            && !self.msg_arrivals.iter().any(|(m, _)| *m == msg)
+        {
+            self.msg_arrivals.push_back((msg, Instant::now()));
+        }
+        if offset >= self.packets.len() {
+            self.packets.resize_with(offset + 1, || None);
+        }
+        let slot = &mut self.packets[offset];
+        if slot.is_none() {
+            *slot = Some(packet);
+            self.count += 1;
+        }
     }
 
     pub fn drop_msg(&mut self, msg: MsgNumber) {
-        self.packets
-            .retain(|_k, packet| packet.header.msg_number != msg);
+        self.remove_arrival(msg);
+        for slot in &mut self.packets {
+            if slot.as_ref().is_some_and(|p| p.header.msg_number == msg) {
+                *slot = None;
+                self.count -= 1;
+            }
+        }
+    }
+
+    /// Discards the fragments of messages that are still incomplete `timeout` after
+    /// their first fragment arrived.
+    /// Returns the sequence number range spanned by each discarded message.
+    pub fn drop_incomplete_msgs(&mut self, timeout: Duration) -> Vec<(SeqNumber, SeqNumber)> {
+        let mut dropped = vec![];
+        let mut idx = 0;
+        while let Some(&(msg, arrival)) = self.msg_arrivals.get(idx) {
+            if arrival.elapsed() <= timeout {
+                idx += 1;
+                continue;
+            }
+            self.msg_arrivals.remove(idx);
+            let (mut first, mut last) = (None, None);
+            let (mut lowest, mut highest) = (None, None);
+            let mut fragments = 0;
+            for (seq, packet) in self.iter().filter(|(_, p)| p.header.msg_number == msg) {
+                match packet.header.position {
+                    PacketPosition::First => first = Some(seq),
+                    PacketPosition::Last => last = Some(seq),
+                    _ => (),
+                }
+                lowest = lowest.or(Some(seq));
+                highest = Some(seq);
+                fragments += 1;
+            }
+            if let (Some(first), Some(last)) = (first, last) {
+                if (last - first + 1) as usize == fragments {
+                    // Message is complete, it is waiting to be read.
+                    continue;
+                }
+            }
+
+            if let (Some(lowest), Some(highest)) = (lowest, highest) {
+                self.drop_msg(msg);
+                dropped.push((first.unwrap_or(lowest), last.unwrap_or(highest)));
+            }
+        }
+        dropped
+    }
+
+    /// Drops at least `count` of the oldest packets waiting to be read,
+    /// without splitting a message in message mode.
+    /// Returns the number of dropped packets.
+    pub fn drop_oldest(&mut self, count: u32) -> u32 {
+        let mut dropped = 0;
+        while self.next_to_read != self.next_to_ack {
+            let packet = self.get(self.next_to_read);
+            if dropped >= count
+                && (!self.message_mode
+                    || packet.is_none_or(|p| {
+                        matches!(
+                            p.header.position,
+                            PacketPosition::First | PacketPosition::Only
+                        )
+                    }))
+            {
+                break;
+            }
+            if let Some(packet) = self.advance() {
+                self.remove_arrival(packet.header.msg_number);
+                dropped += 1;
+            }
+        }
+        dropped
     }
 
     pub fn ack_data(&mut self, to: SeqNumber) {
@@ -41,57 +218,373 @@ impl RcvBuffer {
         }
     }
 
-    pub fn has_data_to_read(&self) -> bool {
-        let first = self.next_to_read;
-        let last = self.next_to_ack;
-        if first <= last {
-            return self.packets.range(first..last).next().is_some();
-        }
+    /// Time at which `packet` can be read, in live mode.
+    fn delivery_time(&self, packet: &UdtDataPacket) -> Option<Instant> {
+        let tsbpd = self.tsbpd.as_ref()?;
+        Some(tsbpd.delivery_time(packet.header.timestamp))
+    }
+
+    fn is_due(&self, packet: &UdtDataPacket, now: Instant) -> bool {
+        self.delivery_time(packet).is_none_or(|time| time <= now)
+    }
 
-        return self
-            .packets
-            .range(first..=SeqNumber::max())
+    /// First acknowledged packet waiting to be read.
+    fn next_packet(&self) -> Option<(SeqNumber, &UdtDataPacket)> {
+        let acknowledged = usize::try_from(self.next_to_ack - self.next_to_read).unwrap_or(0);
+        self.iter()
             .next()
-            .is_some()
-            || self.packets.range(SeqNumber::zero()..last).next().is_some();
-    }
-
-    pub fn read_buffer(&mut self, buf: &mut ReadBuf<'_>) -> usize {
-        if self.next_to_read == self.next_to_ack {
-            return 0;
-        }
-
-        let packets = {
-            if self.next_to_read <= self.next_to_ack {
-                self.packets
-                    .range(self.next_to_read..self.next_to_ack)
-                    .chain(
-                        self.packets.range(SeqNumber::zero()..SeqNumber::zero()), //empty
-                    )
-            } else {
-                self.packets
-                    .range(self.next_to_read..=SeqNumber::max())
-                    .chain(self.packets.range(SeqNumber::zero()..self.next_to_ack))
+            .filter(|(seq, _)| ((*seq - self.next_to_read) as usize) < acknowledged)
+    }
+
+    /// Whether a read would return data, or the end of the stream.
+    pub fn has_data_to_read(&self) -> bool {
+        self.eof
+            || self
+                .next_packet()
+                .is_some_and(|(_, packet)| self.is_due(packet, Instant::now()))
+    }
+
+    /// Whether the peer shut down its sending side and all its data was read.
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// In live mode, time at which the next packet to read is due, if it is not yet.
+    pub fn next_delivery_time(&self) -> Option<Instant> {
+        let (_, packet) = self.next_packet()?;
+        self.delivery_time(packet)
+            .filter(|&time| time > Instant::now())
+    }
+
+    /// In live mode, whether the first packet received after `seq_number` is due:
+    /// the lost packets before it are too late to be delivered.
+    pub fn is_due_after(&self, seq_number: SeqNumber, now: Instant) -> bool {
+        self.iter()
+            .find(|(seq, _)| *seq - seq_number > 0)
+            .is_some_and(|(_, packet)| self.is_due(packet, now))
+    }
+
+    /// Leaves live mode: the packets waiting to be due can be read right away,
+    /// e.g. once the peer closed the connection.
+    pub fn release_all(&mut self) {
+        self.tsbpd = None;
+    }
+
+    /// Finds the next complete message, dropping the messages given up by the receiver.
+    /// Returns the sequence number of its last packet and its size.
+    fn next_complete_msg(&mut self) -> Option<(SeqNumber, usize)> {
+        loop {
+            let mut seq_number = self.next_to_read;
+            let mut len = 0;
+            let complete = loop {
+                if seq_number == self.next_to_ack {
+                    return None;
+                }
+                match self.get(seq_number) {
+                    Some(packet) => {
+                        len += packet.data.len();
+                        if matches!(
+                            packet.header.position,
+                            PacketPosition::Last | PacketPosition::Only
+                        ) {
+                            break true;
+                        }
+                    }
+                    // Acknowledged without being received: given up by the receiver
+                    None => break false,
+                }
+                seq_number = seq_number + 1;
+            };
+            if complete {
+                let first = self.get(self.next_to_read)?;
+                return self
+                    .is_due(first, Instant::now())
+                    .then_some((seq_number, len));
+            }
+            while self.next_to_read != seq_number + 1 {
+                self.advance();
             }
+        }
+    }
+
+    /// Reads the next complete message into `buf`.
+    /// Returns `None` until all the packets of the message were received.
+    pub fn read_msg(&mut self, buf: &mut [u8]) -> Option<Result<UdtMessageInfo>> {
+        let (last, len) = self.next_complete_msg()?;
+        if len > buf.len() {
+            return Some(Err(Error::new(
+                ErrorKind::InvalidInput,
+                UdtError::new(UdtErrorCode::InvParam, "message is larger than the buffer"),
+            )));
+        }
+
+        let header = &self.get(self.next_to_read)?.header;
+        let info = UdtMessageInfo {
+            len,
+            msg_number: header.msg_number.number(),
+            timestamp: Duration::from_micros(header.timestamp.into()),
+            in_order: header.in_order,
         };
+        self.remove_arrival(header.msg_number);
+        let mut written = 0;
+        while self.next_to_read != last + 1 {
+            if let Some(packet) = self.advance() {
+                buf[written..written + packet.data.len()].copy_from_slice(&packet.data);
+                written += packet.data.len();
+            }
+        }
+        Some(Ok(info))
+    }
+
+    /// Reads the next complete message. A message held in a single packet is not copied.
+    pub fn read_msg_bytes(&mut self) -> Option<Bytes> {
+        let (last, len) = self.next_complete_msg()?;
+        if self.next_to_read == last {
+            return self.advance().map(|packet| packet.data);
+        }
+        let msg = self.get(self.next_to_read)?.header.msg_number;
+        self.remove_arrival(msg);
+        let mut data = BytesMut::with_capacity(len);
+        while self.next_to_read != last + 1 {
+            if let Some(packet) = self.advance() {
+                data.extend_from_slice(&packet.data);
+            }
+        }
+        Some(data.freeze())
+    }
 
+    /// Reads the acknowledged data into `buf` until it is full. The last packet may be
+    /// read in part, the rest of it is kept for the next read: a stream read must not
+    /// return 0 while data is available, nor read past the length asked for, e.g. by
+    /// `recv_file` ending in the middle of a packet.
+    pub fn read_buffer(&mut self, buf: &mut impl BufMut) -> usize {
+        let now = Instant::now();
         let mut written = 0;
-        let mut to_remove = vec![];
-        for (key, packet) in packets {
-            let packet_len = packet.data.len();
-            if buf.remaining() < packet_len {
+        while self.next_to_read != self.next_to_ack {
+            let (packet_len, due) = match self.packets.front() {
+                Some(Some(packet)) => (packet.data.len(), self.is_due(packet, now)),
+                // Acknowledged without being received: given up by the receiver
+                _ => {
+                    self.advance();
+                    continue;
+                }
+            };
+            if !due {
                 break;
             }
-            buf.put_slice(&packet.data);
-            written += packet_len;
-            to_remove.push(*key);
-            self.next_to_read = *key + 1;
+            if packet_len == 0 {
+                // End of the stream: the peer does not send anything after it
+                self.eof = true;
+                self.advance();
+                break;
+            }
+            let remaining = buf.remaining_mut();
+            if remaining < packet_len {
+                // The rest of the packet is read by the next call
+                if let Some(Some(packet)) = self.packets.front_mut() {
+                    buf.put_slice(&packet.data[..remaining]);
+                    packet.data.advance(remaining);
+                    written += remaining;
+                }
+                break;
+            }
+            if let Some(packet) = self.advance() {
+                buf.put_slice(&packet.data);
+                written += packet_len;
+            }
         }
+        written
+    }
 
-        for k in to_remove {
-            self.packets.remove(&k);
+    /// Removes the next packet to read and returns its payload, without copying it.
+    /// An empty payload is the end of the stream.
+    pub fn read_packet(&mut self) -> Option<Bytes> {
+        if self.eof {
+            return Some(Bytes::new());
         }
+        let key = match self.next_packet() {
+            Some((key, packet)) if self.is_due(packet, Instant::now()) => key,
+            _ => return None,
+        };
+        while self.next_to_read != key {
+            self.advance();
+        }
+        let data = self.advance().map(|packet| packet.data);
+        self.eof = data.as_ref().is_some_and(Bytes::is_empty);
+        data
+    }
+}
 
-        written
+/// Builds a data packet to insert in a receive buffer.
+#[cfg(test)]
+fn data_packet(
+    seq: u32,
+    msg: u32,
+    position: PacketPosition,
+    timestamp: u32,
+    data: &'static [u8],
+) -> UdtDataPacket {
+    use crate::data_packet::UdtDataPacketHeader;
+    UdtDataPacket {
+        header: UdtDataPacketHeader {
+            seq_number: seq.into(),
+            position,
+            in_order: true,
+            msg_number: msg.into(),
+            timestamp,
+            dest_socket_id: 0,
+        },
+        data: Bytes::from_static(data),
     }
 }
+
+#[test]
+fn test_drop_incomplete_msgs() {
+    let mut buffer = RcvBuffer::new(100, 1.into(), true, None, false);
+    // Message 1 is complete, message 2 misses its middle fragment
+    buffer.insert(data_packet(1, 1, PacketPosition::First, 0, b"data"));
+    buffer.insert(data_packet(2, 1, PacketPosition::Last, 0, b"data"));
+    buffer.insert(data_packet(3, 2, PacketPosition::First, 0, b"data"));
+    buffer.insert(data_packet(5, 2, PacketPosition::Last, 0, b"data"));
+    assert!(buffer
+        .drop_incomplete_msgs(Duration::from_secs(60))
+        .is_empty());
+
+    std::thread::sleep(Duration::from_millis(1));
+    let dropped = buffer.drop_incomplete_msgs(Duration::ZERO);
+    assert_eq!(dropped, [(3.into(), 5.into())]);
+    assert_eq!(buffer.get_available_buf_size(), 98);
+}
+
+#[test]
+fn test_drop_oldest() {
+    let mut buffer = RcvBuffer::new(4, 1.into(), true, None, false);
+    buffer.insert(data_packet(1, 1, PacketPosition::First, 0, b"data"));
+    buffer.insert(data_packet(2, 1, PacketPosition::Last, 0, b"data"));
+    buffer.insert(data_packet(3, 2, PacketPosition::Only, 0, b"data"));
+    buffer.insert(data_packet(4, 3, PacketPosition::Only, 0, b"data"));
+    buffer.ack_data(4.into());
+
+    // The whole first message is dropped, unacknowledged packets are kept
+    assert_eq!(buffer.drop_oldest(1), 2);
+    assert_eq!(buffer.drop_oldest(5), 1);
+    assert_eq!(buffer.get_available_buf_size(), 3);
+    assert!(!buffer.has_data_to_read());
+}
+
+#[test]
+fn test_read_msg() {
+    let mut buffer = RcvBuffer::new(100, 1.into(), false, None, false);
+    buffer.insert(data_packet(1, 4, PacketPosition::First, 1001, b"hello "));
+    buffer.insert(data_packet(2, 4, PacketPosition::Last, 1002, b"world"));
+    buffer.insert(data_packet(
+        3,
+        5,
+        PacketPosition::First,
+        1003,
+        b"incomplete",
+    ));
+    buffer.ack_data(4.into());
+
+    let mut buf = [0; 8];
+    assert!(buffer.read_msg(&mut buf).unwrap().is_err());
+    let mut buf = [0; 64];
+    let info = buffer.read_msg(&mut buf).unwrap().unwrap();
+    assert_eq!(&buf[..info.len], b"hello world");
+    assert_eq!(info.msg_number, 4);
+    assert_eq!(info.timestamp, Duration::from_micros(1001));
+    assert!(info.in_order);
+    assert!(buffer.read_msg(&mut buf).is_none());
+}
+
+#[test]
+fn test_read_buffer_partial() {
+    let mut buffer = RcvBuffer::new(100, 1.into(), false, None, false);
+    buffer.insert(data_packet(1, 1, PacketPosition::Only, 0, b"hello "));
+    buffer.insert(data_packet(2, 2, PacketPosition::Only, 0, b"world"));
+    buffer.ack_data(3.into());
+
+    // A buffer smaller than a packet gets the beginning of it
+    let mut buf = [0; 4];
+    assert_eq!(buffer.read_buffer(&mut &mut buf[..]), 4);
+    assert_eq!(&buf, b"hell");
+    let mut buf = vec![];
+    assert_eq!(buffer.read_buffer(&mut buf), 7);
+    assert_eq!(buf, b"o world");
+    assert!(!buffer.has_data_to_read());
+
+    // The end of the stream is read once the data before it is
+    buffer.insert(data_packet(3, 3, PacketPosition::Only, 0, b"!"));
+    buffer.insert(data_packet(4, 4, PacketPosition::Only, 0, b""));
+    buffer.ack_data(5.into());
+    let mut buf = vec![];
+    assert_eq!(buffer.read_buffer(&mut buf), 1);
+    assert!(buffer.is_eof() && buffer.has_data_to_read());
+    assert_eq!(buffer.read_buffer(&mut buf), 0);
+}
+
+#[test]
+fn test_overflow() {
+    let mut buffer = RcvBuffer::new(4, 1.into(), false, None, false);
+    buffer.insert(data_packet(1, 1, PacketPosition::Only, 0, b"data"));
+    buffer.insert(data_packet(3, 3, PacketPosition::Only, 0, b"data"));
+    buffer.ack_data(2.into());
+    // Received packets beyond the acknowledged ones take room as well
+    assert_eq!(buffer.get_available_buf_size(), 2);
+    assert_eq!(buffer.overflow(4.into()), 0);
+    assert_eq!(buffer.overflow(6.into()), 2);
+
+    // Room is made by reading, or by a larger capacity
+    assert!(buffer.read_packet().is_some());
+    assert_eq!(buffer.overflow(6.into()), 1);
+    buffer.set_max_size(8);
+    assert_eq!(buffer.overflow(6.into()), 0);
+    buffer.set_max_size(0);
+    assert_eq!(buffer.get_available_buf_size(), 0);
+}
+
+#[test]
+fn test_fixed_capacity() {
+    let mut buffer = RcvBuffer::new(4, 1.into(), false, None, true);
+    buffer.insert(data_packet(1, 1, PacketPosition::Only, 0, b"1"));
+    buffer.insert(data_packet(2, 2, PacketPosition::Only, 0, b"2"));
+    buffer.insert(data_packet(4, 4, PacketPosition::Only, 0, b"4"));
+    buffer.ack_data(3.into());
+    assert_eq!(buffer.read_packet().unwrap(), "1");
+    assert_eq!(buffer.read_packet().unwrap(), "2");
+
+    // The slots read are reused past the end of the ring
+    buffer.insert(data_packet(3, 3, PacketPosition::Only, 0, b"3"));
+    buffer.insert(data_packet(6, 6, PacketPosition::Only, 0, b"6"));
+    buffer.ack_data(5.into());
+    assert_eq!(buffer.overflow(7.into()), 1);
+    // A fixed-capacity buffer does not grow
+    buffer.set_max_size(8);
+    assert_eq!(buffer.overflow(7.into()), 1);
+    assert_eq!(buffer.read_packet().unwrap(), "3");
+    assert_eq!(buffer.read_packet().unwrap(), "4");
+    assert!(buffer.read_packet().is_none());
+}
+
+#[test]
+fn test_live_delivery() {
+    let mut buffer = RcvBuffer::new(100, 1.into(), false, Some(Duration::from_secs(1)), false);
+    // Packet 2 is lost, packets 3 and 4 were sent two seconds after packet 1:
+    // packet 1 is a second late
+    buffer.insert(data_packet(1, 1, PacketPosition::Only, 0, b"data"));
+    buffer.insert(data_packet(3, 3, PacketPosition::Only, 2_000_000, b"data"));
+    buffer.insert(data_packet(4, 4, PacketPosition::Only, 2_000_000, b"data"));
+    buffer.ack_data(2.into());
+    assert!(buffer.is_due_after(0.into(), Instant::now()));
+    assert!(!buffer.is_due_after(2.into(), Instant::now()));
+    assert!(buffer.next_delivery_time().is_none());
+    assert!(buffer.read_packet().is_some());
+
+    buffer.ack_data(5.into());
+    let due = buffer.next_delivery_time().unwrap();
+    assert!(due > Instant::now() + Duration::from_millis(900));
+    assert!(!buffer.has_data_to_read());
+    assert_eq!(buffer.read_buffer(&mut vec![]), 0);
+    buffer.release_all();
+    assert_eq!(buffer.read_buffer(&mut vec![]), 8);
+}
@@ -1,13 +1,16 @@
-use crate::multiplexer::UdtMultiplexer;
+use crate::memory::MemoryChannel;
+#[cfg(target_os = "linux")]
+use crate::mmsg::MmsgHeaders;
+use crate::multiplexer::{Channel, UdtMultiplexer};
 use crate::packet::UdtPacket;
-use crate::socket::{SocketId, UdtSocket};
+use crate::queue::{PacketBuf, PacketPool};
+use crate::socket::{SocketId, SocketWork, UdtSocket, UdtStatus};
 use crate::udt::{SocketRef, Udt, UDT_DEBUG};
-use nix::sys::socket::{SockaddrIn, SockaddrIn6};
 use std::collections::{BTreeMap, VecDeque};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, Weak};
-use tokio::io::{Error, ErrorKind, Result};
-use tokio::net::UdpSocket;
+use tokio::io::Result;
 use tokio::time::{Duration, Instant};
 
 #[cfg(not(target_os = "linux"))]
@@ -18,26 +21,70 @@ use tokio_timerfd::sleep;
 const TIMERS_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 const UDP_RCV_TIMEOUT: Duration = Duration::from_micros(30);
 
+/// Length, source address, local address and GRO segment size of a received datagram
+pub(crate) type Datagram = (usize, SocketAddr, Option<IpAddr>, Option<usize>);
+
 #[derive(Debug)]
 pub(crate) struct UdtRcvQueue {
     sockets: Mutex<VecDeque<(Instant, SocketId)>>,
-    mss: u32,
-    channel: Arc<UdpSocket>,
+    datagram_size: usize,
+    batch_size: usize,
+    busy_poll: Option<Duration>,
+    pool: Arc<PacketPool>,
+    channel: Channel,
     multiplexer: Mutex<Weak<UdtMultiplexer>>,
     socket_refs: Mutex<BTreeMap<SocketId, Weak<UdtSocket>>>,
+    #[cfg(target_os = "linux")]
+    headers: Mutex<MmsgHeaders>, // of the `recvmmsg` calls
+    udp_rcv_drops: AtomicU32, // kernel drop counter (SO_RXQ_OVFL)
+    stray_packets: AtomicU64,
 }
 
 impl UdtRcvQueue {
-    pub fn new(channel: Arc<UdpSocket>, mss: u32) -> Self {
+    pub fn new(
+        channel: Channel,
+        datagram_size: usize,
+        batch_size: usize,
+        busy_poll: Option<Duration>,
+        pool_size: usize,
+        fixed_capacity: bool,
+    ) -> Self {
+        let datagram_size = datagram_size.max(1);
+        let batch_size = batch_size.max(1);
         Self {
             sockets: Mutex::new(VecDeque::new()),
-            mss,
+            datagram_size,
+            batch_size,
+            busy_poll: busy_poll.filter(|_| cfg!(target_os = "linux")),
+            pool: PacketPool::new(datagram_size, pool_size, fixed_capacity),
             channel,
             multiplexer: Mutex::new(Weak::new()),
             socket_refs: Mutex::new(BTreeMap::new()),
+            #[cfg(target_os = "linux")]
+            headers: Mutex::new(MmsgHeaders::with_capacity(batch_size)),
+            udp_rcv_drops: AtomicU32::new(0),
+            stray_packets: AtomicU64::new(0),
         }
     }
 
+    /// Number of packets addressed to an unknown socket, or coming from another
+    /// address than the peer of the destination socket.
+    pub fn stray_packets(&self) -> u64 {
+        self.stray_packets.load(Ordering::Relaxed)
+    }
+
+    /// Counts a stray packet. Anyone can send them: they must not be logged beyond
+    /// debug traces, which would flood the logs.
+    fn record_stray_packet(&self, _addr: SocketAddr, _socket_id: SocketId, _reason: &str) {
+        self.stray_packets.fetch_add(1, Ordering::Relaxed);
+        trace_event!(debug, peer = %_addr, socket_id = _socket_id, reason = _reason, "stray packet");
+    }
+
+    /// Number of datagrams dropped by the kernel because the UDP receive buffer was full.
+    pub fn udp_rcv_drops(&self) -> u64 {
+        self.udp_rcv_drops.load(Ordering::Relaxed).into()
+    }
+
     pub fn push_back(&self, socket_id: SocketId) {
         self.sockets
             .lock()
@@ -51,6 +98,14 @@ impl UdtRcvQueue {
         queue.push_back((Instant::now(), socket_id));
     }
 
+    pub fn remove(&self, socket_id: SocketId) {
+        self.sockets
+            .lock()
+            .unwrap()
+            .retain(|(_, id)| socket_id != *id);
+        self.socket_refs.lock().unwrap().remove(&socket_id);
+    }
+
     pub fn set_multiplexer(&self, mux: &Arc<UdtMultiplexer>) {
         *self.multiplexer.lock().unwrap() = Arc::downgrade(mux);
     }
@@ -70,114 +125,201 @@ impl UdtRcvQueue {
         }
     }
 
+    /// Socket setting up a rendezvous connection with `addr`, or connected by one.
+    async fn get_rendezvous_socket(
+        &self,
+        mux: Option<&Arc<UdtMultiplexer>>,
+        addr: SocketAddr,
+    ) -> Option<SocketRef> {
+        let socket_id = mux?.rendezvous.lock().unwrap().get(&addr).copied()?;
+        self.get_socket(socket_id).await
+    }
+
+    /// Receives a batch of datagrams into `bufs`, described in `msgs`. With `busy`,
+    /// the UDP socket is read directly, without waiting for the runtime to report it readable.
     #[cfg(target_os = "linux")]
-    fn receive_packets(&self, buf: &mut [u8]) -> Result<Vec<(usize, SocketAddr)>> {
-        use nix::sys::socket::{
-            recvmmsg, AddressFamily, MsgFlags, RecvMmsgData, SockaddrLike, SockaddrStorage,
-        };
-        use std::io::IoSliceMut;
+    fn receive_packets(
+        &self,
+        bufs: &mut [PacketBuf],
+        msgs: &mut Vec<Datagram>,
+        busy: bool,
+    ) -> Result<()> {
         use std::os::unix::io::AsRawFd;
         use tokio::io::Interest;
-        let bufs = buf.chunks_exact_mut(self.mss as usize);
-        let mut recv_mesg_data: Vec<RecvMmsgData<_>> = bufs
-            .map(|b| RecvMmsgData {
-                iov: [IoSliceMut::new(&mut b[..])],
-                cmsg_buffer: None,
-            })
-            .collect();
-
-        self.channel.try_io(Interest::READABLE, || {
-            let msgs = recvmmsg(
-                self.channel.as_raw_fd(),
-                &mut recv_mesg_data,
-                MsgFlags::MSG_DONTWAIT,
-                None,
-            )
-            .map_err(|err| {
-                if err == nix::errno::Errno::EWOULDBLOCK {
-                    return Error::new(ErrorKind::WouldBlock, "recvmmsg would block");
-                }
-                Error::new(ErrorKind::Other, err)
-            })?
-            .iter()
-            .map(|msg| {
-                let addr: SockaddrStorage = msg.address.unwrap();
-                let socket_addr: SocketAddr = match addr.family() {
-                    Some(AddressFamily::Inet) => {
-                        Self::addr_v4_from_sockaddrin(*addr.as_sockaddr_in().unwrap()).into()
-                    }
-                    Some(AddressFamily::Inet6) => {
-                        Self::addr_v6_from_sockaddrin6(*addr.as_sockaddr_in6().unwrap()).into()
-                    }
-                    _ => unreachable!(),
-                };
-                (msg.bytes, socket_addr)
-            })
-            .collect();
-            Ok(msgs)
-        })
+        let udp_socket = match &self.channel {
+            Channel::Udp(udp_socket) => udp_socket,
+            Channel::Memory(memory) => {
+                self.receive_in_memory(memory, bufs, msgs);
+                return Ok(());
+            }
+        };
+        let mut recvmmsg = || {
+            let drops =
+                self.headers
+                    .lock()
+                    .unwrap()
+                    .recvmmsg(udp_socket.as_raw_fd(), bufs, msgs)?;
+            if let Some(drops) = drops {
+                self.udp_rcv_drops.store(drops, Ordering::Relaxed);
+            }
+            Ok(())
+        };
+        if busy {
+            recvmmsg()
+        } else {
+            udp_socket.try_io(Interest::READABLE, recvmmsg)
+        }
     }
 
     #[cfg(not(target_os = "linux"))]
-    fn receive_packets(&self, buf: &mut [u8]) -> Result<Vec<(usize, SocketAddr)>> {
-        let bufs = buf.chunks_exact_mut(self.mss as usize);
-        let mut msgs = vec![];
-        for mut buf in bufs {
-            match self.channel.try_recv_from(&mut buf) {
-                Ok(msg) => {
-                    msgs.push(msg);
+    fn receive_packets(
+        &self,
+        bufs: &mut [PacketBuf],
+        msgs: &mut Vec<Datagram>,
+        _busy: bool,
+    ) -> Result<()> {
+        let udp_socket = match &self.channel {
+            Channel::Udp(udp_socket) => udp_socket,
+            Channel::Memory(memory) => {
+                self.receive_in_memory(memory, bufs, msgs);
+                return Ok(());
+            }
+        };
+        for buf in bufs {
+            match udp_socket.try_recv_from(buf) {
+                Ok((nbytes, addr)) => {
+                    msgs.push((nbytes, addr, None, None));
                 }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
                 Err(e) => return Err(e),
             }
         }
-        Ok(msgs)
+        Ok(())
+    }
+
+    /// Copies the datagrams queued to an in-memory channel into `bufs`.
+    /// Like UDP, datagrams longer than a buffer are truncated.
+    fn receive_in_memory(
+        &self,
+        memory: &MemoryChannel,
+        bufs: &mut [PacketBuf],
+        msgs: &mut Vec<Datagram>,
+    ) {
+        self.udp_rcv_drops.store(memory.drops(), Ordering::Relaxed);
+        let datagrams = memory.try_recv(bufs.len()).into_iter().zip(bufs.iter_mut());
+        msgs.extend(datagrams.map(|((data, addr), buf)| {
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            (len, addr, None, None)
+        }));
     }
 
     pub(crate) async fn worker(&self) -> Result<()> {
-        let mut buf = vec![0_u8; self.mss as usize * 100];
+        // Datagrams are received into pooled buffers, referenced by the packets without copy
+        // unless they are much smaller (see `PacketBuf::into_datagrams`).
+        // The scratch vectors are reused from one batch to the next.
+        let mut bufs: Vec<PacketBuf> = Vec::with_capacity(self.batch_size);
+        let mut msgs: Vec<Datagram> = Vec::with_capacity(self.batch_size);
+        let mut to_check: Vec<SocketId> = vec![];
         loop {
-            let packets = {
-                let msgs = self.receive_packets(&mut buf).unwrap_or_default();
-                if msgs.is_empty() {
-                    tokio::select! {
-                        _ = sleep(UDP_RCV_TIMEOUT) => (),
-                        _ = self.channel.readable() => ()
-                    };
-                    None
-                } else {
-                    let packets: Vec<_> = msgs
-                        .into_iter()
-                        .zip(buf.chunks_exact_mut(self.mss as usize))
-                        .filter_map(|((nbytes, addr), buf)| {
-                            let packet = UdtPacket::deserialize(&buf[..nbytes]).ok()?;
-
-                            Some((packet, addr))
-                        })
-                        .collect();
-                    Some(packets)
+            while bufs.len() < self.batch_size {
+                // A fixed-capacity pool may be exhausted: datagrams wait in the UDP socket
+                // until packets are released
+                let Some(mut buf) = self.pool.take() else {
+                    break;
+                };
+                buf.resize(self.datagram_size, 0);
+                bufs.push(buf);
+            }
+            msgs.clear();
+            if !bufs.is_empty() {
+                let _ = self.receive_packets(&mut bufs, &mut msgs, false);
+                if let (true, Some(busy_poll)) = (msgs.is_empty(), self.busy_poll) {
+                    let deadline = Instant::now() + busy_poll;
+                    while msgs.is_empty() && Instant::now() < deadline {
+                        std::hint::spin_loop();
+                        let _ = self.receive_packets(&mut bufs, &mut msgs, true);
+                    }
                 }
-            };
+            }
+            if msgs.is_empty() {
+                // In-memory channels use Tokio timers, which follow the paused clock of tests
+                let in_memory = matches!(self.channel, Channel::Memory(_));
+                tokio::select! {
+                    _ = sleep(UDP_RCV_TIMEOUT), if !in_memory => (),
+                    _ = tokio::time::sleep(UDP_RCV_TIMEOUT), if in_memory => (),
+                    _ = self.channel.readable(), if !bufs.is_empty() => ()
+                };
+            }
+            trace_event!(trace, count = msgs.len(), "received datagrams");
 
-            for (packet, addr) in packets.into_iter().flatten() {
-                let socket_id = packet.get_dest_socket_id();
+            let count = msgs.len();
+            let datagrams = msgs.drain(..).zip(bufs.drain(..count)).flat_map(
+                |((nbytes, addr, local_ip, segment_size), buf)| {
+                    // With GRO, a buffer holds consecutive datagrams of `segment_size`
+                    // bytes from the same peer (the last one may be shorter)
+                    let segment_size = segment_size.unwrap_or(nbytes);
+                    buf.into_datagrams(nbytes, segment_size)
+                        .map(move |raw| (raw, addr, local_ip))
+                },
+            );
+            for (raw, addr, local_ip) in datagrams {
+                // The destination socket id is sent in clear, even by encrypted connections
+                let socket_id = match raw.get(12..16) {
+                    Some(id) => SocketId::from_be_bytes(id.try_into().unwrap()),
+                    None => continue,
+                };
                 if socket_id == 0 {
-                    if let Some(handshake) = packet.handshake() {
-                        let mux = {
-                            let lock = self.multiplexer.lock().unwrap();
-                            lock.upgrade()
-                        };
+                    let mux = {
+                        let lock = self.multiplexer.lock().unwrap();
+                        lock.upgrade()
+                    };
+                    if mux
+                        .as_ref()
+                        .is_some_and(|mux| !mux.handshake_limiter.allow(addr.ip()))
+                    {
+                        continue;
+                    }
+                    let packet = match UdtPacket::deserialize(raw) {
+                        Ok(packet) => packet,
+                        Err(_) => continue,
+                    };
+                    // Rendezvous handshakes are sent in clear, even once connected
+                    let rendezvous_socket = match packet.handshake() {
+                        Some(_) => self.get_rendezvous_socket(mux.as_ref(), addr).await,
+                        None => None,
+                    };
+                    if let Some(socket) = rendezvous_socket {
+                        // Only a connecting socket answers, see `process_rendezvous_handshake`
+                        if socket.status() != UdtStatus::Connecting {
+                            continue;
+                        }
+                        let processed =
+                            socket.do_work(SocketWork::Receive, socket.process_packet(packet));
+                        if let Err(err) = processed.await {
+                            socket.break_with(err);
+                            continue;
+                        }
+                        socket
+                            .do_work(SocketWork::Timers, socket.check_timers())
+                            .await;
+                        self.update(socket.socket_id);
+                    } else if let Some(handshake) = packet.handshake() {
                         if let Some(mux) = mux {
                             let listener = mux.listener.read().await;
                             if let Some(listener) = &*listener {
-                                listener.listen_on_handshake(addr, handshake).await?;
+                                let request =
+                                    listener.listen_on_handshake(addr, handshake, local_ip);
+                                if let Err(_err) = listener.in_handshake_span(addr, request).await {
+                                    // A rejected request must not stop the multiplexer, nor be
+                                    // logged: spoofed requests would flood the logs
+                                    mux.handshakes_rejected.fetch_add(1, Ordering::Relaxed);
+                                    trace_event!(debug, peer = %addr, error = %_err, "connection request failed");
+                                }
                             }
                         }
                     } else {
-                        return Err(Error::new(
-                            ErrorKind::InvalidData,
-                            "received non-hanshake packet with socket 0",
-                        ));
+                        self.record_stray_packet(addr, socket_id, "non-handshake packet");
                     }
                 } else {
                     // if !self.sockets.contains(&socket_id) {
@@ -186,58 +328,63 @@ impl UdtRcvQueue {
                     // }
 
                     if let Some(socket) = self.get_socket(socket_id).await {
-                        if socket.peer_addr() == Some(addr) && socket.status().is_alive() {
-                            socket.process_packet(packet).await?;
-                            socket.check_timers().await;
+                        let raw = match socket.open_packet(raw) {
+                            Some(raw) => raw,
+                            None => {
+                                self.record_stray_packet(addr, socket_id, "authentication failure");
+                                continue;
+                            }
+                        };
+                        let packet = match UdtPacket::deserialize(raw) {
+                            Ok(packet) => packet,
+                            Err(_) => continue,
+                        };
+                        let from_peer = socket.peer_addr() == Some(addr)
+                            || socket.check_peer_migration(&packet, addr);
+                        if from_peer && socket.status().is_alive() {
+                            let processed =
+                                socket.do_work(SocketWork::Receive, socket.process_packet(packet));
+                            if let Err(err) = processed.await {
+                                socket.break_with(err);
+                                continue;
+                            }
+                            socket
+                                .do_work(SocketWork::Timers, socket.check_timers())
+                                .await;
                             self.update(socket_id);
-                        } else if *UDT_DEBUG {
-                            eprintln!("Ignoring packet {:?}", packet);
+                        } else {
+                            self.record_stray_packet(addr, socket_id, "unexpected peer");
+                            if *UDT_DEBUG {
+                                eprintln!("Ignoring packet {:?}", packet);
+                            }
                         }
                     } else {
-                        // TODO: implement rendezvous queue
-
-                        if *UDT_DEBUG {
-                            eprintln!("socket not found for socket_id {}", socket_id);
-                            dbg!(packet);
-                        }
+                        self.record_stray_packet(addr, socket_id, "unknown socket");
+                        trace_event!(trace, socket_id, len = raw.len(), "socket not found");
                     }
                 }
             }
 
-            let to_check = {
-                let mut to_check = vec![];
+            {
                 let mut sockets = self.sockets.lock().unwrap();
                 while sockets
                     .front()
-                    .map_or(false, |(ts, _)| ts.elapsed() > TIMERS_CHECK_INTERVAL)
+                    .is_some_and(|(ts, _)| ts.elapsed() > TIMERS_CHECK_INTERVAL)
                 {
                     to_check.push(sockets.pop_front().unwrap().1);
                 }
-                to_check
-            };
+            }
 
-            for socket_id in to_check {
+            for socket_id in to_check.drain(..) {
                 if let Some(socket) = self.get_socket(socket_id).await {
                     if socket.status().is_alive() {
-                        socket.check_timers().await;
+                        socket
+                            .do_work(SocketWork::Timers, socket.check_timers())
+                            .await;
                         self.update(socket_id);
                     }
                 }
             }
         }
     }
-
-    // TEMP: waiting for "nix" next release (> 0.24.2) to include these conversions
-    fn addr_v4_from_sockaddrin(addr: SockaddrIn) -> std::net::SocketAddrV4 {
-        std::net::SocketAddrV4::new(std::net::Ipv4Addr::from(addr.ip()), addr.port())
-    }
-
-    fn addr_v6_from_sockaddrin6(addr: SockaddrIn6) -> std::net::SocketAddrV6 {
-        std::net::SocketAddrV6::new(
-            addr.ip(),
-            addr.port(),
-            u32::from_be(addr.flowinfo()),
-            u32::from_be(addr.scope_id()),
-        )
-    }
 }
@@ -1,13 +1,16 @@
 use crate::data_packet::{PacketPosition, UdtDataPacket, UdtDataPacketHeader};
+use crate::error::{UdtError, UdtErrorCode};
+use crate::queue::{PacketBuf, PacketPool};
 use crate::seq_number::MsgNumber;
 use crate::seq_number::SeqNumber;
 use crate::socket::SocketId;
 use bytes::Bytes;
 use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::sync::Arc;
 use tokio::io::{Error, ErrorKind, Result as IoResult};
 use tokio::time::{Duration, Instant};
 
-const FETCH_BATCH_SIZE: usize = 100;
 const DEFAULT_PAYLOAD_SIZE: usize = 1500;
 
 #[derive(Debug, Clone)]
@@ -16,6 +19,7 @@ pub(crate) struct SndBufferBlock {
     msg_number: MsgNumber,
     origin_time: Instant,
     ttl: Option<u64>, // milliseconds,
+    deadline: Option<Instant>,
     in_order: bool,
     position: PacketPosition,
 }
@@ -28,12 +32,28 @@ impl SndBufferBlock {
         false
     }
 
+    fn has_missed_deadline(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now > deadline)
+    }
+
+    fn can_expire(&self) -> bool {
+        self.deadline.is_some() || self.ttl.is_some()
+    }
+
     fn as_data_packet(
         &self,
         seq_number: SeqNumber,
         dest_socket_id: SocketId,
         start_time: Instant,
+        origin_timestamps: bool,
     ) -> UdtDataPacket {
+        // In live mode, retransmissions keep the time the data was handed to the socket,
+        // from which the receiver schedules its delivery
+        let time = if origin_timestamps {
+            self.origin_time
+        } else {
+            Instant::now()
+        };
         UdtDataPacket {
             data: self.data.clone(),
             header: UdtDataPacketHeader {
@@ -42,7 +62,8 @@ impl SndBufferBlock {
                 seq_number,
                 in_order: self.in_order,
                 position: self.position,
-                timestamp: (start_time.elapsed().as_micros() & (u128::from(u32::MAX))) as u32,
+                timestamp: (time.saturating_duration_since(start_time).as_micros()
+                    & u128::from(u32::MAX)) as u32,
             },
         }
     }
@@ -55,58 +76,213 @@ pub(crate) struct SndBuffer {
     payload_size: usize,
     next_msg_number: MsgNumber,
     current_position: usize,
+    unsent_expirable: usize, // nb of unsent blocks having a deadline or a TTL
+    pool: Option<Arc<PacketPool>>,
+    origin_timestamps: bool, // live mode
+    fixed_size: Option<u32>, // fixed-capacity mode: preallocated size
 }
 
 impl SndBuffer {
-    pub fn new(max_size: u32) -> Self {
+    pub fn new(max_size: u32, origin_timestamps: bool, fixed_capacity: bool) -> Self {
+        let buffer = match fixed_capacity {
+            // The end of the stream may be added to a full buffer
+            true => VecDeque::with_capacity(max_size as usize + 1),
+            false => VecDeque::new(),
+        };
         Self {
             max_size,
-            buffer: VecDeque::new(),
+            buffer,
             payload_size: DEFAULT_PAYLOAD_SIZE, // overwritten after connection
             next_msg_number: MsgNumber::zero(),
             current_position: 0,
+            unsent_expirable: 0,
+            pool: None,
+            origin_timestamps,
+            fixed_size: fixed_capacity.then_some(max_size),
         }
     }
 
-    pub fn add_message(&mut self, data: &[u8], ttl: Option<u64>, in_order: bool) -> IoResult<()> {
-        let msg_number = self.next_msg_number;
-        let now = Instant::now();
-        let chunks = data.chunks(self.payload_size);
-        let chunks_len = chunks.len();
-
+    fn check_capacity(&self, data_len: usize) -> IoResult<usize> {
+        let chunks_len = data_len.div_ceil(self.payload_size);
+        if chunks_len > self.max_size as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                UdtError::new(
+                    UdtErrorCode::LargeMsg,
+                    "message is too large to be held in the send buffer",
+                ),
+            ));
+        }
         if self.buffer.len() + chunks_len > self.max_size as usize {
-            return Err(Error::new(ErrorKind::OutOfMemory, "Send buffer is full"));
+            return Err(Error::new(
+                ErrorKind::OutOfMemory,
+                UdtError::new(UdtErrorCode::AsyncSnd, "Send buffer is full"),
+            ));
         }
+        Ok(chunks_len)
+    }
 
-        self.buffer
-            .extend(chunks.enumerate().map(|(idx, chunk)| SndBufferBlock {
-                data: Bytes::copy_from_slice(chunk),
+    pub fn add_message(
+        &mut self,
+        data: &[u8],
+        ttl: Option<u64>,
+        deadline: Option<Instant>,
+        in_order: bool,
+    ) -> IoResult<()> {
+        self.check_capacity(data.len())?;
+        let chunks = self.copy_chunks([data]);
+        self.push_message(chunks, ttl, deadline, in_order)
+    }
+
+    /// Adds a message made of the concatenation of `bufs`.
+    pub fn add_message_vectored(&mut self, bufs: &[IoSlice<'_>]) -> IoResult<()> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        self.check_capacity(len)?;
+        let chunks = self.copy_chunks(bufs.iter().map(|buf| &**buf));
+        self.push_message(chunks, None, None, false)
+    }
+
+    /// Adds a message without copying its payload: buffer blocks are slices of `data`.
+    pub fn add_message_bytes(
+        &mut self,
+        data: Bytes,
+        ttl: Option<u64>,
+        deadline: Option<Instant>,
+        in_order: bool,
+    ) -> IoResult<()> {
+        let chunks_len = self.check_capacity(data.len())?;
+        let payload_size = self.payload_size;
+        let chunks = (0..chunks_len).map(|idx| {
+            Ok(data.slice(idx * payload_size..data.len().min((idx + 1) * payload_size)))
+        });
+        self.push_message(chunks, ttl, deadline, in_order)
+    }
+
+    /// Copies `bufs` into payload-sized chunks, taken from the packet pool if any.
+    fn copy_chunks<'a, I>(&self, bufs: I) -> CopiedChunks<'a, I::IntoIter>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        CopiedChunks {
+            bufs: bufs.into_iter(),
+            current: &[],
+            pool: self.pool.clone(),
+            payload_size: self.payload_size,
+        }
+    }
+
+    /// Appends the blocks of a message. If a chunk can not be built, e.g. once a
+    /// fixed-capacity packet pool is exhausted, the message is not added.
+    fn push_message(
+        &mut self,
+        chunks: impl Iterator<Item = IoResult<Bytes>>,
+        ttl: Option<u64>,
+        deadline: Option<Instant>,
+        in_order: bool,
+    ) -> IoResult<()> {
+        let msg_number = self.next_msg_number;
+        let now = Instant::now();
+        let first = self.buffer.len();
+        for data in chunks {
+            let data = match data {
+                Ok(data) => data,
+                Err(err) => {
+                    self.buffer.truncate(first);
+                    return Err(err);
+                }
+            };
+            self.buffer.push_back(SndBufferBlock {
+                data,
                 msg_number,
                 origin_time: now,
                 ttl,
+                deadline,
                 in_order,
-                position: {
-                    if idx == 0 && chunks_len == 1 {
-                        PacketPosition::Only
-                    } else if idx == 0 {
-                        PacketPosition::First
-                    } else if idx == chunks_len - 1 {
-                        PacketPosition::Last
-                    } else {
-                        PacketPosition::Middle
-                    }
-                },
-            }));
+                position: PacketPosition::Middle,
+            });
+        }
+
+        let chunks_len = self.buffer.len() - first;
+        for (idx, block) in self.buffer.range_mut(first..).enumerate() {
+            block.position = if idx == 0 && chunks_len == 1 {
+                PacketPosition::Only
+            } else if idx == 0 {
+                PacketPosition::First
+            } else if idx == chunks_len - 1 {
+                PacketPosition::Last
+            } else {
+                PacketPosition::Middle
+            };
+        }
         self.next_msg_number = self.next_msg_number + 1;
+        if deadline.is_some() || ttl.is_some() {
+            self.unsent_expirable += chunks_len;
+        }
         Ok(())
     }
 
-    pub fn ack_data(&mut self, offset: i32) {
-        for _ in 0..offset {
-            if self.buffer.pop_front().is_some() {
-                self.current_position -= 1;
+    /// Adds the end of the stream, an empty packet: the peer reads nothing after it.
+    /// It is added even if the buffer is full.
+    pub fn add_eof(&mut self) {
+        let _ = self.push_message(std::iter::once(Ok(Bytes::new())), None, None, false);
+    }
+
+    /// Removes the messages that missed their deadline or whose TTL expired before
+    /// any of their packets was sent.
+    /// Returns the number of messages removed because of their deadline and of their TTL.
+    pub fn drop_unsent_expired(&mut self) -> (usize, usize) {
+        if self.unsent_expirable == 0 {
+            return (0, 0);
+        }
+        let now = Instant::now();
+        let mut pos = self.current_position;
+        // A partially sent message must be completed
+        while pos < self.buffer.len()
+            && matches!(
+                self.buffer[pos].position,
+                PacketPosition::Middle | PacketPosition::Last
+            )
+        {
+            pos += 1;
+        }
+
+        let (mut deadline_drops, mut ttl_drops) = (0, 0);
+        while pos < self.buffer.len() {
+            let msg_number = self.buffer[pos].msg_number;
+            let end = pos
+                + self
+                    .buffer
+                    .range(pos..)
+                    .take_while(|block| block.msg_number == msg_number)
+                    .count();
+            if self.buffer[pos].has_missed_deadline(now) {
+                self.buffer.drain(pos..end);
+                deadline_drops += 1;
+            } else if self.buffer[pos].has_expired() {
+                self.buffer.drain(pos..end);
+                ttl_drops += 1;
+            } else {
+                pos = end;
             }
         }
+        // Recount, as messages expired by their TTL may have been skipped without being fetched
+        self.unsent_expirable = self
+            .buffer
+            .range(self.current_position..)
+            .filter(|block| block.can_expire())
+            .count();
+        (deadline_drops, ttl_drops)
+    }
+
+    /// Releases the first `offset` blocks, acknowledged by the peer.
+    /// Returns the number of released bytes.
+    pub fn ack_data(&mut self, offset: i32) -> usize {
+        let count = usize::try_from(offset).unwrap_or(0).min(self.buffer.len());
+        self.current_position = self.current_position.saturating_sub(count);
+        self.buffer
+            .drain(..count)
+            .map(|block| block.data.len())
+            .sum()
     }
 
     pub fn read_data(
@@ -134,38 +310,208 @@ impl SndBuffer {
                 }
                 Err((block.msg_number, msg_len))
             } else {
-                Ok(block.as_data_packet(seq_number, dest_socket_id, start_time))
+                Ok(block.as_data_packet(
+                    seq_number,
+                    dest_socket_id,
+                    start_time,
+                    self.origin_timestamps,
+                ))
             }
         } else {
             Err((MsgNumber::zero(), 0)) // No msg found
         }
     }
 
+    /// Appends up to `max_packets` packets not sent yet to `packets`.
     pub fn fetch_batch(
         &mut self,
         mut seq_number: SeqNumber,
         dest_socket_id: SocketId,
         start_time: Instant,
-    ) -> Vec<UdtDataPacket> {
-        let blocks: Vec<_> = self
-            .buffer
-            .range(self.current_position..)
-            .take(FETCH_BATCH_SIZE)
-            .map(|block| {
-                let packet = block.as_data_packet(seq_number, dest_socket_id, start_time);
-                seq_number = seq_number + 1;
-                packet
-            })
-            .collect();
-        self.current_position += blocks.len();
-        blocks
+        max_packets: usize,
+        packets: &mut Vec<UdtDataPacket>,
+    ) {
+        let fetched = packets.len();
+        packets.extend(
+            self.buffer
+                .range(self.current_position..)
+                .take(max_packets)
+                .map(|block| {
+                    if block.can_expire() {
+                        self.unsent_expirable -= 1;
+                    }
+                    let packet = block.as_data_packet(
+                        seq_number,
+                        dest_socket_id,
+                        start_time,
+                        self.origin_timestamps,
+                    );
+                    seq_number = seq_number + 1;
+                    packet
+                }),
+        );
+        self.current_position += packets.len() - fetched;
     }
 
     pub fn is_empty(&self) -> bool {
         self.buffer.is_empty()
     }
 
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Number of bytes which can be added without exceeding the size of the buffer.
+    pub fn room(&self) -> usize {
+        (self.max_size as usize).saturating_sub(self.buffer.len()) * self.payload_size
+    }
+
+    /// Changes the capacity. Blocks beyond a smaller capacity are kept,
+    /// no data is accepted until they are acknowledged.
+    /// A fixed-capacity buffer can not grow past its initial size.
+    pub fn set_max_size(&mut self, max_size: u32) {
+        self.max_size = match self.fixed_size {
+            Some(fixed_size) => max_size.min(fixed_size),
+            None => max_size,
+        };
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.buffer.len() < self.max_size as usize
+    }
+
     pub fn set_payload_size(&mut self, payload_size: usize) {
         self.payload_size = payload_size;
     }
+
+    pub fn set_pool(&mut self, pool: Arc<PacketPool>) {
+        self.pool = Some(pool);
+    }
+}
+
+/// Payload-sized chunks copied from a sequence of buffers, see `SndBuffer::copy_chunks`.
+struct CopiedChunks<'a, I> {
+    bufs: I,
+    current: &'a [u8],
+    pool: Option<Arc<PacketPool>>,
+    payload_size: usize,
+}
+
+impl<'a, I: Iterator<Item = &'a [u8]>> Iterator for CopiedChunks<'a, I> {
+    type Item = IoResult<Bytes>;
+
+    fn next(&mut self) -> Option<IoResult<Bytes>> {
+        while self.current.is_empty() {
+            self.current = self.bufs.next()?;
+        }
+        let mut chunk = match &self.pool {
+            Some(pool) if pool.buf_size() >= self.payload_size => match pool.take() {
+                Some(chunk) => chunk,
+                None => {
+                    return Some(Err(Error::new(
+                        ErrorKind::OutOfMemory,
+                        UdtError::new(UdtErrorCode::AsyncSnd, "Packet pool is exhausted"),
+                    )))
+                }
+            },
+            _ => PacketBuf::detached(self.payload_size),
+        };
+        while chunk.len() < self.payload_size {
+            if self.current.is_empty() {
+                match self.bufs.next() {
+                    Some(buf) => self.current = buf,
+                    None => break,
+                }
+                continue;
+            }
+            let len = self.current.len().min(self.payload_size - chunk.len());
+            chunk.extend_from_slice(&self.current[..len]);
+            self.current = &self.current[len..];
+        }
+        Some(Ok(chunk.freeze()))
+    }
+}
+
+#[test]
+fn test_drop_unsent_expired() {
+    let mut buffer = SndBuffer::new(100, false, false);
+    buffer.set_payload_size(4);
+    let past = Instant::now() - Duration::from_millis(1);
+    buffer
+        .add_message(b"sent", None, Some(past), false)
+        .unwrap();
+    let mut packets = vec![];
+    buffer.fetch_batch(1.into(), 0, Instant::now(), 100, &mut packets);
+    assert_eq!(packets.len(), 1);
+
+    buffer
+        .add_message(b"expired.", None, Some(past), false)
+        .unwrap();
+    buffer.add_message(b"ttl.", Some(0), None, false).unwrap();
+    buffer.add_message(b"kept", None, None, false).unwrap();
+    std::thread::sleep(Duration::from_millis(1));
+    assert_eq!(buffer.drop_unsent_expired(), (1, 1));
+
+    packets.clear();
+    buffer.fetch_batch(2.into(), 0, Instant::now(), 100, &mut packets);
+    assert_eq!(packets.len(), 1);
+    assert_eq!(&packets[0].data[..], b"kept");
+}
+
+#[test]
+fn test_capacity() {
+    let mut buffer = SndBuffer::new(3, false, false);
+    buffer.set_payload_size(4);
+    buffer.add_message(b"12345678", None, None, false).unwrap();
+    assert_eq!(buffer.room(), 4);
+    let full = buffer.add_message(b"12345", None, None, false).unwrap_err();
+    assert_eq!(full.kind(), ErrorKind::OutOfMemory);
+    let too_large = buffer.add_message(&[0; 13], None, None, false).unwrap_err();
+    assert_eq!(too_large.kind(), ErrorKind::InvalidInput);
+
+    // Data beyond a smaller capacity is kept
+    buffer.set_max_size(1);
+    assert_eq!(buffer.room(), 0);
+    assert!(!buffer.has_room());
+    buffer.set_max_size(4);
+    buffer.add_message(b"12345", None, None, false).unwrap();
+}
+
+#[test]
+fn test_fixed_capacity() {
+    let mut buffer = SndBuffer::new(4, false, true);
+    buffer.set_payload_size(4);
+    buffer.set_pool(PacketPool::new(4, 2, true));
+    // A message not fitting in the pool is rolled back
+    let exhausted = buffer
+        .add_message(b"123456789", None, None, false)
+        .unwrap_err();
+    assert_eq!(exhausted.kind(), ErrorKind::OutOfMemory);
+    assert!(buffer.is_empty());
+    buffer.add_message(b"12345678", None, None, false).unwrap();
+    assert_eq!(buffer.len(), 2);
+
+    buffer.set_max_size(10);
+    assert_eq!(buffer.room(), 8);
+}
+
+#[test]
+fn test_origin_timestamps() {
+    let start_time = Instant::now();
+    for origin_timestamps in [false, true] {
+        let mut buffer = SndBuffer::new(10, origin_timestamps, false);
+        buffer.set_payload_size(4);
+        buffer.add_message(b"data", None, None, false).unwrap();
+        let handed = start_time.elapsed();
+        std::thread::sleep(Duration::from_millis(20));
+        let mut packets = vec![];
+        buffer.fetch_batch(1.into(), 0, start_time, 1, &mut packets);
+        let timestamp = Duration::from_micros(packets[0].header.timestamp.into());
+        // Live mode stamps the time the data was handed to the socket, not the send time
+        assert_eq!(
+            timestamp <= handed,
+            origin_timestamps,
+            "{timestamp:?} {handed:?}"
+        );
+    }
 }
@@ -1,13 +1,23 @@
-use crate::socket::{SocketId, UdtSocket};
+use crate::data_packet::{UdtDataPacket, UDT_DATA_HEADER_SIZE};
+use crate::error::{UdtError, UdtErrorCode};
+use crate::multiplexer::UdtMultiplexer;
+use crate::packet::UdtPacket;
+use crate::rate_limiter::EgressLimiter;
+use crate::socket::{SocketId, SocketWork, UdtSocket};
 use crate::udt::{SocketRef, Udt};
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, BinaryHeap};
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::{Arc, Mutex, Weak};
-use tokio::io::Result;
+use tokio::io::{Error, ErrorKind, Result};
 use tokio::sync::Notify;
 use tokio::time::Instant;
 
-const TOKIO_CHANNEL_CAPACITY: usize = 50;
+// Batches of data packets queued to the sending task, beyond which the worker waits
+const DATA_QUEUE_CAPACITY: usize = 50;
+// Control packets queued beyond are dropped, like datagrams beyond a UDP send buffer
+const CONTROL_QUEUE_CAPACITY: usize = 1024;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 struct SendQueueNode {
@@ -28,21 +38,130 @@ impl PartialOrd for SendQueueNode {
     }
 }
 
+/// Control packet ready to be sent: sealed, with the address and source IP of its socket.
+#[derive(Debug)]
+struct QueuedControl {
+    addr: SocketAddr,
+    packet: UdtPacket,
+    src_ip: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+struct ControlQueue {
+    packets: Mutex<VecDeque<QueuedControl>>,
+    notify: Notify,
+}
+
+impl ControlQueue {
+    fn pop(&self) -> Option<QueuedControl> {
+        self.packets.lock().unwrap().pop_front()
+    }
+}
+
+/// Batches of data packets handed by the worker to the sending task, bounded like a channel
+/// but allocated up front.
+#[derive(Debug)]
+struct DataQueue {
+    batches: Mutex<VecDeque<(SocketRef, Vec<UdtDataPacket>)>>,
+    closed: AtomicBool, // the worker or the sending task stopped
+    ready: Notify,      // a batch was queued
+    room: Notify,       // a batch was taken
+}
+
+impl DataQueue {
+    fn new() -> Self {
+        Self {
+            batches: Mutex::new(VecDeque::with_capacity(DATA_QUEUE_CAPACITY)),
+            closed: AtomicBool::new(false),
+            ready: Notify::new(),
+            room: Notify::new(),
+        }
+    }
+
+    /// Queues a batch, waiting while `DATA_QUEUE_CAPACITY` batches are queued.
+    /// Fails once the sending task stopped.
+    async fn push(&self, socket: SocketRef, packets: Vec<UdtDataPacket>) -> Result<()> {
+        loop {
+            if self.closed.load(AtomicOrdering::Acquire) {
+                return Err(Error::other(UdtError::new(
+                    UdtErrorCode::Thread,
+                    "packet sending task stopped",
+                )));
+            }
+            if self.batches.lock().unwrap().len() < DATA_QUEUE_CAPACITY {
+                break;
+            }
+            self.room.notified().await;
+        }
+        self.batches.lock().unwrap().push_back((socket, packets));
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<(SocketRef, Vec<UdtDataPacket>)> {
+        let batch = self.batches.lock().unwrap().pop_front();
+        if batch.is_some() {
+            self.room.notify_one();
+        }
+        batch
+    }
+}
+
+/// Closes a data queue when the worker or the sending task stops, even by a panic.
+struct CloseOnDrop(Arc<DataQueue>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.closed.store(true, AtomicOrdering::Release);
+        self.0.ready.notify_one();
+        self.0.room.notify_one();
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct UdtSndQueue {
     queue: Mutex<BinaryHeap<SendQueueNode>>,
+    control: Arc<ControlQueue>,
     notify: Notify,
     start_time: Instant,
     socket_refs: Mutex<BTreeMap<SocketId, Weak<UdtSocket>>>,
+    egress_limiter: Arc<EgressLimiter>,
+    tokio_timers: bool, // instead of timerfd, to follow the paused clock of tests
+    // Batches of data packets handed to the sending task, reused once sent
+    batches: Arc<Mutex<Vec<Vec<UdtDataPacket>>>>,
 }
 
 impl UdtSndQueue {
-    pub fn new() -> Self {
+    /// With `fixed_batch_size`, the batches of data packets and the control queue are
+    /// allocated up front.
+    pub fn new(
+        egress_limiter: Arc<EgressLimiter>,
+        tokio_timers: bool,
+        fixed_batch_size: Option<usize>,
+    ) -> Self {
+        // Batches queued to the sending task, plus the ones being filled and sent
+        let batches = match fixed_batch_size {
+            Some(size) => (0..DATA_QUEUE_CAPACITY + 2)
+                .map(|_| Vec::with_capacity(size))
+                .collect(),
+            None => vec![],
+        };
+        let control = ControlQueue {
+            packets: Mutex::new(VecDeque::with_capacity(match fixed_batch_size {
+                Some(_) => CONTROL_QUEUE_CAPACITY,
+                None => 0,
+            })),
+            notify: Notify::new(),
+        };
         UdtSndQueue {
             queue: Mutex::new(BinaryHeap::new()),
+            control: Arc::new(control),
             notify: Notify::new(),
             start_time: Instant::now(),
             socket_refs: Mutex::new(BTreeMap::new()),
+            egress_limiter,
+            tokio_timers,
+            batches: Arc::new(Mutex::new(batches)),
         }
     }
 
@@ -61,16 +180,52 @@ impl UdtSndQueue {
         }
     }
 
-    pub async fn worker(&self) -> Result<()> {
-        let (tx, mut rx) = tokio::sync::mpsc::channel(TOKIO_CHANNEL_CAPACITY);
+    pub async fn worker(&self, mux: Weak<UdtMultiplexer>) -> Result<()> {
+        // Each side closes the queue when it stops
+        let data = CloseOnDrop(Arc::new(DataQueue::new()));
+        let receiver = CloseOnDrop(data.0.clone());
 
+        let control = self.control.clone();
+        let batches = self.batches.clone();
         tokio::spawn(async move {
-            while let Some((socket, packets)) = rx.recv().await {
-                let socket: SocketRef = socket;
-                socket
-                    .send_data_packets(packets)
-                    .await
-                    .expect("failed to send packets");
+            let data = receiver;
+            loop {
+                // Feedback packets do not wait behind paced data
+                while let Some(queued) = control.pop() {
+                    let Some(mux) = mux.upgrade() else {
+                        return;
+                    };
+                    let sent = mux
+                        .send_to(&queued.addr, queued.packet, queued.src_ip)
+                        .await;
+                    if let Err(_err) = sent {
+                        trace_event!(debug, peer = %queued.addr, error = %_err, "failed to send control packet");
+                    }
+                }
+                let (socket, mut packets) = match data.0.pop() {
+                    Some(batch) => batch,
+                    None if data.0.closed.load(AtomicOrdering::Acquire) => break,
+                    None => {
+                        tokio::select! {
+                            biased;
+                            _ = control.notify.notified() => (),
+                            _ = data.0.ready.notified() => (),
+                        }
+                        continue;
+                    }
+                };
+                let res = socket
+                    .do_work(SocketWork::Send, socket.send_data_packets(&mut packets))
+                    .await;
+                packets.clear();
+                batches.lock().unwrap().push(packets);
+                if let Err(err) = res {
+                    if err.kind() == ErrorKind::WouldBlock {
+                        // Unsent packets are retransmitted after a loss report
+                        continue;
+                    }
+                    socket.break_with(err);
+                }
             }
         });
 
@@ -91,16 +246,44 @@ impl UdtSndQueue {
             };
             match next_node {
                 Ok(node) => {
+                    if let Some(ts) = self.egress_limiter.held_until(node.socket_id) {
+                        self.insert(ts, node.socket_id);
+                        continue;
+                    }
                     if let Some(socket) = self.get_socket(node.socket_id).await {
-                        if let Some((packets, ts)) = socket.next_data_packets().await? {
+                        let mut packets = self.batches.lock().unwrap().pop().unwrap_or_default();
+                        let next = socket
+                            .do_work(SocketWork::Send, socket.next_data_packets(&mut packets))
+                            .await;
+                        let next = match next {
+                            Ok(next) => next,
+                            Err(err) => {
+                                self.recycle(packets);
+                                return Err(err);
+                            }
+                        };
+                        if let Some(ts) = next {
+                            let nbytes = packets
+                                .iter()
+                                .map(|p| p.payload_len() + UDT_DATA_HEADER_SIZE)
+                                .sum();
+                            let delay = self.egress_limiter.consume(node.socket_id, nbytes);
+                            let ts = std::cmp::max(ts, Instant::now() + delay);
                             self.insert(ts, node.socket_id);
-                            tx.send((socket, packets)).await.unwrap();
+                            if packets.is_empty() {
+                                // Held back by the bandwidth cap of the socket
+                                self.recycle(packets);
+                                continue;
+                            }
+                            data.0.push(socket, packets).await?;
+                        } else {
+                            self.recycle(packets);
                         }
                     }
                 }
                 Err(Some(ts)) => {
                     tokio::select! {
-                        _ = Self::sleep_until(ts) => {}
+                        res = self.sleep_until(ts) => res?,
                         _ = self.notify.notified() => {}
                     }
                 }
@@ -149,25 +332,49 @@ impl UdtSndQueue {
         }
     }
 
+    /// Queues a control packet to `addr`, sent before the next batch of data packets.
+    /// The packet is dropped if `CONTROL_QUEUE_CAPACITY` packets are already queued.
+    pub fn push_control(&self, addr: SocketAddr, packet: UdtPacket, src_ip: Option<IpAddr>) {
+        {
+            let mut packets = self.control.packets.lock().unwrap();
+            if packets.len() >= CONTROL_QUEUE_CAPACITY {
+                trace_event!(debug, peer = %addr, "control queue full, packet dropped");
+                return;
+            }
+            packets.push_back(QueuedControl {
+                addr,
+                packet,
+                src_ip,
+            });
+        }
+        self.control.notify.notify_one();
+    }
+
     pub fn remove(&self, socket_id: SocketId) {
-        let mut sockets = self.queue.lock().unwrap();
-        *sockets = sockets
-            .iter()
-            .filter(|n| n.socket_id != socket_id)
-            .cloned()
-            .collect();
+        self.queue
+            .lock()
+            .unwrap()
+            .retain(|n| n.socket_id != socket_id);
+    }
+
+    /// Keeps an emptied batch of data packets for reuse.
+    fn recycle(&self, mut packets: Vec<UdtDataPacket>) {
+        packets.clear();
+        self.batches.lock().unwrap().push(packets);
     }
 
     #[cfg(target_os = "linux")]
-    async fn sleep_until(instant: tokio::time::Instant) {
-        tokio_timerfd::Delay::new(instant.into_std())
-            .expect("failed to init delay")
-            .await
-            .expect("timerfd failed");
+    async fn sleep_until(&self, instant: tokio::time::Instant) -> Result<()> {
+        if self.tokio_timers {
+            tokio::time::sleep_until(instant).await;
+            return Ok(());
+        }
+        tokio_timerfd::Delay::new(instant.into_std())?.await
     }
 
     #[cfg(not(target_os = "linux"))]
-    async fn sleep_until(instant: tokio::time::Instant) {
-        tokio::time::sleep_until(instant).await
+    async fn sleep_until(&self, instant: tokio::time::Instant) -> Result<()> {
+        tokio::time::sleep_until(instant).await;
+        Ok(())
     }
 }
@@ -1,6 +1,7 @@
 use crate::flow::UdtFlow;
 use crate::seq_number::SeqNumber;
 use crate::socket::SYN_INTERVAL;
+use rand::rngs::StdRng;
 use rand::Rng;
 use tokio::time::{Duration, Instant};
 
@@ -29,10 +30,11 @@ pub struct RateControl {
 
     ack_period: Duration,
     ack_pkt_interval: usize,
+    rng: StdRng,
 }
 
 impl RateControl {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(rng: StdRng) -> Self {
         Self {
             pkt_send_period: Duration::from_micros(1),
             congestion_window_size: 16.0,
@@ -57,6 +59,7 @@ impl RateControl {
 
             ack_period: SYN_INTERVAL,
             ack_pkt_interval: 0,
+            rng,
         }
     }
 
@@ -96,6 +99,16 @@ impl RateControl {
         std::cmp::min(SYN_INTERVAL, self.ack_period)
     }
 
+    #[must_use]
+    pub fn get_rtt(&self) -> Duration {
+        self.rtt
+    }
+
+    #[must_use]
+    pub fn is_slow_start(&self) -> bool {
+        self.slow_start
+    }
+
     pub fn set_rtt(&mut self, rtt: Duration) {
         self.rtt = rtt;
     }
@@ -149,12 +162,12 @@ impl RateControl {
 
         let mut b = f64::from(self.bandwidth) - 1.0 / self.pkt_send_period.as_secs_f64();
         if (self.pkt_send_period > self.last_dec_period) && (f64::from(self.bandwidth) / 9.0 < b) {
-            b = self.bandwidth as f64 / 9.0;
+            b = f64::from(self.bandwidth) / 9.0;
         }
         let increase = if b <= 0.0 {
             MIN_INC
         } else {
-            let inc = 10.0_f64.powf((b * self.mss as f64 * 8.0).log10().ceil()) * 1.5e-6 / self.mss;
+            let inc = 10.0_f64.powf((b * self.mss * 8.0).log10().ceil()) * 1.5e-6 / self.mss;
             if inc < MIN_INC {
                 MIN_INC
             } else {
@@ -191,13 +204,13 @@ impl RateControl {
             self.dec_random = if self.avg_nak_num == 0 {
                 1
             } else {
-                rand::thread_rng().gen_range(1..=self.avg_nak_num)
+                self.rng.gen_range(1..=self.avg_nak_num)
             };
         } else {
             self.dec_count += 1;
             if self.dec_count <= 5 {
                 self.nak_count += 1;
-                if self.nak_count % self.dec_random == 0 {
+                if self.nak_count.is_multiple_of(self.dec_random) {
                     self.pkt_send_period = self.pkt_send_period.mul_f64(1.125);
                     self.last_dec_seq = self.curr_snd_seq_number;
                 }
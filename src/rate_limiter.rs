@@ -0,0 +1,267 @@
+use crate::configuration::UdtHandshakeRateLimit;
+use crate::socket::SocketId;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+// Beyond this number of sources, the idle ones are forgotten
+const MAX_HANDSHAKE_SOURCES: usize = 4096;
+// The idle sources are looked for at most once per interval, not for each new source
+const HANDSHAKE_SOURCES_PURGE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sockets which have not sent for this long no longer get a share of the egress budget
+const EGRESS_SHARE_IDLE_TIME: Duration = Duration::from_millis(100);
+
+/// Token bucket: the balance may go negative, the debt is then
+/// converted to a delay before the next send.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64, // bytes per second
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64, burst_bytes: u64) -> Self {
+        Self {
+            rate: bytes_per_sec as f64,
+            burst: burst_bytes as f64,
+            tokens: burst_bytes as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = (now - self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Consumes `bytes` tokens and returns the delay to wait before sending again.
+    pub fn consume(&mut self, bytes: usize) -> Duration {
+        self.refill();
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 || self.rate <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Consumes `tokens` tokens if available, without going into debt.
+    pub fn try_consume(&mut self, tokens: usize) -> bool {
+        self.refill();
+        if self.tokens < tokens as f64 {
+            return false;
+        }
+        self.tokens -= tokens as f64;
+        true
+    }
+
+    fn has_tokens(&mut self, tokens: usize) -> bool {
+        self.refill();
+        self.tokens >= tokens as f64
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst
+    }
+}
+
+/// Egress budget: a token bucket and the fair share of each socket sending.
+#[derive(Debug)]
+struct EgressBudget {
+    bucket: TokenBucket,
+    // Time from which each active socket is within its share of the rate
+    shares: HashMap<SocketId, Instant>,
+    purged_at: Instant,
+}
+
+/// Optional token bucket shared by the send queues of all multiplexers,
+/// limiting the aggregated egress rate of the process.
+///
+/// While the budget is exhausted, it is shared evenly between the active sockets:
+/// a socket sending more than its share is held back by the time the other ones
+/// need to send theirs, whatever the size of its batches.
+#[derive(Debug, Default)]
+pub(crate) struct EgressLimiter {
+    budget: Mutex<Option<EgressBudget>>,
+}
+
+impl EgressLimiter {
+    pub fn set(&self, bucket: Option<TokenBucket>) {
+        *self.budget.lock().unwrap() = bucket.map(|bucket| EgressBudget {
+            bucket,
+            shares: HashMap::new(),
+            purged_at: Instant::now(),
+        });
+    }
+
+    /// Consumes `bytes` sent by `socket_id` and returns the delay before it sends again.
+    pub fn consume(&self, socket_id: SocketId, bytes: usize) -> Duration {
+        let mut budget = self.budget.lock().unwrap();
+        let budget = match &mut *budget {
+            Some(budget) => budget,
+            None => return Duration::ZERO,
+        };
+        let now = Instant::now();
+        if now - budget.purged_at >= EGRESS_SHARE_IDLE_TIME {
+            budget
+                .shares
+                .retain(|_, share| *share + EGRESS_SHARE_IDLE_TIME > now);
+            budget.purged_at = now;
+        }
+        let delay = budget.bucket.consume(bytes);
+        let active_sockets =
+            budget.shares.len() + usize::from(!budget.shares.contains_key(&socket_id));
+        let share = budget.shares.entry(socket_id).or_insert(now);
+        *share = (*share).max(now);
+        if delay.is_zero() || budget.bucket.rate <= 0.0 {
+            // Sockets are only held to their share when the budget is exhausted,
+            // so that the share left unused by the slower ones goes to the others
+            return delay;
+        }
+        *share += Duration::from_secs_f64((bytes * active_sockets) as f64 / budget.bucket.rate);
+        delay.max(*share - now)
+    }
+
+    /// Time until which `socket_id` is held back because it sent more than its share,
+    /// even if it is rescheduled earlier by the acknowledgements or loss reports.
+    pub fn held_until(&self, socket_id: SocketId) -> Option<Instant> {
+        let budget = self.budget.lock().unwrap();
+        let share = *budget.as_ref()?.shares.get(&socket_id)?;
+        (share > Instant::now()).then_some(share)
+    }
+}
+
+#[derive(Debug)]
+struct HandshakeBuckets {
+    limit: UdtHandshakeRateLimit,
+    global: TokenBucket,
+    sources: HashMap<IpAddr, TokenBucket>,
+    purged_at: Instant,
+}
+
+/// Optional rate limits of the handshakes received by a multiplexer,
+/// set from the configuration of its listener.
+#[derive(Debug, Default)]
+pub(crate) struct HandshakeLimiter {
+    buckets: Mutex<Option<HandshakeBuckets>>,
+    dropped: AtomicU64,
+}
+
+impl HandshakeLimiter {
+    pub fn set(&self, limit: Option<UdtHandshakeRateLimit>) {
+        *self.buckets.lock().unwrap() = limit.map(|limit| HandshakeBuckets {
+            limit,
+            global: TokenBucket::new(limit.global_rate.into(), limit.global_burst.into()),
+            sources: HashMap::new(),
+            purged_at: Instant::now(),
+        });
+    }
+
+    /// Whether a handshake from `ip` can be processed. Rejected handshakes are counted.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets = match &mut *buckets {
+            Some(buckets) => buckets,
+            None => return true,
+        };
+        // Handshakes beyond the global limit do not use the tokens of their source
+        if !buckets.global.has_tokens(1) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let now = Instant::now();
+        if buckets.sources.len() >= MAX_HANDSHAKE_SOURCES
+            && !buckets.sources.contains_key(&ip)
+            && now - buckets.purged_at >= HANDSHAKE_SOURCES_PURGE_INTERVAL
+        {
+            buckets.sources.retain(|_, bucket| !bucket.is_full());
+            buckets.purged_at = now;
+        }
+        let limit = buckets.limit;
+        let source_allowed = if buckets.sources.len() < MAX_HANDSHAKE_SOURCES {
+            buckets
+                .sources
+                .entry(ip)
+                .or_insert_with(|| {
+                    TokenBucket::new(limit.per_source_rate.into(), limit.per_source_burst.into())
+                })
+                .try_consume(1)
+        } else {
+            // Too many active sources to track: only the global limit applies
+            buckets
+                .sources
+                .get_mut(&ip)
+                .is_none_or(|bucket| bucket.try_consume(1))
+        };
+        let allowed = source_allowed && buckets.global.try_consume(1);
+        if !allowed {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Number of handshakes dropped because of the rate limits.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn test_handshake_limiter() {
+    let limiter = HandshakeLimiter::default();
+    let (flooder, other): (IpAddr, IpAddr) =
+        ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+    assert!((0..100).all(|_| limiter.allow(flooder)));
+
+    limiter.set(Some(UdtHandshakeRateLimit {
+        per_source_rate: 1,
+        per_source_burst: 5,
+        global_rate: 1,
+        global_burst: 8,
+    }));
+    assert_eq!((0..10).filter(|_| limiter.allow(flooder)).count(), 5);
+    // Other sources are not limited by the flooder, until the global limit is reached
+    assert_eq!((0..10).filter(|_| limiter.allow(other)).count(), 3);
+    assert_eq!(limiter.dropped(), 12);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handshake_limiter_global_first() {
+    let limiter = HandshakeLimiter::default();
+    let flooder: IpAddr = "192.0.2.1".parse().unwrap();
+    limiter.set(Some(UdtHandshakeRateLimit {
+        per_source_rate: 0,
+        per_source_burst: 2,
+        global_rate: 1,
+        global_burst: 1,
+    }));
+    assert!(limiter.allow(flooder));
+    // Denied by the global limit: the last token of the source is kept
+    assert!((0..10).all(|_| !limiter.allow(flooder)));
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(limiter.allow(flooder));
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(!limiter.allow(flooder));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_egress_fair_share() {
+    let limiter = EgressLimiter::default();
+    assert_eq!(limiter.consume(1, 1_000_000), Duration::ZERO);
+
+    limiter.set(Some(TokenBucket::new(1000, 0)));
+    assert_eq!(limiter.consume(1, 100), Duration::from_millis(100));
+    assert_eq!(limiter.consume(2, 10), Duration::from_millis(110));
+    // Socket 1 already sent its share: it waits for socket 2 to send as much
+    assert_eq!(limiter.consume(1, 100), Duration::from_millis(300));
+    assert!(limiter.held_until(1).is_some());
+    assert!(limiter.held_until(3).is_none());
+}
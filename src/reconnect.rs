@@ -0,0 +1,407 @@
+use crate::configuration::UdtConfiguration;
+use crate::connection::UdtConnection;
+use bytes::{Bytes, BytesMut};
+use rand::Rng;
+use std::future::{poll_fn, Future};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind, ReadBuf, Result,
+};
+use tokio::net::{lookup_host, ToSocketAddrs};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant, Sleep};
+
+/// Reconnection behaviour of a [`UdtReconnectingConnection`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UdtReconnectPolicy {
+    /// Delay before the second attempt to connect, the first one is immediate.
+    /// It doubles after each failed attempt.
+    /// Default: 100ms
+    pub initial_backoff: Duration,
+    /// Longest delay between two attempts.
+    /// Default: 30 seconds
+    pub max_backoff: Duration,
+    /// Fraction of the delay added or removed at random, so that the clients of a
+    /// restarting server do not all reconnect at the same time.
+    /// Default: 0.2
+    pub jitter: f64,
+    /// Bytes written while disconnected that are kept to be sent once reconnected.
+    /// Beyond it, writes wait for the next attempt.
+    /// Default: 1 MiB
+    pub max_buffered: usize,
+    /// Number of consecutive failed attempts after which the call connecting fails
+    /// with the error of the last attempt. `None` retries indefinitely.
+    /// Default: None
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for UdtReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            jitter: 0.2,
+            max_buffered: 1 << 20,
+            max_attempts: None,
+        }
+    }
+}
+
+/// Stream connection dialing its peer again when it breaks, for long-lived links.
+///
+/// Connections are attempted by the calls needing one, with an exponential backoff.
+/// While disconnected, writes are kept up to `max_buffered` bytes and sent first once
+/// reconnected. The data still unacknowledged by a connection when it breaks is lost:
+/// the application protocol must be able to resume (e.g. with sequence numbers).
+pub struct UdtReconnectingConnection {
+    addrs: Vec<SocketAddr>,
+    config: Option<UdtConfiguration>,
+    policy: UdtReconnectPolicy,
+    connection: Option<UdtConnection>,
+    pending: BytesMut, // written while disconnected
+    // Attempt in progress, which sends `pending` once connected.
+    // It runs in a task so that it goes on when the call waiting for it is cancelled.
+    attempt: Option<JoinHandle<Result<UdtConnection>>>,
+    backoff: Duration,
+    next_attempt: Pin<Box<Sleep>>,
+    failed_attempts: u32,
+    connections: u64,
+}
+
+impl UdtReconnectingConnection {
+    /// Connects to `addr`, retrying as set by `policy`.
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        config: Option<UdtConfiguration>,
+        policy: UdtReconnectPolicy,
+    ) -> Result<Self> {
+        if !(0.0..=1.0).contains(&policy.jitter) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "reconnect jitter must be between 0 and 1",
+            ));
+        }
+        let addrs: Vec<_> = lookup_host(addr).await?.collect();
+        if addrs.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            ));
+        }
+        let mut connection = Self {
+            addrs,
+            config,
+            policy,
+            connection: None,
+            pending: BytesMut::new(),
+            attempt: None,
+            backoff: policy.initial_backoff,
+            next_attempt: Box::pin(sleep_until(Instant::now())),
+            failed_attempts: 0,
+            connections: 0,
+        };
+        poll_fn(|cx| connection.poll_connected(cx)).await?;
+        Ok(connection)
+    }
+
+    /// Current connection, `None` while disconnected.
+    #[must_use]
+    pub fn connection(&self) -> Option<&UdtConnection> {
+        self.connection.as_ref()
+    }
+
+    /// Number of times the connection was established again after breaking.
+    #[must_use]
+    pub fn reconnects(&self) -> u64 {
+        self.connections.saturating_sub(1)
+    }
+
+    /// Number of bytes written while disconnected, waiting for the next connection.
+    #[must_use]
+    pub fn buffered(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Sends `data`, or keeps it for the next connection if disconnected.
+    pub async fn send(&mut self, data: &[u8]) -> Result<()> {
+        loop {
+            if let Some(connection) = &mut self.connection {
+                match connection.write_all(data).await {
+                    Ok(()) => return Ok(()),
+                    Err(err) if connection.socket().status().is_alive() => return Err(err),
+                    Err(_) => self.on_broken(),
+                }
+            }
+            if self.waiting_for_attempt()
+                && self.pending.len() + data.len() <= self.policy.max_buffered
+            {
+                self.pending.extend_from_slice(data);
+                return Ok(());
+            }
+            poll_fn(|cx| self.poll_connected(cx)).await?;
+        }
+    }
+
+    /// Receives data into `buf`, reconnecting first if disconnected.
+    /// Returns 0 once the peer shut down its sending side.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
+        AsyncReadExt::read(self, buf).await
+    }
+
+    /// Waits until the data sent, including the one kept while disconnected,
+    /// is acknowledged by the peer.
+    pub async fn flush(&mut self) -> Result<()> {
+        AsyncWriteExt::flush(self).await
+    }
+
+    /// Closes the current connection, if any. The next calls connect again.
+    pub async fn close(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            connection.close().await;
+        }
+    }
+
+    /// Whether the next attempt to connect is not due yet, so that writes are kept.
+    fn waiting_for_attempt(&self) -> bool {
+        self.attempt.is_none() && !self.next_attempt.is_elapsed()
+    }
+
+    /// Polls until connected, attempting to connect once the backoff elapsed.
+    /// Fails once `max_attempts` consecutive attempts failed.
+    fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        loop {
+            if self.connection.is_some() {
+                return Poll::Ready(Ok(()));
+            }
+            let attempt = match &mut self.attempt {
+                Some(attempt) => attempt,
+                None => {
+                    ready!(self.next_attempt.as_mut().poll(cx));
+                    self.attempt.insert(self.start_attempt())
+                }
+            };
+            let res =
+                ready!(Pin::new(attempt).poll(cx)).unwrap_or_else(|err| Err(Error::other(err)));
+            self.attempt = None;
+            self.on_attempt(res)?;
+        }
+    }
+
+    /// Spawns an attempt to connect, which then sends the data kept while disconnected.
+    fn start_attempt(&self) -> JoinHandle<Result<UdtConnection>> {
+        let addrs = self.addrs.clone();
+        let config = self.config.clone();
+        let pending = Bytes::copy_from_slice(&self.pending);
+        tokio::spawn(async move {
+            let mut connection = UdtConnection::connect(&addrs[..], config).await?;
+            connection.write_all(&pending).await?;
+            Ok(connection)
+        })
+    }
+
+    fn on_attempt(&mut self, res: Result<UdtConnection>) -> Result<()> {
+        match res {
+            Ok(connection) => {
+                self.pending.clear();
+                self.connections += 1;
+                self.connection = Some(connection);
+                self.failed_attempts = 0;
+                self.backoff = self.policy.initial_backoff;
+                Ok(())
+            }
+            Err(err) => {
+                self.failed_attempts += 1;
+                if self
+                    .policy
+                    .max_attempts
+                    .is_some_and(|max| self.failed_attempts >= max)
+                {
+                    self.failed_attempts = 0;
+                    return Err(err);
+                }
+                let jitter = self.policy.jitter;
+                let factor = 1.0 + rand::thread_rng().gen_range(-jitter..=jitter);
+                let next_attempt = Instant::now() + self.backoff.mul_f64(factor);
+                self.next_attempt.as_mut().reset(next_attempt);
+                self.backoff = (self.backoff * 2).min(self.policy.max_backoff);
+                Ok(())
+            }
+        }
+    }
+
+    fn on_broken(&mut self) {
+        // Dropping the connection releases it in the background
+        self.connection = None;
+        self.next_attempt.as_mut().reset(Instant::now());
+    }
+}
+
+impl Drop for UdtReconnectingConnection {
+    fn drop(&mut self) {
+        if let Some(attempt) = &self.attempt {
+            attempt.abort();
+        }
+    }
+}
+
+impl AsyncRead for UdtReconnectingConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = &mut *self;
+        loop {
+            ready!(this.poll_connected(cx))?;
+            let connection = this.connection.as_mut().unwrap();
+            match ready!(Pin::new(&mut *connection).poll_read(cx, buf)) {
+                Err(_) if !connection.socket().status().is_alive() => this.on_broken(),
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+}
+
+/// Writes are kept while disconnected, like with `send`, up to `max_buffered` bytes.
+impl AsyncWrite for UdtReconnectingConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        let this = &mut *self;
+        loop {
+            if let Some(connection) = &mut this.connection {
+                match ready!(Pin::new(&mut *connection).poll_write(cx, buf)) {
+                    Err(_) if !connection.socket().status().is_alive() => this.on_broken(),
+                    res => return Poll::Ready(res),
+                }
+            }
+            let room = this.policy.max_buffered.saturating_sub(this.pending.len());
+            if this.waiting_for_attempt() && room > 0 {
+                let len = room.min(buf.len());
+                this.pending.extend_from_slice(&buf[..len]);
+                return Poll::Ready(Ok(len));
+            }
+            ready!(this.poll_connected(cx))?;
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = &mut *self;
+        loop {
+            ready!(this.poll_connected(cx))?;
+            let connection = this.connection.as_mut().unwrap();
+            match ready!(Pin::new(&mut *connection).poll_flush(cx)) {
+                Err(_) if !connection.socket().status().is_alive() => this.on_broken(),
+                res => return Poll::Ready(res),
+            }
+        }
+    }
+
+    /// Shuts down the sending side of the current connection, if any.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut self.connection {
+            Some(connection) => Pin::new(connection).poll_shutdown(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_reconnect_backoff() {
+    let free_port = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = free_port.local_addr().unwrap();
+    drop(free_port);
+    let config = UdtConfiguration {
+        connect_timeout: Some(Duration::from_secs(1)),
+        ..Default::default()
+    };
+    let policy = UdtReconnectPolicy {
+        jitter: f64::NAN,
+        ..Default::default()
+    };
+    let invalid = UdtReconnectingConnection::connect(addr, Some(config.clone()), policy);
+    assert_eq!(invalid.await.err().unwrap().kind(), ErrorKind::InvalidInput);
+
+    // Nothing listens on the port: every attempt fails
+    let policy = UdtReconnectPolicy {
+        initial_backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(4),
+        jitter: 0.0,
+        max_attempts: Some(5),
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let res = UdtReconnectingConnection::connect(addr, Some(config), policy).await;
+    assert!(res.is_err());
+    // The attempts are 1, 2, 4 and 4 seconds apart, each taking up to `connect_timeout`
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_secs(11) && elapsed <= Duration::from_secs(17),
+        "{elapsed:?}"
+    );
+}
+
+// Not on paused time: the receive workers poll their UDP socket on the system clock
+#[tokio::test]
+async fn test_reconnect_buffering() {
+    use crate::UdtListener;
+    use tokio::time::timeout;
+
+    let listener = UdtListener::bind("127.0.0.1:0".parse().unwrap(), None)
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = UdtConfiguration {
+        connect_timeout: Some(Duration::from_secs(1)),
+        ..Default::default()
+    };
+    let policy = UdtReconnectPolicy {
+        initial_backoff: Duration::from_secs(1),
+        jitter: 0.0,
+        max_buffered: 8,
+        ..Default::default()
+    };
+    let (client, accepted) = tokio::join!(
+        UdtReconnectingConnection::connect(addr, Some(config), policy),
+        listener.accept()
+    );
+    let mut client = client.unwrap();
+    let (_, server) = accepted.unwrap();
+
+    // The server goes away: the connection breaks and the first attempt to reconnect fails
+    listener.close().await;
+    server.close().await;
+    while client.connection().unwrap().socket().status().is_alive() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    client.send(b"1234").await.unwrap();
+    assert!(client.connection().is_none());
+    client.write_all(b"5678").await.unwrap();
+    assert_eq!(client.buffered(), 8);
+    // Beyond `max_buffered`, writes wait for the next attempt
+    let write = timeout(Duration::from_millis(100), client.write(b"9")).await;
+    assert!(write.is_err());
+
+    // The data kept is sent first once reconnected
+    let listener = UdtListener::bind(addr, None).await.unwrap();
+    let accept = async {
+        let (_, mut server) = listener.accept().await.unwrap();
+        let mut buf = [0; 9];
+        server.read_exact(&mut buf).await.unwrap();
+        buf
+    };
+    let (flushed, received) = tokio::join!(
+        async {
+            client.write_all(b"9").await.unwrap();
+            client.flush().await
+        },
+        accept
+    );
+    flushed.unwrap();
+    assert_eq!(&received, b"123456789");
+    assert_eq!(client.reconnects(), 1);
+}
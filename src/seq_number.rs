@@ -35,7 +35,11 @@ impl<T: SeqConstants> GenericSeqNumber<T> {
     }
 
     pub fn random() -> Self {
-        rand::thread_rng().gen_range(0..=T::MAX_NUMBER).into()
+        Self::random_from(&mut rand::thread_rng())
+    }
+
+    pub(crate) fn random_from(rng: &mut impl Rng) -> Self {
+        rng.gen_range(0..=T::MAX_NUMBER).into()
     }
 
     pub fn zero() -> Self {
@@ -66,7 +70,8 @@ impl<T: SeqConstants> std::ops::Add<i32> for GenericSeqNumber<T> {
     type Output = GenericSeqNumber<T>;
 
     fn add(self, rhs: i32) -> Self {
-        let resp = ((i64::from(self.number) + i64::from(rhs)).rem_euclid(T::MAX_NUMBER as i64 + 1)) as u32;
+        let resp =
+            ((i64::from(self.number) + i64::from(rhs)).rem_euclid(T::MAX_NUMBER as i64 + 1)) as u32;
         resp.into()
     }
 }
@@ -0,0 +1,100 @@
+//! Deterministic simulation of UDT endpoints, for regression tests of the congestion
+//! control and of the loss recovery.
+//!
+//! A [`Simulation`] connects any number of client and server pairs in memory, through
+//! [`ImpairedLink`]s. The seeds of the links and of the random choices of the protocol
+//! (see `UdtConfiguration::rng_seed`) are all drawn from the seed of the simulation.
+//! On a current-thread runtime with a paused clock, e.g. `#[tokio::test(start_paused = true)]`,
+//! the delays take no real time and a simulation replays the same way for a given seed.
+use crate::configuration::UdtConfiguration;
+use crate::connection::UdtConnection;
+use crate::test_utils::{ImpairedLink, ImpairedLinkStats, Impairments};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io::Result;
+
+/// Simulated network of connection pairs, each going through its own impaired link.
+/// The links stop when the simulation is dropped.
+#[derive(Debug)]
+pub struct Simulation {
+    rng: StdRng,
+    links: Vec<ImpairedLink>,
+}
+
+impl Simulation {
+    #[must_use]
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            links: vec![],
+        }
+    }
+
+    /// Connects a client and a server through a new link, applying `upstream` impairments
+    /// to the datagrams sent by the client and `downstream` ones to those sent by the server.
+    /// The `rng_seed` of `config` is replaced by one drawn from the simulation.
+    pub async fn connect(
+        &mut self,
+        config: Option<UdtConfiguration>,
+        upstream: Impairments,
+        downstream: Impairments,
+    ) -> Result<(UdtConnection, UdtConnection)> {
+        let mut config = config.unwrap_or_default();
+        config.rng_seed = Some(self.rng.gen());
+        let link_seed = self.rng.gen();
+        let (link, client, server) =
+            ImpairedLink::pair(Some(config), upstream, downstream, link_seed).await?;
+        self.links.push(link);
+        Ok((client, server))
+    }
+
+    /// Counters of the links, in the order the pairs were connected.
+    #[must_use]
+    pub fn link_stats(&self) -> Vec<ImpairedLinkStats> {
+        self.links.iter().map(ImpairedLink::stats).collect()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_simulation_replays() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{Duration, Instant};
+
+    async fn run(seed: u64) -> (Vec<ImpairedLinkStats>, Vec<u64>, Duration) {
+        let impairments = Impairments {
+            loss: 0.02,
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(2),
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(seed);
+        let start = Instant::now();
+        let mut transfers = vec![];
+        for _ in 0..3 {
+            let (mut client, mut server) = sim
+                .connect(None, impairments.clone(), impairments.clone())
+                .await
+                .unwrap();
+            transfers.push(tokio::spawn(async move {
+                let data = vec![7; 200_000];
+                client.write_all(&data).await.unwrap();
+                client.flush().await.unwrap();
+                let mut received = vec![0; data.len()];
+                server.read_exact(&mut received).await.unwrap();
+                assert!(received == data);
+                client.stats().pkt_retrans
+            }));
+        }
+        let mut retransmissions = vec![];
+        for transfer in transfers {
+            retransmissions.push(transfer.await.unwrap());
+        }
+        (sim.link_stats(), retransmissions, start.elapsed())
+    }
+
+    let (links, retransmissions, elapsed) = run(11).await;
+    assert!(links.iter().all(|stats| stats.lost > 0));
+    assert!(retransmissions.iter().all(|&retrans| retrans > 0));
+    assert_eq!(run(11).await, (links.clone(), retransmissions, elapsed));
+    assert_ne!(run(12).await.0, links);
+}
@@ -1,38 +1,73 @@
-use crate::configuration::UdtConfiguration;
-use crate::control_packet::{AckOptionalInfo, ControlPacketType, HandShakeInfo, UdtControlPacket};
+#[cfg(feature = "cc-trace")]
+use crate::cc_trace::{CcTraceEntry, CcTraceEvent, CcTraceSink, CcTraceSinkRef};
+use crate::common::{canonical_ip, is_ipv6_link_local};
+use crate::configuration::{
+    Ipv6FlowLabel, RcvOverloadPolicy, UdtConfiguration, UdtNegotiatedParams, UDT4_VERSION,
+};
+use crate::control_packet::{
+    AckOptionalInfo, ControlPacketType, HandShakeInfo, HandshakeRejection, HandshakeRole,
+    UdtControlPacket, ACK_INFO_SIZE, UDT_CONTROL_HEADER_SIZE,
+};
+#[cfg(feature = "encryption")]
+use crate::crypto::{self, PacketCipher};
 use crate::data_packet::{UdtDataPacket, UDT_DATA_HEADER_SIZE};
+use crate::error::{UdtError, UdtErrorCode};
+use crate::event::{UdtConnectionEvent, UdtUserControlHandler};
 use crate::flow::{UdtFlow, PROBE_MODULO};
+use crate::handshake_extension::{self, RESUMPTION_EXTENSION};
+use crate::message::UdtMessageInfo;
+use crate::metrics;
 use crate::multiplexer::UdtMultiplexer;
 use crate::packet::UdtPacket;
+use crate::path_mtu::PathMtu;
 use crate::queue::{RcvBuffer, SndBuffer};
 use crate::rate_control::RateControl;
-use crate::seq_number::SeqNumber;
-use crate::state::SocketState;
-use crate::udt::{SocketRef, Udt};
-use once_cell::sync::Lazy;
-use rand::distributions::Alphanumeric;
-use rand::Rng;
-use sha2::{Digest, Sha256};
+use crate::seq_number::{AckSeqNumber, SeqNumber};
+use crate::state::{PeerMigration, SocketState};
+use crate::stats::UdtStatistics;
+use crate::syn_cookie::SynCookies;
+use crate::udt::{SocketRef, Udt, UDT_DEBUG};
+use bytes::{BufMut, Bytes};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 use std::cmp::Ordering;
-use std::collections::BTreeSet;
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{BTreeSet, VecDeque};
+use std::future::Future;
+use std::io::IoSlice;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, Mutex, RwLock, Weak};
-use std::task::Poll;
+use std::task::{Context, Poll, Wake, Waker};
 use tokio::io::{Error, ErrorKind, ReadBuf, Result};
-use tokio::sync::{Notify, RwLock as TokioRwLock};
-use tokio::time::{Duration, Instant};
+use tokio::sync::futures::Notified;
+use tokio::sync::{broadcast, Notify, RwLock as TokioRwLock};
+use tokio::time::{Duration, Instant, Sleep};
 
 pub(crate) const SYN_INTERVAL: Duration = Duration::from_millis(10);
 const MIN_EXP_INTERVAL: Duration = Duration::from_millis(300);
 const PACKETS_BETWEEN_LIGHT_ACK: usize = 64;
-
-static SALT: Lazy<String> = Lazy::new(|| {
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(30)
-        .map(char::from)
-        .collect()
-});
+const FLOW_LABEL_MASK: u32 = 0x000F_FFFF;
+// Receivers advertise at least 2 packets of free buffer, even when it is full
+const PEER_STALL_WINDOW: u32 = 2;
+const STALL_PROBE_INTERVAL: Duration = Duration::from_millis(100);
+// Room for a few seconds of ACK events, sent up to every SYN interval
+const EVENTS_CAPACITY: usize = 256;
+const WAKERS_CAPACITY: usize = 4;
+// Challenges of a new peer address are sent at most once per interval and per connection,
+// to a few of the last addresses packets came from
+const MIGRATION_CHALLENGE_INTERVAL: Duration = Duration::from_millis(100);
+const MIGRATION_CHALLENGED_ADDRS: usize = 4;
+// Sending time covered by each send round with `snd_batch_adaptive`
+const ADAPTIVE_BATCH_WINDOW: Duration = Duration::from_micros(500);
+// Sending time at `max_bandwidth_bps` that may be sent in a burst, e.g. after a late wake-up
+const BANDWIDTH_CAP_BURST: Duration = Duration::from_millis(2);
+// Interval of the -2 handshakes repeated by a rendezvous socket until the peer is connected
+const RENDEZVOUS_REPLY_INTERVAL: Duration = Duration::from_millis(250);
+// Interval of the connection requests repeated by a client resuming its session
+const RESUMPTION_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+// Minimum delay before an unanswered path MTU probe is repeated
+const PMTUD_MIN_PROBE_TIMEOUT: Duration = Duration::from_millis(100);
 
 pub type SocketId = u32;
 
@@ -57,6 +92,14 @@ impl TryFrom<u32> for SocketType {
     }
 }
 
+/// Kind of work done by the queue workers on behalf of a socket
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SocketWork {
+    Send,
+    Receive,
+    Timers,
+}
+
 #[derive(Debug)]
 pub struct UdtSocket {
     pub socket_id: SocketId,
@@ -68,6 +111,8 @@ pub struct UdtSocket {
     pub initial_seq_number: SeqNumber,
 
     pub(crate) queued_sockets: TokioRwLock<BTreeSet<SocketId>>,
+    pub(crate) refused_connections: AtomicU64,
+    broken_cause: Mutex<Option<String>>,
     pub(crate) accept_notify: Notify,
     pub(crate) multiplexer: RwLock<Weak<UdtMultiplexer>>,
     pub configuration: RwLock<UdtConfiguration>,
@@ -76,14 +121,80 @@ pub struct UdtSocket {
     snd_buffer: Mutex<SndBuffer>,
     flow: RwLock<UdtFlow>,
     pub rate_control: RwLock<RateControl>,
-    // self_ip: Option<IpAddr>,
+    self_ip: Mutex<Option<IpAddr>>, // local address contacted by the peer
     start_time: Instant,
+    syn_cookies: SynCookies,
 
     state: Mutex<SocketState>,
 
     connect_notify: Notify,
     rcv_notify: Notify,
     ack_notify: Notify,
+    rcv_wakers: Arc<WakerSet>,
+    snd_wakers: WakerSet,
+    delivery_timer: Mutex<Option<Pin<Box<Sleep>>>>, // live mode: wakes readers once data is due
+    events: broadcast::Sender<UdtConnectionEvent>,
+    // Subscribed when the socket is created, for the first subscriber to get the
+    // events emitted before the connection is returned, such as `Connected`
+    first_events: Mutex<Option<broadcast::Receiver<UdtConnectionEvent>>>,
+    user_control_handler: RwLock<Option<UserControlHandlerRef>>,
+    #[cfg(feature = "cc-trace")]
+    cc_trace: RwLock<Option<CcTraceSinkRef>>,
+    #[cfg(feature = "encryption")]
+    cipher: once_cell::sync::OnceCell<PacketCipher>,
+    #[cfg(feature = "encryption")]
+    session_salt: [u8; 16], // sent in the handshake, see `PacketCipher::new`
+    #[cfg(feature = "tracing")]
+    span: tracing::Span, // of the work done for the socket by the workers
+}
+
+#[derive(Clone)]
+struct UserControlHandlerRef(UdtUserControlHandler);
+
+impl std::fmt::Debug for UserControlHandlerRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UdtUserControlHandler")
+    }
+}
+
+/// Wakers of the tasks polling a socket for readiness.
+#[derive(Debug)]
+struct WakerSet {
+    wakers: Mutex<Vec<Waker>>,
+    // Swapped with the list of wakers to wake them, keeping the capacity of both
+    spare: Mutex<Vec<Waker>>,
+}
+
+impl Default for WakerSet {
+    fn default() -> Self {
+        // Room for a few tasks polling the socket, allocated up front
+        Self {
+            wakers: Mutex::new(Vec::with_capacity(WAKERS_CAPACITY)),
+            spare: Mutex::new(Vec::with_capacity(WAKERS_CAPACITY)),
+        }
+    }
+}
+
+impl WakerSet {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&self) {
+        // New wakers are registered while the others are woken
+        let mut wakers = self.spare.lock().unwrap();
+        std::mem::swap(&mut *self.wakers.lock().unwrap(), &mut *wakers);
+        wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+impl Wake for WakerSet {
+    fn wake(self: Arc<Self>) {
+        self.wake_all();
+    }
 }
 
 impl UdtSocket {
@@ -94,8 +205,16 @@ impl UdtSocket {
         configuration: Option<UdtConfiguration>,
     ) -> Self {
         let now = Instant::now();
-        let initial_seq_number = isn.unwrap_or_else(SeqNumber::random);
         let configuration = configuration.unwrap_or_default();
+        #[cfg(feature = "test-utils")]
+        let mut rng = match configuration.rng_seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        #[cfg(not(feature = "test-utils"))]
+        let mut rng = StdRng::from_entropy();
+        let initial_seq_number = isn.unwrap_or_else(|| SeqNumber::random_from(&mut rng));
+        let (events, first_events) = broadcast::channel(EVENTS_CAPACITY);
         Self {
             socket_id,
             socket_type,
@@ -105,22 +224,51 @@ impl UdtSocket {
             peer_socket_id: Mutex::new(None),
             listen_socket: None,
             queued_sockets: TokioRwLock::new(BTreeSet::new()),
+            refused_connections: AtomicU64::new(0),
+            broken_cause: Mutex::new(None),
             accept_notify: Notify::new(),
             multiplexer: RwLock::new(Weak::new()),
-            snd_buffer: Mutex::new(SndBuffer::new(configuration.snd_buf_size)),
+            snd_buffer: Mutex::new(SndBuffer::new(
+                configuration.snd_buf_size,
+                configuration.live_latency.is_some(),
+                configuration.fixed_capacity,
+            )),
             rcv_buffer: Mutex::new(RcvBuffer::new(
                 configuration.rcv_buf_size,
                 initial_seq_number,
+                socket_type == SocketType::Datagram,
+                configuration.live_latency,
+                configuration.fixed_capacity,
             )),
             flow: RwLock::new(UdtFlow::default()),
-            rate_control: RwLock::new(RateControl::new()),
-            // self_ip: None,
+            rate_control: RwLock::new(RateControl::new(rng)),
+            self_ip: Mutex::new(None),
             start_time: now,
+            syn_cookies: SynCookies::new(),
 
             state: Mutex::new(SocketState::new(initial_seq_number, &configuration)),
             connect_notify: Notify::new(),
             rcv_notify: Notify::new(),
             ack_notify: Notify::new(),
+            rcv_wakers: Arc::default(),
+            snd_wakers: WakerSet::default(),
+            delivery_timer: Mutex::new(None),
+            events,
+            first_events: Mutex::new(Some(first_events)),
+            user_control_handler: RwLock::new(None),
+            #[cfg(feature = "cc-trace")]
+            cc_trace: RwLock::new(None),
+            #[cfg(feature = "encryption")]
+            cipher: once_cell::sync::OnceCell::new(),
+            #[cfg(feature = "encryption")]
+            session_salt: crypto::new_session_salt(),
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!(
+                "udt_socket",
+                socket_id,
+                label = configuration.label.as_deref(),
+                peer = tracing::field::Empty,
+            ),
             configuration: RwLock::new(configuration),
         }
     }
@@ -133,10 +281,24 @@ impl UdtSocket {
 
     fn set_peer_addr(&self, peer: SocketAddr) {
         *self.peer_addr.lock().unwrap() = Some(peer);
+        #[cfg(feature = "tracing")]
+        self.span.record("peer", tracing::field::display(peer));
+        let payload_size = self.snd_payload_size();
         self.snd_buffer
             .lock()
             .unwrap()
-            .set_payload_size(self.get_max_payload_size() as usize);
+            .set_payload_size(payload_size as usize);
+    }
+
+    /// Sets the local address packets must be sent from, when the multiplexer is bound
+    /// to a wildcard address on a multi-homed host.
+    pub(crate) fn with_self_ip(self, ip: Option<IpAddr>) -> Self {
+        *self.self_ip.lock().unwrap() = ip;
+        self
+    }
+
+    fn self_ip(&self) -> Option<IpAddr> {
+        *self.self_ip.lock().unwrap()
     }
 
     pub fn with_listen_socket(
@@ -153,7 +315,8 @@ impl UdtSocket {
         *self.status.lock().unwrap() = UdtStatus::Opened;
     }
 
-    fn rcv_buffer(&self) -> std::sync::MutexGuard<RcvBuffer> {
+    /// When both are needed, the state is locked before the receive buffer.
+    fn rcv_buffer(&self) -> std::sync::MutexGuard<'_, RcvBuffer> {
         self.rcv_buffer.lock().unwrap()
     }
 
@@ -165,7 +328,7 @@ impl UdtSocket {
         *self.peer_socket_id.lock().unwrap()
     }
 
-    fn state(&self) -> std::sync::MutexGuard<SocketState> {
+    fn state(&self) -> std::sync::MutexGuard<'_, SocketState> {
         self.state.lock().unwrap()
     }
 
@@ -173,6 +336,7 @@ impl UdtSocket {
         self,
         peer: SocketAddr,
         mut hs: HandShakeInfo,
+        extensions: Vec<(u16, Bytes)>,
     ) -> Result<SocketRef> {
         {
             let mut configuration = self.configuration.write().unwrap();
@@ -182,13 +346,31 @@ impl UdtSocket {
                 configuration.mss = hs.max_packet_size;
             }
 
-            self.flow.write().unwrap().flow_window_size = hs.max_window_size;
-            hs.max_window_size =
-                std::cmp::min(configuration.rcv_buf_size, configuration.flight_flag_size);
+            let max_window_size = hs
+                .max_window_size
+                .min(configuration.rcv_buf_size)
+                .min(configuration.flight_flag_size);
+            configuration.flight_flag_size = max_window_size;
+            self.flow.write().unwrap().flow_window_size = max_window_size;
+            hs.max_window_size = max_window_size;
+        }
+        // Validated by the listener
+        {
+            let mut state = self.state();
+            state.udt_version = hs.udt_version;
+            state.peer_handshake_extensions = std::mem::take(&mut hs.extensions);
+            state.resumption_token = handshake_extension::find(&extensions, RESUMPTION_EXTENSION)
+                .and_then(|token| token.try_into().ok());
+            state.handshake_extensions = extensions.clone();
         }
+        hs.extensions = extensions;
         // self.set_self_ip(hs.ip_address);
         hs.ip_address = peer.ip();
         hs.socket_id = self.socket_id;
+        let peer_salt = hs.encryption_salt;
+        self.sign_handshake(&mut hs, HandshakeRole::Responder);
+        self.init_cipher(peer_salt);
+        self.init_path_mtu();
 
         // TODO: use network information cache to set RTT, bandwidth, etc.
 
@@ -202,6 +384,8 @@ impl UdtSocket {
         }
 
         *self.status.lock().unwrap() = UdtStatus::Connected;
+        trace_event!(info, socket_id = self.socket_id, peer = %peer, "connection accepted");
+        self.emit_event(UdtConnectionEvent::Connected);
 
         let packet = UdtControlPacket::new_handshake(
             hs,
@@ -210,7 +394,7 @@ impl UdtSocket {
 
         if let Some(mux) = self.multiplexer() {
             mux.rcv_queue.push_back(self.socket_id);
-            mux.send_to(&peer, packet.into()).await?;
+            mux.send_to(&peer, packet.into(), self.self_ip()).await?;
         }
 
         let socket = Arc::new(self);
@@ -219,16 +403,16 @@ impl UdtSocket {
 
     pub fn set_multiplexer(&self, mux: &Arc<UdtMultiplexer>) {
         *self.multiplexer.write().unwrap() = Arc::downgrade(mux);
+        self.snd_buffer
+            .lock()
+            .unwrap()
+            .set_pool(mux.packet_pool.clone());
     }
 
     pub(crate) fn multiplexer(&self) -> Option<Arc<UdtMultiplexer>> {
         self.multiplexer.read().unwrap().upgrade()
     }
 
-    // pub fn set_self_ip(&mut self, ip: IpAddr) {
-    //     self.self_ip = Some(ip);
-    // }
-
     // pub async fn self_addr(&self) -> Option<SocketAddr> {
     //     if let Some(mux) = self.multiplexer.lock().unwrap().upgrade() {
     //         return Some(mux.get_local_addr());
@@ -236,11 +420,33 @@ impl UdtSocket {
     //     None
     // }
 
-    pub(crate) async fn next_data_packets(&self) -> Result<Option<(Vec<UdtDataPacket>, Instant)>> {
+    /// The peer advertised a (nearly) full receive buffer: only a probe
+    /// is sent now and then until it reopens.
+    fn mark_peer_stalled(&self, state: &mut SocketState, now: Instant) {
+        if state.peer_stalled {
+            return;
+        }
+        state.peer_stalled = true;
+        state.stall_probe = false;
+        state.next_stall_probe = now + STALL_PROBE_INTERVAL;
+        state.stats.snd_peer_stalls += 1;
+        trace_event!(debug, socket_id = self.socket_id, "peer stalled");
+        self.emit_event(UdtConnectionEvent::PeerStalled);
+    }
+
+    /// Appends the next data packets to send to `packets`, and returns when the socket
+    /// can send again, or `None` if it has nothing to send.
+    pub(crate) async fn next_data_packets(
+        &self,
+        packets: &mut Vec<UdtDataPacket>,
+    ) -> Result<Option<Instant>> {
         if !self.status().is_alive() {
             eprintln!(
-                "No data to send: socket {} has status {:?}",
+                "No data to send: socket {}{} has status {:?}",
                 self.socket_id,
+                self.label()
+                    .map(|l| format!(" ({})", l))
+                    .unwrap_or_default(),
                 self.status()
             );
             return Ok(None);
@@ -250,6 +456,13 @@ impl UdtSocket {
 
         let to_resend = {
             let mut state = self.state();
+            // The second packet of a probing pair is sent right after the first one,
+            // so that the peer measures the capacity of the link
+            let probe_pair = std::mem::take(&mut state.probe_pair_pending);
+            if state.bandwidth_target_time > now && !probe_pair {
+                // The packets already sent do not fit in the bandwidth cap yet
+                return Ok(Some(state.bandwidth_target_time));
+            }
             let data_delay = now - state.next_data_target_time;
 
             if !data_delay.is_zero() {
@@ -257,13 +470,30 @@ impl UdtSocket {
             }
 
             let last_data_ack_processed = state.last_data_ack_processed;
+            let flow_window_size = self.flow.read().unwrap().flow_window_size;
+            if !state.snd_loss_list.is_empty()
+                && flow_window_size <= PEER_STALL_WINDOW
+                && (state.curr_snd_seq_number + 1 - state.last_ack_received)
+                    >= flow_window_size as i32
+            {
+                // Retransmissions would be dropped by the full receive buffer
+                // of the peer, but for the probe
+                self.mark_peer_stalled(&mut state, now);
+                if !state.stall_probe {
+                    state.next_data_target_time = now;
+                    state.interpacket_time_diff = Duration::ZERO;
+                    return Ok(None);
+                }
+                state.stall_probe = false;
+            }
             state
                 .snd_loss_list
-                .pop_after(last_data_ack_processed)
+                .pop_first()
                 .map(|seq| (seq, seq - last_data_ack_processed))
         };
 
-        let packets = match to_resend {
+        let retransmission = to_resend.is_some();
+        match to_resend {
             Some((seq, offset)) => {
                 // Loss retransmission has priority
                 if offset < 0 {
@@ -291,14 +521,22 @@ impl UdtSocket {
                         self.send_packet(drop.into()).await?;
 
                         let mut state = self.state();
-                        let last_data_ack_processed = state.last_data_ack_processed;
-                        state.snd_loss_list.remove_all(last_data_ack_processed, end);
+                        state.stats.snd_ttl_drops += 1;
+                        state.snd_loss_list.remove_up_to(end);
                         if (end + 1) - state.curr_snd_seq_number > 0 {
                             state.curr_snd_seq_number = end + 1;
                         }
                         return Ok(None);
                     }
-                    Ok(packet) => vec![packet],
+                    Ok(packet) => {
+                        trace_event!(
+                            trace,
+                            socket_id = self.socket_id,
+                            seq = seq.number(),
+                            "retransmitting"
+                        );
+                        packets.push(packet);
+                    }
                 }
             }
             None => {
@@ -307,49 +545,109 @@ impl UdtSocket {
                     .read()
                     .unwrap()
                     .get_congestion_window_size();
-                let window_size = std::cmp::min(
-                    self.flow.read().unwrap().flow_window_size,
-                    congestion_window_size as u32,
-                );
+                let flow_window_size = self.flow.read().unwrap().flow_window_size;
+                let window_size = std::cmp::min(flow_window_size, congestion_window_size as u32);
+                let (snd_batch_size, snd_batch_adaptive, bandwidth_interval) = {
+                    let configuration = self.configuration.read().unwrap();
+                    (
+                        configuration.snd_batch_size,
+                        configuration.snd_batch_adaptive,
+                        configuration.max_bandwidth_interval(configuration.mss as usize),
+                    )
+                };
                 let mut state = self.state();
-                if (state.curr_snd_seq_number - state.last_ack_received) > window_size as i32 {
-                    state.next_data_target_time = now;
-                    state.interpacket_time_diff = Duration::ZERO;
-                    return Ok(None);
+                let interpacket_interval = state.interpacket_interval.max(bandwidth_interval);
+                let mut max_packets = if snd_batch_adaptive && !interpacket_interval.is_zero() {
+                    let batch = ADAPTIVE_BATCH_WINDOW.as_nanos() / interpacket_interval.as_nanos();
+                    usize::try_from(batch)
+                        .unwrap_or(usize::MAX)
+                        .min(snd_batch_size)
+                } else {
+                    snd_batch_size
                 }
-                match self.snd_buffer.lock().unwrap().fetch_batch(
-                    state.curr_snd_seq_number + 1,
-                    self.peer_socket_id().unwrap(),
-                    self.start_time,
-                ) {
-                    packets if !packets.is_empty() => {
-                        let new_snd_seq_number = state.curr_snd_seq_number + packets.len() as i32;
-                        state.curr_snd_seq_number = new_snd_seq_number;
-                        self.rate_control
-                            .write()
-                            .unwrap()
-                            .set_curr_snd_seq_number(new_snd_seq_number);
-                        if state.curr_snd_seq_number.number() % 16 == 0 {
-                            probe = true;
-                        }
-                        packets
+                .max(1);
+                // Packets that can be sent past the last one acknowledged
+                let window_left =
+                    window_size as i32 - (state.curr_snd_seq_number + 1 - state.last_ack_received);
+                if window_left <= 0 {
+                    if flow_window_size <= PEER_STALL_WINDOW {
+                        self.mark_peer_stalled(&mut state, now);
                     }
-                    _ => {
+                    if !state.stall_probe {
                         state.next_data_target_time = now;
                         state.interpacket_time_diff = Duration::ZERO;
                         return Ok(None);
                     }
+                    // Probe the peer with a single packet, in case the ACK
+                    // reopening the window was lost
+                    state.stall_probe = false;
+                    max_packets = 1;
+                } else {
+                    // The batch does not overrun the window either
+                    max_packets = max_packets.min(window_left as usize);
+                }
+                let mut snd_buffer = self.snd_buffer.lock().unwrap();
+                let (deadline_drops, ttl_drops) = snd_buffer.drop_unsent_expired();
+                if deadline_drops + ttl_drops > 0 {
+                    state.stats.snd_deadline_drops += deadline_drops as u64;
+                    state.stats.snd_ttl_drops += ttl_drops as u64;
+                    self.notify_writers();
+                }
+                snd_buffer.fetch_batch(
+                    state.curr_snd_seq_number + 1,
+                    self.peer_socket_id().unwrap(),
+                    self.start_time,
+                    max_packets,
+                    packets,
+                );
+                if packets.is_empty() {
+                    state.next_data_target_time = now;
+                    state.interpacket_time_diff = Duration::ZERO;
+                    return Ok(None);
+                }
+                let new_snd_seq_number = state.curr_snd_seq_number + packets.len() as i32;
+                state.curr_snd_seq_number = new_snd_seq_number;
+                self.rate_control
+                    .write()
+                    .unwrap()
+                    .set_curr_snd_seq_number(new_snd_seq_number);
+                if state.curr_snd_seq_number.number().is_multiple_of(16) {
+                    probe = true;
                 }
             }
-        };
+        }
 
+        let nbytes = packets
+            .iter()
+            .map(|p| p.payload_len() + UDT_DATA_HEADER_SIZE)
+            .sum();
+        let (retransmit_pacing, bandwidth_interval) = {
+            let configuration = self.configuration.read().unwrap();
+            (
+                configuration.retransmit_pacing,
+                configuration.max_bandwidth_interval(nbytes),
+            )
+        };
+        let mut state = self.state();
+        if !bandwidth_interval.is_zero() {
+            // Retransmissions and probing pairs count against the bandwidth cap as well.
+            // Some unused budget is kept to make up for late wake-ups of the send queue.
+            let earliest = now.checked_sub(BANDWIDTH_CAP_BURST).unwrap_or(now);
+            state.bandwidth_target_time =
+                state.bandwidth_target_time.max(earliest) + bandwidth_interval;
+        }
         // update stats
         if probe {
-            return Ok(Some((packets, now)));
+            state.probe_pair_pending = true;
+            return Ok(Some(now));
         }
 
-        let mut state = self.state();
-        let interval = state.interpacket_interval * packets.len() as u32;
+        let mut interval = state.interpacket_interval * packets.len() as u32;
+        if retransmission {
+            state.stats.pkt_retrans += packets.len() as u64;
+            // Lost packets may be resent ahead of the pacing schedule
+            interval = interval.mul_f64(retransmit_pacing.clamp(0.0, 1.0));
+        }
         let target_time = if state.interpacket_time_diff >= interval {
             state.interpacket_time_diff -= interval;
             now
@@ -359,45 +657,142 @@ impl UdtSocket {
             target
         };
 
-        Ok(Some((packets, target_time)))
+        Ok(Some(target_time.max(state.bandwidth_target_time)))
     }
 
-    fn compute_cookie(&self, addr: &SocketAddr, offset: Option<isize>) -> u32 {
-        let timestamp = (self.start_time.elapsed().as_secs() / 60) + offset.unwrap_or(0) as u64; // secret changes every one minute
-        let host = addr.ip();
-        let port = addr.port();
-        let salt: &str = &(*SALT);
-        u32::from_be_bytes(
-            Sha256::digest(format!("{salt}:{host}:{port}:{timestamp}").as_bytes())[..4]
-                .try_into()
-                .unwrap(),
-        )
+    pub(crate) async fn send_to(&self, addr: &SocketAddr, packet: UdtPacket) -> Result<()> {
+        self.send_from(self.self_ip(), addr, packet).await
     }
 
-    pub(crate) async fn send_to(&self, addr: &SocketAddr, packet: UdtPacket) -> Result<()> {
-        self.multiplexer()
-            .expect("multiplexer not initialized")
-            .send_to(addr, packet)
+    async fn send_from(
+        &self,
+        local_ip: Option<IpAddr>,
+        addr: &SocketAddr,
+        packet: UdtPacket,
+    ) -> Result<()> {
+        self.require_multiplexer()?
+            .send_to(&self.with_flow_label(addr), self.seal(packet)?, local_ip)
             .await?;
         Ok(())
     }
 
+    /// Gets the multiplexer, which is gone once the socket is closed and released.
+    fn require_multiplexer(&self) -> Result<Arc<UdtMultiplexer>> {
+        self.multiplexer().ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotConnected,
+                UdtError::new(UdtErrorCode::UnboundSock, "socket has no multiplexer"),
+            )
+        })
+    }
+
+    /// Encrypts the packet once the connection has a cipher. Handshakes are sent in clear.
+    fn seal(&self, packet: UdtPacket) -> Result<UdtPacket> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = self.cipher.get() {
+            if packet.handshake().is_none() {
+                let sealed = cipher.seal(&packet).ok_or_else(|| {
+                    Error::other(UdtError::new(
+                        UdtErrorCode::SecFail,
+                        "encryption counters exhausted",
+                    ))
+                })?;
+                return Ok(UdtPacket::Sealed(sealed));
+            }
+        }
+        Ok(packet)
+    }
+
+    /// Decrypts a packet received from the peer.
+    /// Returns None if it was not sealed with the key of the connection.
+    pub(crate) fn open_packet(&self, raw: Bytes) -> Option<Bytes> {
+        #[cfg(feature = "encryption")]
+        if let Some(cipher) = self.cipher.get() {
+            return cipher.open(&raw);
+        }
+        Some(raw)
+    }
+
+    /// Adds the salt of the session key and the proof of the encryption key
+    /// sent by `role` to a handshake, when the connection is encrypted.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn sign_handshake(&self, hs: &mut HandShakeInfo, role: HandshakeRole) {
+        hs.encryption_proof = None;
+        hs.encryption_salt = None;
+        let configuration = self.configuration.read().unwrap();
+        if let Some(key) = &configuration.encryption_key {
+            hs.encryption_salt = Some(self.session_salt);
+            hs.encryption_proof = Some(crypto::handshake_proof(key, hs, role));
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn sign_handshake(&self, hs: &mut HandShakeInfo, _role: HandshakeRole) {
+        hs.encryption_proof = None;
+        hs.encryption_salt = None;
+    }
+
+    /// Whether the peer uses the same encryption key, or none like this socket.
+    #[cfg(feature = "encryption")]
+    fn check_handshake_proof(&self, hs: &HandShakeInfo, peer_role: HandshakeRole) -> bool {
+        match &self.configuration.read().unwrap().encryption_key {
+            Some(key) => crypto::verify_handshake_proof(key, hs, peer_role),
+            None => hs.encryption_proof.is_none(),
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn check_handshake_proof(&self, hs: &HandShakeInfo, _peer_role: HandshakeRole) -> bool {
+        hs.encryption_proof.is_none()
+    }
+
+    #[cfg(feature = "encryption")]
+    fn init_cipher(&self, peer_salt: Option<[u8; 16]>) {
+        let key = self.configuration.read().unwrap().encryption_key.clone();
+        if let (Some(key), Some(peer_socket_id)) = (key, self.peer_socket_id()) {
+            let _ = self.cipher.set(PacketCipher::new(
+                &key,
+                (self.socket_id, self.session_salt),
+                (peer_socket_id, peer_salt.unwrap_or_default()),
+            ));
+        }
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn init_cipher(&self, _peer_salt: Option<[u8; 16]>) {}
+
     pub(crate) async fn listen_on_handshake(
         &self,
         addr: SocketAddr,
         hs: &HandShakeInfo,
+        local_ip: Option<IpAddr>,
     ) -> Result<()> {
         if !self.status().is_alive() {
-            return Err(Error::new(ErrorKind::ConnectionRefused, "socket closed"));
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                UdtError::new(UdtErrorCode::NoServer, "socket closed"),
+            ));
         }
 
+        trace_event!(
+            debug,
+            socket_id = self.socket_id,
+            peer = %addr,
+            connection_type = hs.connection_type,
+            "connection request"
+        );
         if hs.connection_type == 1 {
             // Regular connection, respond to handshake
             let mut hs_response = hs.clone();
             let dest_socket_id = hs_response.socket_id;
-            hs_response.syn_cookie = self.compute_cookie(&addr, None);
+            hs_response.syn_cookie = self.syn_cookies.generate(&addr, hs, Instant::now());
+            // The initiator settles on the highest version both sides implement.
+            // UDT4 initiators ignore it and send their request again as is.
+            hs_response.udt_version = UdtConfiguration::udt_version();
+            // The initiator checks that the cookie comes from a listener knowing the key
+            self.sign_handshake(&mut hs_response, HandshakeRole::Responder);
             let hs_packet = UdtControlPacket::new_handshake(hs_response, dest_socket_id);
-            self.send_to(&addr, hs_packet.into()).await?;
+            self.send_from(local_ip, &addr, hs_packet.into()).await?;
             return Ok(());
         }
 
@@ -409,121 +804,748 @@ impl UdtSocket {
         }
 
         // Validate client response
-        let syn_cookie = hs.syn_cookie;
-        if syn_cookie != self.compute_cookie(&addr, None)
-            && syn_cookie != self.compute_cookie(&addr, Some(-1))
-        {
-            // Invalid cookie;
-            return Err(Error::new(ErrorKind::PermissionDenied, "invalid cookie"));
+        if !self.syn_cookies.validate(&addr, hs, Instant::now()) {
+            // Invalid or expired cookie: the request may come from a spoofed address
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                UdtError::new(UdtErrorCode::SecFail, "invalid cookie"),
+            ));
         }
 
-        let dest_socket_id = hs.socket_id;
-        let udt_version = UdtConfiguration::udt_version();
-        if hs.udt_version != udt_version || hs.socket_type != self.socket_type {
-            // Reject request
-            let mut hs_response = hs.clone();
-            hs_response.connection_type = 1002; // Error codes defined in C++ implementation
-            let hs_packet = UdtControlPacket::new_handshake(hs_response, dest_socket_id);
-            self.send_to(&addr, hs_packet.into()).await?;
+        let udt_version = self
+            .configuration
+            .read()
+            .unwrap()
+            .negotiate_udt_version(hs.udt_version);
+        let addr_mismatch = self.check_handshake_addr(hs, local_ip);
+        let key_mismatch = !self.check_handshake_proof(hs, HandshakeRole::Initiator);
+        if udt_version.is_none()
+            || hs.socket_type != self.socket_type
+            || addr_mismatch
+            || key_mismatch
+        {
+            self.reject_handshake(addr, hs, HandshakeRejection::Peer, local_ip)
+                .await?;
             return Err(Error::new(
                 ErrorKind::ConnectionRefused,
-                "configuration mismatch",
+                UdtError::new(
+                    UdtErrorCode::ConnRej,
+                    if addr_mismatch {
+                        "handshake address mismatch"
+                    } else if key_mismatch {
+                        "encryption key mismatch"
+                    } else if udt_version.is_none() {
+                        "unsupported UDT version"
+                    } else {
+                        "configuration mismatch"
+                    },
+                ),
             ));
         }
 
+        // The response carries the version settled on
+        let mut hs = hs.clone();
+        if let Some(version) = udt_version {
+            hs.udt_version = version;
+        }
         Udt::get()
             .write()
             .await
-            .new_connection(self, addr, hs)
+            .new_connection(self, addr, &hs, local_ip)
             .await?;
         // Send handshake packet in case of errors on connection?
 
         Ok(())
     }
 
+    /// Replies to a connection request with a rejection, which fails the connection
+    /// of the peer right away instead of letting it time out.
+    pub(crate) async fn reject_handshake(
+        &self,
+        addr: SocketAddr,
+        hs: &HandShakeInfo,
+        rejection: HandshakeRejection,
+        local_ip: Option<IpAddr>,
+    ) -> Result<()> {
+        self.refused_connections
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let mut hs_response = hs.clone();
+        // UDT4 peers only know the generic rejection
+        hs_response.connection_type = if hs.udt_version > UDT4_VERSION {
+            rejection as i32
+        } else {
+            HandshakeRejection::Peer as i32
+        };
+        hs_response.encryption_proof = None;
+        hs_response.encryption_salt = None;
+        hs_response.extensions = vec![];
+        let hs_packet = UdtControlPacket::new_handshake(hs_response, hs.socket_id);
+        self.send_from(local_ip, &addr, hs_packet.into()).await
+    }
+
+    /// Compares the server address in a connection request with the local address
+    /// it was received on. Returns whether the request must be rejected.
+    fn check_handshake_addr(&self, hs: &HandShakeInfo, local_ip: Option<IpAddr>) -> bool {
+        let mux = match self.multiplexer() {
+            Some(mux) => mux,
+            None => return false,
+        };
+        let local_ip = match local_ip.or_else(|| {
+            mux.channel
+                .local_addr()
+                .ok()
+                .map(|addr| addr.ip())
+                .filter(|ip| !ip.is_unspecified())
+        }) {
+            Some(ip) => ip,
+            None => return false,
+        };
+        if canonical_ip(hs.ip_address) == canonical_ip(local_ip) {
+            return false;
+        }
+        mux.handshake_addr_mismatches
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.configuration.read().unwrap().verify_handshake_addr
+    }
+
+    /// Called with the packets received from another address than the peer one.
+    /// With `peer_migration`, the new address is challenged (see `check_migration_challenge`),
+    /// and replaces the peer address once the ACK2 echoing the challenge comes from there.
+    /// A later address replaces the pending one, so that a spoofed packet can not
+    /// block a migration. With an encryption key, only authenticated packets are considered.
+    /// Returns whether the packet must be processed.
+    pub(crate) fn check_peer_migration(&self, packet: &UdtPacket, addr: SocketAddr) -> bool {
+        if self.status() != UdtStatus::Connected {
+            return false;
+        }
+        {
+            let configuration = self.configuration.read().unwrap();
+            if !configuration.peer_migration {
+                return false;
+            }
+            #[cfg(feature = "encryption")]
+            if configuration.encryption_key.is_some() && self.cipher.get().is_none() {
+                return false;
+            }
+        }
+        let echo = match packet {
+            UdtPacket::Control(packet) if matches!(packet.packet_type, ControlPacketType::Ack2) => {
+                packet.ack_seq_number()
+            }
+            _ => None,
+        };
+
+        {
+            let mut state = self.state();
+            let migration = state.peer_migration.get_or_insert_with(|| PeerMigration {
+                addr,
+                challenge: AckSeqNumber::random(),
+                challenged: VecDeque::new(),
+                challenge_due: false,
+            });
+            if echo != Some(migration.challenge) || !migration.challenged.contains(&addr) {
+                migration.addr = addr;
+                migration.challenge_due = true;
+                return false;
+            }
+            state.peer_migration = None;
+        }
+        self.set_peer_addr(addr);
+        trace_event!(info, socket_id = self.socket_id, peer = %addr, "peer migrated");
+        self.emit_event(UdtConnectionEvent::PeerMigrated(addr));
+        true
+    }
+
+    /// Sends the challenge of a pending peer migration: an ACK whose sequence number is random,
+    /// which the peer echoes in an ACK2. It is repeated as the peer may skip ACK2s
+    /// (at most one per SYN interval), but only after a packet came from the new address
+    /// and at most once per `MIGRATION_CHALLENGE_INTERVAL`, so that spoofed packets
+    /// can not turn the connection into a reflector.
+    async fn check_migration_challenge(&self, now: Instant) {
+        let (addr, challenge, last_sent_ack) = {
+            let mut state = self.state();
+            if state
+                .last_migration_challenge
+                .is_some_and(|last| now < last + MIGRATION_CHALLENGE_INTERVAL)
+            {
+                return;
+            }
+            let last_sent_ack = state.last_sent_ack;
+            let migration = match &mut state.peer_migration {
+                Some(migration) if migration.challenge_due => migration,
+                _ => return,
+            };
+            migration.challenge_due = false;
+            let addr = migration.addr;
+            migration
+                .challenged
+                .retain(|challenged| *challenged != addr);
+            migration.challenged.push_front(addr);
+            migration.challenged.truncate(MIGRATION_CHALLENGED_ADDRS);
+            let challenge = migration.challenge;
+            state.last_migration_challenge = Some(now);
+            (addr, challenge, last_sent_ack)
+        };
+        let packet = match self.challenge_ack(challenge, last_sent_ack) {
+            Some(packet) => packet,
+            None => return,
+        };
+        trace_event!(debug, socket_id = self.socket_id, peer = %addr, "challenging new peer address");
+        if let Err(_err) = self.send_to(&addr, packet.into()).await {
+            trace_event!(debug, socket_id = self.socket_id, peer = %addr, error = %_err, "failed to challenge new peer address");
+        }
+    }
+
+    /// Full ACK repeating the last one sent, whose sequence number is `challenge`:
+    /// the peer echoes it in an ACK2.
+    fn challenge_ack(
+        &self,
+        challenge: AckSeqNumber,
+        last_sent_ack: SeqNumber,
+    ) -> Option<UdtControlPacket> {
+        let peer_socket_id = self.peer_socket_id()?;
+        let available_buf_size = self.advertised_window(last_sent_ack);
+        let info = {
+            let flow = self.flow.read().unwrap();
+            AckOptionalInfo {
+                rtt: flow.rtt.as_micros().try_into().unwrap_or(u32::MAX),
+                rtt_variance: flow.rtt_var.as_micros().try_into().unwrap_or(u32::MAX),
+                available_buf_size,
+                pack_recv_rate: 0,
+                link_capacity: 0,
+            }
+        };
+        Some(UdtControlPacket::new_ack(
+            challenge,
+            last_sent_ack,
+            peer_socket_id,
+            Some(info),
+        ))
+    }
+
+    /// Probes a larger packet size with path MTU discovery: the probe is a challenge ACK
+    /// padded to the probed size. The ACK2 echoing it proves that the path carries it.
+    async fn check_path_mtu(&self, now: Instant) {
+        if self.status() != UdtStatus::Connected {
+            return;
+        }
+        let timeout = {
+            let flow = self.flow.read().unwrap();
+            (flow.rtt + 4 * flow.rtt_var + SYN_INTERVAL).max(PMTUD_MIN_PROBE_TIMEOUT)
+        };
+        let (probe, last_sent_ack) = {
+            let mut state = self.state();
+            let probe = match &mut state.path_mtu {
+                Some(path_mtu) => path_mtu.next_probe(now, timeout),
+                None => return,
+            };
+            (probe, state.last_sent_ack)
+        };
+        let (size, challenge) = match probe {
+            Some(probe) => probe,
+            None => return,
+        };
+        let packet = match self.challenge_ack(challenge, last_sent_ack) {
+            Some(packet) => packet,
+            None => return,
+        };
+        let padding = (size as usize).saturating_sub(
+            self.packet_overhead() as usize + UDT_CONTROL_HEADER_SIZE + ACK_INFO_SIZE,
+        );
+        trace_event!(debug, socket_id = self.socket_id, size, "probing path MTU");
+        if let Err(err) = self.send_packet(packet.with_padding(padding).into()).await {
+            if err.raw_os_error() == Some(nix::libc::EMSGSIZE) {
+                if let Some(path_mtu) = &mut self.state().path_mtu {
+                    path_mtu.on_probe_failed();
+                }
+            } else {
+                trace_event!(debug, socket_id = self.socket_id, error = %err, "failed to send path MTU probe");
+            }
+        }
+    }
+
+    /// Handles an ACK2 which may answer a probe of path MTU discovery.
+    fn on_path_mtu_echo(&self, echo: AckSeqNumber) {
+        let size = match &mut self.state().path_mtu {
+            Some(path_mtu) => path_mtu.on_echo(echo),
+            None => return,
+        };
+        if let Some(size) = size {
+            trace_event!(info, socket_id = self.socket_id, size, "path MTU raised");
+            self.snd_buffer
+                .lock()
+                .unwrap()
+                .set_payload_size(self.payload_size(size) as usize);
+            self.emit_event(UdtConnectionEvent::PathMtuChanged(size));
+        }
+    }
+
     pub(crate) async fn process_packet(&self, packet: UdtPacket) -> Result<()> {
         match packet {
             UdtPacket::Control(ctrl) => self.process_ctrl(ctrl).await,
+            // A rendezvous peer may connect and send data before its handshake arrives
+            UdtPacket::Data(_) if self.status() == UdtStatus::Connecting => Ok(()),
             UdtPacket::Data(data) => self.process_data(data).await,
+            // Received packets are opened before being deserialized
+            #[cfg(feature = "encryption")]
+            UdtPacket::Sealed(_) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "cannot process an encrypted packet",
+            )),
         }
     }
 
-    async fn process_ctrl(&self, packet: UdtControlPacket) -> Result<()> {
+    /// Connects with the settings of the handshake of the peer, which carries the
+    /// version settled on and the sequence number of its first data packet.
+    fn complete_connection(&self, hs: &HandShakeInfo) {
+        let mut configuration = self.configuration.write().unwrap();
+        configuration.mss = configuration.mss.min(hs.max_packet_size);
+        configuration.flight_flag_size = configuration.flight_flag_size.min(hs.max_window_size);
+        self.flow.write().unwrap().flow_window_size = configuration.flight_flag_size;
+        let mut state = self.state();
+        state.udt_version = hs.udt_version;
+        state.peer_handshake_extensions = hs.extensions.clone();
+        state.resumption_token = handshake_extension::find(&hs.extensions, RESUMPTION_EXTENSION)
+            .and_then(|token| token.try_into().ok());
+        state.last_sent_ack = hs.initial_seq_number;
+        state.last_ack2_received = hs.initial_seq_number;
+        state.curr_rcv_seq_number = hs.initial_seq_number - 1;
+        *self.peer_socket_id.lock().unwrap() = Some(hs.socket_id);
+        // self.self_ip = Some(hs.ip_address);
+
+        // TODO: check size of loss lists
+
         {
-            let mut state = self.state();
-            state.exp_count = 1;
-            state.last_rsp_time = Instant::now();
+            let mut rate_control = self.rate_control.write().unwrap();
+            rate_control.init(
+                configuration.mss,
+                &self.flow.read().unwrap(),
+                state.curr_snd_seq_number,
+            );
         }
+        drop((configuration, state));
+        // A rendezvous peer starts from its own sequence number
+        self.rcv_buffer().reset(hs.initial_seq_number);
+        self.init_cipher(hs.encryption_salt);
+        self.init_path_mtu();
 
-        match packet.packet_type {
-            ControlPacketType::Handshake(hs) => {
-                if self.status() != UdtStatus::Connecting {
-                    return Err(Error::new(
-                        ErrorKind::InvalidData,
-                        format!(
-                            "unexpected handshake for socket with status {:?}",
-                            self.status
-                        ),
-                    ));
-                }
+        *self.status.lock().unwrap() = UdtStatus::Connected;
+        trace_event!(info, socket_id = self.socket_id, "connected");
+        self.emit_event(UdtConnectionEvent::Connected);
+        self.connect_notify.notify_waiters();
+    }
 
-                // TODO: handle rendezvous mode
-                if hs.connection_type > 0 {
-                    let mut hs = hs.clone();
-                    hs.connection_type = -1;
-                    hs.socket_id = self.socket_id;
-                    let hs_packet = UdtControlPacket::new_handshake(hs, 0);
-                    self.send_packet(hs_packet.into()).await?;
-                } else {
-                    // post connect
-                    let mut configuration = self.configuration.write().unwrap();
-                    configuration.mss = hs.max_packet_size;
-                    configuration.flight_flag_size = hs.max_window_size;
-                    let mut state = self.state();
-                    state.last_sent_ack = hs.initial_seq_number;
-                    state.last_ack2_received = hs.initial_seq_number;
-                    state.curr_rcv_seq_number = hs.initial_seq_number - 1;
-                    *self.peer_socket_id.lock().unwrap() = Some(hs.socket_id);
-                    // self.self_ip = Some(hs.ip_address);
+    /// Extension blocks of the connection request carrying the cookie of the listener.
+    /// A client resuming its session only presents its token, and the first packet it is
+    /// missing: the application extensions were checked when connecting.
+    fn connection_request_extensions(&self) -> Vec<(u16, Bytes)> {
+        let configuration = self.configuration.read().unwrap();
+        let state = self.state();
+        if let Some(token) = state.resumption_token.filter(|_| state.resuming) {
+            let mut value = Vec::with_capacity(token.len() + 4);
+            value.extend_from_slice(&token);
+            value.extend_from_slice(&state.ack_point().number().to_be_bytes());
+            return vec![(RESUMPTION_EXTENSION, value.into())];
+        }
+        let mut extensions = match self.peer_addr() {
+            Some(peer) => configuration.handshake_extensions.request(peer),
+            None => vec![],
+        };
+        if configuration.resumption_timeout.is_some() {
+            // Asks the listener for a resumption token
+            extensions.push((RESUMPTION_EXTENSION, Bytes::new()));
+        }
+        extensions
+    }
 
-                    // TODO: check size of loss lists
+    /// How long the session can be resumed once broken, if the peers agreed on it.
+    pub(crate) fn resumption_window(&self) -> Option<Duration> {
+        self.state().resumption_token?;
+        self.configuration.read().unwrap().resumption_timeout
+    }
 
-                    {
-                        let mut rate_control = self.rate_control.write().unwrap();
-                        rate_control.init(
-                            configuration.mss,
-                            &self.flow.read().unwrap(),
-                            state.curr_snd_seq_number,
-                        );
-                    }
+    pub(crate) fn resumption_token(&self) -> Option<[u8; 16]> {
+        self.state().resumption_token
+    }
 
-                    *self.status.lock().unwrap() = UdtStatus::Connected;
-                    self.connect_notify.notify_waiters();
+    /// Resumes the broken session of this client with the listener which accepted it,
+    /// presenting its token in connection requests repeated until the listener answers.
+    pub(crate) async fn resume(&self) -> Result<()> {
+        let (window, connect_timeout) = {
+            let configuration = self.configuration.read().unwrap();
+            (
+                configuration.resumption_timeout,
+                configuration.connect_timeout,
+            )
+        };
+        let resumable = self.status() == UdtStatus::Broken
+            && self.listen_socket.is_none()
+            && self.resumption_token().is_some();
+        let (Some(window), Some(addr), true) = (window, self.peer_addr(), resumable) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                UdtError::new(UdtErrorCode::InvOp, "connection cannot be resumed"),
+            ));
+        };
+        self.state().resuming = true;
+        *self.status.lock().unwrap() = UdtStatus::Connecting;
+        trace_event!(debug, socket_id = self.socket_id, peer = %addr, "resuming session");
+
+        let deadline = Instant::now() + connect_timeout.unwrap_or(window);
+        let result = loop {
+            if let Err(err) = self.send_connection_request(addr).await {
+                self.break_with(err);
+                break Err(self.connect_error());
+            }
+            let retry = deadline.min(Instant::now() + RESUMPTION_RETRY_INTERVAL);
+            match tokio::time::timeout_at(retry, self.wait_for_connection()).await {
+                Ok(UdtStatus::Connected) => break Ok(()),
+                Ok(_) => break Err(self.connect_error()),
+                Err(_) if Instant::now() >= deadline => {
+                    let err = Error::new(
+                        ErrorKind::TimedOut,
+                        UdtError::new(UdtErrorCode::NoServer, "session resumption timed out"),
+                    );
+                    self.break_with(err);
+                    break Err(self.connect_error());
                 }
+                Err(_) => (),
             }
-            ControlPacketType::KeepAlive => (),
-            ControlPacketType::Ack(ref ack) => {
-                match &ack.info {
-                    None => {
-                        let mut state = self.state();
-                        let seq = ack.next_seq_number;
-                        let nb_acked = seq - state.last_ack_received;
+        };
+        self.state().resuming = false;
+        result
+    }
+
+    /// Goes on with the session restored by the listener, which tells the first packet
+    /// it is missing in its answer.
+    fn complete_resumption(&self, hs: &HandShakeInfo) -> Result<()> {
+        let peer_rcv_next = handshake_extension::find(&hs.extensions, RESUMPTION_EXTENSION)
+            .and_then(|value| <[u8; 4]>::try_from(value).ok())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::ConnectionRefused,
+                    UdtError::new(UdtErrorCode::ConnRej, "session not resumed by the peer"),
+                )
+            })?;
+        self.restore_snd_position(u32::from_be_bytes(peer_rcv_next).into())?;
+        self.state().resuming = false;
+        self.revive();
+        Ok(())
+    }
+
+    /// Resumes the session of this accepted socket for its client, now at `peer`,
+    /// which is missing the data from `peer_rcv_next`.
+    /// Returns the value of the extension block answering the client.
+    pub(crate) fn resume_session(
+        &self,
+        peer: SocketAddr,
+        local_ip: Option<IpAddr>,
+        peer_rcv_next: SeqNumber,
+    ) -> Result<Bytes> {
+        if !matches!(self.status(), UdtStatus::Connected | UdtStatus::Broken) {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                UdtError::new(UdtErrorCode::ConnRej, "session closed"),
+            ));
+        }
+        self.restore_snd_position(peer_rcv_next)?;
+        self.set_peer_addr(peer);
+        *self.self_ip.lock().unwrap() = local_ip;
+        self.revive();
+        let rcv_next = self.state().ack_point();
+        Ok(Bytes::copy_from_slice(&rcv_next.number().to_be_bytes()))
+    }
+
+    /// Sends again the data from `peer_rcv_next`, the first packet the peer is missing.
+    /// It cannot be before the data the peer acknowledged, nor after the data sent.
+    fn restore_snd_position(&self, peer_rcv_next: SeqNumber) -> Result<()> {
+        let mut state = self.state();
+        let curr_snd_seq_number = state.curr_snd_seq_number;
+        if peer_rcv_next - state.last_data_ack_processed < 0
+            || curr_snd_seq_number + 1 - peer_rcv_next < 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                UdtError::new(
+                    UdtErrorCode::ConnRej,
+                    "positions of the resumed session do not match",
+                ),
+            ));
+        }
+        if peer_rcv_next != curr_snd_seq_number + 1 {
+            state
+                .snd_loss_list
+                .insert(peer_rcv_next, curr_snd_seq_number);
+        }
+        Ok(())
+    }
+
+    /// Reconnects the session with its buffers, after `restore_snd_position`.
+    fn revive(&self) {
+        {
+            let mut state = self.state();
+            let now = Instant::now();
+            state.exp_count = 1;
+            state.last_rsp_time = now;
+            state.last_peer_activity = now;
+            state.peer_idle = false;
+            state.unanswered_keep_alives = 0;
+        }
+        *self.broken_cause.lock().unwrap() = None;
+        *self.status.lock().unwrap() = UdtStatus::Connected;
+        if let Some(mux) = self.multiplexer() {
+            // Broken sockets left the timers of the receive queue
+            mux.rcv_queue.remove(self.socket_id);
+            mux.rcv_queue.push_back(self.socket_id);
+        }
+        self.update_snd_queue(true);
+        trace_event!(info, socket_id = self.socket_id, "session resumed");
+        self.emit_event(UdtConnectionEvent::SessionResumed);
+        self.notify_all();
+    }
+
+    /// Rendezvous connection setup: both peers send handshakes of type 0 until they
+    /// hear from the other side, then of type -1. A handshake of type -1 or -2 shows
+    /// that the peer heard this socket, which then connects and sends handshakes of
+    /// type -2 until the peer shows it is connected too (see `check_rendezvous_reply`).
+    /// Handshakes are only routed to the socket while it is connecting: once connected,
+    /// answering them would reflect the traffic of anyone spoofing the peer address.
+    async fn process_rendezvous_handshake(&self, mut hs: HandShakeInfo) -> Result<()> {
+        if self.status() != UdtStatus::Connecting {
+            return Ok(());
+        }
+
+        if let Some(rejection) = HandshakeRejection::from_connection_type(hs.connection_type) {
+            self.break_with(Error::new(
+                ErrorKind::ConnectionRefused,
+                UdtError::new(UdtErrorCode::ConnRej, rejection.message()),
+            ));
+            return Ok(());
+        }
+        if hs.connection_type > 0 {
+            // Regular connection request
+            return Ok(());
+        }
+
+        if !self.check_handshake_proof(&hs, HandshakeRole::Initiator) {
+            self.break_with(Error::new(
+                ErrorKind::PermissionDenied,
+                UdtError::new(UdtErrorCode::SecFail, "encryption key mismatch"),
+            ));
+            return Ok(());
+        }
+        let udt_version = self
+            .configuration
+            .read()
+            .unwrap()
+            .negotiate_udt_version(hs.udt_version);
+        hs.udt_version = match udt_version {
+            Some(version) => version,
+            None => {
+                self.break_with(Error::new(
+                    ErrorKind::ConnectionRefused,
+                    UdtError::new(UdtErrorCode::ConnRej, "unsupported UDT version"),
+                ));
+                return Ok(());
+            }
+        };
+        if hs.socket_type != self.socket_type {
+            self.break_with(Error::new(
+                ErrorKind::ConnectionRefused,
+                UdtError::new(UdtErrorCode::ConnRej, "configuration mismatch"),
+            ));
+            return Ok(());
+        }
+        if hs.connection_type == 0 {
+            self.state().rendezvous_connection_type = -1;
+            return self.send_rendezvous_handshake().await;
+        }
+        // Extensions are only exchanged with a listener
+        hs.extensions.clear();
+        self.complete_connection(&hs);
+        {
+            let mut state = self.state();
+            state.rendezvous_connection_type = -2;
+            state.rendezvous_reply_due = Some(Instant::now() + RENDEZVOUS_REPLY_INTERVAL);
+        }
+        self.send_rendezvous_handshake().await
+    }
+
+    /// Repeats the -2 handshake of a rendezvous connection, as the peer stays in the
+    /// connection setup if it was lost. It stops once a packet of the peer shows it
+    /// is connected too, which are only sent to the peer address.
+    async fn check_rendezvous_reply(&self, now: Instant) {
+        {
+            let mut state = self.state();
+            match state.rendezvous_reply_due {
+                Some(due) if now >= due => {
+                    state.rendezvous_reply_due = Some(now + RENDEZVOUS_REPLY_INTERVAL)
+                }
+                _ => return,
+            }
+        }
+        if let Err(_err) = self.send_rendezvous_handshake().await {
+            trace_event!(debug, socket_id = self.socket_id, error = %_err, "failed to send rendezvous handshake");
+        }
+    }
+
+    /// Sends the handshake of the rendezvous connection setup,
+    /// repeated until connected since the first ones may not get through the NAT.
+    pub(crate) async fn send_rendezvous_handshake(&self) -> Result<()> {
+        let addr = match self.peer_addr() {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        // Each lock is released before the next one is taken: `check_live_latency`
+        // holds the state while it locks the receive buffer
+        let available_buf_size = self.rcv_buffer().get_available_buf_size();
+        let connection_type = self.state().rendezvous_connection_type;
+        let mut hs = {
+            let configuration = self.configuration.read().unwrap();
+            HandShakeInfo {
+                udt_version: UdtConfiguration::udt_version(),
+                initial_seq_number: self.initial_seq_number,
+                max_packet_size: configuration.mss,
+                max_window_size: std::cmp::min(configuration.flight_flag_size, available_buf_size),
+                connection_type,
+                socket_type: self.socket_type,
+                socket_id: self.socket_id,
+                ip_address: addr.ip(),
+                syn_cookie: 0,
+                encryption_proof: None,
+                encryption_salt: None,
+                extensions: vec![],
+            }
+        };
+        self.sign_handshake(&mut hs, HandshakeRole::Initiator);
+        // The peer socket id may not be known yet: the handshake is routed by address
+        let hs_packet = UdtControlPacket::new_handshake(hs, 0);
+        self.send_to(&addr, hs_packet.into()).await
+    }
+
+    async fn process_ctrl(&self, packet: UdtControlPacket) -> Result<()> {
+        {
+            let mut state = self.state();
+            let now = Instant::now();
+            state.exp_count = 1;
+            state.last_rsp_time = now;
+            state.last_peer_activity = now;
+            state.peer_idle = false;
+            state.unanswered_keep_alives = 0;
+            state.rendezvous_reply_due = None;
+        }
+
+        match packet.packet_type {
+            ControlPacketType::Handshake(hs) => {
+                let rendezvous = self.configuration.read().unwrap().rendezvous;
+                if rendezvous {
+                    return self.process_rendezvous_handshake(hs).await;
+                }
+                if self.status() != UdtStatus::Connecting {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "unexpected handshake for socket with status {:?}",
+                            self.status
+                        ),
+                    ));
+                }
+
+                if let Some(rejection) =
+                    HandshakeRejection::from_connection_type(hs.connection_type)
+                {
+                    self.break_with(Error::new(
+                        ErrorKind::ConnectionRefused,
+                        UdtError::new(UdtErrorCode::ConnRej, rejection.message()),
+                    ));
+                } else if hs.connection_type > 0 {
+                    if !self.check_handshake_proof(&hs, HandshakeRole::Responder) {
+                        self.break_with(Error::new(
+                            ErrorKind::PermissionDenied,
+                            UdtError::new(UdtErrorCode::SecFail, "encryption key mismatch"),
+                        ));
+                        return Ok(());
+                    }
+                    let udt_version = self
+                        .configuration
+                        .read()
+                        .unwrap()
+                        .negotiate_udt_version(hs.udt_version);
+                    let mut hs = hs.clone();
+                    hs.udt_version = match udt_version {
+                        Some(version) => version,
+                        None => {
+                            self.break_with(Error::new(
+                                ErrorKind::ConnectionRefused,
+                                UdtError::new(UdtErrorCode::ConnRej, "unsupported UDT version"),
+                            ));
+                            return Ok(());
+                        }
+                    };
+                    hs.connection_type = -1;
+                    hs.socket_id = self.socket_id;
+                    hs.extensions = self.connection_request_extensions();
+                    self.sign_handshake(&mut hs, HandshakeRole::Initiator);
+                    let hs_packet = UdtControlPacket::new_handshake(hs, 0);
+                    self.send_packet(hs_packet.into()).await?;
+                } else {
+                    // post connect
+                    if !self.check_handshake_proof(&hs, HandshakeRole::Responder) {
+                        self.break_with(Error::new(
+                            ErrorKind::PermissionDenied,
+                            UdtError::new(UdtErrorCode::SecFail, "encryption key mismatch"),
+                        ));
+                        return Ok(());
+                    }
+                    if self.state().resuming {
+                        if let Err(err) = self.complete_resumption(&hs) {
+                            self.break_with(err);
+                        }
+                        return Ok(());
+                    }
+                    if let Some(peer) = self.peer_addr() {
+                        let extensions = self
+                            .configuration
+                            .read()
+                            .unwrap()
+                            .handshake_extensions
+                            .clone();
+                        if let Err(err) = extensions.on_response(peer, &hs.extensions) {
+                            self.break_with(err);
+                            return Ok(());
+                        }
+                    }
+                    self.complete_connection(&hs);
+                }
+            }
+            ControlPacketType::KeepAlive => (),
+            ControlPacketType::Ack(ref ack) => {
+                self.state().stats.ack_recv += 1;
+                match &ack.info {
+                    None => {
+                        let mut state = self.state();
+                        let seq = ack.next_seq_number;
+                        let nb_acked = seq - state.last_ack_received;
                         if nb_acked >= 0 {
                             state.last_ack_received = seq;
-                            self.flow.write().unwrap().flow_window_size -= (nb_acked) as u32;
+                            let mut flow = self.flow.write().unwrap();
+                            flow.flow_window_size =
+                                flow.flow_window_size.saturating_sub(nb_acked as u32);
                         }
                     }
                     Some(extra) => {
                         let ack_seq = packet.ack_seq_number().unwrap();
                         let send_ack2 = {
                             let state = self.state();
+                            // Path MTU probes are always answered
                             state.last_ack2_time.elapsed() > SYN_INTERVAL
                                 || ack_seq == state.last_ack2_sent_back
+                                || ack.padding > 0
                         };
                         if send_ack2 {
                             if let Some(peer) = self.peer_socket_id() {
                                 let ack2_packet = UdtControlPacket::new_ack2(ack_seq, peer);
-                                self.send_packet(ack2_packet.into()).await?;
+                                self.send_feedback(ack2_packet.into());
                                 let mut state = self.state();
                                 state.last_ack2_sent_back = ack_seq;
                                 state.last_ack2_time = Instant::now();
@@ -531,19 +1553,35 @@ impl UdtSocket {
                         }
 
                         let seq = ack.next_seq_number;
+                        trace_event!(
+                            trace,
+                            socket_id = self.socket_id,
+                            seq = seq.number(),
+                            rtt_us = extra.rtt,
+                            "ACK received"
+                        );
 
-                        {
+                        let released = {
                             let mut state = self.state();
                             if (seq - state.curr_snd_seq_number) > 1 {
                                 // This should not happen
                                 eprintln!("Udt socket broken: seq number is larger than expected");
                                 *self.status.lock().unwrap() = UdtStatus::Broken;
+                                self.emit_event(UdtConnectionEvent::Broken);
                             }
 
                             if (seq - state.last_ack_received) >= 0 {
                                 self.flow.write().unwrap().flow_window_size =
                                     extra.available_buf_size;
                                 state.last_ack_received = seq;
+                                if state.peer_stalled
+                                    && extra.available_buf_size > PEER_STALL_WINDOW
+                                {
+                                    state.peer_stalled = false;
+                                    state.stall_probe = false;
+                                    self.emit_event(UdtConnectionEvent::PeerResumed);
+                                    self.update_snd_queue(true);
+                                }
                             }
 
                             let offset = seq - state.last_data_ack_processed;
@@ -552,15 +1590,18 @@ impl UdtSocket {
                                 return Ok(());
                             }
 
-                            self.snd_buffer.lock().unwrap().ack_data(offset);
-                            let last_data_ack_processed = state.last_data_ack_processed;
-                            state
-                                .snd_loss_list
-                                .remove_all(last_data_ack_processed, seq - 1);
+                            let released = self.snd_buffer.lock().unwrap().ack_data(offset);
+                            state.watchdog.on_acked(released);
+                            state.snd_loss_list.remove_up_to(seq - 1);
                             // TODO record times for monitoring purposes
                             state.last_data_ack_processed = seq;
-                            self.update_snd_queue(false);
-                            self.ack_notify.notify_waiters();
+                            released
+                        };
+                        self.update_snd_queue(false);
+                        if released > 0 {
+                            // Wake up writers once per ACK, after the socket state is released
+                            self.notify_writers();
+                            self.emit_event(UdtConnectionEvent::AckReceived(released as u32));
                         }
 
                         let mut flow = self.flow.write().unwrap();
@@ -583,21 +1624,20 @@ impl UdtSocket {
                             rate_control.on_ack(seq);
                         }
                         self.cc_update();
+                        #[cfg(feature = "cc-trace")]
+                        self.trace_cc(CcTraceEvent::Ack);
                     }
                 }
             }
             ControlPacketType::Ack2 => {
                 let ack_seq = packet.ack_seq_number().unwrap();
                 let window = self.state().ack_window.get(ack_seq);
+                if window.is_none() {
+                    self.on_path_mtu_echo(ack_seq);
+                }
                 if let Some((seq, rtt)) = window {
                     let mut flow = self.flow.write().unwrap();
-                    let rtt_abs_diff = {
-                        if rtt > flow.rtt {
-                            rtt - flow.rtt
-                        } else {
-                            flow.rtt - rtt
-                        }
-                    };
+                    let rtt_abs_diff = rtt.abs_diff(flow.rtt);
                     flow.update_rtt_var(rtt_abs_diff);
                     flow.update_rtt(rtt);
                     drop(flow);
@@ -608,37 +1648,37 @@ impl UdtSocket {
                 }
             }
             ControlPacketType::Nak(ref nak) => {
+                self.state().stats.nak_recv += 1;
+                trace_event!(
+                    debug,
+                    socket_id = self.socket_id,
+                    entries = nak.losses().count(),
+                    "NAK received"
+                );
                 let mut broken = false;
                 {
                     let mut rate_control = self.rate_control.write().unwrap();
-                    if nak.loss_info.is_empty() {
+                    let Some(first_loss) = nak.losses().next() else {
                         eprintln!("Received NAK with empty list");
                         return Ok(());
-                    }
-                    rate_control.on_loss((nak.loss_info[0] & 0x7fff_ffff).into());
+                    };
+                    rate_control.on_loss(*first_loss.start());
                 }
                 self.cc_update();
+                #[cfg(feature = "cc-trace")]
+                self.trace_cc(CcTraceEvent::Nak);
 
-                let loss_iter = &mut nak.loss_info.iter();
                 let mut state = self.state();
-                while let Some(loss) = loss_iter.next() {
-                    let (seq_start, seq_end) = {
-                        if loss & 0x8000_0000 == 0 {
-                            ((*loss).into(), (*loss).into())
-                        } else if let Some(seq_end) = loss_iter.next() {
-                            let seq_start: SeqNumber = (loss & 0x7fff_ffff).into();
-                            let seq_end: SeqNumber = (*seq_end).into();
-                            (seq_start, seq_end)
-                        } else {
-                            broken = true;
-                            break;
-                        }
-                        
-                    };
+                let mut lost = 0;
+                for loss in nak.losses() {
+                    let (seq_start, seq_end) = (*loss.start(), *loss.end());
                     if (seq_start - seq_end > 0) || (seq_end - state.curr_snd_seq_number > 0) {
                         broken = true;
                         break;
                     }
+                    state.watchdog.on_loss((seq_end - seq_start) as u64 + 1);
+                    state.stats.pkt_snd_loss += (seq_end - seq_start) as u64 + 1;
+                    lost += (seq_end - seq_start) as u32 + 1;
                     if seq_start - state.last_ack_received >= 0 {
                         state.snd_loss_list.insert(seq_start, seq_end);
                     } else if seq_end - state.last_ack_received >= 0 {
@@ -648,16 +1688,24 @@ impl UdtSocket {
                 }
 
                 if broken {
+                    trace_event!(warn, socket_id = self.socket_id, "invalid NAK received");
                     println!("NAK is broken: {:?} {:?}", nak, state);
                     *self.status.lock().unwrap() = UdtStatus::Broken;
+                    self.emit_event(UdtConnectionEvent::Broken);
                     return Ok(());
                 }
+                drop(state);
+                self.emit_event(UdtConnectionEvent::LossDetected(lost));
 
                 self.update_snd_queue(true);
             }
             ControlPacketType::Shutdown => {
+                trace_event!(info, socket_id = self.socket_id, "shutdown received");
+                // The data waiting for its delivery time in live mode is read right away
+                self.rcv_buffer().release_all();
                 *self.status.lock().unwrap() = UdtStatus::Closing;
                 self.notify_all();
+                self.emit_event(UdtConnectionEvent::Closed);
             }
             ControlPacketType::MsgDropRequest(ref drop) => {
                 let msg_number = packet.msg_seq_number().unwrap();
@@ -672,7 +1720,12 @@ impl UdtSocket {
                     state.curr_rcv_seq_number = drop.last_seq_number;
                 }
             }
-            ControlPacketType::UserDefined => unimplemented!(),
+            ControlPacketType::UserDefined(ref payload) => {
+                let handler = self.user_control_handler.read().unwrap().clone();
+                if let Some(handler) = handler {
+                    (handler.0)(packet.reserved, payload);
+                }
+            }
         }
         Ok(())
     }
@@ -682,7 +1735,13 @@ impl UdtSocket {
         {
             let mut state = self.state();
             state.last_rsp_time = now;
+            state.last_peer_activity = now;
+            state.peer_idle = false;
+            state.unanswered_keep_alives = 0;
+            state.rendezvous_reply_due = None;
             state.pkt_count += 1;
+            state.stats.pkt_recv += 1;
+            state.stats.bytes_recv += packet.data.len() as u64;
         }
 
         let seq_number = packet.header.seq_number;
@@ -691,7 +1750,7 @@ impl UdtSocket {
             let mut flow = self.flow.write().unwrap();
             flow.on_pkt_arrival(now);
 
-            if seq_number.number() % PROBE_MODULO == 0 {
+            if seq_number.number().is_multiple_of(PROBE_MODULO) {
                 flow.on_probe1_arrival();
             } else if seq_number.number() % PROBE_MODULO == 1 {
                 flow.on_probe2_arrival();
@@ -700,49 +1759,87 @@ impl UdtSocket {
 
         // trace_rcv++
         // recv_total++
-        let offset = seq_number - self.state().last_sent_ack;
+        let offset = {
+            let mut state = self.state();
+            // Anything up to the last received packet that is not reported lost
+            // has already been received (or given up on)
+            if seq_number - state.curr_rcv_seq_number <= 0
+                && !state.rcv_loss_list.contains(seq_number)
+            {
+                state.stats.rcv_duplicates += 1;
+                return Ok(());
+            }
+            seq_number - state.last_sent_ack
+        };
         if offset < 0 {
             // seq number is too late
             return Ok(());
         }
 
-        let payload_len = {
+        let drop_oldest =
+            self.configuration.read().unwrap().rcv_overload_policy == RcvOverloadPolicy::DropOldest;
+        let (payload_len, dropped) = {
             let mut rcv_buffer = self.rcv_buffer();
-            let available_buf_size = rcv_buffer.get_available_buf_size();
-            if available_buf_size < offset as u32 {
+            let overflow = rcv_buffer.overflow(seq_number);
+            let mut dropped = 0;
+            if overflow > 0 && drop_oldest {
+                dropped = rcv_buffer.drop_oldest(overflow);
+            }
+            if rcv_buffer.overflow(seq_number) > 0 {
+                (None, dropped)
+            } else {
+                let payload_len = packet.payload_len();
+                rcv_buffer.insert(packet);
+                (Some(payload_len), dropped)
+            }
+        };
+        self.state().stats.rcv_overload_drops += u64::from(dropped);
+        let Some(payload_len) = payload_len else {
+            self.state().stats.rcv_buffer_full += 1;
+            if *UDT_DEBUG {
                 eprintln!("not enough space in rcv buffer");
-                return Ok(());
             }
-
-            let payload_len = packet.payload_len();
-            rcv_buffer.insert(packet);
-            payload_len
+            return Ok(());
         };
 
         if (seq_number - self.state().curr_rcv_seq_number) > 1 {
             // some packets have been lost in between
-            let nak_packet = {
+            let (nak_packet, lost) = {
                 let mut state = self.state();
                 let curr_rcv_seq_number = state.curr_rcv_seq_number;
                 state
                     .rcv_loss_list
                     .insert(curr_rcv_seq_number + 1, seq_number - 1);
+                state.stats.pkt_rcv_loss += (seq_number - curr_rcv_seq_number - 1) as u64;
+                let configuration = self.configuration.read().unwrap();
+                if configuration.rcv_latency.is_some() {
+                    let times = &mut state.rcv_loss_times;
+                    let full = configuration.fixed_capacity && times.len() == times.capacity();
+                    match times.back_mut() {
+                        // Rather than growing, a full queue extends its newest range: those
+                        // losses are given up with it, a little early.
+                        Some(newest) if full => newest.1 = seq_number - 1,
+                        _ => times.push_back((curr_rcv_seq_number + 1, seq_number - 1, now)),
+                    }
+                }
+                drop(configuration);
 
                 // send NAK immediately
-                let loss_list = {
-                    if state.curr_rcv_seq_number + 1 == seq_number - 1 {
-                        vec![(seq_number - 1).number()]
-                    } else {
-                        vec![
-                            (state.curr_rcv_seq_number + 1).number() | 0x8000_0000,
-                            (seq_number - 1).number(),
-                        ]
-                    }
-                };
-                UdtControlPacket::new_nak(loss_list, self.peer_socket_id().unwrap_or(0))
+                let losses = (state.curr_rcv_seq_number + 1)..=(seq_number - 1);
+                (
+                    UdtControlPacket::new_nak(losses, self.peer_socket_id().unwrap_or(0)),
+                    seq_number - curr_rcv_seq_number - 1,
+                )
             };
-            self.send_packet(nak_packet.into()).await?;
-            // TODO increment NAK stats
+            self.send_feedback(nak_packet.into());
+            self.emit_event(UdtConnectionEvent::LossDetected(lost as u32));
+            self.state().stats.nak_sent += 1;
+            trace_event!(
+                debug,
+                socket_id = self.socket_id,
+                seq = seq_number.number(),
+                "NAK sent"
+            );
         }
 
         if payload_len < self.get_max_payload_size() as usize {
@@ -756,15 +1853,110 @@ impl UdtSocket {
         } else {
             state.rcv_loss_list.remove(seq_number);
         }
+        if state
+            .rcv_window_edge
+            .is_some_and(|edge| seq_number + 1 - edge >= 0)
+        {
+            // The sender cannot send past the advertised window until acknowledged
+            state.next_ack_time = now;
+        }
 
         Ok(())
     }
 
     pub fn get_max_payload_size(&self) -> u32 {
+        let mss = self.configuration.read().unwrap().mss;
+        self.payload_size(mss)
+    }
+
+    /// Payload size of the data packets sent, which is below the maximum
+    /// until path MTU discovery finds that the path carries it.
+    fn snd_payload_size(&self) -> u32 {
+        let path_mtu = self.state().path_mtu.as_ref().map(PathMtu::size);
+        match path_mtu {
+            Some(size) => self.payload_size(size),
+            None => self.get_max_payload_size(),
+        }
+    }
+
+    /// Payload size of the data packets of `packet_size` bytes, including the IP and UDP headers.
+    fn payload_size(&self, packet_size: u32) -> u32 {
+        packet_size - self.packet_overhead() - UDT_DATA_HEADER_SIZE as u32
+    }
+
+    /// Size of the IP and UDP headers, and of the encryption of the packets.
+    fn packet_overhead(&self) -> u32 {
+        #[allow(unused_mut)]
+        let mut overhead = match self.peer_addr().map(|a| a.ip()) {
+            Some(IpAddr::V6(_)) => 40,
+            _ => 28,
+        };
+        #[cfg(feature = "encryption")]
+        if self.configuration.read().unwrap().encryption_key.is_some() {
+            overhead += crypto::SEAL_OVERHEAD as u32;
+        }
+        overhead
+    }
+
+    /// Sizes the data packets once the maximum packet size is negotiated,
+    /// and starts path MTU discovery if enabled.
+    fn init_path_mtu(&self) {
+        let (enabled, mss) = {
+            let configuration = self.configuration.read().unwrap();
+            (configuration.path_mtu_discovery, configuration.mss)
+        };
+        if enabled {
+            self.state().path_mtu = Some(PathMtu::new(mss));
+        }
+        let payload_size = self.snd_payload_size();
+        self.snd_buffer
+            .lock()
+            .unwrap()
+            .set_payload_size(payload_size as usize);
+    }
+
+    /// Size of the data packets sent, including the IP and UDP headers.
+    pub(crate) fn packet_size(&self) -> u32 {
+        let path_mtu = self.state().path_mtu.as_ref().map(PathMtu::size);
+        path_mtu.unwrap_or_else(|| self.configuration.read().unwrap().mss)
+    }
+
+    /// Extension blocks sent in the handshake response, repeated if the request is.
+    pub(crate) fn handshake_extensions(&self) -> Vec<(u16, Bytes)> {
+        self.state().handshake_extensions.clone()
+    }
+
+    /// Value of the extension block of type `ext_type` received from the peer in the handshake.
+    pub(crate) fn peer_handshake_extension(&self, ext_type: u16) -> Option<Bytes> {
+        self.state()
+            .peer_handshake_extensions
+            .iter()
+            .find(|(block_type, _)| *block_type == ext_type)
+            .map(|(_, value)| value.clone())
+    }
+
+    pub(crate) fn negotiated_params(&self) -> UdtNegotiatedParams {
+        let udt_version = self.state().udt_version;
         let configuration = self.configuration.read().unwrap();
-        match self.peer_addr().map(|a| a.ip()) {
-            Some(IpAddr::V6(_)) => configuration.mss - 40 - UDT_DATA_HEADER_SIZE as u32,
-            _ => configuration.mss - 28 - UDT_DATA_HEADER_SIZE as u32,
+        UdtNegotiatedParams {
+            mss: configuration.mss,
+            max_window_size: configuration.flight_flag_size,
+            udt_version,
+        }
+    }
+
+    /// Sends a feedback packet (ACK, NAK, keep-alive...) to the peer,
+    /// ahead of the data packets waiting to be sent by the multiplexer.
+    fn send_feedback(&self, packet: UdtPacket) {
+        let (Some(mux), Some(addr)) = (self.multiplexer(), self.peer_addr()) else {
+            return;
+        };
+        match self.seal(packet) {
+            Ok(packet) => {
+                mux.snd_queue
+                    .push_control(self.with_flow_label(&addr), packet, self.self_ip())
+            }
+            Err(err) => self.break_with(err),
         }
     }
 
@@ -775,12 +1967,52 @@ impl UdtSocket {
         Ok(())
     }
 
-    pub(crate) async fn send_data_packets(&self, packets: Vec<UdtDataPacket>) -> Result<()> {
+    /// Sets the configured flow label on an IPv6 destination address.
+    fn with_flow_label(&self, addr: &SocketAddr) -> SocketAddr {
+        let label = match self.configuration.read().unwrap().ipv6_flow_label {
+            Some(Ipv6FlowLabel::FromSocketId) => self.socket_id.wrapping_mul(0x9E37_79B9) >> 12,
+            Some(Ipv6FlowLabel::Fixed(label)) => label,
+            None => return *addr,
+        } & FLOW_LABEL_MASK;
+        match addr {
+            SocketAddr::V6(addr) if label != 0 => {
+                let mut addr = *addr;
+                // sin6_flowinfo is in network byte order
+                addr.set_flowinfo(label.to_be());
+                addr.into()
+            }
+            _ => *addr,
+        }
+    }
+
+    /// Sends and removes the `packets` of a batch.
+    pub(crate) async fn send_data_packets(&self, packets: &mut Vec<UdtDataPacket>) -> Result<()> {
         if let Some(addr) = self.peer_addr() {
-            self.multiplexer()
-                .expect("multiplexer not initialized")
-                .send_mmsg_to(&addr, packets.into_iter().map(|p| p.into()))
+            {
+                let mut state = self.state();
+                state.watchdog.on_sent(packets.len());
+                state.stats.pkt_sent += packets.len() as u64;
+                state.stats.bytes_sent += packets.iter().map(|p| p.data.len() as u64).sum::<u64>();
+            }
+            trace_event!(
+                trace,
+                socket_id = self.socket_id,
+                count = packets.len(),
+                "sending data packets"
+            );
+            // Packets are sealed as they are serialized, up to the first failure
+            let mut sealed = Ok(());
+            let packets = packets.drain(..).map_while(|p| match self.seal(p.into()) {
+                Ok(packet) => Some(packet),
+                Err(err) => {
+                    sealed = Err(err);
+                    None
+                }
+            });
+            self.require_multiplexer()?
+                .send_mmsg_to(&self.with_flow_label(&addr), packets, self.self_ip())
                 .await?;
+            sealed?;
         }
         Ok(())
     }
@@ -788,13 +2020,7 @@ impl UdtSocket {
     async fn send_ack(&self, light: bool) -> Result<()> {
         let seq_number = {
             let state = self.state();
-            let seq_number = match state
-                .rcv_loss_list
-                .peek_after(state.curr_rcv_seq_number + 1)
-            {
-                Some(num) => num,
-                None => state.curr_rcv_seq_number + 1,
-            };
+            let seq_number = state.ack_point();
             if seq_number == state.last_ack2_received {
                 return Ok(());
             }
@@ -809,7 +2035,8 @@ impl UdtSocket {
                 self.peer_socket_id().unwrap(),
                 None,
             );
-            self.send_packet(ack_packet.into()).await?;
+            self.send_feedback(ack_packet.into());
+            self.state().stats.ack_sent += 1;
             return Ok(());
         }
 
@@ -820,7 +2047,7 @@ impl UdtSocket {
                 Ordering::Greater => {
                     self.rcv_buffer().ack_data(seq_number);
                     state.last_sent_ack = seq_number;
-                    self.rcv_notify.notify_waiters();
+                    self.notify_readers();
                 }
                 Ordering::Equal => {
                     let last_sent_ack_elapsed = state.last_sent_ack_time.elapsed();
@@ -840,16 +2067,15 @@ impl UdtSocket {
             let mut state = self.state();
             if (state.last_sent_ack - state.last_ack2_received) > 0 {
                 state.last_ack_seq_number = state.last_ack_seq_number + 1;
+                let last_sent_ack = state.last_sent_ack;
                 drop(state);
+                let available_buf_size = self.advertised_window(last_sent_ack);
                 let mut ack_info = {
                     let flow = self.flow.read().unwrap();
                     AckOptionalInfo {
                         rtt: flow.rtt.as_micros().try_into().unwrap_or(u32::MAX),
                         rtt_variance: flow.rtt_var.as_micros().try_into().unwrap_or(u32::MAX),
-                        available_buf_size: std::cmp::max(
-                            self.rcv_buffer().get_available_buf_size(),
-                            2,
-                        ),
+                        available_buf_size,
                         pack_recv_rate: 0,
                         link_capacity: 0,
                     }
@@ -873,8 +2099,9 @@ impl UdtSocket {
         };
 
         if let Some(ack_packet) = ack_packet {
-            self.send_packet(ack_packet.into()).await?;
+            self.send_feedback(ack_packet.into());
             let mut state = self.state();
+            state.stats.ack_sent += 1;
             let last_sent_ack = state.last_sent_ack;
             let last_ack_seq_number = state.last_ack_seq_number;
             state.ack_window.store(last_sent_ack, last_ack_seq_number);
@@ -883,14 +2110,72 @@ impl UdtSocket {
         Ok(())
     }
 
+    /// Flow window advertised by a full ACK of `ack`: the free space of the receive buffer,
+    /// down to zero when the application stops reading, which stalls the sender.
+    fn advertised_window(&self, ack: SeqNumber) -> u32 {
+        let available = self.rcv_buffer().get_available_buf_size();
+        self.state().rcv_window_edge = Some(ack + available as i32);
+        available
+    }
+
+    /// Runs `read` on the receive buffer, then tells the sender once the reads
+    /// opened the window by half the buffer, instead of waiting for the next ACK
+    /// or stall probe: the sender may be blocked, with no packet to acknowledge.
+    fn read_rcv_buffer<T>(&self, read: impl FnOnce(&mut RcvBuffer) -> T) -> T {
+        let (res, available) = {
+            let mut rcv_buffer = self.rcv_buffer();
+            let res = read(&mut rcv_buffer);
+            (res, rcv_buffer.get_available_buf_size())
+        };
+        let (ack_seq, last_sent_ack) = {
+            let mut state = self.state();
+            // With data left to acknowledge, the next regular ACK is close
+            if state.ack_point() != state.last_sent_ack {
+                return res;
+            }
+            let half_buffer = self.configuration.read().unwrap().rcv_buf_size / 2;
+            match state.rcv_window_edge {
+                Some(edge)
+                    if (state.last_sent_ack + available as i32) - edge
+                        >= half_buffer.max(1) as i32 => {}
+                _ => return res,
+            }
+            state.last_ack_seq_number = state.last_ack_seq_number + 1;
+            (state.last_ack_seq_number, state.last_sent_ack)
+        };
+        if let Some(ack_packet) = self.challenge_ack(ack_seq, last_sent_ack) {
+            self.send_feedback(ack_packet.into());
+            let mut state = self.state();
+            state.stats.ack_sent += 1;
+            state.ack_window.store(last_sent_ack, ack_seq);
+        }
+        res
+    }
+
     fn cc_update(&self) {
+        let period = self.rate_control.read().unwrap().get_pkt_send_period();
         let mut state = self.state();
-        state.interpacket_interval = self.rate_control.read().unwrap().get_pkt_send_period();
+        #[cfg(feature = "cc-trace")]
+        let rate_changed = state.interpacket_interval != period;
+        state.interpacket_interval = period;
+        drop(state);
+        #[cfg(feature = "cc-trace")]
+        if rate_changed {
+            self.trace_cc(CcTraceEvent::RateChange);
+        }
     }
 
     pub(crate) async fn check_timers(&self) {
         self.cc_update();
+        self.check_rcv_latency();
+        self.check_live_latency();
+        self.check_peer_stall();
+        self.check_alarms();
         let now = Instant::now();
+        self.check_metrics(now);
+        self.check_migration_challenge(now).await;
+        self.check_rendezvous_reply(now).await;
+        self.check_path_mtu(now).await;
 
         let ack_interval = self.rate_control.read().unwrap().get_ack_pkt_interval();
         if now > self.state().next_ack_time
@@ -917,6 +2202,10 @@ impl UdtSocket {
             }
         }
 
+        self.check_msg_reassembly_timeout();
+        self.check_keep_alive(now);
+        self.check_peer_idle();
+
         let next_exp_time = {
             let (rtt, rtt_var) = {
                 let flow = self.flow.read().unwrap();
@@ -928,26 +2217,40 @@ impl UdtSocket {
             state.last_rsp_time + next_exp
         };
         if now > next_exp_time {
-            {
+            // The client of a resumable session may come back later
+            let resumption_window = self
+                .resumption_window()
+                .filter(|_| self.listen_socket.is_some());
+            let (peer_idle_timeout, max_exp_count) = {
+                let configuration = self.configuration.read().unwrap();
+                let peer_idle_timeout = match resumption_window {
+                    Some(window) => configuration.peer_idle_timeout.max(window),
+                    None => configuration.peer_idle_timeout,
+                };
+                (peer_idle_timeout, configuration.max_exp_count)
+            };
+            let (exp_count, idle) = {
                 let state = self.state();
-                if state.exp_count > 16 && state.last_rsp_time.elapsed() > Duration::from_secs(5) {
-                    // Connection is broken
-                    *self.status.lock().unwrap() = UdtStatus::Broken;
-                    self.update_snd_queue(true);
-                    return;
-                }
+                (state.exp_count, state.last_peer_activity.elapsed())
+            };
+            if exp_count > max_exp_count && idle > peer_idle_timeout {
+                self.break_with(Error::new(
+                    ErrorKind::TimedOut,
+                    UdtError::new(
+                        UdtErrorCode::ConnLost,
+                        format!(
+                            "no response from peer for {:?} ({} expirations)",
+                            idle,
+                            exp_count - 1
+                        ),
+                    ),
+                ));
+                self.update_snd_queue(true);
+                return;
             }
 
-            if self.snd_buffer.lock().unwrap().is_empty() {
-                if let Some(peer_socket_id) = self.peer_socket_id() {
-                    let keep_alive = UdtControlPacket::new_keep_alive(peer_socket_id);
-                    self.send_packet(keep_alive.into())
-                        .await
-                        .unwrap_or_else(|err| {
-                            eprintln!("failed to send keep alive: {:?}", err);
-                        });
-                }
-            } else {
+            // Keep-alives of an idle connection are sent by `check_keep_alive`
+            if !self.snd_buffer.lock().unwrap().is_empty() {
                 {
                     let mut state = self.state();
                     if (state.last_ack_received != state.curr_snd_seq_number + 1)
@@ -961,8 +2264,16 @@ impl UdtSocket {
                     }
                 }
 
+                trace_event!(
+                    debug,
+                    socket_id = self.socket_id,
+                    exp_count = self.state().exp_count,
+                    "retransmission timeout"
+                );
                 self.rate_control.write().unwrap().on_timeout();
                 self.cc_update();
+                #[cfg(feature = "cc-trace")]
+                self.trace_cc(CcTraceEvent::Timeout);
                 self.update_snd_queue(true);
             }
 
@@ -973,27 +2284,284 @@ impl UdtSocket {
         }
     }
 
-    fn update_snd_queue(&self, reschedule: bool) {
-        if let Some(mux) = self.multiplexer() {
-            mux.snd_queue.update(self.socket_id, reschedule);
-        }
-    }
-
-    pub fn send(&self, data: &[u8]) -> Result<()> {
-        if self.socket_type != SocketType::Stream {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "socket needs to be configured in stream mode to send data buffer",
+    fn check_keep_alive(&self, now: Instant) {
+        let (interval, idle_only, max_unanswered) = {
+            let configuration = self.configuration.read().unwrap();
+            match configuration.keep_alive_interval {
+                Some(interval) => (
+                    interval,
+                    configuration.keep_alive_idle_only,
+                    configuration.keep_alive_max_unanswered,
+                ),
+                None => return,
+            }
+        };
+        let unanswered = {
+            let mut state = self.state();
+            if now < state.next_keep_alive_time {
+                return;
+            }
+            state.next_keep_alive_time = now + interval;
+            if idle_only && !self.snd_buffer.lock().unwrap().is_empty() {
+                return;
+            }
+            state.unanswered_keep_alives += 1;
+            state.unanswered_keep_alives
+        };
+        if max_unanswered.is_some_and(|max| unanswered > max) {
+            self.break_with(Error::new(
+                ErrorKind::TimedOut,
+                UdtError::new(
+                    UdtErrorCode::ConnLost,
+                    format!("peer did not answer {} keep-alives", unanswered - 1),
+                ),
             ));
+            return;
+        }
+        if let Some(peer_socket_id) = self.peer_socket_id() {
+            let keep_alive = UdtControlPacket::new_keep_alive(peer_socket_id);
+            self.send_feedback(keep_alive.into());
+        }
+    }
+
+    fn check_peer_idle(&self) {
+        let peer_idle_timeout = self.configuration.read().unwrap().peer_idle_timeout;
+        let idle = {
+            let mut state = self.state();
+            let idle = state.last_peer_activity.elapsed();
+            if state.peer_idle || idle <= peer_idle_timeout {
+                return;
+            }
+            state.peer_idle = true;
+            idle
+        };
+        trace_event!(
+            debug,
+            socket_id = self.socket_id,
+            idle_ms = idle.as_millis(),
+            "peer idle"
+        );
+        self.emit_event(UdtConnectionEvent::PeerIdle(idle));
+    }
+
+    fn check_peer_stall(&self) {
+        let mut state = self.state();
+        let now = Instant::now();
+        if state.peer_stalled && now >= state.next_stall_probe {
+            state.stall_probe = true;
+            state.next_stall_probe = now + STALL_PROBE_INTERVAL;
+            drop(state);
+            self.update_snd_queue(true);
+        }
+    }
+
+    fn check_metrics(&self, now: Instant) {
+        let (sink, interval) = {
+            let configuration = self.configuration.read().unwrap();
+            match &configuration.metrics_sink {
+                Some(sink) => (sink.clone(), configuration.metrics_interval),
+                None => return,
+            }
+        };
+        {
+            let mut state = self.state();
+            if now < state.next_metrics_time {
+                return;
+            }
+            state.next_metrics_time = now + interval;
+        }
+        metrics::report(
+            sink.as_ref(),
+            self.socket_id,
+            self.label().as_deref(),
+            &self.stats(),
+        );
+    }
+
+    fn check_alarms(&self) {
+        let thresholds = match &self.configuration.read().unwrap().alarms {
+            Some(thresholds) => thresholds.clone(),
+            None => return,
+        };
+        let rtt = self.flow.read().unwrap().rtt;
+        let sending = !self.snd_buffer.lock().unwrap().is_empty();
+        let events = self
+            .state()
+            .watchdog
+            .check(&thresholds, rtt, sending, Instant::now());
+        for event in events {
+            self.emit_event(event);
+        }
+    }
+
+    /// Gives up the losses detected more than `rcv_latency` ago, oldest first.
+    /// Losses already recovered are forgotten as soon as they reach the front of the queue,
+    /// so the queue stays proportional to the losses still pending.
+    fn check_rcv_latency(&self) {
+        let latency = match self.configuration.read().unwrap().rcv_latency {
+            Some(latency) => latency,
+            None => return,
+        };
+        let mut state = self.state();
+        let mut dropped = 0;
+        while let Some(&(first, last, detected)) = state.rcv_loss_times.front() {
+            let lost = state.rcv_loss_list.count(first, last);
+            if lost > 0 && detected.elapsed() <= latency {
+                break;
+            }
+            state.rcv_loss_times.pop_front();
+            if lost == 0 {
+                continue;
+            }
+            dropped += lost;
+            // The next ACK skips the range: the sender stops retransmitting it
+            // and the data after the gap becomes readable.
+            state.rcv_loss_list.remove_all(first, last);
+        }
+        if dropped > 0 {
+            state.stats.rcv_too_late_drops += dropped as u64;
+            state.next_ack_time = Instant::now();
+        }
+    }
+
+    /// In live mode, gives up the lost packets once the packet received after them is due,
+    /// oldest first: waiting longer for them would hold back the whole stream.
+    fn check_live_latency(&self) {
+        if self.configuration.read().unwrap().live_latency.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        // Locks the receive buffer while holding the state, the required order
+        let mut state = self.state();
+        let mut dropped = 0;
+        loop {
+            let ack_point = state.ack_point();
+            if !state.rcv_loss_list.contains(ack_point)
+                || !self.rcv_buffer().is_due_after(ack_point, now)
+            {
+                break;
+            }
+            state.rcv_loss_list.remove(ack_point);
+            dropped += 1;
+        }
+        if dropped > 0 {
+            state.stats.rcv_too_late_drops += dropped;
+            state.next_ack_time = now;
+        }
+    }
+
+    fn check_msg_reassembly_timeout(&self) {
+        if self.socket_type != SocketType::Datagram {
+            return;
+        }
+        let timeout = match self.configuration.read().unwrap().msg_reassembly_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let dropped = self.rcv_buffer().drop_incomplete_msgs(timeout);
+        if dropped.is_empty() {
+            return;
+        }
+        let mut state = self.state();
+        state.stats.rcv_msg_reassembly_timeouts += dropped.len() as u64;
+        for (first, last) in dropped {
+            // Missing fragments should not be reported as lost anymore
+            state.rcv_loss_list.remove_all(first, last);
+        }
+    }
+
+    fn update_snd_queue(&self, reschedule: bool) {
+        if let Some(mux) = self.multiplexer() {
+            mux.snd_queue.update(self.socket_id, reschedule);
+        }
+    }
+
+    pub fn send(&self, data: &[u8]) -> Result<()> {
+        self.send_with_deadline(data, None)
+    }
+
+    /// Sends `data`, which is removed from the send buffer if none of it
+    /// could be sent before `deadline`.
+    pub(crate) fn send_with_deadline(&self, data: &[u8], deadline: Option<Instant>) -> Result<()> {
+        self.enqueue(SocketType::Stream, data.len(), |snd_buffer| {
+            snd_buffer.add_message(data, None, deadline, false)
+        })
+    }
+
+    /// Sends `data` without copying it: the send buffer keeps references to the payload.
+    pub(crate) fn send_bytes(&self, data: Bytes) -> Result<()> {
+        self.enqueue(SocketType::Stream, data.len(), |snd_buffer| {
+            snd_buffer.add_message_bytes(data, None, None, false)
+        })
+    }
+
+    /// Sends the concatenation of `bufs`, copied once into the send buffer.
+    pub(crate) fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        self.enqueue(SocketType::Stream, len, |snd_buffer| {
+            snd_buffer.add_message_vectored(bufs)
+        })?;
+        Ok(len)
+    }
+
+    /// Sends `data` as a single message on a datagram socket.
+    pub(crate) fn send_msg(
+        &self,
+        data: &[u8],
+        ttl: Option<Duration>,
+        in_order: bool,
+    ) -> Result<()> {
+        let ttl = ttl.map(|ttl| ttl.as_millis() as u64);
+        self.enqueue(SocketType::Datagram, data.len(), |snd_buffer| {
+            snd_buffer.add_message(data, ttl, None, in_order)
+        })
+    }
+
+    /// Sends `data` as a single in-order message on a datagram socket, without copying it.
+    pub(crate) fn send_msg_bytes(&self, data: Bytes) -> Result<()> {
+        self.enqueue(SocketType::Datagram, data.len(), |snd_buffer| {
+            snd_buffer.add_message_bytes(data, None, None, true)
+        })
+    }
+
+    fn enqueue(
+        &self,
+        socket_type: SocketType,
+        len: usize,
+        add: impl FnOnce(&mut SndBuffer) -> Result<()>,
+    ) -> Result<()> {
+        if self.socket_type != socket_type {
+            return Err(match self.socket_type {
+                SocketType::Stream => Error::new(
+                    ErrorKind::InvalidInput,
+                    UdtError::new(
+                        UdtErrorCode::StreamIll,
+                        "socket needs to be configured in datagram mode to send messages",
+                    ),
+                ),
+                SocketType::Datagram => Error::new(
+                    ErrorKind::InvalidInput,
+                    UdtError::new(
+                        UdtErrorCode::DgramIll,
+                        "socket needs to be configured in stream mode to send data buffer",
+                    ),
+                ),
+            });
         }
         if self.status() != UdtStatus::Connected {
             return Err(Error::new(
                 ErrorKind::NotConnected,
-                "UDT socket is not connected",
+                UdtError::new(UdtErrorCode::NoConn, "UDT socket is not connected"),
+            ));
+        }
+        if self.state().snd_shutdown {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                UdtError::new(UdtErrorCode::InvOp, "sending side was shut down"),
             ));
         }
 
-        if data.is_empty() {
+        if len == 0 {
             return Ok(());
         }
 
@@ -1002,84 +2570,118 @@ impl UdtSocket {
             self.state().last_rsp_time = Instant::now();
         }
 
-        self.snd_buffer
-            .lock()
-            .unwrap()
-            .add_message(data, None, false)?;
+        add(&mut self.snd_buffer.lock().unwrap())?;
         self.update_snd_queue(false);
         Ok(())
     }
 
     pub async fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+        self.recv_buf(&mut ReadBuf::new(buf)).await
+    }
+
+    /// Receives data into `buf`, until it is full or no data is left to read.
+    pub(crate) async fn recv_buf(&self, buf: &mut impl BufMut) -> Result<usize> {
+        self.check_readable()?;
+        if !buf.has_remaining_mut() {
+            return Ok(0);
+        }
+
+        loop {
+            self.wait_for_data_to_read().await;
+            self.check_readable()?;
+
+            // Nothing is read if the timer of live mode woke up before the data was due
+            let (written, eof) = self
+                .read_rcv_buffer(|rcv_buffer| (rcv_buffer.read_buffer(buf), rcv_buffer.is_eof()));
+            if written > 0 || eof {
+                // TODO: handle UDT timeout
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Receives the payload of the next packet, without copying it.
+    /// An empty payload is the end of the stream.
+    pub(crate) async fn recv_bytes(&self) -> Result<Bytes> {
+        loop {
+            self.check_readable()?;
+            if let Some(data) = self.read_rcv_buffer(RcvBuffer::read_packet) {
+                return Ok(data);
+            }
+            self.wait_for_data_to_read().await;
+        }
+    }
+
+    /// Checks that stream data can be read: the socket is connected,
+    /// or it is broken but data is left in the receive buffer.
+    fn check_readable(&self) -> Result<()> {
         if self.socket_type != SocketType::Stream {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
-                "cannot recv on non-stream socket",
+                UdtError::new(UdtErrorCode::DgramIll, "cannot recv on non-stream socket"),
             ));
         }
         let status = self.status();
         if !status.is_alive() {
             if !self.rcv_buffer().has_data_to_read() {
-                return Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    "connection was closed or broken",
-                ));
+                return Err(self.broken_error());
             }
         } else if status != UdtStatus::Connected {
             return Err(Error::new(
                 ErrorKind::NotConnected,
-                "UDT socket not connected",
+                UdtError::new(UdtErrorCode::NoConn, "UDT socket not connected"),
             ));
         }
+        Ok(())
+    }
 
-        if buf.is_empty() {
-            return Ok(0);
-        }
-
-        self.wait_for_data_to_read().await;
-
-        let status = self.status();
-        if !status.is_alive() {
-            if !self.rcv_buffer().has_data_to_read() {
+    /// Receives the next complete message, i.e. the data of a single send call of the peer.
+    pub(crate) async fn recv_msg(&self, buf: &mut [u8]) -> Result<UdtMessageInfo> {
+        loop {
+            let notified = self.rcv_notify.notified();
+            if let Some(res) = self.read_rcv_buffer(|rcv_buffer| rcv_buffer.read_msg(buf)) {
+                return res;
+            }
+            let status = self.status();
+            if !status.is_alive() {
+                return Err(self.broken_error());
+            } else if status != UdtStatus::Connected {
                 return Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    "connection was closed or broken",
+                    ErrorKind::NotConnected,
+                    UdtError::new(UdtErrorCode::NoConn, "UDT socket not connected"),
                 ));
             }
-        } else if status != UdtStatus::Connected {
-            return Err(Error::new(
-                ErrorKind::NotConnected,
-                "UDT socket not connected",
-            ));
+            self.wait_for_delivery(notified).await;
         }
-
-        let mut buf = ReadBuf::new(buf);
-        let written = self.rcv_buffer().read_buffer(&mut buf);
-
-        // TODO: handle UDT timeout
-        Ok(written)
     }
 
-    pub(crate) fn poll_recv(&self, buf: &mut ReadBuf<'_>) -> Poll<Result<usize>> {
-        if self.socket_type != SocketType::Stream {
-            return Poll::Ready(Err(Error::new(
-                ErrorKind::InvalidInput,
-                "cannot recv on non-stream socket",
-            )));
+    /// Polls for the next complete message.
+    /// Returns `None` once the connection is closed or broken and no message is left.
+    pub(crate) fn poll_recv_msg_bytes(&self, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
+        if let Some(data) = self.read_rcv_buffer(RcvBuffer::read_msg_bytes) {
+            return Poll::Ready(Some(Ok(data)));
         }
         let status = self.status();
         if !status.is_alive() {
-            if !self.rcv_buffer().has_data_to_read() {
-                return Poll::Ready(Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    "connection was closed or broken",
-                )));
-            }
+            return Poll::Ready(None);
         } else if status != UdtStatus::Connected {
-            return Poll::Ready(Err(Error::new(
+            return Poll::Ready(Some(Err(Error::new(
                 ErrorKind::NotConnected,
-                "UDT socket not connected",
-            )));
+                UdtError::new(UdtErrorCode::NoConn, "UDT socket not connected"),
+            ))));
+        }
+        self.rcv_wakers.register(cx.waker());
+        self.arm_delivery_timer();
+        // A message may have been completed before the waker was registered
+        match self.read_rcv_buffer(RcvBuffer::read_msg_bytes) {
+            Some(data) => Poll::Ready(Some(Ok(data))),
+            None => Poll::Pending,
+        }
+    }
+
+    pub(crate) fn poll_recv(&self, buf: &mut ReadBuf<'_>) -> Poll<Result<usize>> {
+        if let Err(err) = self.check_readable() {
+            return Poll::Ready(Err(err));
         }
 
         if !self.rcv_buffer().has_data_to_read() {
@@ -1089,7 +2691,7 @@ impl UdtSocket {
         if buf.remaining() == 0 {
             return Poll::Ready(Ok(0));
         }
-        let written = self.rcv_buffer().read_buffer(buf);
+        let written = self.read_rcv_buffer(|rcv_buffer| rcv_buffer.read_buffer(buf));
         Poll::Ready(Ok(written))
     }
 
@@ -1101,29 +2703,80 @@ impl UdtSocket {
         if self.status() != UdtStatus::Init {
             return Err(Error::new(
                 ErrorKind::Unsupported,
-                format!("expected status Init, found {:?}", self.status),
+                UdtError::new(
+                    UdtErrorCode::ConnSock,
+                    format!("expected status Init, found {:?}", self.status),
+                ),
+            ));
+        }
+
+        if let SocketAddr::V6(addr) = addr {
+            if addr.scope_id() == 0 && is_ipv6_link_local(addr.ip()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    UdtError::new(
+                        UdtErrorCode::InvParam,
+                        "link-local IPv6 address requires a scope id",
+                    ),
+                ));
+            }
+        }
+
+        let rendezvous = self.configuration.read().unwrap().rendezvous;
+        if rendezvous && bind_addr.is_none() && self.multiplexer().is_none() {
+            // The peer sends its handshakes to a port it must know beforehand
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                UdtError::new(
+                    UdtErrorCode::RdvUnbound,
+                    "rendezvous connection setup requires a bound socket",
+                ),
             ));
         }
 
         self.open();
-        {
+        if self.multiplexer().is_none() {
+            // An IPv6 peer cannot be reached from the default IPv4 UDP socket
+            let bind_addr =
+                bind_addr.or_else(|| addr.is_ipv6().then(|| (Ipv6Addr::UNSPECIFIED, 0).into()));
             let mut udt = Udt::get().write().await;
             udt.update_mux(self, bind_addr).await?;
         }
+        let addr = match (addr, self.multiplexer()) {
+            // IPv4 peers are reached through IPv4-mapped addresses from a dual-stack socket
+            (SocketAddr::V4(v4), Some(mux))
+                if mux.channel.local_addr().is_ok_and(|local| local.is_ipv6()) =>
+            {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            _ => addr,
+        };
 
         *self.status.lock().unwrap() = UdtStatus::Connecting;
         self.set_peer_addr(addr);
 
-        // TODO: use rendezvous queue?
+        if rendezvous {
+            let mux = self.require_multiplexer()?;
+            mux.rendezvous.lock().unwrap().insert(addr, self.socket_id);
+            trace_event!(debug, socket_id = self.socket_id, peer = %addr, "sending rendezvous handshake");
+            return self.send_rendezvous_handshake().await;
+        }
+
+        trace_event!(debug, socket_id = self.socket_id, peer = %addr, "sending handshake");
+        self.send_connection_request(addr).await
+    }
 
+    /// Sends the first handshake of a regular connection setup, answered with a cookie.
+    async fn send_connection_request(&self, addr: SocketAddr) -> Result<()> {
         let hs_packet = {
             let configuration = self.configuration.read().unwrap();
             let hs = HandShakeInfo {
-                udt_version: UdtConfiguration::udt_version(),
+                // Any UDT4 responder answers it, advertising its own version
+                udt_version: UDT4_VERSION,
                 initial_seq_number: self.initial_seq_number,
                 max_packet_size: configuration.mss,
                 max_window_size: std::cmp::min(
-                    self.flow.read().unwrap().flow_window_size,
+                    configuration.flight_flag_size,
                     self.rcv_buffer().get_available_buf_size(),
                 ),
                 connection_type: 1,
@@ -1131,54 +2784,167 @@ impl UdtSocket {
                 socket_id: self.socket_id,
                 ip_address: addr.ip(),
                 syn_cookie: 0,
+                encryption_proof: None,
+                encryption_salt: None,
+                extensions: vec![],
             };
             UdtControlPacket::new_handshake(hs, 0)
         };
-        self.send_to(&addr, hs_packet.into()).await?;
-
-        Ok(())
+        self.send_to(&addr, hs_packet.into()).await
     }
 
     pub fn status(&self) -> UdtStatus {
         *self.status.lock().unwrap()
     }
 
+    pub(crate) fn stats(&self) -> UdtStatistics {
+        let (interpacket_interval, mut stats) = {
+            let state = self.state();
+            (state.interpacket_interval, state.stats.clone())
+        };
+        {
+            let flow = self.flow.read().unwrap();
+            stats.rtt = flow.rtt;
+            stats.rtt_var = flow.rtt_var;
+            stats.bandwidth = flow.peer_bandwidth;
+            stats.rcv_rate = flow.get_pkt_rcv_speed();
+        }
+        stats.snd_rate = (Duration::from_secs(1).as_nanos()
+            / interpacket_interval.as_nanos().max(1))
+        .try_into()
+        .unwrap_or(u64::MAX);
+        stats.snd_buffer_packets = self.snd_buffer.lock().unwrap().len();
+        stats.rcv_buffer_packets = self.rcv_buffer().len();
+        stats.label = self.label();
+        stats
+    }
+
+    /// Runs `work` done by a queue worker for the socket, in the span of the socket, and
+    /// adds the time spent processing it to the statistics, whether it fails or not.
+    /// Only the time spent in polls counts, not the time waiting for locks or I/O.
+    pub(crate) async fn do_work<F: Future>(&self, kind: SocketWork, work: F) -> F::Output {
+        let mut work = std::pin::pin!(self.in_span(work));
+        let mut elapsed = Duration::ZERO;
+        let output = std::future::poll_fn(|cx| {
+            let start = std::time::Instant::now();
+            let poll = work.as_mut().poll(cx);
+            elapsed += start.elapsed();
+            poll
+        })
+        .await;
+        self.add_work_time(kind, elapsed);
+        output
+    }
+
+    /// Runs `work` done for the socket in its span, which identifies it
+    /// in the events of the `tracing` feature.
+    fn in_span<F: Future>(&self, work: F) -> impl Future<Output = F::Output> {
+        #[cfg(feature = "tracing")]
+        let work = tracing::Instrument::instrument(work, self.span.clone());
+        work
+    }
+
+    /// Runs the connection setup `work` with `_peer` in a `udt_handshake` span,
+    /// within the span of the socket.
+    pub(crate) fn in_handshake_span<F: Future>(
+        &self,
+        _peer: SocketAddr,
+        work: F,
+    ) -> impl Future<Output = F::Output> {
+        #[cfg(feature = "tracing")]
+        let work = tracing::Instrument::instrument(
+            work,
+            tracing::debug_span!(parent: &self.span, "udt_handshake", peer = %_peer),
+        );
+        work
+    }
+
+    fn add_work_time(&self, work: SocketWork, elapsed: Duration) {
+        let stats = &mut self.state().stats;
+        match work {
+            SocketWork::Send => stats.snd_work_time += elapsed,
+            SocketWork::Receive => stats.rcv_work_time += elapsed,
+            SocketWork::Timers => stats.timers_work_time += elapsed,
+        }
+    }
+
+    pub(crate) fn last_activity(&self) -> Instant {
+        self.state().last_peer_activity
+    }
+
+    /// Shuts down the sending side of a stream socket: once the data sent before
+    /// is read, the reads of the peer return the end of the stream, and it can still
+    /// send data to this socket. Returns `false` if the peer does not implement it (UDT4).
+    pub(crate) fn shutdown_snd(&self) -> bool {
+        if self.socket_type != SocketType::Stream || self.status() != UdtStatus::Connected {
+            return false;
+        }
+        {
+            let mut state = self.state();
+            if state.udt_version <= UDT4_VERSION {
+                return false;
+            }
+            if state.snd_shutdown {
+                return true;
+            }
+            state.snd_shutdown = true;
+        }
+        trace_event!(debug, socket_id = self.socket_id, "sending side shut down");
+        self.snd_buffer.lock().unwrap().add_eof();
+        self.update_snd_queue(false);
+        true
+    }
+
+    pub(crate) fn is_snd_shutdown(&self) -> bool {
+        self.state().snd_shutdown
+    }
+
+    pub(crate) fn snd_buffer_room(&self) -> usize {
+        self.snd_buffer.lock().unwrap().room()
+    }
+
     pub fn snd_buffer_is_empty(&self) -> bool {
         self.snd_buffer.lock().unwrap().is_empty()
     }
 
     pub async fn close(&self) {
+        let linger = self.configuration.read().unwrap().linger_timeout;
+        self.close_with_linger(Duration::from_secs(linger.unwrap_or(0).into()))
+            .await;
+    }
+
+    /// Closes the socket, first waiting up to `linger` for the data in the send buffer
+    /// to be acknowledged by the peer.
+    pub(crate) async fn close_with_linger(&self, linger: Duration) {
         let status = self.status();
         if status == UdtStatus::Closed || status == UdtStatus::Closing {
             return;
         }
-        let now = Instant::now();
-        let linger_timeout = Duration::from_secs(
-            self.configuration
-                .read()
-                .unwrap()
-                .linger_timeout
-                .unwrap_or(0)
-                .into(),
-        );
+        let deadline = Instant::now() + linger;
 
-        while self.status() == UdtStatus::Connected
-            && !self.snd_buffer_is_empty()
-            && now.elapsed() < linger_timeout
-        {
-            self.wait_for_next_ack_or_empty_snd_buffer().await;
+        while self.status() == UdtStatus::Connected && !self.snd_buffer_is_empty() {
+            // The peer may stop acknowledging: do not wait for the next ACK past the deadline
+            let ack = self.wait_for_next_ack_or_empty_snd_buffer();
+            if tokio::time::timeout_at(deadline, ack).await.is_err() {
+                break;
+            }
         }
 
         if let Some(mux) = self.multiplexer() {
             mux.snd_queue.remove(self.socket_id);
+            mux.rcv_queue.remove(self.socket_id);
             let listener_id = mux.listener.read().await.clone().map(|s| s.socket_id);
             if listener_id == Some(self.socket_id) {
                 *mux.listener.write().await = None;
             }
+            if let Some(peer) = self.peer_addr() {
+                let mut rendezvous = mux.rendezvous.lock().unwrap();
+                if rendezvous.get(&peer) == Some(&self.socket_id) {
+                    rendezvous.remove(&peer);
+                }
+            }
         }
 
-        // TODO: remove socket from rendez-vous queue
-
         if self.status() == UdtStatus::Connected {
             let shutdown = UdtControlPacket::new_shutdown(self.peer_socket_id().unwrap());
             self.send_packet(shutdown.into())
@@ -1189,14 +2955,268 @@ impl UdtSocket {
         }
 
         // TODO: keep channel stats in cache
+        trace_event!(info, socket_id = self.socket_id, "closed");
         *self.status.lock().unwrap() = UdtStatus::Closing;
         self.notify_all();
+        self.emit_event(UdtConnectionEvent::Closed);
+    }
+
+    /// Breaks the connection because of an error raised by a background task,
+    /// which is then reported by subsequent reads and to [`process::subscribe_errors`](crate::process::subscribe_errors).
+    pub(crate) fn break_with(&self, cause: Error) {
+        {
+            let mut status = self.status.lock().unwrap();
+            if !status.is_alive() {
+                return;
+            }
+            *status = UdtStatus::Broken;
+        }
+        trace_event!(warn, socket_id = self.socket_id, cause = %cause, "connection broken");
+        *self.broken_cause.lock().unwrap() = Some(cause.to_string());
+        self.notify_all();
+        self.emit_event(UdtConnectionEvent::Broken);
+        Udt::report_error(Some(self.socket_id), None, self.label(), cause);
+    }
+
+    /// Smoothed RTT and RTT variance, updated by each ACK/ACK2 exchange.
+    pub(crate) fn rtt(&self) -> (Duration, Duration) {
+        let flow = self.flow.read().unwrap();
+        (flow.rtt, flow.rtt_var)
+    }
+
+    pub(crate) fn label(&self) -> Option<String> {
+        self.configuration.read().unwrap().label.clone()
+    }
+
+    pub(crate) fn set_label(&self, label: Option<String>) {
+        #[cfg(feature = "tracing")]
+        self.span.record("label", label.as_deref());
+        self.configuration.write().unwrap().label = label;
+    }
+
+    pub(crate) fn max_bandwidth(&self) -> Option<u64> {
+        self.configuration.read().unwrap().max_bandwidth_bps
+    }
+
+    pub(crate) fn set_max_bandwidth(&self, bps: Option<u64>) {
+        self.configuration.write().unwrap().max_bandwidth_bps = bps;
+    }
+
+    pub(crate) fn snd_buf_size(&self) -> u32 {
+        self.configuration.read().unwrap().snd_buf_size
+    }
+
+    pub(crate) fn set_snd_buf_size(&self, packets: u32) {
+        self.configuration.write().unwrap().snd_buf_size = packets;
+        self.snd_buffer.lock().unwrap().set_max_size(packets);
+        self.notify_writers();
+    }
+
+    pub(crate) fn snd_syn(&self) -> bool {
+        self.configuration.read().unwrap().snd_syn
+    }
+
+    pub(crate) fn set_snd_syn(&self, snd_syn: bool) {
+        self.configuration.write().unwrap().snd_syn = snd_syn;
+    }
+
+    pub(crate) fn rcv_buf_size(&self) -> u32 {
+        self.configuration.read().unwrap().rcv_buf_size
+    }
+
+    pub(crate) fn set_rcv_buf_size(&self, packets: u32) {
+        self.configuration.write().unwrap().rcv_buf_size = packets;
+        self.rcv_buffer().set_max_size(packets);
+    }
+
+    /// Error reported when the connection broke before being set up, e.g. rejected by the peer.
+    pub(crate) fn connect_error(&self) -> Error {
+        let cause = self
+            .broken_cause
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(|| "connection setup failed".to_string());
+        Error::new(
+            ErrorKind::ConnectionRefused,
+            UdtError::new(UdtErrorCode::ConnRej, cause),
+        )
+    }
+
+    fn broken_error(&self) -> Error {
+        let message = match &*self.broken_cause.lock().unwrap() {
+            Some(cause) => format!("connection was broken: {}", cause),
+            None => "connection was closed or broken".to_string(),
+        };
+        Error::new(
+            ErrorKind::BrokenPipe,
+            UdtError::new(UdtErrorCode::ConnLost, message),
+        )
+    }
+
+    #[cfg(feature = "cc-trace")]
+    pub(crate) fn set_cc_trace(&self, sink: Option<Arc<dyn CcTraceSink>>) {
+        *self.cc_trace.write().unwrap() = sink.map(CcTraceSinkRef);
     }
 
-    fn notify_all(&self) {
+    #[cfg(feature = "cc-trace")]
+    fn trace_cc(&self, event: CcTraceEvent) {
+        let sink = self.cc_trace.read().unwrap().clone();
+        if let Some(sink) = sink {
+            let entry = {
+                let rate_control = self.rate_control.read().unwrap();
+                CcTraceEntry {
+                    socket_id: self.socket_id,
+                    timestamp: self.start_time.elapsed(),
+                    event,
+                    congestion_window_size: rate_control.get_congestion_window_size(),
+                    pkt_send_period: rate_control.get_pkt_send_period(),
+                    rtt: rate_control.get_rtt(),
+                    slow_start: rate_control.is_slow_start(),
+                }
+            };
+            sink.0.record(&entry);
+        }
+    }
+
+    pub(crate) fn set_user_control_handler(&self, handler: Option<UdtUserControlHandler>) {
+        *self.user_control_handler.write().unwrap() = handler.map(UserControlHandlerRef);
+    }
+
+    /// Sends a user-defined control packet, ahead of the pending data.
+    pub(crate) fn send_user_control(&self, reserved: u16, payload: &[u8]) -> Result<()> {
+        if self.status() != UdtStatus::Connected {
+            return Err(Error::new(
+                ErrorKind::NotConnected,
+                UdtError::new(UdtErrorCode::NoConn, "UDT socket is not connected"),
+            ));
+        }
+        if payload.len() > self.get_max_payload_size() as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                UdtError::new(
+                    UdtErrorCode::LargeMsg,
+                    "user control payload does not fit in a packet",
+                ),
+            ));
+        }
+        let packet = UdtControlPacket::new_user_defined(
+            reserved,
+            payload.to_vec(),
+            self.peer_socket_id().unwrap_or(0),
+        );
+        self.send_feedback(packet.into());
+        Ok(())
+    }
+
+    pub(crate) fn subscribe_events(&self) -> broadcast::Receiver<UdtConnectionEvent> {
+        self.first_events
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| self.events.subscribe())
+    }
+
+    pub(crate) fn emit_event(&self, event: UdtConnectionEvent) {
+        // Nobody may be listening
+        let _ = self.events.send(event);
+    }
+
+    pub(crate) fn notify_all(&self) {
         self.accept_notify.notify_waiters();
-        self.rcv_notify.notify_waiters();
         self.connect_notify.notify_waiters();
+        self.notify_readers();
+        self.notify_writers();
+    }
+
+    fn notify_readers(&self) {
+        self.rcv_notify.notify_waiters();
+        self.rcv_wakers.wake_all();
+    }
+
+    fn notify_writers(&self) {
+        self.ack_notify.notify_waiters();
+        self.snd_wakers.wake_all();
+    }
+
+    fn is_readable(&self) -> bool {
+        !self.status().is_alive() || self.rcv_buffer().has_data_to_read()
+    }
+
+    fn is_writable(&self) -> bool {
+        !self.status().is_alive() || self.snd_buffer.lock().unwrap().has_room()
+    }
+
+    /// Polls until there is data to read, or the connection is broken.
+    pub(crate) fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_readable() {
+            return Poll::Ready(());
+        }
+        self.rcv_wakers.register(cx.waker());
+        self.arm_delivery_timer();
+        // Data may have arrived before the waker was registered
+        if self.is_readable() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Polls until the send buffer has room, or the connection is broken.
+    pub(crate) fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_writable() {
+            return Poll::Ready(());
+        }
+        self.snd_wakers.register(cx.waker());
+        if self.is_writable() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Polls until the send buffer has room for `len` bytes, or the connection is broken.
+    pub(crate) fn poll_snd_room(&self, cx: &mut Context<'_>, len: usize) -> Poll<()> {
+        let has_room = || !self.status().is_alive() || self.snd_buffer_room() >= len;
+        if has_room() {
+            return Poll::Ready(());
+        }
+        self.snd_wakers.register(cx.waker());
+        if has_room() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Polls until the socket is closed, or closing.
+    pub(crate) fn poll_closed(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let is_closed = || matches!(self.status(), UdtStatus::Closing | UdtStatus::Closed);
+        if is_closed() {
+            return Poll::Ready(());
+        }
+        self.snd_wakers.register(cx.waker());
+        if is_closed() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Polls until the send buffer is empty, waking up after each ACK.
+    pub(crate) fn poll_snd_buffer_empty(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.snd_buffer_is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        if !self.status().is_alive() {
+            return Poll::Ready(Err(self.broken_error()));
+        }
+        self.snd_wakers.register(cx.waker());
+        if self.snd_buffer_is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
     }
 
     pub(crate) async fn wait_for_data_to_read(&self) {
@@ -1213,8 +3233,35 @@ impl UdtSocket {
                 None
             }
         } {
-            notified.await;
+            self.wait_for_delivery(notified).await;
+        }
+    }
+
+    /// Waits for `notified`, or in live mode until the next packet to read is due.
+    async fn wait_for_delivery(&self, notified: Notified<'_>) {
+        let due = self.rcv_buffer().next_delivery_time();
+        match due {
+            Some(due) => {
+                let _ = tokio::time::timeout_at(due, notified).await;
+            }
+            None => notified.await,
+        }
+    }
+
+    /// In live mode, wakes the tasks polling for data once the next packet to read is due.
+    fn arm_delivery_timer(&self) {
+        let due = match self.rcv_buffer().next_delivery_time() {
+            Some(due) => due,
+            None => return,
+        };
+        let mut timer = self.delivery_timer.lock().unwrap();
+        let sleep = timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(due)));
+        if sleep.deadline() != due {
+            sleep.as_mut().reset(due);
         }
+        // Polled with the wakers of all the readers, which check the buffer again when woken
+        let waker = Waker::from(self.rcv_wakers.clone());
+        let _ = sleep.as_mut().poll(&mut Context::from_waker(&waker));
     }
 
     pub(crate) async fn wait_for_connection(&self) -> UdtStatus {
@@ -1282,3 +3329,58 @@ impl UdtStatus {
         *self != UdtStatus::Broken && *self != UdtStatus::Closing && *self != UdtStatus::Closed
     }
 }
+
+#[tokio::test]
+async fn test_peer_migration() {
+    use crate::connection::UdtConnection;
+    use crate::multiplexer::Channel;
+
+    let config = UdtConfiguration {
+        peer_migration: true,
+        ..Default::default()
+    };
+    let (client, server) = UdtConnection::pair(Some(config)).await.unwrap();
+    let mux = client.socket().multiplexer().unwrap();
+    let new_addr = match &mux.channel {
+        Channel::Memory(channel) => channel.rebind(),
+        _ => unreachable!(),
+    };
+    client.send(b"moved").await.unwrap();
+    let mut buf = [0; 16];
+    let len = tokio::time::timeout(Duration::from_secs(5), server.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"moved");
+    assert_eq!(server.peer_addr().unwrap(), new_addr);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_max_bandwidth() {
+    use crate::connection::UdtConnection;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Virtual time taken to transfer `len` bytes
+    async fn transfer(max_bandwidth_bps: Option<u64>, len: usize) -> Duration {
+        let config = UdtConfiguration {
+            max_bandwidth_bps,
+            ..Default::default()
+        };
+        let (mut client, mut server) = UdtConnection::pair(Some(config)).await.unwrap();
+        let start = Instant::now();
+        let sender = tokio::spawn(async move {
+            client.write_all(&vec![0; len]).await.unwrap();
+            client
+        });
+        let mut received = vec![0; len];
+        server.read_exact(&mut received).await.unwrap();
+        let elapsed = start.elapsed();
+        drop(sender.await.unwrap());
+        elapsed
+    }
+
+    // 100 kB/s
+    let capped = transfer(Some(800_000), 200_000).await;
+    assert!(capped >= Duration::from_secs(2) && capped < Duration::from_millis(2500));
+    assert!(transfer(None, 200_000).await < Duration::from_secs(1));
+}
@@ -0,0 +1,164 @@
+use crate::connection::UdtConnection;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, Result};
+
+/// Borrowed read half of a [`UdtConnection`], created by [`UdtConnection::split`].
+pub struct ReadHalf<'a>(&'a UdtConnection);
+
+/// Borrowed write half of a [`UdtConnection`], created by [`UdtConnection::split`].
+/// Shutting it down shuts down the sending side of the connection only (half-close),
+/// as with [`UdtConnection`] itself: the read half still receives the data of the peer.
+pub struct WriteHalf<'a>(&'a UdtConnection);
+
+/// Owned read half of a [`UdtConnection`], created by [`UdtConnection::into_split`].
+pub struct OwnedReadHalf(Arc<UdtConnection>);
+
+/// Owned write half of a [`UdtConnection`], created by [`UdtConnection::into_split`].
+/// Shutting it down or dropping it shuts down the sending side of the connection
+/// only (half-close): the read half still receives the data of the peer.
+/// The connection is closed once both halves are dropped.
+pub struct OwnedWriteHalf(Arc<UdtConnection>);
+
+pub(crate) fn split(connection: &mut UdtConnection) -> (ReadHalf<'_>, WriteHalf<'_>) {
+    (ReadHalf(connection), WriteHalf(connection))
+}
+
+pub(crate) fn split_owned(connection: UdtConnection) -> (OwnedReadHalf, OwnedWriteHalf) {
+    let connection = Arc::new(connection);
+    (
+        OwnedReadHalf(connection.clone()),
+        OwnedWriteHalf(connection),
+    )
+}
+
+impl AsyncRead for ReadHalf<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        self.0.poll_read_priv(cx, buf)
+    }
+}
+
+impl AsyncWrite for WriteHalf<'_> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.0.poll_write_priv(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        self.0.poll_write_vectored_priv(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.0.poll_flush_priv(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.0.poll_shutdown_priv(cx)
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        self.0.poll_read_priv(cx, buf)
+    }
+}
+
+impl AsyncWrite for OwnedWriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.0.poll_write_priv(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        self.0.poll_write_vectored_priv(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.0.poll_flush_priv(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.0.poll_shutdown_priv(cx)
+    }
+}
+
+impl Drop for OwnedWriteHalf {
+    fn drop(&mut self) {
+        self.0.shutdown_snd_on_drop();
+    }
+}
+
+impl AsRef<UdtConnection> for ReadHalf<'_> {
+    fn as_ref(&self) -> &UdtConnection {
+        self.0
+    }
+}
+
+impl AsRef<UdtConnection> for WriteHalf<'_> {
+    fn as_ref(&self) -> &UdtConnection {
+        self.0
+    }
+}
+
+impl AsRef<UdtConnection> for OwnedReadHalf {
+    fn as_ref(&self) -> &UdtConnection {
+        &self.0
+    }
+}
+
+impl AsRef<UdtConnection> for OwnedWriteHalf {
+    fn as_ref(&self) -> &UdtConnection {
+        &self.0
+    }
+}
+
+#[tokio::test]
+async fn test_half_close() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (client, mut server) = UdtConnection::pair(None).await.unwrap();
+    let (mut read, mut write) = client.into_split();
+    write.write_all(b"request").await.unwrap();
+    write.shutdown().await.unwrap();
+    let mut received = vec![];
+    server.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"request");
+    // The other direction is still open
+    server.write_all(b"response").await.unwrap();
+    let mut buf = [0; 8];
+    read.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"response");
+
+    // Dropping the write half shuts it down as well
+    let (client, mut server) = UdtConnection::pair(None).await.unwrap();
+    let (mut read, write) = client.into_split();
+    drop(write);
+    assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+    server.write_all(b"response").await.unwrap();
+    read.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"response");
+}
@@ -1,3 +1,3 @@
 mod socket_state;
 
-pub(crate) use socket_state::SocketState;
+pub(crate) use socket_state::{PeerMigration, SocketState};
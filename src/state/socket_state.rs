@@ -1,13 +1,35 @@
 use crate::ack_window::AckWindow;
-use crate::configuration::UdtConfiguration;
-use crate::loss_list::LossList;
+use crate::configuration::{UdtConfiguration, UDT4_VERSION};
+use crate::loss_list::{LossList, SndLossList};
+use crate::path_mtu::PathMtu;
 use crate::seq_number::{AckSeqNumber, SeqNumber};
 use crate::socket::SYN_INTERVAL;
+use crate::stats::UdtStatistics;
+use crate::watchdog::Watchdog;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
 use tokio::time::{Duration, Instant};
 
+/// Validation of a new address of the peer, see `UdtConfiguration::peer_migration`.
+#[derive(Debug, Clone)]
+pub(crate) struct PeerMigration {
+    /// Last address packets of the connection came from
+    pub addr: SocketAddr,
+    /// ACK sequence number of the challenge, echoed by the ACK2 of the peer
+    pub challenge: AckSeqNumber,
+    /// Addresses the challenge was sent to, the last ones first
+    pub challenged: VecDeque<SocketAddr>,
+    /// Whether a packet came from `addr` since the last challenge
+    pub challenge_due: bool,
+}
+
 #[derive(Debug)]
 pub(crate) struct SocketState {
     pub last_rsp_time: Instant,
+    /// Last time a packet was received from the peer.
+    /// Unlike `last_rsp_time`, it is never reset by timers.
+    pub last_peer_activity: Instant,
 
     // Receiving related,
     pub last_sent_ack: SeqNumber,
@@ -16,6 +38,8 @@ pub(crate) struct SocketState {
     pub last_ack_seq_number: AckSeqNumber,
     pub rcv_loss_list: LossList,
     pub last_ack2_received: SeqNumber,
+    /// Detection time of each loss range, used to enforce `rcv_latency`.
+    pub rcv_loss_times: VecDeque<(SeqNumber, SeqNumber, Instant)>,
 
     // Sending related
     pub last_ack_received: SeqNumber,
@@ -23,7 +47,7 @@ pub(crate) struct SocketState {
     pub last_ack2_sent_back: AckSeqNumber,
     pub curr_snd_seq_number: SeqNumber,
     pub last_ack2_time: Instant,
-    pub snd_loss_list: LossList,
+    pub snd_loss_list: SndLossList,
 
     pub next_ack_time: Instant,
     pub interpacket_interval: Duration,
@@ -31,20 +55,66 @@ pub(crate) struct SocketState {
     pub pkt_count: usize,
     pub light_ack_counter: usize,
     pub exp_count: u32,
+    pub next_keep_alive_time: Instant,
+    pub unanswered_keep_alives: u32,
+    pub next_metrics_time: Instant,
 
     pub next_data_target_time: Instant,
+    pub bandwidth_target_time: Instant, // when the data sent so far fits in `max_bandwidth_bps`
+    pub probe_pair_pending: bool,
+
+    pub peer_stalled: bool,
+    pub peer_idle: bool,    // PeerIdle event sent for the current silence
+    pub snd_shutdown: bool, // the end of the stream was queued, nothing can be sent after it
+    pub rendezvous_connection_type: i32, // 0, -1 once the peer was heard, -2 once connected
+    // Next repetition of the -2 rendezvous handshake, until a packet shows the peer connected
+    pub rendezvous_reply_due: Option<Instant>,
+    pub next_stall_probe: Instant,
+    pub stall_probe: bool, // a single packet may be sent despite the full window
+    pub rcv_window_edge: Option<SeqNumber>, // end of the flow window advertised by the last full ACK
+
+    pub udt_version: u32,                        // negotiated in the handshake
+    pub handshake_extensions: Vec<(u16, Bytes)>, // sent in the handshake response
+    pub peer_handshake_extensions: Vec<(u16, Bytes)>,
+    pub resumption_token: Option<[u8; 16]>, // issued by the listener, to resume the session
+    pub resuming: bool,                     // the client presents the token in its handshake
+    pub peer_migration: Option<PeerMigration>,
+    pub last_migration_challenge: Option<Instant>,
+    pub path_mtu: Option<PathMtu>,
 
     pub ack_window: AckWindow,
+    pub watchdog: Watchdog,
+
+    pub stats: UdtStatistics,
 }
 
 impl SocketState {
-    pub fn new(isn: SeqNumber, _configuration: &UdtConfiguration) -> Self {
+    pub fn new(isn: SeqNumber, configuration: &UdtConfiguration) -> Self {
         let now = Instant::now();
+        // Disjoint loss ranges are separated by at least one delivered packet of the window
+        let (rcv_losses, snd_losses) = (
+            configuration.rcv_buf_size as usize / 2 + 1,
+            configuration.snd_buf_size as usize / 2 + 1,
+        );
+        let (rcv_loss_list, snd_loss_list) = if configuration.fixed_capacity {
+            (
+                LossList::with_capacity(rcv_losses),
+                SndLossList::with_capacity(snd_losses),
+            )
+        } else {
+            (LossList::new(), SndLossList::new())
+        };
 
         Self {
             last_rsp_time: now,
+            last_peer_activity: now,
             last_ack_seq_number: AckSeqNumber::zero(),
-            rcv_loss_list: LossList::new(),
+            rcv_loss_list,
+            rcv_loss_times: VecDeque::with_capacity(if configuration.fixed_capacity {
+                rcv_losses
+            } else {
+                0
+            }),
             curr_rcv_seq_number: isn - 1,
 
             next_ack_time: now + SYN_INTERVAL,
@@ -54,6 +124,9 @@ impl SocketState {
             light_ack_counter: 0,
 
             exp_count: 1,
+            next_keep_alive_time: now,
+            unanswered_keep_alives: 0,
+            next_metrics_time: now,
             last_ack_received: isn,
             last_sent_ack: isn - 1,
             last_sent_ack_time: now,
@@ -63,11 +136,42 @@ impl SocketState {
             last_ack2_sent_back: isn.number().into(),
             last_ack2_time: now,
             last_data_ack_processed: isn,
-            snd_loss_list: LossList::new(),
+            snd_loss_list,
 
             next_data_target_time: now,
+            bandwidth_target_time: now,
+            probe_pair_pending: false,
+
+            peer_stalled: false,
+            peer_idle: false,
+            snd_shutdown: false,
+            rendezvous_connection_type: 0,
+            rendezvous_reply_due: None,
+            next_stall_probe: now,
+            stall_probe: false,
+            rcv_window_edge: None,
+
+            udt_version: UDT4_VERSION,
+            handshake_extensions: vec![],
+            peer_handshake_extensions: vec![],
+            resumption_token: None,
+            resuming: false,
+            peer_migration: None,
+            last_migration_challenge: None,
+            path_mtu: None,
 
             ack_window: AckWindow::new(1024),
+            watchdog: Watchdog::new(),
+
+            stats: UdtStatistics::default(),
         }
     }
+
+    /// Sequence number up to which received data can be acknowledged:
+    /// the first loss, or the next packet expected.
+    pub fn ack_point(&self) -> SeqNumber {
+        self.rcv_loss_list
+            .peek_after(self.curr_rcv_seq_number + 1)
+            .unwrap_or(self.curr_rcv_seq_number + 1)
+    }
 }
@@ -0,0 +1,184 @@
+use std::ops::AddAssign;
+use std::time::Duration;
+
+/// Snapshot of the statistics of a UDT connection.
+/// Work times are the time the multiplexer workers spent processing the connection,
+/// including its non-blocking system calls but not the time they waited.
+/// Like the `perfmon` statistics of the reference implementation, counters are totals
+/// since the connection was opened, and other fields are sampled with the snapshot.
+#[derive(Debug, Clone, Default)]
+pub struct UdtStatistics {
+    /// Label of the connection, see `UdtConfiguration::label`.
+    /// Statistics combined over several connections have none.
+    pub label: Option<String>,
+    /// Number of data packets sent, including retransmissions.
+    pub pkt_sent: u64,
+    /// Number of payload bytes sent, including retransmissions.
+    pub bytes_sent: u64,
+    /// Number of data packets retransmitted.
+    pub pkt_retrans: u64,
+    /// Number of data packets received, including duplicates.
+    pub pkt_recv: u64,
+    /// Number of payload bytes received, including duplicates.
+    pub bytes_recv: u64,
+    /// Number of packets reported lost by the peer.
+    pub pkt_snd_loss: u64,
+    /// Number of packets detected lost by the receiver.
+    pub pkt_rcv_loss: u64,
+    /// Number of ACK packets sent (full and light).
+    pub ack_sent: u64,
+    /// Number of ACK packets received (full and light).
+    pub ack_recv: u64,
+    /// Number of NAK (loss report) packets sent.
+    pub nak_sent: u64,
+    /// Number of NAK (loss report) packets received.
+    pub nak_recv: u64,
+    /// Estimated round-trip time.
+    pub rtt: Duration,
+    /// Estimated round-trip time variance.
+    pub rtt_var: Duration,
+    /// Link capacity estimated by the peer from packet pairs, in packets per second.
+    pub bandwidth: u32,
+    /// Sending rate set by congestion control, in packets per second.
+    pub snd_rate: u64,
+    /// Arrival rate of the data packets, in packets per second.
+    pub rcv_rate: u32,
+    /// Number of packets in the send buffer, waiting to be sent or acknowledged.
+    pub snd_buffer_packets: usize,
+    /// Number of packets in the receive buffer, waiting to be read.
+    pub rcv_buffer_packets: usize,
+    /// Number of incomplete messages discarded by the receiver
+    /// after `msg_reassembly_timeout` expired.
+    pub rcv_msg_reassembly_timeouts: u64,
+    /// Number of lost packets given up on by the receiver
+    /// because they could not be recovered within `rcv_latency`,
+    /// or before the packet following them was due in live mode.
+    pub rcv_too_late_drops: u64,
+    /// Number of messages removed from the send buffer
+    /// because they could not be sent before their deadline.
+    pub snd_deadline_drops: u64,
+    /// Number of messages given up on by the sender because their TTL expired
+    /// before they were sent or acknowledged.
+    pub snd_ttl_drops: u64,
+    /// Number of packets refused by the receiver because its buffer was full.
+    pub rcv_buffer_full: u64,
+    /// Number of packets dropped before being read, to make room for new packets
+    /// with `RcvOverloadPolicy::DropOldest`.
+    pub rcv_overload_drops: u64,
+    /// Number of data packets received more than once (e.g. a retransmission racing
+    /// with a late original), discarded without being written to the receive buffer.
+    pub rcv_duplicates: u64,
+    /// Number of times sending was paused because the peer receive buffer was full.
+    pub snd_peer_stalls: u64,
+    /// Time spent by the send worker on the connection:
+    /// packetization, pacing decisions and sending data packets.
+    pub snd_work_time: Duration,
+    /// Time spent by the receive worker processing the packets of the connection,
+    /// including ACK and loss report handling.
+    pub rcv_work_time: Duration,
+    /// Time spent by the receive worker on the timers of the connection:
+    /// ACK generation, loss reports and retransmission timeouts.
+    pub timers_work_time: Duration,
+}
+
+/// Combines the statistics of several connections.
+/// Rates and buffer occupancies are summed, RTT estimates keep the maximum.
+impl AddAssign<&UdtStatistics> for UdtStatistics {
+    fn add_assign(&mut self, other: &UdtStatistics) {
+        self.pkt_sent += other.pkt_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.pkt_retrans += other.pkt_retrans;
+        self.pkt_recv += other.pkt_recv;
+        self.bytes_recv += other.bytes_recv;
+        self.pkt_snd_loss += other.pkt_snd_loss;
+        self.pkt_rcv_loss += other.pkt_rcv_loss;
+        self.ack_sent += other.ack_sent;
+        self.ack_recv += other.ack_recv;
+        self.nak_sent += other.nak_sent;
+        self.nak_recv += other.nak_recv;
+        self.rtt = self.rtt.max(other.rtt);
+        self.rtt_var = self.rtt_var.max(other.rtt_var);
+        self.bandwidth += other.bandwidth;
+        self.snd_rate += other.snd_rate;
+        self.rcv_rate += other.rcv_rate;
+        self.snd_buffer_packets += other.snd_buffer_packets;
+        self.rcv_buffer_packets += other.rcv_buffer_packets;
+        self.rcv_msg_reassembly_timeouts += other.rcv_msg_reassembly_timeouts;
+        self.rcv_too_late_drops += other.rcv_too_late_drops;
+        self.snd_deadline_drops += other.snd_deadline_drops;
+        self.snd_ttl_drops += other.snd_ttl_drops;
+        self.rcv_buffer_full += other.rcv_buffer_full;
+        self.rcv_overload_drops += other.rcv_overload_drops;
+        self.rcv_duplicates += other.rcv_duplicates;
+        self.snd_peer_stalls += other.snd_peer_stalls;
+        self.snd_work_time += other.snd_work_time;
+        self.rcv_work_time += other.rcv_work_time;
+        self.timers_work_time += other.timers_work_time;
+    }
+}
+
+/// Snapshot of the statistics of a UDT multiplexer,
+/// i.e. of the UDP socket shared by a listener and its connections
+#[derive(Debug, Clone, Default)]
+pub struct UdtMultiplexerStatistics {
+    /// Number of datagrams dropped by the kernel because the UDP receive buffer
+    /// was full (Linux only, from `SO_RXQ_OVFL`). Unlike protocol losses, these drops
+    /// are fixed by raising `udp_rcv_buf_size` or reading faster.
+    pub udp_rcv_drops: u64,
+    /// Number of connection requests whose handshake address did not match
+    /// the local address they were received on.
+    pub handshake_addr_mismatches: u64,
+    /// Number of handshakes dropped because of the `handshake_rate_limit` of the listener.
+    pub handshakes_dropped: u64,
+    /// Number of connection requests the listener failed or refused to answer,
+    /// e.g. with an invalid cookie or rejected by the backlog or a handshake extension.
+    pub handshakes_rejected: u64,
+    /// Number of packets ignored because they were addressed to an unknown socket id,
+    /// or did not come from the peer of the destination socket
+    /// (e.g. after a NAT rebinding, or from a misconfigured peer).
+    pub stray_packets: u64,
+    /// Number of times a send or receive worker of the multiplexer failed or panicked.
+    /// Workers are restarted, unless they keep failing: the sockets of the multiplexer
+    /// are then broken.
+    pub worker_failures: u64,
+}
+
+#[tokio::test]
+async fn test_stats_label() {
+    use crate::configuration::UdtConfiguration;
+    use crate::connection::UdtConnection;
+
+    let config = UdtConfiguration {
+        label: Some("job-1".into()),
+        ..Default::default()
+    };
+    let (client, _server) = UdtConnection::pair(Some(config)).await.unwrap();
+    assert_eq!(client.stats().label.as_deref(), Some("job-1"));
+    client.set_label(Some("job-2".into()));
+    assert_eq!(client.stats().label.as_deref(), Some("job-2"));
+}
+
+#[tokio::test]
+async fn test_work_time() {
+    use crate::connection::UdtConnection;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut client, mut server) = UdtConnection::pair(None).await.unwrap();
+    let start = std::time::Instant::now();
+    let len = 1 << 20;
+    let sender = tokio::spawn(async move {
+        client.write_all(&vec![0; len]).await.unwrap();
+        client.flush().await.unwrap();
+        client
+    });
+    let mut received = vec![0; len];
+    server.read_exact(&mut received).await.unwrap();
+    let client = sender.await.unwrap();
+    let elapsed = start.elapsed();
+    let (client_stats, server_stats) = (client.stats(), server.stats());
+    assert!(client_stats.snd_work_time > Duration::ZERO);
+    assert!(server_stats.rcv_work_time > Duration::ZERO);
+    // Only the processing counts, not the waits between packets
+    assert!(client_stats.snd_work_time + client_stats.rcv_work_time < elapsed);
+    assert!(server_stats.rcv_work_time + server_stats.timers_work_time < elapsed);
+}
@@ -0,0 +1,154 @@
+use crate::common::ip_to_bytes;
+use crate::control_packet::HandShakeInfo;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Period after which the secret of the cookies is replaced.
+/// A cookie is valid until the secret it was made with is replaced twice,
+/// i.e. for at least one period and at most two.
+pub(crate) const SYN_COOKIE_PERIOD: Duration = Duration::from_secs(30);
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+struct Secrets {
+    current: [u8; 32],
+    previous: Option<[u8; 32]>,
+    rotated_at: Instant,
+}
+
+/// Stateless cookies answering the first phase of a connection request.
+/// The listener allocates a socket only once the peer echoes a valid cookie,
+/// which proves that it receives the packets sent to its address.
+#[derive(Debug)]
+pub(crate) struct SynCookies {
+    secrets: Mutex<Secrets>,
+}
+
+impl SynCookies {
+    pub fn new() -> Self {
+        Self {
+            secrets: Mutex::new(Secrets {
+                current: Self::new_secret(),
+                previous: None,
+                rotated_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn new_secret() -> [u8; 32] {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        secret
+    }
+
+    fn secrets(&self, now: Instant) -> std::sync::MutexGuard<'_, Secrets> {
+        let mut secrets = self.secrets.lock().unwrap();
+        let elapsed = now.saturating_duration_since(secrets.rotated_at);
+        if elapsed >= SYN_COOKIE_PERIOD {
+            let current = std::mem::replace(&mut secrets.current, Self::new_secret());
+            // The previous secret expires as well after two periods without rotation
+            secrets.previous = (elapsed < 2 * SYN_COOKIE_PERIOD).then_some(current);
+            secrets.rotated_at = now;
+        }
+        secrets
+    }
+
+    /// The cookie authenticates the peer address, socket id and initial sequence number,
+    /// so that it cannot be replayed by another peer or for another connection.
+    fn mac(secret: &[u8; 32], addr: &SocketAddr, hs: &HandShakeInfo) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(&ip_to_bytes(addr.ip()));
+        mac.update(&addr.port().to_be_bytes());
+        mac.update(&hs.socket_id.to_be_bytes());
+        mac.update(&hs.initial_seq_number.number().to_be_bytes());
+        mac
+    }
+
+    pub fn generate(&self, addr: &SocketAddr, hs: &HandShakeInfo, now: Instant) -> u32 {
+        let secrets = self.secrets(now);
+        let tag = Self::mac(&secrets.current, addr, hs)
+            .finalize()
+            .into_bytes();
+        u32::from_be_bytes(tag[..4].try_into().unwrap())
+    }
+
+    pub fn validate(&self, addr: &SocketAddr, hs: &HandShakeInfo, now: Instant) -> bool {
+        let secrets = self.secrets(now);
+        let cookie = hs.syn_cookie.to_be_bytes();
+        std::iter::once(&secrets.current)
+            .chain(&secrets.previous)
+            .any(|secret| {
+                Self::mac(secret, addr, hs)
+                    .verify_truncated_left(&cookie)
+                    .is_ok()
+            })
+    }
+}
+
+#[cfg(test)]
+fn test_handshake(socket_id: u32) -> HandShakeInfo {
+    HandShakeInfo {
+        udt_version: 4,
+        socket_type: crate::socket::SocketType::Stream,
+        initial_seq_number: 42.into(),
+        max_packet_size: 1500,
+        max_window_size: 8192,
+        connection_type: 1,
+        socket_id,
+        syn_cookie: 0,
+        ip_address: std::net::Ipv4Addr::LOCALHOST.into(),
+        encryption_proof: None,
+        encryption_salt: None,
+        extensions: vec![],
+    }
+}
+
+#[test]
+fn test_syn_cookie_expiry() {
+    let cookies = SynCookies::new();
+    let addr: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+    let start = Instant::now();
+    let mut hs = test_handshake(7);
+    hs.syn_cookie = cookies.generate(&addr, &hs, start);
+
+    assert!(cookies.validate(&addr, &hs, start));
+    // Still valid after one rotation of the secret
+    assert!(cookies.validate(&addr, &hs, start + SYN_COOKIE_PERIOD));
+    assert!(!cookies.validate(&addr, &hs, start + 2 * SYN_COOKIE_PERIOD));
+
+    let cookies = SynCookies::new();
+    let start = Instant::now();
+    hs.syn_cookie = cookies.generate(&addr, &hs, start);
+    // Both secrets are replaced after a long idle time
+    assert!(!cookies.validate(&addr, &hs, start + 2 * SYN_COOKIE_PERIOD));
+}
+
+#[test]
+fn test_syn_cookie_replay() {
+    let cookies = SynCookies::new();
+    let addr: SocketAddr = "192.0.2.1:9000".parse().unwrap();
+    let now = Instant::now();
+    let mut hs = test_handshake(7);
+    hs.syn_cookie = cookies.generate(&addr, &hs, now);
+    assert!(cookies.validate(&addr, &hs, now));
+
+    let spoofed: SocketAddr = "192.0.2.2:9000".parse().unwrap();
+    assert!(!cookies.validate(&spoofed, &hs, now));
+    let other_port: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+    assert!(!cookies.validate(&other_port, &hs, now));
+
+    let mut other_connection = hs.clone();
+    other_connection.socket_id = 8;
+    assert!(!cookies.validate(&addr, &other_connection, now));
+    let mut other_isn = hs.clone();
+    other_isn.initial_seq_number = 43.into();
+    assert!(!cookies.validate(&addr, &other_isn, now));
+
+    // Another listener does not accept the cookie
+    assert!(!SynCookies::new().validate(&addr, &hs, now));
+}
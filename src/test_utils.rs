@@ -0,0 +1,434 @@
+//! Network impairment simulator, to test UDT connections against loss, reordering,
+//! duplication, jitter and limited bandwidth without a real network.
+//!
+//! An [`ImpairedLink`] relays the datagrams between a client and a server of the same
+//! process, over the loopback interface. The client connects to [`ImpairedLink::local_addr`]
+//! instead of the server address. [`ImpairedLink::pair`] instead connects both ends in
+//! memory, like [`UdtConnection::pair`]: with a paused Tokio clock, the delays then take
+//! no real time. The impairments of each datagram are drawn from a seeded random
+//! generator: a given seed always drops, duplicates and delays the same datagrams.
+use crate::configuration::UdtConfiguration;
+use crate::connection::UdtConnection;
+use crate::memory::MemoryChannel;
+use crate::multiplexer::Channel;
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BinaryHeap;
+use std::io::Result;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// Impairments applied to the datagrams going in one direction of an [`ImpairedLink`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impairments {
+    /// Probability that a datagram is dropped (0.0 to 1.0)
+    pub loss: f64,
+    /// Probability that a datagram is delivered twice
+    pub duplication: f64,
+    /// Probability that a datagram is held back by `reorder_delay`,
+    /// letting the following ones overtake it
+    pub reordering: f64,
+    pub reorder_delay: Duration,
+    /// Constant one-way delay
+    pub delay: Duration,
+    /// Maximum random delay added to `delay`. The datagrams stay in order,
+    /// unless they are reordered on purpose.
+    pub jitter: Duration,
+    /// Bandwidth of the link in bytes per second, None for unlimited
+    pub bandwidth: Option<u64>,
+    /// Number of datagrams waiting for the bandwidth, beyond which datagrams are dropped
+    pub queue_size: usize,
+}
+
+impl Default for Impairments {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+            queue_size: 1000,
+        }
+    }
+}
+
+/// Counters of an [`ImpairedLink`], for both directions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImpairedLinkStats {
+    pub forwarded: u64,
+    pub lost: u64,
+    /// Datagrams dropped because the bandwidth queue was full
+    pub overflowed: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    forwarded: AtomicU64,
+    lost: AtomicU64,
+    overflowed: AtomicU64,
+    duplicated: AtomicU64,
+    reordered: AtomicU64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Scheduled {
+    at: Instant,
+    order: u64,
+    data: Vec<u8>,
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Earliest first in the max-heap
+        (other.at, other.order).cmp(&(self.at, self.order))
+    }
+}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Applies the impairments of one direction and schedules the datagrams
+struct Direction {
+    impairments: Impairments,
+    rng: StdRng,
+    queue: BinaryHeap<Scheduled>,
+    next_order: u64,
+    last_delivery: Instant,
+    link_free_at: Instant, // end of the transmission of the previous datagram
+    counters: Arc<Counters>,
+}
+
+impl Direction {
+    fn new(impairments: Impairments, seed: u64, counters: Arc<Counters>) -> Self {
+        let now = Instant::now();
+        Self {
+            impairments,
+            rng: StdRng::seed_from_u64(seed),
+            queue: BinaryHeap::new(),
+            next_order: 0,
+            last_delivery: now,
+            link_free_at: now,
+            counters,
+        }
+    }
+
+    fn push(&mut self, data: Vec<u8>, now: Instant) {
+        let impairments = &self.impairments;
+        if self.rng.gen_bool(impairments.loss.clamp(0.0, 1.0)) {
+            self.counters.lost.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if self.queue.len() >= impairments.queue_size {
+            self.counters.overflowed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let copies = if self.rng.gen_bool(impairments.duplication.clamp(0.0, 1.0)) {
+            self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+            2
+        } else {
+            1
+        };
+        let reordered = self.rng.gen_bool(impairments.reordering.clamp(0.0, 1.0));
+        let jitter = impairments.jitter.mul_f64(self.rng.gen::<f64>());
+
+        let mut at = now;
+        if let Some(bandwidth) = impairments.bandwidth.filter(|bw| *bw > 0) {
+            let transmission =
+                Duration::from_secs_f64((data.len() * copies) as f64 / bandwidth as f64);
+            self.link_free_at = self.link_free_at.max(now) + transmission;
+            at = self.link_free_at;
+        }
+        at += impairments.delay + jitter;
+        if reordered {
+            self.counters.reordered.fetch_add(1, Ordering::Relaxed);
+            at += impairments.reorder_delay;
+        } else {
+            at = at.max(self.last_delivery);
+            self.last_delivery = at;
+        }
+        for _ in 0..copies {
+            self.queue.push(Scheduled {
+                at,
+                order: self.next_order,
+                data: data.clone(),
+            });
+            self.next_order += 1;
+        }
+    }
+
+    /// Takes the datagrams to deliver at `now`.
+    fn pop_due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut due = vec![];
+        while self
+            .queue
+            .peek()
+            .is_some_and(|scheduled| scheduled.at <= now)
+        {
+            due.push(self.queue.pop().unwrap().data);
+        }
+        due
+    }
+}
+
+/// Relays the datagrams between a client and a server, applying impairments.
+/// The first peer sending to [`ImpairedLink::local_addr`] is the client.
+/// The relay stops when the link is dropped.
+#[derive(Debug)]
+pub struct ImpairedLink {
+    local_addr: SocketAddr,
+    counters: Arc<Counters>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ImpairedLink {
+    /// Creates a link to `server`, applying `upstream` impairments to the datagrams
+    /// sent by the client and `downstream` ones to the datagrams sent by the server.
+    pub async fn new(
+        server: SocketAddr,
+        upstream: Impairments,
+        downstream: Impairments,
+        seed: u64,
+    ) -> Result<Self> {
+        let loopback: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::LOCALHOST, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::LOCALHOST, 0).into(),
+        };
+        let front = Arc::new(UdpSocket::bind(loopback).await?);
+        let back = Arc::new(UdpSocket::bind(loopback).await?);
+        let local_addr = front.local_addr()?;
+        let counters = Arc::new(Counters::default());
+        let client = Arc::new(Mutex::new(None));
+
+        let upstream = Direction::new(upstream, seed, counters.clone());
+        let downstream = Direction::new(downstream, seed.wrapping_add(1), counters.clone());
+        let tasks = vec![
+            tokio::spawn(Self::relay(
+                front.clone(),
+                back.clone(),
+                Some(server),
+                client.clone(),
+                upstream,
+            )),
+            tokio::spawn(Self::relay(back, front, None, client, downstream)),
+        ];
+        Ok(Self {
+            local_addr,
+            counters,
+            tasks,
+        })
+    }
+
+    /// Connects a client and a server exchanging their packets in memory, as with
+    /// [`UdtConnection::pair`], through a link applying `upstream` impairments to the
+    /// datagrams sent by the client and `downstream` ones to those sent by the server.
+    /// The handshake is not impaired. Delays follow the Tokio clock, which tests can
+    /// pause. [`ImpairedLink::local_addr`] is the address of the server.
+    pub async fn pair(
+        config: Option<UdtConfiguration>,
+        upstream: Impairments,
+        downstream: Impairments,
+        seed: u64,
+    ) -> Result<(Self, UdtConnection, UdtConnection)> {
+        let (client, server) = UdtConnection::pair(config).await?;
+        let counters = Arc::new(Counters::default());
+        let tasks = [
+            (&client, upstream, seed),
+            (&server, downstream, seed.wrapping_add(1)),
+        ]
+        .into_iter()
+        .map(|(connection, impairments, seed)| {
+            let channel = match &connection.socket().multiplexer().unwrap().channel {
+                Channel::Memory(channel) => channel.clone(),
+                Channel::Udp(_) => unreachable!("pair connections exchange packets in memory"),
+            };
+            let (relay, datagrams) = mpsc::unbounded_channel();
+            channel.set_relay(relay);
+            let direction = Direction::new(impairments, seed, counters.clone());
+            tokio::spawn(Self::relay_in_memory(
+                datagrams,
+                Arc::downgrade(&channel),
+                direction,
+            ))
+        })
+        .collect();
+        let link = Self {
+            local_addr: client.peer_addr()?,
+            counters,
+            tasks,
+        };
+        Ok((link, client, server))
+    }
+
+    /// Creates a link applying the same impairments in both directions.
+    pub async fn symmetric(
+        server: SocketAddr,
+        impairments: Impairments,
+        seed: u64,
+    ) -> Result<Self> {
+        Self::new(server, impairments.clone(), impairments, seed).await
+    }
+
+    /// Forwards the datagrams received on `from` with `to`, to `dest` or else to the client.
+    async fn relay(
+        from: Arc<UdpSocket>,
+        to: Arc<UdpSocket>,
+        dest: Option<SocketAddr>,
+        client: Arc<Mutex<Option<SocketAddr>>>,
+        mut direction: Direction,
+    ) {
+        let mut buf = vec![0; 65536];
+        loop {
+            let next = direction.queue.peek().map(|scheduled| scheduled.at);
+            tokio::select! {
+                received = from.recv_from(&mut buf) => {
+                    let (len, addr) = match received {
+                        Ok(received) => received,
+                        Err(_) => continue,
+                    };
+                    if dest.is_some() {
+                        client.lock().unwrap().get_or_insert(addr);
+                    }
+                    direction.push(buf[..len].to_vec(), Instant::now());
+                }
+                _ = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                    for data in direction.pop_due(Instant::now()) {
+                        let dest = dest.or_else(|| *client.lock().unwrap());
+                        if let Some(dest) = dest {
+                            if to.send_to(&data, dest).await.is_ok() {
+                                direction.counters.forwarded.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Delivers the datagrams sent by the `from` in-memory endpoint to its peer.
+    async fn relay_in_memory(
+        mut datagrams: mpsc::UnboundedReceiver<Bytes>,
+        from: Weak<MemoryChannel>,
+        mut direction: Direction,
+    ) {
+        loop {
+            let next = direction.queue.peek().map(|scheduled| scheduled.at);
+            tokio::select! {
+                received = datagrams.recv() => match received {
+                    Some(data) => direction.push(data.to_vec(), Instant::now()),
+                    None => return,
+                },
+                _ = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                    let Some(from) = from.upgrade() else {
+                        return;
+                    };
+                    for data in direction.pop_due(Instant::now()) {
+                        from.deliver(data.into());
+                        direction.counters.forwarded.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Address the client must connect to.
+    #[must_use]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> ImpairedLinkStats {
+        let counters = &self.counters;
+        ImpairedLinkStats {
+            forwarded: counters.forwarded.load(Ordering::Relaxed),
+            lost: counters.lost.load(Ordering::Relaxed),
+            overflowed: counters.overflowed.load(Ordering::Relaxed),
+            duplicated: counters.duplicated.load(Ordering::Relaxed),
+            reordered: counters.reordered.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for ImpairedLink {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[test]
+fn test_impairments_are_deterministic() {
+    let run = |seed| {
+        let counters = Arc::new(Counters::default());
+        let impairments = Impairments {
+            loss: 0.1,
+            duplication: 0.05,
+            reordering: 0.05,
+            jitter: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let mut direction = Direction::new(impairments, seed, counters.clone());
+        let now = Instant::now();
+        for idx in 0..1000u32 {
+            direction.push(idx.to_be_bytes().to_vec(), now);
+        }
+        let delivered: Vec<_> = std::iter::from_fn(|| direction.queue.pop())
+            .map(|scheduled| scheduled.data)
+            .collect();
+        let lost = counters.lost.load(Ordering::Relaxed);
+        let duplicated = counters.duplicated.load(Ordering::Relaxed);
+        assert_eq!(delivered.len() as u64, 1000 - lost + duplicated);
+        assert!(lost > 50 && lost < 150);
+        delivered
+    };
+    assert_eq!(run(7), run(7));
+    assert_ne!(run(7), run(8));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_impaired_pair() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let impairments = Impairments {
+        loss: 0.02,
+        reordering: 0.02,
+        delay: Duration::from_millis(50),
+        jitter: Duration::from_millis(5),
+        ..Default::default()
+    };
+    let (link, mut client, mut server) =
+        ImpairedLink::pair(None, impairments.clone(), impairments, 3)
+            .await
+            .unwrap();
+    let data: Vec<u8> = (0..1_000_000u32).map(|idx| idx as u8).collect();
+    let start = Instant::now();
+    let sent = data.clone();
+    let sender = tokio::spawn(async move {
+        client.write_all(&sent).await.unwrap();
+        client.flush().await.unwrap();
+        client
+    });
+    let mut received = vec![0; data.len()];
+    server.read_exact(&mut received).await.unwrap();
+    assert!(received == data);
+    let _client = sender.await.unwrap();
+    // At least the round trip of the delays, in virtual time
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    let stats = link.stats();
+    assert!(stats.lost > 0 && stats.reordered > 0 && stats.forwarded > 0);
+}
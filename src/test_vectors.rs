@@ -0,0 +1,156 @@
+//! Canonical encodings of UDT packets, as produced by this crate.
+//!
+//! Other implementations can encode the packet described by each vector and compare
+//! their output with [`assert_matches`], or feed the bytes to their decoder.
+use crate::packet::UdtPacket;
+use bytes::Bytes;
+use std::io::{Error, ErrorKind, Result};
+
+/// Byte-exact encoding of a packet
+#[derive(Debug, Clone, Copy)]
+pub struct TestVector {
+    pub name: &'static str,
+    /// Field values of the encoded packet
+    pub description: &'static str,
+    pub bytes: &'static [u8],
+}
+
+pub const TEST_VECTORS: &[TestVector] = &[
+    TestVector {
+        name: "data_only",
+        description: "data: seq 1, position only, not in order, msg 1, timestamp 16, \
+                      dest socket 0x11223344, payload \"UDT\"",
+        bytes: &[
+            0x00, 0x00, 0x00, 0x01, 0xc0, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x10, 0x11, 0x22,
+            0x33, 0x44, b'U', b'D', b'T',
+        ],
+    },
+    TestVector {
+        name: "data_first_in_order_max",
+        description: "data: seq 0x7fffffff, position first, in order, msg 0x1fffffff, \
+                      timestamp 0, dest socket 1, empty payload",
+        bytes: &[
+            0x7f, 0xff, 0xff, 0xff, 0xbf, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x01,
+        ],
+    },
+    TestVector {
+        name: "handshake_ipv4",
+        description: "handshake: dest socket 0, version 4, stream socket, isn 1000, mss 1500, \
+                      window 8192, connection type 1, socket id 0xcafe, cookie 0x5eed, \
+                      address 127.0.0.1",
+        bytes: &[
+            0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x03, 0xe8,
+            0x00, 0x00, 0x05, 0xdc, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00,
+            0xca, 0xfe, 0x00, 0x00, 0x5e, 0xed, 0x7f, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ],
+    },
+    TestVector {
+        name: "keep_alive",
+        description: "keep-alive: dest socket 0x0a0b0c0d",
+        bytes: &[
+            0x80, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x0b,
+            0x0c, 0x0d,
+        ],
+    },
+    TestVector {
+        name: "ack_full",
+        description: "ack: ack number 5, dest socket 2, next seq 1000, rtt 100000us, \
+                      rtt variance 50000us, available buffer 8192, receive rate 1000, \
+                      link capacity 2000",
+        bytes: &[
+            0x80, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x02, 0x00, 0x00, 0x03, 0xe8, 0x00, 0x01, 0x86, 0xa0, 0x00, 0x00, 0xc3, 0x50,
+            0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x03, 0xe8, 0x00, 0x00, 0x07, 0xd0,
+        ],
+    },
+    TestVector {
+        name: "ack_light",
+        description: "light ack: ack number 0, dest socket 2, next seq 1064",
+        bytes: &[
+            0x80, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x02, 0x00, 0x00, 0x04, 0x28,
+        ],
+    },
+    TestVector {
+        name: "nak",
+        description: "nak: dest socket 2, lost range 10-12, lost packet 20",
+        bytes: &[
+            0x80, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x02, 0x80, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x14,
+        ],
+    },
+    TestVector {
+        name: "shutdown",
+        description: "shutdown: dest socket 2",
+        bytes: &[
+            0x80, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x02,
+        ],
+    },
+    TestVector {
+        name: "ack2",
+        description: "ack2: ack number 5, dest socket 2",
+        bytes: &[
+            0x80, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x02,
+        ],
+    },
+    TestVector {
+        name: "msg_drop_request",
+        description: "message drop request: msg 7, dest socket 2, seq 100-103",
+        bytes: &[
+            0x80, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x02, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x67,
+        ],
+    },
+];
+
+/// Decodes `bytes` with this crate and encodes the packet back,
+/// failing unless the result is identical to the input.
+pub fn check_roundtrip(bytes: &[u8]) -> Result<()> {
+    let encoded = UdtPacket::deserialize(Bytes::copy_from_slice(bytes))?.serialize();
+    match first_difference(bytes, &encoded) {
+        None => Ok(()),
+        Some(offset) => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("re-encoded packet differs at byte {}", offset),
+        )),
+    }
+}
+
+/// Panics unless `bytes` survive a decode/encode round trip through this crate.
+pub fn assert_roundtrip(bytes: &[u8]) {
+    if let Err(err) = check_roundtrip(bytes) {
+        panic!("round trip failed for {:02x?}: {}", bytes, err);
+    }
+}
+
+/// Panics unless `encoded`, the encoding of the packet described by `vector`
+/// produced by another implementation, is byte-exact.
+pub fn assert_matches(vector: &TestVector, encoded: &[u8]) {
+    if let Some(offset) = first_difference(vector.bytes, encoded) {
+        panic!(
+            "encoding of {} differs at byte {}:\n expected {:02x?}\n      got {:02x?}",
+            vector.name, offset, vector.bytes, encoded
+        );
+    }
+}
+
+fn first_difference(expected: &[u8], actual: &[u8]) -> Option<usize> {
+    expected
+        .iter()
+        .zip(actual)
+        .position(|(a, b)| a != b)
+        .or_else(|| (expected.len() != actual.len()).then(|| expected.len().min(actual.len())))
+}
+
+#[test]
+fn test_vectors_roundtrip() {
+    for vector in TEST_VECTORS {
+        assert_roundtrip(vector.bytes);
+    }
+    assert!(check_roundtrip(&[0x00, 0x01]).is_err());
+}
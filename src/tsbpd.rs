@@ -0,0 +1,92 @@
+use tokio::time::{Duration, Instant};
+
+/// Timestamp-based packet delivery of live mode: each packet is released to the
+/// application `latency` after the sender timestamped it, as measured on the
+/// receiver clock. The sender clock is mapped onto the receiver one with the
+/// fastest transit observed, so the jitter of the network is absorbed by the latency.
+#[derive(Debug)]
+pub(crate) struct Tsbpd {
+    latency: Duration,
+    start_time: Instant,
+    offset: Option<i64>, // µs: smallest arrival time since start_time minus sender timestamp
+    last_timestamp: Option<i64>, // µs: latest sender timestamp, unwrapped
+}
+
+impl Tsbpd {
+    pub fn new(latency: Duration) -> Self {
+        Self {
+            latency,
+            start_time: Instant::now(),
+            offset: None,
+            last_timestamp: None,
+        }
+    }
+
+    /// Sender timestamps wrap around after about 71 minutes: they are unwrapped
+    /// relative to the latest one.
+    fn unwrap_timestamp(&self, timestamp: u32) -> i64 {
+        match self.last_timestamp {
+            Some(last) => last + i64::from(timestamp.wrapping_sub(last as u32) as i32),
+            None => i64::from(timestamp),
+        }
+    }
+
+    pub fn on_arrival(&mut self, timestamp: u32, now: Instant) {
+        let timestamp = self.unwrap_timestamp(timestamp);
+        self.last_timestamp = Some(
+            self.last_timestamp
+                .map_or(timestamp, |last| last.max(timestamp)),
+        );
+        let arrival = now.duration_since(self.start_time).as_micros() as i64;
+        let offset = arrival - timestamp;
+        if self.offset.is_none_or(|current| offset < current) {
+            self.offset = Some(offset);
+        }
+    }
+
+    /// Time at which the packet sent at `timestamp` is delivered.
+    pub fn delivery_time(&self, timestamp: u32) -> Instant {
+        let offset = self.offset.unwrap_or_default();
+        let time = self.unwrap_timestamp(timestamp) + offset + self.latency.as_micros() as i64;
+        self.start_time + Duration::from_micros(time.max(0) as u64)
+    }
+}
+
+#[test]
+fn test_tsbpd_delivery_time() {
+    let latency = Duration::from_millis(120);
+    let mut tsbpd = Tsbpd::new(latency);
+    let start = tsbpd.start_time;
+
+    // The first packet took 10ms, the second one 2ms: the transit of the faster one is kept
+    tsbpd.on_arrival(5_000, start + Duration::from_millis(15));
+    assert_eq!(
+        tsbpd.delivery_time(5_000),
+        start + Duration::from_millis(135)
+    );
+    tsbpd.on_arrival(6_000, start + Duration::from_millis(8));
+    assert_eq!(
+        tsbpd.delivery_time(5_000),
+        start + Duration::from_millis(127)
+    );
+    tsbpd.on_arrival(20_000, start + Duration::from_millis(40));
+    assert_eq!(
+        tsbpd.delivery_time(20_000),
+        start + Duration::from_millis(142)
+    );
+
+    // Timestamps wrapping around keep increasing delivery times
+    let mut tsbpd = Tsbpd::new(latency);
+    tsbpd.on_arrival(
+        u32::MAX - 1_000,
+        tsbpd.start_time + Duration::from_secs(5000),
+    );
+    let before_wrap = tsbpd.delivery_time(u32::MAX - 1_000);
+    let arrival = tsbpd.start_time + Duration::from_secs(5000) + Duration::from_micros(2_001);
+    tsbpd.on_arrival(1_000, arrival);
+    assert_eq!(
+        tsbpd.delivery_time(1_000),
+        before_wrap + Duration::from_micros(2_001)
+    );
+    assert_eq!(tsbpd.delivery_time(u32::MAX - 1_000), before_wrap);
+}
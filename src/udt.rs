@@ -1,41 +1,71 @@
-use super::configuration::UdtConfiguration;
-use crate::control_packet::{HandShakeInfo, UdtControlPacket};
+use super::configuration::{UdtConfiguration, UdtEgressRateLimit};
+use crate::control_packet::{HandShakeInfo, HandshakeRejection, HandshakeRole, UdtControlPacket};
+use crate::error::{UdtBackgroundError, UdtError, UdtErrorCode};
+use crate::event::UdtConnectionEvent;
+use crate::handshake_extension::{self, RESUMPTION_EXTENSION};
+use crate::memory::MemoryChannel;
 use crate::multiplexer::{MultiplexerId, UdtMultiplexer};
+use crate::rate_limiter::{EgressLimiter, TokenBucket};
 use crate::seq_number::SeqNumber;
 use crate::socket::{SocketId, SocketType, UdtSocket, UdtStatus};
+use bytes::Bytes;
 use once_cell::sync::{Lazy, OnceCell};
 use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
 use std::io::{Error, ErrorKind, Result};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tokio::time::sleep;
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, RwLock};
+use tokio::time::{sleep, Duration, Instant};
 
 pub(crate) type SocketRef = Arc<UdtSocket>;
 
 static UDT_INSTANCE: OnceCell<RwLock<Udt>> = OnceCell::new();
+const DEFAULT_PURGE_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_ID_REUSE_GUARD: Duration = Duration::from_secs(60);
+const BACKGROUND_ERRORS_CAPACITY: usize = 64;
+static BACKGROUND_ERRORS: Lazy<broadcast::Sender<UdtBackgroundError>> =
+    Lazy::new(|| broadcast::channel(BACKGROUND_ERRORS_CAPACITY).0);
 pub(crate) static UDT_DEBUG: Lazy<bool> =
     Lazy::new(|| std::env::var("UDT_DEBUG").unwrap_or_default() != "");
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub(crate) struct Udt {
     sockets: BTreeMap<SocketId, SocketRef>,
     // closed_sockets: BTreeMap<SocketId, SocketRef>,
     multiplexers: BTreeMap<MultiplexerId, Arc<UdtMultiplexer>>,
     next_socket_id: SocketId,
     peers: BTreeMap<(SocketId, SeqNumber), BTreeSet<SocketId>>, // peer socket id -> local socket id
+    egress_limiter: Arc<EgressLimiter>,
+    closing_since: BTreeMap<SocketId, Instant>,
+    broken_since: BTreeMap<SocketId, Instant>, // resumable sessions
+    purged_ids: BTreeMap<SocketId, Instant>,   // ids that cannot be reused yet
+    purged_sockets: u64,
+    purge_delay: Duration,
+    id_reuse_guard: Duration,
+    max_connections: Option<usize>,
 }
 
 impl Udt {
     fn new() -> Self {
         Self {
+            sockets: BTreeMap::new(),
+            multiplexers: BTreeMap::new(),
             next_socket_id: rand::random(),
-            ..Default::default()
+            peers: BTreeMap::new(),
+            egress_limiter: Arc::default(),
+            closing_since: BTreeMap::new(),
+            broken_since: BTreeMap::new(),
+            purged_ids: BTreeMap::new(),
+            purged_sockets: 0,
+            purge_delay: DEFAULT_PURGE_DELAY,
+            id_reuse_guard: DEFAULT_ID_REUSE_GUARD,
+            max_connections: None,
         }
     }
 
-    pub fn get() -> &'static RwLock<Self> {
+    pub(crate) fn get() -> &'static RwLock<Self> {
         UDT_INSTANCE.get_or_init(|| {
             Udt::cleanup_worker();
             RwLock::new(Udt::new())
@@ -43,9 +73,153 @@ impl Udt {
     }
 
     fn get_new_socket_id(&mut self) -> SocketId {
-        let socket_id = self.next_socket_id;
-        self.next_socket_id = self.next_socket_id.wrapping_sub(1);
-        socket_id
+        loop {
+            let socket_id = self.next_socket_id;
+            self.next_socket_id = self.next_socket_id.wrapping_sub(1);
+            // Late packets may still target recently purged sockets
+            if !self.sockets.contains_key(&socket_id) && !self.purged_ids.contains_key(&socket_id) {
+                return socket_id;
+            }
+        }
+    }
+
+    pub(crate) async fn set_purge_delay(delay: Duration) {
+        Self::get().write().await.purge_delay = delay;
+    }
+
+    pub(crate) async fn set_socket_id_reuse_guard(guard: Duration) {
+        Self::get().write().await.id_reuse_guard = guard;
+    }
+
+    pub(crate) async fn purged_sockets() -> u64 {
+        Self::get().read().await.purged_sockets
+    }
+
+    pub(crate) async fn set_egress_rate_limit(limit: Option<UdtEgressRateLimit>) {
+        Self::get()
+            .read()
+            .await
+            .egress_limiter
+            .set(limit.map(|limit| TokenBucket::new(limit.bytes_per_sec.get(), limit.burst_bytes)));
+    }
+
+    pub(crate) async fn set_max_connections(limit: Option<usize>) {
+        Self::get().write().await.max_connections = limit;
+    }
+
+    /// Whether accepting a new connection for `listener` would exceed the limits.
+    fn too_many_connections(&self, listener: &UdtSocket, listener_limit: Option<usize>) -> bool {
+        let accepted = || {
+            self.sockets
+                .values()
+                .filter(|socket| socket.listen_socket.is_some() && socket.status().is_alive())
+        };
+        listener_limit.is_some_and(|limit| {
+            accepted()
+                .filter(|socket| socket.listen_socket == Some(listener.socket_id))
+                .count()
+                >= limit
+        }) || self
+            .max_connections
+            .is_some_and(|limit| accepted().count() >= limit)
+    }
+
+    pub(crate) async fn close_all(timeout: Duration) {
+        let sockets: Vec<_> = Self::get().read().await.sockets.values().cloned().collect();
+        let mut closing: Vec<_> = sockets
+            .into_iter()
+            .map(|socket| tokio::spawn(async move { socket.close().await }))
+            .collect();
+        let _ = tokio::time::timeout(timeout, async {
+            for handle in &mut closing {
+                let _ = handle.await;
+            }
+        })
+        .await;
+        // The sockets still lingering are closed abruptly below
+        for handle in closing {
+            handle.abort();
+        }
+
+        let mut udt = Self::get().write().await;
+        for (_, mux) in std::mem::take(&mut udt.multiplexers) {
+            mux.shutdown().await;
+        }
+        for (_, socket) in std::mem::take(&mut udt.sockets) {
+            let status = std::mem::replace(&mut *socket.status.lock().unwrap(), UdtStatus::Closed);
+            socket.notify_all();
+            if !matches!(status, UdtStatus::Closing | UdtStatus::Closed) {
+                socket.emit_event(UdtConnectionEvent::Closed);
+            }
+        }
+        udt.peers.clear();
+    }
+
+    pub(crate) fn subscribe_errors() -> broadcast::Receiver<UdtBackgroundError> {
+        BACKGROUND_ERRORS.subscribe()
+    }
+
+    pub(crate) fn report_error(
+        socket_id: Option<SocketId>,
+        multiplexer_id: Option<MultiplexerId>,
+        label: Option<String>,
+        error: Error,
+    ) {
+        let error = UdtBackgroundError {
+            socket_id,
+            multiplexer_id,
+            label,
+            error: Arc::new(error),
+        };
+        trace_event!(warn, error = %error, "background error");
+        let _ = BACKGROUND_ERRORS.send(error);
+    }
+
+    /// Closes a socket, then releases the multiplexers no longer used by any open socket.
+    pub(crate) async fn close_socket(socket: &SocketRef) {
+        socket.close().await;
+        Self::get().write().await.release_idle_multiplexers().await;
+    }
+
+    pub(crate) async fn close_socket_with_linger(socket: &SocketRef, linger: Duration) {
+        socket.close_with_linger(linger).await;
+        Self::get().write().await.release_idle_multiplexers().await;
+    }
+
+    /// Stops the multiplexers which are not used by any open socket,
+    /// so that their UDP sockets are released.
+    async fn release_idle_multiplexers(&mut self) {
+        let in_use: BTreeSet<_> = self
+            .sockets
+            .values()
+            .filter(|s| !matches!(s.status(), UdtStatus::Closing | UdtStatus::Closed))
+            .filter_map(|s| s.multiplexer().map(|mux| mux.id))
+            .collect();
+        let idle: Vec<_> = self
+            .multiplexers
+            .keys()
+            .filter(|id| !in_use.contains(id))
+            .copied()
+            .collect();
+        for mux_id in idle {
+            if let Some(mux) = self.multiplexers.remove(&mux_id) {
+                mux.shutdown().await;
+            }
+        }
+    }
+
+    /// Breaks the open sockets of a multiplexer which cannot carry traffic anymore.
+    pub(crate) fn break_multiplexer_sockets(&self, mux_id: MultiplexerId, cause: &str) {
+        for socket in self
+            .sockets
+            .values()
+            .filter(|s| s.multiplexer().is_some_and(|mux| mux.id == mux_id))
+        {
+            socket.break_with(Error::other(UdtError::new(
+                UdtErrorCode::ConnLost,
+                cause.to_string(),
+            )));
+        }
     }
 
     pub(crate) fn get_socket(&self, socket_id: SocketId) -> Option<SocketRef> {
@@ -76,11 +250,14 @@ impl Udt {
         None
     }
 
-    pub fn new_socket(
+    pub(crate) fn new_socket(
         &mut self,
         socket_type: SocketType,
         config: Option<UdtConfiguration>,
     ) -> Result<&SocketRef> {
+        if let Some(config) = &config {
+            config.validate()?;
+        }
         let socket = UdtSocket::new(self.get_new_socket_id(), socket_type, None, config);
         let socket_id = socket.socket_id;
         if let Entry::Vacant(e) = self.sockets.entry(socket_id) {
@@ -88,7 +265,7 @@ impl Udt {
         }
         Err(Error::new(
             ErrorKind::AlreadyExists,
-            "socket_id already exists",
+            UdtError::new(UdtErrorCode::Resource, "socket_id already exists"),
         ))
     }
 
@@ -97,7 +274,16 @@ impl Udt {
         listener_socket: &UdtSocket,
         peer: SocketAddr,
         hs: &HandShakeInfo,
+        local_ip: Option<IpAddr>,
     ) -> Result<()> {
+        // A client resuming its session presents the token it was given
+        if let Some(resumption) = handshake_extension::find(&hs.extensions, RESUMPTION_EXTENSION)
+            .filter(|value| !value.is_empty())
+        {
+            return self
+                .resume_connection(listener_socket, peer, hs, resumption, local_ip)
+                .await;
+        }
         if let Some(existing_peer_socket) = self
             .get_peer_socket(peer, hs.socket_id, hs.initial_seq_number)
             .await
@@ -114,7 +300,7 @@ impl Udt {
             } else {
                 // Respond with existing socket configuration.
                 let source_socket_id = hs.socket_id;
-                let hs = {
+                let mut hs = {
                     let mut hs = hs.clone();
                     let configuration = socket.configuration.read().unwrap();
                     hs.initial_seq_number = socket.initial_seq_number;
@@ -122,8 +308,10 @@ impl Udt {
                     hs.max_window_size = configuration.flight_flag_size;
                     hs.connection_type = -1;
                     hs.socket_id = socket.socket_id;
+                    hs.extensions = socket.handshake_extensions();
                     hs
                 };
+                socket.sign_handshake(&mut hs, HandshakeRole::Responder);
                 let packet = UdtControlPacket::new_handshake(hs, source_socket_id);
                 socket.send_to(&peer, packet.into()).await?;
                 return Ok(());
@@ -132,17 +320,52 @@ impl Udt {
 
         let new_socket_id = self.get_new_socket_id();
 
-        let new_socket = {
+        let (new_socket, extensions) = {
             let multiplexer = listener_socket
                 .multiplexer
                 .read()
                 .unwrap()
                 .upgrade()
-                .ok_or_else(|| Error::new(ErrorKind::Other, "Listener has no multiplexer"))?;
+                .ok_or_else(|| {
+                    Error::other(UdtError::new(
+                        UdtErrorCode::UnboundSock,
+                        "Listener has no multiplexer",
+                    ))
+                })?;
 
             let config = listener_socket.configuration.read().unwrap().clone();
             if listener_socket.queued_sockets.read().await.len() >= config.accept_queue_size {
-                return Err(Error::new(ErrorKind::Other, "Too many queued sockets"));
+                listener_socket
+                    .reject_handshake(peer, hs, HandshakeRejection::Backlog, local_ip)
+                    .await?;
+                return Err(Error::other(UdtError::new(
+                    UdtErrorCode::Resource,
+                    "Too many queued sockets",
+                )));
+            }
+            if self.too_many_connections(listener_socket, config.max_connections) {
+                listener_socket
+                    .reject_handshake(peer, hs, HandshakeRejection::Busy, local_ip)
+                    .await?;
+                return Err(Error::other(UdtError::new(
+                    UdtErrorCode::Resource,
+                    "Too many connections",
+                )));
+            }
+            let mut extensions = match config.handshake_extensions.respond(peer, &hs.extensions) {
+                Ok(extensions) => extensions,
+                Err(err) => {
+                    listener_socket
+                        .reject_handshake(peer, hs, HandshakeRejection::Peer, local_ip)
+                        .await?;
+                    return Err(err);
+                }
+            };
+            if config.resumption_timeout.is_some()
+                && handshake_extension::find(&hs.extensions, RESUMPTION_EXTENSION).is_some()
+            {
+                let token: [u8; 16] = rand::random();
+                extensions.push((RESUMPTION_EXTENSION, Bytes::copy_from_slice(&token)));
             }
 
             let new_socket = UdtSocket::new(
@@ -152,15 +375,18 @@ impl Udt {
                 Some(config),
             )
             .with_peer(peer, hs.socket_id)
+            .with_self_ip(local_ip)
             .with_listen_socket(listener_socket.socket_id, &multiplexer);
             new_socket.open();
-            new_socket
+            (new_socket, extensions)
         };
 
         let ns_id = new_socket.socket_id;
         let ns_isn = new_socket.initial_seq_number;
         let ns_peer_socket_id = hs.socket_id;
-        let new_socket_ref = new_socket.connect_on_handshake(peer, hs.clone()).await?;
+        let new_socket_ref = new_socket
+            .connect_on_handshake(peer, hs.clone(), extensions)
+            .await?;
 
         self.peers
             .entry((ns_peer_socket_id, ns_isn))
@@ -173,13 +399,74 @@ impl Udt {
         Ok(())
     }
 
-    pub async fn bind(&mut self, socket_id: SocketId, addr: SocketAddr) -> Result<()> {
-        let socket = self
-            .get_socket(socket_id)
-            .ok_or_else(|| Error::new(ErrorKind::Other, "unknown socket id"))?;
+    /// Resumes the session of a client of `listener_socket`, now at `peer`, which presents
+    /// its token and the first packet it is missing in the `resumption` block of `hs`.
+    async fn resume_connection(
+        &self,
+        listener_socket: &UdtSocket,
+        peer: SocketAddr,
+        hs: &HandShakeInfo,
+        resumption: &[u8],
+        local_ip: Option<IpAddr>,
+    ) -> Result<()> {
+        let session = match (resumption.get(..16), resumption.get(16..)) {
+            (Some(token), Some(rcv_next)) if rcv_next.len() == 4 => {
+                self.sockets.values().find(|socket| {
+                    socket.listen_socket == Some(listener_socket.socket_id)
+                        && socket.peer_socket_id() == Some(hs.socket_id)
+                        && socket.resumption_token().as_ref().map(|t| &t[..]) == Some(token)
+                })
+            }
+            _ => None,
+        };
+        let resumed = match session {
+            Some(socket) => {
+                let rcv_next = u32::from_be_bytes(resumption[16..].try_into().unwrap());
+                socket
+                    .resume_session(peer, local_ip, rcv_next.into())
+                    .map(|block| (socket, block))
+            }
+            None => Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                UdtError::new(UdtErrorCode::ConnRej, "unknown session"),
+            )),
+        };
+        let (socket, block) = match resumed {
+            Ok(resumed) => resumed,
+            Err(err) => {
+                listener_socket
+                    .reject_handshake(peer, hs, HandshakeRejection::Peer, local_ip)
+                    .await?;
+                return Err(err);
+            }
+        };
+
+        let source_socket_id = hs.socket_id;
+        let mut hs = hs.clone();
+        {
+            let configuration = socket.configuration.read().unwrap();
+            hs.initial_seq_number = socket.initial_seq_number;
+            hs.max_packet_size = configuration.mss;
+            hs.max_window_size = configuration.flight_flag_size;
+        }
+        hs.connection_type = -1;
+        hs.socket_id = socket.socket_id;
+        hs.extensions = vec![(RESUMPTION_EXTENSION, block)];
+        socket.sign_handshake(&mut hs, HandshakeRole::Responder);
+        let packet = UdtControlPacket::new_handshake(hs, source_socket_id);
+        socket.send_to(&peer, packet.into()).await
+    }
+
+    pub(crate) async fn bind(&mut self, socket_id: SocketId, addr: SocketAddr) -> Result<()> {
+        let socket = self.get_socket(socket_id).ok_or_else(|| {
+            Error::other(UdtError::new(UdtErrorCode::InvSock, "unknown socket id"))
+        })?;
 
         if socket.status() != UdtStatus::Init {
-            return Err(Error::new(ErrorKind::Other, "socket already binded"));
+            return Err(Error::other(UdtError::new(
+                UdtErrorCode::BoundSock,
+                "socket already binded",
+            )));
         }
 
         self.update_mux(&socket, Some(addr)).await?;
@@ -187,6 +474,68 @@ impl Udt {
         Ok(())
     }
 
+    /// Attaches `socket` to a new multiplexer running on `udp_socket`, already bound by the user.
+    pub(crate) fn attach_udp_socket(
+        &mut self,
+        socket: &UdtSocket,
+        udp_socket: UdpSocket,
+    ) -> Result<()> {
+        if socket.status() != UdtStatus::Init || socket.multiplexer().is_some() {
+            return Err(Error::other(UdtError::new(
+                UdtErrorCode::BoundSock,
+                "socket already binded",
+            )));
+        }
+        let mux = {
+            let configuration = socket.configuration.read().unwrap();
+            UdtMultiplexer::from_udp_socket(
+                socket.socket_id,
+                udp_socket,
+                &configuration,
+                self.egress_limiter.clone(),
+            )?
+        };
+        self.multiplexers.insert(mux.id, mux.clone());
+        socket.set_multiplexer(&mux);
+        UdtMultiplexer::run(mux);
+        Ok(())
+    }
+
+    /// Attaches `socket` and `peer` to two new multiplexers exchanging datagrams in memory.
+    /// Returns the address of `socket`, for `peer` to connect to.
+    pub(crate) fn attach_memory_pair(
+        &mut self,
+        socket: &UdtSocket,
+        peer: &UdtSocket,
+    ) -> Result<SocketAddr> {
+        for socket in [socket, peer] {
+            if socket.status() != UdtStatus::Init || socket.multiplexer().is_some() {
+                return Err(Error::other(UdtError::new(
+                    UdtErrorCode::BoundSock,
+                    "socket already binded",
+                )));
+            }
+        }
+        let capacity = socket.configuration.read().unwrap().udp_rcv_buf_size;
+        let (channel, peer_channel) = MemoryChannel::pair(capacity);
+        let addr = channel.local_addr();
+        for (socket, channel) in [(socket, channel), (peer, peer_channel)] {
+            let mux = {
+                let configuration = socket.configuration.read().unwrap();
+                UdtMultiplexer::in_memory(
+                    socket.socket_id,
+                    channel,
+                    &configuration,
+                    self.egress_limiter.clone(),
+                )?
+            };
+            self.multiplexers.insert(mux.id, mux.clone());
+            socket.set_multiplexer(&mux);
+            UdtMultiplexer::run(mux);
+        }
+        Ok(addr)
+    }
+
     pub(crate) async fn update_mux(
         &mut self,
         socket: &UdtSocket,
@@ -194,14 +543,15 @@ impl Udt {
     ) -> Result<()> {
         if socket.configuration.read().unwrap().reuse_mux {
             if let Some(bind_addr) = bind_addr {
-                let port = bind_addr.port();
-                if port > 0 {
-                    for mux in self.multiplexers.values() {
-                        let socket_mss = socket.configuration.read().unwrap().mss;
-                        if mux.reusable && mux.port == port && mux.mss == socket_mss {
-                            socket.set_multiplexer(mux);
-                            return Ok(());
-                        }
+                if bind_addr.port() > 0 {
+                    let configuration = socket.configuration.read().unwrap();
+                    if let Some(mux) = self
+                        .multiplexers
+                        .values()
+                        .find(|mux| mux.can_share(bind_addr, &configuration))
+                    {
+                        socket.set_multiplexer(mux);
+                        return Ok(());
                     }
                 }
             }
@@ -210,12 +560,23 @@ impl Udt {
         // A new multiplexer is needed
         let mux = {
             let configuration = socket.configuration.read().unwrap().clone();
-            let (mux_id, mux) = if let Some(bind_addr) = bind_addr {
-                UdtMultiplexer::bind(socket.socket_id, bind_addr, &configuration).await?
+            let mux = if let Some(bind_addr) = bind_addr {
+                UdtMultiplexer::bind(
+                    socket.socket_id,
+                    bind_addr,
+                    &configuration,
+                    self.egress_limiter.clone(),
+                )
+                .await?
             } else {
-                UdtMultiplexer::new(socket.socket_id, &configuration).await?
+                UdtMultiplexer::new(
+                    socket.socket_id,
+                    &configuration,
+                    self.egress_limiter.clone(),
+                )
+                .await?
             };
-            self.multiplexers.insert(mux_id, mux.clone());
+            self.multiplexers.insert(mux.id, mux.clone());
             mux
         };
         socket.set_multiplexer(&mux);
@@ -224,11 +585,19 @@ impl Udt {
     }
 
     async fn remove_broken_sockets(&mut self) {
-        for (_, sock) in self
+        let now = Instant::now();
+        for (socket_id, sock) in self
             .sockets
             .iter()
             .filter(|(_, s)| s.status() == UdtStatus::Broken)
         {
+            // A resumable session is kept with its buffers until the window closes
+            if let Some(window) = sock.resumption_window() {
+                let broken_since = *self.broken_since.entry(*socket_id).or_insert(now);
+                if now - broken_since < window {
+                    continue;
+                }
+            }
             if let Some(listen_socket_id) = sock.listen_socket {
                 if let Some(listener) = self.sockets.get(&listen_socket_id) {
                     listener
@@ -244,17 +613,44 @@ impl Udt {
             });
         }
 
-        let to_remove: Vec<_> = self
+        self.broken_since.retain(|socket_id, _| {
+            self.sockets
+                .get(socket_id)
+                .is_some_and(|s| s.status() == UdtStatus::Broken)
+        });
+
+        for (socket_id, _) in self
             .sockets
             .iter()
             .filter(|(_, s)| s.status() == UdtStatus::Closing)
+        {
+            self.closing_since.entry(*socket_id).or_insert(now);
+        }
+
+        let to_remove: Vec<_> = self
+            .closing_since
+            .iter()
+            .filter(|(_, since)| since.elapsed() >= self.purge_delay)
             .map(|(socket_id, _)| *socket_id)
             .collect();
         for socket_id in to_remove {
+            self.closing_since.remove(&socket_id);
             if let Some(sock) = self.sockets.remove(&socket_id) {
                 *sock.status.lock().unwrap() = UdtStatus::Closed;
+                self.purged_ids.insert(socket_id, now);
+                self.purged_sockets += 1;
             }
+            self.peers.retain(|_, ids| {
+                ids.remove(&socket_id);
+                !ids.is_empty()
+            });
         }
+
+        let id_reuse_guard = self.id_reuse_guard;
+        self.purged_ids
+            .retain(|_, purged_at| purged_at.elapsed() < id_reuse_guard);
+
+        self.release_idle_multiplexers().await;
     }
 
     fn cleanup_worker() {
@@ -262,7 +658,7 @@ impl Udt {
             let udt = Self::get();
             loop {
                 udt.write().await.remove_broken_sockets().await;
-                sleep(std::time::Duration::from_secs(1)).await;
+                sleep(Duration::from_secs(1)).await;
             }
         });
     }
@@ -0,0 +1,124 @@
+use crate::configuration::UdtAlarmThresholds;
+use crate::event::{UdtAlarm, UdtConnectionEvent};
+use tokio::time::{Duration, Instant};
+
+/// Period over which loss rate and goodput are measured
+pub(crate) const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+const ALARMS: [UdtAlarm; 3] = [UdtAlarm::Rtt, UdtAlarm::LossRate, UdtAlarm::Goodput];
+
+/// Tracks the metrics watched by the alarms of a connection over the current interval.
+#[derive(Debug)]
+pub(crate) struct Watchdog {
+    interval_start: Instant,
+    sent_packets: u64,
+    lost_packets: u64,
+    acked_bytes: u64,
+    crossed_since: [Option<Instant>; 3],
+    raised: [bool; 3],
+}
+
+impl Watchdog {
+    pub fn new() -> Self {
+        Self {
+            interval_start: Instant::now(),
+            sent_packets: 0,
+            lost_packets: 0,
+            acked_bytes: 0,
+            crossed_since: [None; 3],
+            raised: [false; 3],
+        }
+    }
+
+    pub fn on_sent(&mut self, packets: usize) {
+        self.sent_packets += packets as u64;
+    }
+
+    pub fn on_loss(&mut self, packets: u64) {
+        self.lost_packets += packets;
+    }
+
+    pub fn on_acked(&mut self, bytes: usize) {
+        self.acked_bytes += bytes as u64;
+    }
+
+    /// Closes the current interval if it is over, and returns the alarms raised or cleared.
+    /// `sending` tells whether data was waiting to be sent: goodput is not watched otherwise.
+    pub fn check(
+        &mut self,
+        thresholds: &UdtAlarmThresholds,
+        rtt: Duration,
+        sending: bool,
+        now: Instant,
+    ) -> Vec<UdtConnectionEvent> {
+        let elapsed = now.saturating_duration_since(self.interval_start);
+        if elapsed < WATCHDOG_INTERVAL {
+            return vec![];
+        }
+
+        let loss_rate = if self.sent_packets > 0 {
+            self.lost_packets as f64 / self.sent_packets as f64
+        } else {
+            0.0
+        };
+        let goodput = self.acked_bytes as f64 / elapsed.as_secs_f64();
+        let crossed = [
+            thresholds.max_rtt.is_some_and(|max| rtt > max),
+            thresholds.max_loss_rate.is_some_and(|max| loss_rate > max),
+            sending
+                && thresholds
+                    .min_goodput
+                    .is_some_and(|min| goodput < min as f64),
+        ];
+        self.interval_start = now;
+        self.sent_packets = 0;
+        self.lost_packets = 0;
+        self.acked_bytes = 0;
+
+        let mut events = vec![];
+        for (idx, alarm) in ALARMS.into_iter().enumerate() {
+            if !crossed[idx] {
+                self.crossed_since[idx] = None;
+                if std::mem::take(&mut self.raised[idx]) {
+                    events.push(UdtConnectionEvent::AlarmCleared(alarm));
+                }
+                continue;
+            }
+            let since = *self.crossed_since[idx].get_or_insert(now);
+            if !self.raised[idx] && now.duration_since(since) >= thresholds.sustain {
+                self.raised[idx] = true;
+                events.push(UdtConnectionEvent::AlarmRaised(alarm));
+            }
+        }
+        events
+    }
+}
+
+#[test]
+fn test_watchdog_sustained_loss() {
+    let thresholds = UdtAlarmThresholds {
+        max_loss_rate: Some(0.1),
+        sustain: Duration::from_secs(2),
+        ..Default::default()
+    };
+    let mut watchdog = Watchdog::new();
+    let mut now = watchdog.interval_start;
+    let mut tick = |watchdog: &mut Watchdog, lost| {
+        watchdog.on_sent(100);
+        watchdog.on_loss(lost);
+        now += WATCHDOG_INTERVAL;
+        watchdog.check(&thresholds, Duration::ZERO, true, now)
+    };
+
+    assert!(tick(&mut watchdog, 20).is_empty());
+    assert!(tick(&mut watchdog, 20).is_empty());
+    assert_eq!(
+        tick(&mut watchdog, 20),
+        [UdtConnectionEvent::AlarmRaised(UdtAlarm::LossRate)]
+    );
+    assert!(tick(&mut watchdog, 20).is_empty());
+    assert_eq!(
+        tick(&mut watchdog, 0),
+        [UdtConnectionEvent::AlarmCleared(UdtAlarm::LossRate)]
+    );
+}
@@ -0,0 +1,47 @@
+//! `process::close_all` closes every socket of the process: it runs in its own test binary.
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio_udt::{process, UdtConnection, UdtConnectionEvent, UdtListener};
+
+fn assert_closed(events: &mut broadcast::Receiver<UdtConnectionEvent>) {
+    let mut last = None;
+    while let Ok(event) = events.try_recv() {
+        last = Some(event);
+    }
+    assert_eq!(last, Some(UdtConnectionEvent::Closed));
+}
+
+#[tokio::test]
+async fn test_close_all() {
+    let listener = UdtListener::bind("127.0.0.1:0".parse().unwrap(), None)
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, accepted) = tokio::join!(UdtConnection::connect(addr, None), listener.accept());
+    let mut client = client.unwrap();
+    let (_, mut server) = accepted.unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let (mut client_events, mut server_events) =
+        (client.subscribe_events(), server.subscribe_events());
+    // Unacknowledged data keeps the sockets lingering past the timeout: they are closed abruptly
+    client.write_all(&[0; 100_000]).await.unwrap();
+    server.write_all(&[0; 100_000]).await.unwrap();
+
+    process::close_all(Duration::ZERO).await;
+
+    assert_closed(&mut client_events);
+    assert_closed(&mut server_events);
+    assert!(client.write_all(b"closed").await.is_err());
+    assert!(listener.accept().await.is_err());
+    // The multiplexers release their UDP sockets once their aborted workers are dropped
+    for addr in [addr, client_addr] {
+        let mut attempts = 0;
+        while UdpSocket::bind(addr).await.is_err() {
+            attempts += 1;
+            assert!(attempts < 100, "UDP socket {} not released", addr);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
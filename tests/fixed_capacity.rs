@@ -0,0 +1,59 @@
+//! Transfers in fixed-capacity mode perform no heap allocation once the connection is
+//! warmed up: it runs in its own test binary, with a counting global allocator.
+#![cfg(target_os = "linux")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_udt::{UdtConfiguration, UdtConnection, UdtListener};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_steady_state_does_not_allocate() {
+    let configuration = UdtConfiguration::fixed_capacity_profile(1024);
+    let listener = UdtListener::bind("127.0.0.1:0".parse().unwrap(), Some(configuration.clone()))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, accepted) = tokio::join!(
+        UdtConnection::connect(addr, Some(configuration)),
+        listener.accept()
+    );
+    let mut client = client.unwrap();
+    let (_, mut server) = accepted.unwrap();
+
+    let data = vec![1u8; 1_000_000];
+    let mut buf = vec![0u8; data.len()];
+    for round in 0..4 {
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let (written, read) = tokio::join!(client.write_all(&data), server.read_exact(&mut buf));
+        written.unwrap();
+        read.unwrap();
+        assert_eq!(buf, data);
+        // The first rounds warm up the runtime and the connection
+        if round >= 2 {
+            assert_eq!(ALLOCATIONS.load(Ordering::Relaxed), allocations);
+        }
+    }
+}